@@ -0,0 +1,51 @@
+//! Cost of the `ProxyStream` accessors, which go through the boxed extras for anything but the
+//! peer address and state.
+//!
+//! Run with `cargo bench --bench accessors`. Each accessor is called `CALLS` times on a stream
+//! accepted with a version 1 header, and the best of `ROUNDS` rounds is reported in nanoseconds
+//! per call. Each should stay within a few nanoseconds; anything well beyond that is worth
+//! looking into.
+
+extern crate hyper;
+extern crate hyper_networklistener_proxy;
+
+use std::hint::black_box;
+use std::io::Write;
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+use hyper::net::{HttpListener, NetworkStream};
+use hyper_networklistener_proxy::{ProxyListener, ProxyProtocolVersion};
+
+const CALLS: u32 = 10_000_000;
+const ROUNDS: usize = 5;
+
+
+fn time<F: FnMut()>(mut f: F) -> Duration {
+    (0..ROUNDS).map(|_| {
+        let start = Instant::now();
+        for _ in 0..CALLS {
+            f();
+        }
+        start.elapsed()
+    }).min().unwrap()
+}
+
+
+fn report(name: &str, elapsed: Duration) {
+    println!("{:>12}: {:.2} ns/call", name, elapsed.as_secs_f64() * 1e9 / f64::from(CALLS));
+}
+
+
+fn main() {
+    let mut listener = ProxyListener::new(HttpListener::new("127.0.0.1:0").expect("should be able to bind"), ProxyProtocolVersion::V1);
+    let mut client = TcpStream::connect(listener.bound_addr().unwrap()).expect("should be able to connect");
+    client.write_all(b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n").expect("write must succeed");
+    let mut stream = listener.accept_detailed().expect("should accept");
+
+    report("peer_addr", time(|| { black_box(black_box(&mut stream).peer_addr().unwrap()); }));
+    report("proxy_state", time(|| { black_box(black_box(&stream).proxy_state()); }));
+    report("proxy_info", time(|| { black_box(black_box(&stream).proxy_info().map(|info| info.source_addr())); }));
+    report("bytes_read", time(|| { black_box(black_box(&stream).bytes_read()); }));
+    report("age", time(|| { black_box(black_box(&stream).age()); }));
+}
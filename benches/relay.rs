@@ -0,0 +1,129 @@
+//! Relay throughput of `proxy_copy_bidirectional` with splice(2) compared with plain copies.
+//!
+//! Run with `cargo bench --bench relay`. Each round pushes `TRANSFER_MB` from a client through
+//! a relay to a loopback upstream, which reads and discards it; the best of `ROUNDS` rounds is
+//! reported for each. Wrapping the accepted streams in a type the relay cannot see through
+//! forces the copying fallback. On platforms without splice(2) both numbers measure the
+//! fallback.
+
+extern crate hyper;
+extern crate hyper_networklistener_proxy;
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use hyper::net::{HttpListener, HttpStream, NetworkListener, NetworkStream};
+use hyper_networklistener_proxy::{proxy_copy_bidirectional, ProxyListener, ProxyProtocolVersion};
+
+const TRANSFER_MB: usize = 512;
+const ROUNDS: usize = 5;
+const CHUNK: usize = 64 * 1024;
+const HEADER: &[u8] = b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n";
+
+
+/// An `HttpStream` the relay cannot find the socket of
+#[derive(Clone)]
+struct Opaque(HttpStream);
+
+impl Read for Opaque {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for Opaque {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl NetworkStream for Opaque {
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        self.0.peer_addr()
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.0.set_read_timeout(dur)
+    }
+
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.0.set_write_timeout(dur)
+    }
+
+    fn close(&mut self, how: Shutdown) -> io::Result<()> {
+        self.0.close(how)
+    }
+}
+
+
+/// An `HttpListener` accepting `Opaque` streams
+#[derive(Clone)]
+struct OpaqueListener(HttpListener);
+
+impl NetworkListener for OpaqueListener {
+    type Stream = Opaque;
+
+    fn accept(&mut self) -> hyper::Result<Opaque> {
+        self.0.accept().map(Opaque)
+    }
+
+    fn local_addr(&mut self) -> io::Result<SocketAddr> {
+        self.0.local_addr()
+    }
+}
+
+
+/// Time relaying `TRANSFER_MB` from a client of `front` to an upstream
+fn transfer<L>(front: L) -> Duration where L: NetworkListener + Clone, L::Stream: Clone + Send {
+    let mut front = ProxyListener::new(front, ProxyProtocolVersion::V1);
+    let back = TcpListener::bind("127.0.0.1:0").expect("should be able to bind");
+    let (front_addr, back_addr) = (front.local_addr().unwrap(), back.local_addr().unwrap());
+    let sender = thread::spawn(move || {
+        let mut conn = TcpStream::connect(front_addr).expect("should be able to connect");
+        conn.write_all(HEADER).expect("write must succeed");
+        let chunk = vec![0x5au8; CHUNK];
+        for _ in 0..(TRANSFER_MB * 1024 * 1024 / CHUNK) {
+            conn.write_all(&chunk).expect("write must succeed");
+        }
+        conn.shutdown(Shutdown::Write).expect("shutdown must succeed");
+        io::copy(&mut conn, &mut io::sink()).expect("read must succeed");
+    });
+    let receiver = thread::spawn(move || {
+        let (mut conn, _) = back.accept().expect("should accept");
+        conn.shutdown(Shutdown::Write).expect("shutdown must succeed");
+        io::copy(&mut conn, &mut io::sink()).expect("read must succeed")
+    });
+
+    let mut stream = front.accept_detailed().expect("should accept");
+    let mut upstream = TcpStream::connect(back_addr).expect("should be able to connect");
+    let start = Instant::now();
+    proxy_copy_bidirectional(&mut stream, &mut upstream).expect("relay should succeed");
+    let elapsed = start.elapsed();
+    sender.join().unwrap();
+    assert_eq!(receiver.join().unwrap() as usize, TRANSFER_MB * 1024 * 1024);
+    elapsed
+}
+
+
+fn best_of<F: FnMut() -> Duration>(mut f: F) -> Duration {
+    (0..ROUNDS).map(|_| f()).min().unwrap()
+}
+
+
+fn mb_per_sec(elapsed: Duration) -> f64 {
+    TRANSFER_MB as f64 / (elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9)
+}
+
+
+fn main() {
+    let spliced = mb_per_sec(best_of(|| transfer(HttpListener::new("127.0.0.1:0").unwrap())));
+    let copied = mb_per_sec(best_of(|| transfer(OpaqueListener(HttpListener::new("127.0.0.1:0").unwrap()))));
+    println!("splice(2): {:8.0} MB/s", spliced);
+    println!("copy:      {:8.0} MB/s ({:+.1}%)", copied, (copied - spliced) / spliced * 100.0);
+}
@@ -0,0 +1,78 @@
+//! Loopback throughput of `ProxyStream<HttpStream>` compared with a bare `HttpStream`.
+//!
+//! Run with `cargo bench --bench throughput`. Each round pushes `TRANSFER_MB` through a
+//! loopback connection and reads it on the accepting side, through either an `HttpListener`
+//! or a `ProxyListener` wrapping one; the best of `ROUNDS` rounds is reported for each. The
+//! wrapped stream should stay within 5% of the bare one. Loopback numbers are noisy, so
+//! anything beyond that is reported rather than treated as a failure.
+
+extern crate hyper;
+extern crate hyper_networklistener_proxy;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use hyper::net::{HttpListener, NetworkListener};
+use hyper_networklistener_proxy::{ProxyListener, ProxyProtocolVersion};
+
+const TRANSFER_MB: usize = 512;
+const ROUNDS: usize = 5;
+const CHUNK: usize = 64 * 1024;
+const BOUND: f64 = 0.05;
+
+
+/// Send `TRANSFER_MB` (after `preamble`) to whatever `listener` is bound to, and time how long
+/// reading it all off of the accepted stream takes
+fn transfer<L: NetworkListener>(mut listener: L, preamble: &'static [u8]) -> Duration {
+    let addr = listener.local_addr().expect("should be able to find local addr");
+    let sender = thread::spawn(move || {
+        let mut conn = TcpStream::connect(addr).expect("should be able to connect");
+        conn.write_all(preamble).expect("write must succeed");
+        let chunk = vec![0x5au8; CHUNK];
+        for _ in 0..(TRANSFER_MB * 1024 * 1024 / CHUNK) {
+            conn.write_all(&chunk).expect("write must succeed");
+        }
+    });
+    let mut stream = listener.accept().expect("should accept");
+    let mut buf = vec![0u8; CHUNK];
+    let mut total = 0;
+    let start = Instant::now();
+    loop {
+        match stream.read(&mut buf).expect("read must succeed") {
+            0 => break,
+            n => total += n,
+        }
+    }
+    let elapsed = start.elapsed();
+    sender.join().unwrap();
+    assert_eq!(total, TRANSFER_MB * 1024 * 1024);
+    elapsed
+}
+
+
+fn best_of<F: FnMut() -> Duration>(mut f: F) -> Duration {
+    (0..ROUNDS).map(|_| f()).min().unwrap()
+}
+
+
+fn mb_per_sec(elapsed: Duration) -> f64 {
+    TRANSFER_MB as f64 / (elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9)
+}
+
+
+fn main() {
+    let bare = best_of(|| transfer(HttpListener::new("127.0.0.1:0").unwrap(), b""));
+    let wrapped = best_of(|| {
+        let listener = ProxyListener::new(HttpListener::new("127.0.0.1:0").unwrap(), ProxyProtocolVersion::V1);
+        transfer(listener, b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n")
+    });
+    let (bare, wrapped) = (mb_per_sec(bare), mb_per_sec(wrapped));
+    let delta = (bare - wrapped) / bare;
+    println!("HttpStream:              {:8.0} MB/s", bare);
+    println!("ProxyStream<HttpStream>: {:8.0} MB/s ({:+.1}%)", wrapped, -delta * 100.0);
+    if delta > BOUND {
+        println!("ProxyStream is more than {:.0}% slower than the bare stream", BOUND * 100.0);
+    }
+}
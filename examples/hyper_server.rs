@@ -1,29 +1,27 @@
 extern crate hyper_networklistener_proxy;
 extern crate clap;
 extern crate hyper;
-extern crate iron;
-extern crate router;
 #[macro_use] extern crate log;
 extern crate env_logger;
 
-use std::time::{SystemTime, UNIX_EPOCH};
-
 use clap::Arg;
-use hyper_networklistener_proxy::{ProxyListener, ProxyProtocolVersion};
-use iron::prelude::*;
+use hyper_networklistener_proxy::{ProxyAwareHandler, ProxyInfo, ProxyListener, ProxyProtocolVersion};
 use hyper::net::HttpListener;
-use router::Router;
-use iron::status;
-
-fn handler(request: &mut Request) -> IronResult<Response> {
-    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-    debug!("got request from {:?}", request.remote_addr);
-    Ok(Response::with((status::Ok, format!("time: {}\nyou: {}", now, request.remote_addr))))
+use hyper::server::{Request, Response, Server};
+
+fn handler(request: Request, response: Response, proxy: Option<&ProxyInfo>) {
+    debug!("got request from {:?} via {:?}", request.remote_addr, proxy);
+    let destination = match proxy.and_then(|p| p.destination_addr()) {
+        Some(addr) => addr.to_string(),
+        None => "unknown".to_string(),
+    };
+    let body = format!("you: {}\nyou connected to: {}\n", request.remote_addr, destination);
+    response.send(body.as_bytes()).unwrap();
 }
 
 
 fn main() {
-    let matches = clap::App::new("time_server")
+    let matches = clap::App::new("hyper_server")
                             .version("0.1.0")
                             .author("James Brown <jbrown@easypost.com>")
                             .arg(Arg::with_name("bind")
@@ -37,11 +35,7 @@ fn main() {
     env_logger::init().unwrap();
 
     let inner_listener = HttpListener::new(matches.value_of("bind").unwrap()).unwrap();
-    let listener = ProxyListener::new(inner_listener, ProxyProtocolVersion::V1);
-
-    let mut router = Router::new();
-
-    router.get("/", handler, "index");
+    let listener = ProxyListener::new(inner_listener, ProxyProtocolVersion::Any);
 
-    Iron::new(router).listen(listener, iron::Protocol::http()).unwrap();
+    Server::new(listener).handle(ProxyAwareHandler::new(handler)).unwrap();
 }
@@ -0,0 +1,72 @@
+extern crate hyper_networklistener_proxy;
+extern crate clap;
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::process;
+
+use clap::Arg;
+use hyper_networklistener_proxy::ProxyProtocolVersion;
+use hyper_networklistener_proxy::proxy_protocol::explain;
+
+
+/// Decode a hexdump: pairs of hex digits, ignoring whitespace and any `0x` prefixes
+fn decode_hex(text: &str) -> Result<Vec<u8>, String> {
+    let digits: String = text.split_whitespace()
+        .map(|word| word.trim_start_matches("0x"))
+        .collect();
+    if !digits.len().is_multiple_of(2) {
+        return Err("odd number of hex digits".to_string());
+    }
+    (0..digits.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i+2], 16).map_err(|e| format!("bad hex at digit {}: {}", i, e)))
+        .collect()
+}
+
+
+fn main() {
+    let matches = clap::App::new("proxy_decode")
+                            .version("0.1.0")
+                            .about("Explain how some captured bytes parse as a PROXY protocol header")
+                            .arg(Arg::with_name("proxy-protocol")
+                                     .short("p")
+                                     .takes_value(true)
+                                     .possible_values(&["v1", "v2", "any"])
+                                     .default_value("any")
+                                     .help("PROXY protocol version to expect"))
+                            .arg(Arg::with_name("hex")
+                                     .short("x")
+                                     .help("Input is a hexdump rather than raw bytes"))
+                            .arg(Arg::with_name("input")
+                                     .value_name("FILE")
+                                     .help("File to read (default: stdin)"))
+                            .get_matches();
+
+    let version = match matches.value_of("proxy-protocol").unwrap() {
+        "v1" => ProxyProtocolVersion::V1,
+        "v2" => ProxyProtocolVersion::V2,
+        _ => ProxyProtocolVersion::Any,
+    };
+
+    let mut input = Vec::new();
+    let read = match matches.value_of("input") {
+        Some(path) => File::open(path).and_then(|mut f| f.read_to_end(&mut input)),
+        None => io::stdin().read_to_end(&mut input),
+    };
+    if let Err(e) = read {
+        eprintln!("could not read input: {}", e);
+        process::exit(1);
+    }
+
+    if matches.is_present("hex") {
+        input = match decode_hex(&String::from_utf8_lossy(&input)) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                eprintln!("could not decode hexdump: {}", e);
+                process::exit(1);
+            }
+        };
+    }
+
+    print!("{}", explain(&input, version));
+}
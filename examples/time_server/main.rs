@@ -0,0 +1,37 @@
+extern crate hyper_networklistener_proxy;
+extern crate clap;
+extern crate hyper;
+extern crate iron;
+extern crate router;
+#[macro_use] extern crate log;
+extern crate env_logger;
+
+use clap::Arg;
+
+mod server;
+
+
+fn main() {
+    let matches = clap::App::new("time_server")
+                            .version("0.1.0")
+                            .author("James Brown <jbrown@easypost.com>")
+                            .arg(Arg::with_name("bind")
+                                     .short("B")
+                                     .takes_value(true)
+                                     .required(true)
+                                     .value_name("LISTEN_ADDRESS")
+                                     .help("Address to bind to"))
+                            .arg(Arg::with_name("proxy-protocol")
+                                     .long("proxy-protocol")
+                                     .takes_value(true)
+                                     .default_value("v1")
+                                     .possible_values(&["off", "v1", "v2", "any"])
+                                     .value_name("VERSION")
+                                     .help("PROXY protocol version to expect from the load balancer"))
+                            .get_matches();
+
+    env_logger::init().unwrap();
+
+    let version = server::parse_mode(matches.value_of("proxy-protocol").unwrap()).unwrap();
+    server::serve(matches.value_of("bind").unwrap(), version).unwrap();
+}
@@ -0,0 +1,115 @@
+//! The time server itself, kept apart from `main` so that the integration tests can run it
+//! in-process.
+
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hyper::net::HttpListener;
+use hyper_networklistener_proxy::{ParseVersionError, ProxyConfig, ProxyInfo, ProxyListener, ProxyProtocolVersion, ProxyRegistry};
+use iron::prelude::*;
+use iron::{status, Handler, Listening, Protocol};
+use iron::error::HttpResult;
+use iron::headers::ContentType;
+use router::Router;
+
+
+/// Parse the `--proxy-protocol` flag: `off` for no PROXY protocol at all, or a version
+pub fn parse_mode(mode: &str) -> Result<Option<ProxyProtocolVersion>, ParseVersionError> {
+    if mode.eq_ignore_ascii_case("off") {
+        Ok(None)
+    } else {
+        mode.parse().map(Some)
+    }
+}
+
+
+/// Serve on `bind`, reading PROXY headers of version `version` (if any)
+pub fn serve(bind: &str, version: Option<ProxyProtocolVersion>) -> HttpResult<Listening> {
+    let registry = ProxyRegistry::new();
+    let handler = app(registry.clone());
+    let inner = HttpListener::new(bind)?;
+    match version {
+        Some(version) => {
+            let config = ProxyConfig::new(version).registry(registry);
+            Iron::new(handler).listen(ProxyListener::from_config(inner, &config), Protocol::http())
+        },
+        None => Iron::new(handler).listen(inner, Protocol::http()),
+    }
+}
+
+
+/// The routes, behind a check answering `LOCAL` connections (health checks from the proxy
+/// itself) straight away
+pub fn app(registry: ProxyRegistry) -> impl Handler {
+    let mut router = Router::new();
+    router.get("/", time, "index");
+    let info_registry = registry.clone();
+    router.get("/info", move |request: &mut Request| info(request, info_registry.lookup(request.remote_addr)), "info");
+
+    move |request: &mut Request| {
+        if registry.lookup(request.remote_addr).map(|info| info.is_local()).unwrap_or(false) {
+            return Ok(Response::with((status::Ok, "ok\n")));
+        }
+        router.handle(request)
+    }
+}
+
+
+fn time(request: &mut Request) -> IronResult<Response> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    debug!("got request from {:?}", request.remote_addr);
+    Ok(Response::with((status::Ok, format!("time: {}\nyou: {}", now, request.remote_addr))))
+}
+
+
+/// What was learned from the connection's PROXY header: `key=value` pairs by default, or JSON
+/// with `?format=json`
+fn info(request: &mut Request, proxy: Option<ProxyInfo>) -> IronResult<Response> {
+    let json = request.url.query().map(|query| query.split('&').any(|param| param == "format=json")).unwrap_or(false);
+    if !json {
+        let body = match proxy {
+            Some(info) => format!("{}\n", info.to_kv_string()),
+            None => "no PROXY header\n".to_string(),
+        };
+        return Ok(Response::with((status::Ok, body)));
+    }
+    let body = match proxy {
+        Some(info) => format!(
+            "{{\"src\":{},\"dst\":{},\"version\":{},\"proto\":{},\"local\":{},\"ssl_verified\":{}}}\n",
+            json_addr(info.source_addr()), json_addr(info.destination_addr()), info.version(),
+            json_string(info.proto().as_str()), info.is_local(),
+            info.ssl_info().map(|ssl| ssl.cert_verified().to_string()).unwrap_or_else(|| "null".to_string())),
+        None => "null\n".to_string(),
+    };
+    let mut response = Response::with((status::Ok, body));
+    response.headers.set(ContentType::json());
+    Ok(response)
+}
+
+
+fn json_addr(addr: Option<SocketAddr>) -> String {
+    match addr {
+        Some(addr) => json_string(&addr.to_string()),
+        None => "null".to_string(),
+    }
+}
+
+
+/// `s` as a JSON string literal, quoted and escaped
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -6,21 +6,88 @@
 //! Wrapping an HTTP listener so that it will expect the PROXY protocol v2
 //!
 //! ```no_run
+//! # extern crate hyper;
+//! # extern crate hyper_networklistener_proxy;
 //! use hyper_networklistener_proxy::{ProxyListener, ProxyProtocolVersion};
 //! use hyper::net::HttpListener;
 //!
-//! let listener = ProxyListener(
+//! # fn main() {
+//! let listener = ProxyListener::new(
 //!     HttpListener::new("127.0.0.1:8080").unwrap(),
 //!     ProxyProtocolVersion::V2
 //! );
+//!
+//! // or, from a (listener, version) pair
+//! let listener: ProxyListener<HttpListener> =
+//!     (HttpListener::new("127.0.0.1:8081").unwrap(), ProxyProtocolVersion::V2).into();
+//! # }
 //! ```
+//!
+//! # Untrusted input
+//!
+//! Headers are read from whoever manages to connect, so the parsers are written never to
+//! panic on the bytes they are given, however those are malformed, truncated or oversized:
+//! anything which is not a valid header is a `ProxyReadError`. `tests/adversarial.rs` holds
+//! the inputs which are known to have been trouble for parsers like these.
 
 extern crate hyper;
 extern crate byteorder;
+#[cfg(unix)]
+extern crate libc;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(feature = "iron")]
+extern crate iron;
+#[cfg(feature = "log-kv")]
+#[macro_use]
+extern crate log;
+#[cfg(all(test, feature = "serde"))]
+extern crate toml;
 
 mod proxy_stream;
+#[cfg(feature = "iron")]
+pub mod proxy_cert;
+pub mod proxy_config;
+pub mod proxy_handler;
+pub mod proxy_info;
+#[cfg(feature = "log-kv")]
+mod proxy_kv;
+pub mod proxy_limit;
 pub mod proxy_listener;
+#[cfg(unix)]
+pub mod proxy_multi;
+pub mod proxy_observer;
+pub mod proxy_outbound;
 pub mod proxy_protocol;
+#[cfg(feature = "rdns")]
+pub mod proxy_rdns;
+pub mod proxy_registry;
+pub mod proxy_relay;
+#[cfg(feature = "iron")]
+pub mod proxy_route;
+pub mod proxy_strip;
+pub mod proxy_trust;
+#[cfg(feature = "testutil")]
+pub mod testutil;
 
-pub use proxy_listener::ProxyListener;
-pub use proxy_protocol::ProxyProtocolVersion;
+pub use proxy_config::{ProxyConfig, ProxyCompat, PeerAddrFallback, FailureResponse, PeerHop, UnspecifiedSource};
+pub use proxy_handler::{ProxyAwareHandler, ProxyHandler};
+pub use proxy_info::ProxyInfo;
+pub use proxy_limit::{ConnectionLimiter, LimitPolicy, RateLimiter};
+pub use proxy_observer::{ConnectionClosed, ConnectionLimited, LimitKind, ProxyObserver};
+pub use proxy_outbound::OutboundAddresses;
+pub use proxy_listener::{AcceptError, MapStreams, ProxiedAddrs, ProxyHttpStream, ProxyListener, ProxyStream, ProxyWarning, TryCloneStream};
+#[cfg(unix)]
+pub use proxy_multi::MultiProxyListener;
+pub use proxy_protocol::{Command, DetectedVersion, HeaderParser, HexDump, ParseConfig, Proto, ProxyProtocolHeader, ProxyProtocolVersion, ProxyReadError, ParseVersionError, SslInfo, Tlv, Transport, UnixPath};
+#[cfg(feature = "rdns")]
+pub use proxy_rdns::ReverseDns;
+pub use proxy_registry::ProxyRegistry;
+pub use proxy_relay::proxy_copy_bidirectional;
+#[cfg(feature = "iron")]
+pub use proxy_cert::{ClientCertRequired, RequireClientCert};
+#[cfg(feature = "iron")]
+pub use proxy_route::DestinationRouter;
+pub use proxy_strip::StripProxy;
+pub use proxy_trust::{Cidr, ParseCidrError, TrustedHandle, TrustedProxies};
@@ -0,0 +1,98 @@
+use std::error::Error;
+use std::fmt;
+
+use iron::{status, BeforeMiddleware, IronError, IronResult, Request};
+
+use proxy_registry::ProxyRegistry;
+
+
+/// An Iron `BeforeMiddleware` which only lets through requests arriving over connections
+/// whose proxy verified a client certificate, according to the `PP2_TYPE_SSL` TLV of their
+/// version 2 header (see `SslInfo::cert_verified`). Any other request is answered with an
+/// empty `403 Forbidden` (or the status set with `status`), unless its path is exempt.
+///
+/// The `ProxyInfo` of each request's connection is looked up in a `ProxyRegistry`, which must
+/// be the one attached to the listener (see `ProxyConfig::registry`); a connection which is
+/// not in it has no SSL TLV as far as the guard is concerned.
+///
+/// Only available with the `iron` feature.
+///
+/// ```no_run
+/// # extern crate hyper;
+/// # extern crate hyper_networklistener_proxy;
+/// # extern crate iron;
+/// use hyper::net::HttpListener;
+/// use hyper_networklistener_proxy::{ProxyConfig, ProxyListener, ProxyProtocolVersion, ProxyRegistry, RequireClientCert};
+/// use iron::prelude::*;
+/// use iron::status;
+///
+/// # fn main() {
+/// let registry = ProxyRegistry::new();
+/// let config = ProxyConfig::new(ProxyProtocolVersion::V2).registry(registry.clone());
+/// let listener = ProxyListener::from_config(HttpListener::new("0.0.0.0:8080").unwrap(), &config);
+/// let mut chain = Chain::new(|_: &mut Request| Ok(Response::with((status::Ok, "hello, verified client"))));
+/// chain.link_before(RequireClientCert::new(registry).status(status::Unauthorized).exempt("/healthcheck"));
+/// Iron::new(chain).listen(listener, iron::Protocol::http()).unwrap();
+/// # }
+/// ```
+pub struct RequireClientCert {
+    registry: ProxyRegistry,
+    status: status::Status,
+    exempt: Vec<String>,
+}
+
+
+/// The error a request refused by `RequireClientCert` fails with, which Iron logs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientCertRequired;
+
+impl fmt::Display for ClientCertRequired {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("the connection's proxy did not verify a client certificate")
+    }
+}
+
+impl Error for ClientCertRequired {}
+
+
+impl RequireClientCert {
+    /// Construct a guard which looks up connections in `registry`
+    pub fn new(registry: ProxyRegistry) -> Self {
+        RequireClientCert {
+            registry,
+            status: status::Forbidden,
+            exempt: Vec::new(),
+        }
+    }
+
+    /// The status to refuse requests with. Defaults to `403 Forbidden`.
+    pub fn status(mut self, status: status::Status) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// Let through requests for exactly `path` (ignoring any query string) regardless of the
+    /// connection, e.g. for health checks sent by the load balancer itself
+    pub fn exempt<S: Into<String>>(mut self, path: S) -> Self {
+        self.exempt.push(path.into());
+        self
+    }
+
+    fn is_exempt(&self, request: &Request) -> bool {
+        let path = format!("/{}", request.url.path().join("/"));
+        self.exempt.contains(&path)
+    }
+}
+
+
+impl BeforeMiddleware for RequireClientCert {
+    fn before(&self, request: &mut Request) -> IronResult<()> {
+        let verified = self.registry.lookup(request.remote_addr)
+            .and_then(|info| info.ssl_info().map(|ssl| ssl.cert_verified()))
+            .unwrap_or(false);
+        if verified || self.is_exempt(request) {
+            return Ok(());
+        }
+        Err(IronError::new(ClientCertRequired, self.status))
+    }
+}
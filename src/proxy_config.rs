@@ -0,0 +1,727 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use proxy_protocol::{ParseConfig, Proto, ProxyProtocolVersion, DEFAULT_MAX_V1_LINE_LEN, DEFAULT_MAX_V2_BLOCK_LEN};
+use proxy_limit::{ConnectionLimiter, RateLimiter};
+use proxy_observer::{Observer, ProxyObserver};
+#[cfg(feature = "rdns")]
+use proxy_rdns::ReverseDns;
+use proxy_registry::ProxyRegistry;
+use proxy_trust::TrustedHandle;
+
+
+/// What a `ProxyStream` should report from `peer_addr()` when the header did not carry a
+/// source address (e.g., `PROXY UNKNOWN`) *and* the inner stream cannot report one either,
+/// as is the case for unix-socket streams.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Deserialize), serde(rename_all = "snake_case"))]
+pub enum PeerAddrFallback {
+    /// Return the inner stream's error. Note that Iron fails the whole request when this
+    /// happens. This is the default.
+    #[default]
+    Error,
+    /// Report `0.0.0.0:0`
+    Unspecified,
+    /// Report the given address
+    Fixed(SocketAddr),
+}
+
+
+/// What to send to a client whose first bytes could not be parsed as a PROXY header, just
+/// before the connection is closed.
+///
+/// People pointing a browser (or `curl`) straight at a port which expects the PROXY protocol
+/// otherwise just see a reset connection. Responses are only ever sent to clients whose
+/// bytes look like the start of an HTTP request or a TLS handshake; anything else is closed
+/// without a word. Writing the response is best-effort and bounded by a short write timeout,
+/// so a client which does not read cannot hold up the listener.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Deserialize), serde(rename_all = "snake_case"))]
+pub enum FailureResponse {
+    /// Just close the connection. This is the default.
+    #[default]
+    Nothing,
+    /// Send HTTP clients a short `HTTP/1.0 400 Bad Request` explaining that the port expects
+    /// the PROXY protocol, and TLS clients a fatal `handshake_failure` alert
+    Canned,
+    /// Send the given bytes to HTTP and TLS clients alike
+    Custom(Vec<u8>),
+}
+
+
+/// What a `ProxyListener` should do with a header whose source address is unspecified
+/// (`0.0.0.0` or `::`) or has port 0, which no real client connects from, and which usually
+/// means the sender is misconfigured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Deserialize), serde(rename_all = "snake_case"))]
+pub enum UnspecifiedSource {
+    /// Take the addresses at face value. This is the default.
+    #[default]
+    Accept,
+    /// Fail the connection with `ProxyReadError::UnspecifiedSource`
+    Reject,
+    /// Treat the header as if it were `PROXY UNKNOWN`: drop its addresses (keeping any TLVs),
+    /// so that the stream reports the socket peer (or the `PeerAddrFallback`), and warn with
+    /// `ProxyWarning::UnspecifiedSource`
+    Downgrade,
+}
+
+
+/// Which of a chain of headers (see `ProxyConfig::max_proxy_hops`) a stream takes its
+/// `proxy_info`, and so its peer address, from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Deserialize), serde(rename_all = "snake_case"))]
+pub enum PeerHop {
+    /// The first header on the connection, sent by the proxy nearest the listener. This is
+    /// the default.
+    #[default]
+    Outermost,
+    /// The last header read, sent by the proxy furthest from the listener
+    Innermost,
+}
+
+
+/// Configuration for a `ProxyListener`.
+///
+/// Constructed with `ProxyConfig::new` and then adjusted with the builder-style methods below,
+/// all of which are optional; the defaults match the behavior of `ProxyListener::new`.
+///
+/// ```
+/// use hyper_networklistener_proxy::{ProxyConfig, ProxyProtocolVersion, ProxyRegistry};
+///
+/// let registry = ProxyRegistry::new();
+/// let config = ProxyConfig::new(ProxyProtocolVersion::V2)
+///     .registry(registry.clone());
+/// ```
+///
+/// With the `serde` feature, a `ProxyConfig` can also be deserialized, e.g. from a section of
+/// a TOML file. Only `version` is required; every other setting is named after its builder
+/// method and defaults as described there, except that durations are given as whole
+/// milliseconds in fields with an `_ms` suffix (`header_timeout_ms`, etc.). The registry,
+/// observer, reverse DNS, limiters and clock cannot be deserialized, and need to be added
+/// afterwards; `trusted_proxies` is a list of CIDR blocks, which is put in a new
+/// `TrustedHandle` (see `trusted_handle`). Unknown fields are an error.
+///
+/// ```toml
+/// version = "v2"
+/// optional = true
+/// trusted_proxies = ["10.0.0.0/8", "fd00::/8"]
+/// allowed_families = ["tcp4", "tcp6", "unknown"]
+/// peer_addr_fallback = { fixed = "192.0.2.1:0" }
+/// failure_response = "canned"
+/// header_timeout_ms = 500
+/// ```
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Deserialize), serde(deny_unknown_fields))]
+pub struct ProxyConfig {
+    pub(crate) version: ProxyProtocolVersion,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) registry: Option<ProxyRegistry>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) peer_addr_fallback: PeerAddrFallback,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) failure_response: FailureResponse,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) optional: bool,
+    #[cfg_attr(feature = "serde", serde(default, rename = "grace_period_ms", deserialize_with = "de::millis"))]
+    pub(crate) grace_period: Option<Duration>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) allowed_families: Option<Vec<Proto>>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) require_bound_family: bool,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) unspecified_source: UnspecifiedSource,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) require_proxied_address: bool,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) require_header_in_first_read: bool,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) max_proxy_hops: usize,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) peer_hop: PeerHop,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) tcp_nodelay: bool,
+    #[cfg_attr(feature = "serde", serde(default, rename = "tcp_keepalive_ms", deserialize_with = "de::millis"))]
+    pub(crate) tcp_keepalive: Option<Duration>,
+    #[cfg_attr(feature = "serde", serde(default, rename = "header_timeout_ms", deserialize_with = "de::timeout"))]
+    pub(crate) header_timeout: Option<Duration>,
+    #[cfg_attr(feature = "serde", serde(default, rename = "stream_read_timeout_ms", deserialize_with = "de::timeout"))]
+    pub(crate) stream_read_timeout: Option<Duration>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) skip_header_timeouts: bool,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) retain_raw_header: bool,
+    #[cfg_attr(feature = "serde", serde(default, deserialize_with = "de::trusted"))]
+    pub(crate) trusted_proxies: Option<TrustedHandle>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) observer: Option<Observer>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) parse: ParseConfig,
+    #[cfg(feature = "rdns")]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) reverse_dns: Option<ReverseDns>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) connection_limit: Option<ConnectionLimiter>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) rate_limit: Option<RateLimiter>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) clock: Option<Clock>,
+}
+
+
+/// A clock as set with `ProxyConfig::clock`, which needs to be `Debug`
+#[derive(Clone)]
+pub(crate) struct Clock(Arc<dyn Fn() -> Instant + Send + Sync>);
+
+impl Clock {
+    /// The time by `clock`, or by `Instant::now` if there is none
+    pub(crate) fn now(clock: Option<&Clock>) -> Instant {
+        match clock {
+            Some(clock) => (clock.0)(),
+            None => Instant::now(),
+        }
+    }
+}
+
+impl fmt::Debug for Clock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Clock")
+    }
+}
+
+
+#[cfg(feature = "serde")]
+mod de {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer};
+
+    use proxy_trust::{Cidr, TrustedHandle, TrustedProxies};
+
+    /// An optional duration, given as a whole number of milliseconds
+    pub fn millis<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+        Ok(Option::<u64>::deserialize(deserializer)?.map(Duration::from_millis))
+    }
+
+    /// An optional read timeout, given as a whole number of milliseconds, zero meaning none
+    pub fn timeout<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+        Ok(super::nonzero(millis(deserializer)?))
+    }
+
+    /// A fresh `TrustedHandle`, given as a list of CIDR blocks
+    pub fn trusted<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<TrustedHandle>, D::Error> {
+        let cidrs = Option::<Vec<Cidr>>::deserialize(deserializer)?;
+        Ok(cidrs.map(|cidrs| TrustedHandle::new(TrustedProxies::new(cidrs))))
+    }
+}
+
+
+/// `timeout`, unless it is zero: sockets refuse a zero read timeout (with an `InvalidInput`
+/// error), so one is taken to mean no timeout rather than failing every connection
+fn nonzero(timeout: Option<Duration>) -> Option<Duration> {
+    timeout.filter(|timeout| *timeout != Duration::from_secs(0))
+}
+
+
+impl ProxyConfig {
+    /// Construct a configuration expecting the given version of the PROXY protocol
+    pub fn new(version: ProxyProtocolVersion) -> Self {
+        ProxyConfig {
+            version,
+            registry: None,
+            peer_addr_fallback: PeerAddrFallback::default(),
+            failure_response: FailureResponse::default(),
+            optional: false,
+            grace_period: None,
+            allowed_families: None,
+            require_bound_family: false,
+            unspecified_source: UnspecifiedSource::default(),
+            require_proxied_address: false,
+            require_header_in_first_read: false,
+            max_proxy_hops: 1,
+            peer_hop: PeerHop::default(),
+            tcp_nodelay: false,
+            tcp_keepalive: None,
+            header_timeout: None,
+            stream_read_timeout: None,
+            skip_header_timeouts: false,
+            retain_raw_header: false,
+            trusted_proxies: None,
+            observer: None,
+            parse: ParseConfig::default(),
+            #[cfg(feature = "rdns")]
+            reverse_dns: None,
+            connection_limit: None,
+            rate_limit: None,
+            clock: None,
+        }
+    }
+
+    /// Record every accepted connection in `registry` so that its `ProxyInfo` can be looked
+    /// up by peer address
+    pub fn registry(mut self, registry: ProxyRegistry) -> Self {
+        self.registry = Some(registry);
+        self
+    }
+
+    /// Choose what streams report as their peer address when neither the header nor the inner
+    /// stream has one. Defaults to `PeerAddrFallback::Error`. Streams which used the fallback
+    /// report `ProxyState::Placeholder` from `proxy_state()`.
+    pub fn peer_addr_fallback(mut self, fallback: PeerAddrFallback) -> Self {
+        self.peer_addr_fallback = fallback;
+        self
+    }
+
+    /// Choose what, if anything, to send to HTTP or TLS clients which connect without sending
+    /// a PROXY header. Defaults to `FailureResponse::Nothing`.
+    pub fn failure_response(mut self, response: FailureResponse) -> Self {
+        self.failure_response = response;
+        self
+    }
+
+    /// Make the header optional: connections which do not start with a PROXY header of the
+    /// configured version are handed to hyper untouched, with the socket peer as their peer
+    /// address, instead of failing. This is meant for ports which are reached both
+    /// through a load balancer and directly. A connection is only taken to carry a header
+    /// once it has sent the whole `PROXY ` literal or version 2 signature. Defaults to `false`.
+    pub fn optional(mut self, optional: bool) -> Self {
+        self.optional = optional;
+        self
+    }
+
+    /// In optional mode, treat a client which sends nothing at all for `grace` as a plain
+    /// connection, rather than waiting for its first byte. This is needed for protocols in
+    /// which the client waits for the server to speak first. A zero `grace` only looks at
+    /// what the client has already sent by the time the connection is accepted.
+    /// Defaults to `None` (wait for the first byte indefinitely, or until the read timeout).
+    pub fn grace_period(mut self, grace: Option<Duration>) -> Self {
+        self.grace_period = grace;
+        self
+    }
+
+    /// Only accept headers declaring one of `families`; any other header fails the
+    /// connection with a `PermissionDenied` I/O error, just as an unparseable header would.
+    /// Headers which carry no addresses (`PROXY UNKNOWN`, and `LOCAL` health checks from
+    /// version 2 senders) are only accepted if `Proto::Unknown` is listed. Plain connections
+    /// in optional mode are not affected. Defaults to allowing every family.
+    pub fn allowed_families(mut self, families: &[Proto]) -> Self {
+        self.allowed_families = Some(families.to_vec());
+        self
+    }
+
+    /// Fail connections whose header claims addresses of a different family from the
+    /// listener's bound address (e.g., a `TCP6` header on a listener bound to an IPv4
+    /// address), with a `PermissionDenied` I/O error. A listener bound to `::` is taken to be
+    /// dual-stack, and accepts IPv4 headers too; IPv4-mapped IPv6 addresses count as IPv4.
+    /// Nothing is checked if the listener could not report its address. Defaults to `false`.
+    pub fn require_bound_family(mut self, require: bool) -> Self {
+        self.require_bound_family = require;
+        self
+    }
+
+    /// Choose what to do with headers whose source address is `0.0.0.0` or `::`, or whose
+    /// source port is 0, in either version. This is checked before `allowed_families`, so a
+    /// downgraded header is only accepted if `Proto::Unknown` is allowed. Headers without
+    /// addresses are not affected. Defaults to `UnspecifiedSource::Accept`.
+    pub fn unspecified_source(mut self, policy: UnspecifiedSource) -> Self {
+        self.unspecified_source = policy;
+        self
+    }
+
+    /// Fail connections whose header carries no client address, instead of falling back to
+    /// the socket's peer: `LOCAL` health checks, version 2 headers with an unspecified family,
+    /// `PROXY UNKNOWN`, and Unix socket headers (which have paths, but no IP), along with any
+    /// header `unspecified_source` has downgraded. They fail with
+    /// `ProxyReadError::NoClientAddress`, as a `PermissionDenied` I/O error, so that a
+    /// misconfigured proxy can be told apart from a malformed header. Plain connections in
+    /// optional mode are not affected. Defaults to `false`.
+    pub fn require_proxied_address(mut self, require: bool) -> Self {
+        self.require_proxied_address = require;
+        self
+    }
+
+    /// Read the header with a single `read()`, as large as the longest header the
+    /// `parse_config` allows, and fail the connection with `ProxyReadError::SplitHeader` if
+    /// the header is not whole within it. The spec asks senders to send their header in one
+    /// go, and lets receivers insist on it; doing so leaves a middlebox no room to slip in
+    /// bytes between the parts of a header which arrive separately. Whatever the read
+    /// returned after the header is kept and read back first, as usual. With
+    /// `max_proxy_hops`, all the headers have to be in that first read, and with `optional`
+    /// the read is only taken to be a header if it starts like one. Defaults to `false`.
+    pub fn require_header_in_first_read(mut self, require: bool) -> Self {
+        self.require_header_in_first_read = require;
+        self
+    }
+
+    /// Read up to `hops` consecutive headers off of each connection, as sent when it passed
+    /// through several proxies which each added one, instead of just the first. The headers
+    /// after the first may be of either version, whatever the configured one, and each is
+    /// checked just as the first is (e.g., against `allowed_families`). All of them are kept
+    /// for `ProxyStream::proxy_hops`; which one the stream reports otherwise is chosen with
+    /// `peer_hop`. 0 is taken as 1. Defaults to 1.
+    ///
+    /// Seeing whether another header follows means waiting for the bytes after each one, so
+    /// this is only suitable for protocols in which the client speaks first, like HTTP; a
+    /// client which sends nothing until `grace_period` (if set) or the header timeout passes
+    /// is taken to have sent its last header.
+    pub fn max_proxy_hops(mut self, hops: usize) -> Self {
+        self.max_proxy_hops = hops;
+        self
+    }
+
+    /// Choose which of a chain of headers (see `max_proxy_hops`) streams take their
+    /// `proxy_info` and peer address from. Defaults to `PeerHop::Outermost`.
+    pub fn peer_hop(mut self, hop: PeerHop) -> Self {
+        self.peer_hop = hop;
+        self
+    }
+
+    /// Set `TCP_NODELAY` on every accepted connection, before its header is read. Defaults to
+    /// `false` (leave the socket as the inner listener accepted it).
+    ///
+    /// Like `tcp_keepalive`, this only applies to `HttpStream`s, and failing to set the option
+    /// does not fail the connection.
+    pub fn tcp_nodelay(mut self, nodelay: bool) -> Self {
+        self.tcp_nodelay = nodelay;
+        self
+    }
+
+    /// Enable `SO_KEEPALIVE` on every accepted connection, with probes starting after the
+    /// connection has been idle for `idle` (rounded to whole seconds; this part is only
+    /// supported on Linux, Android, macOS and iOS). Keepalive itself is only supported on unix.
+    /// Defaults to `None` (leave the socket as the inner listener accepted it).
+    pub fn tcp_keepalive(mut self, idle: Option<Duration>) -> Self {
+        self.tcp_keepalive = idle;
+        self
+    }
+
+    /// Give every connection `timeout` to send its whole header, instead of the listener's
+    /// read timeout (as set by hyper from `Server::set_read_timeout`, or Iron's
+    /// `Timeouts::read`). A well-behaved load balancer sends the header straight away, so this
+    /// can be much shorter than the timeout for reading requests. Defaults to `None` (use the
+    /// listener's read timeout); a zero `timeout` is taken to mean `None`.
+    pub fn header_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.header_timeout = nonzero(timeout);
+        self
+    }
+
+    /// Set the read timeout of every connection to `timeout` once its header has been read.
+    /// Defaults to `None`, which leaves the stream with the listener's read timeout (restoring
+    /// it, if `header_timeout` replaced it); a zero `timeout` is taken to mean `None`.
+    ///
+    /// Note that hyper (and so Iron) sets its own read timeout whenever it starts reading a
+    /// request body, and its keep-alive timeout between requests, so this only governs the
+    /// wait for the first request.
+    pub fn stream_read_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.stream_read_timeout = nonzero(timeout);
+        self
+    }
+
+    /// Have `accept` (and `accept_detailed`) skip over connections which time out before
+    /// sending their whole header and wait for the next one, as they do for connections
+    /// refused under `LimitPolicy::CloseSilently`, rather than returning an error for each
+    /// (which hyper logs). Defaults to `false`.
+    ///
+    /// Either way, the error for a timed out header is an `io::ErrorKind::TimedOut` error (see
+    /// `ProxyReadError::is_timeout`), which servers generally treat as a routine disconnect.
+    pub fn skip_header_timeouts(mut self, skip: bool) -> Self {
+        self.skip_header_timeouts = skip;
+        self
+    }
+
+    /// Keep a copy of each connection's PROXY header exactly as it was sent, TLVs and all, for
+    /// `ProxyStream::raw_header` (e.g., to write to an audit log). Defaults to `false`, since
+    /// otherwise nothing read while parsing the header is kept.
+    pub fn retain_raw_header(mut self, retain: bool) -> Self {
+        self.retain_raw_header = retain;
+        self
+    }
+
+    /// Only read headers from connections whose socket peer is one of the addresses in
+    /// `trusted`; connections from anywhere else are handed to hyper untouched, with the
+    /// socket peer as their peer address, just as plain connections are in optional mode.
+    /// Connections whose socket peer is unknown (such as unix-socket streams) are never
+    /// trusted. `trusted` is consulted afresh on every accept, so it can be updated while the
+    /// listener is running. Defaults to `None` (read a header from every connection).
+    pub fn trusted_proxies(mut self, trusted: TrustedHandle) -> Self {
+        self.trusted_proxies = Some(trusted);
+        self
+    }
+
+    /// The `TrustedHandle` set with `trusted_proxies` (or deserialized), if any, for updating
+    /// the trusted addresses at runtime
+    pub fn trusted_handle(&self) -> Option<TrustedHandle> {
+        self.trusted_proxies.clone()
+    }
+
+    /// Report what happens to accepted connections to `observer`; see `ProxyObserver`.
+    /// Defaults to `None`.
+    pub fn observer(mut self, observer: Arc<dyn ProxyObserver>) -> Self {
+        self.observer = Some(Observer(observer));
+        self
+    }
+
+    /// How strictly to parse version 1 headers; see `ParseConfig`. Defaults to
+    /// `ParseConfig::default()`.
+    pub fn parse_config(mut self, parse: ParseConfig) -> Self {
+        self.parse = parse;
+        self
+    }
+
+    /// The most bytes a client may send before its header is complete; see
+    /// `ParseConfig::max_header_len`, which this sets on the current `parse_config`. Past it
+    /// the connection is dropped with `HeaderTooLong`, however slowly the bytes arrive.
+    /// Defaults to `None`.
+    pub fn max_header_len(mut self, len: Option<usize>) -> Self {
+        self.parse = self.parse.max_header_len(len);
+        self
+    }
+
+    /// Look up the name of every client with a header in `reverse_dns`, in the background, and
+    /// report it to the observer when the connection closes if it has been found by then; see
+    /// `ReverseDns`. Defaults to `None`. Only available with the `rdns` feature.
+    #[cfg(feature = "rdns")]
+    pub fn reverse_dns(mut self, reverse_dns: ReverseDns) -> Self {
+        self.reverse_dns = Some(reverse_dns);
+        self
+    }
+
+    /// Cap the number of connections each client can have open at once; see
+    /// `ConnectionLimiter`. Defaults to `None`.
+    pub fn connection_limit(mut self, limiter: ConnectionLimiter) -> Self {
+        self.connection_limit = Some(limiter);
+        self
+    }
+
+    /// Cap the rate at which each client can open connections; see `RateLimiter`. Defaults to
+    /// `None`.
+    pub fn rate_limit(mut self, limiter: RateLimiter) -> Self {
+        self.rate_limit = Some(limiter);
+        self
+    }
+
+    pub(crate) fn now(&self) -> Instant {
+        Clock::now(self.clock.as_ref())
+    }
+
+    /// Tell the time with `clock` rather than `Instant::now`, for `ProxyStream::age` and the
+    /// `duration` reported to the observer; for tests which need connections to age without
+    /// waiting. Wall-clock timestamps (see `ProxyInfo::accepted_at`) are not affected.
+    /// Defaults to `None`.
+    pub fn clock<F>(mut self, clock: F) -> Self where F: Fn() -> Instant + Send + Sync + 'static {
+        self.clock = Some(Clock(Arc::new(clock)));
+        self
+    }
+}
+
+
+/// Presets matching what particular load balancers send, for those who would rather not look
+/// up which version of the protocol their load balancer speaks.
+///
+/// Each preset spells out every setting which decides which headers are accepted: the
+/// version, the families (and so whether `LOCAL` health checks and `PROXY UNKNOWN` get in),
+/// what becomes of unspecified and missing client addresses, and the whole `ParseConfig`,
+/// including how much room is left for TLVs. A preset therefore accepts the same headers
+/// whatever the defaults of those settings become. Everything to do with the deployment
+/// rather than the load balancer (timeouts, limits, trusted proxies and so on) is left at its
+/// default.
+///
+/// Each preset is a starting point: the `ProxyConfig` returned by `config()` can be adjusted
+/// further with the usual builder methods.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyCompat {
+    /// HAProxy with `send-proxy`: version 1, parsed strictly, since HAProxy wrote the spec
+    HaproxyV1,
+    /// HAProxy with `send-proxy-v2`: version 2, from TCP or Unix socket frontends, with
+    /// `LOCAL` health checks (`check-send-proxy`) and TLVs (ALPN, authority, SSL, unique ID)
+    HaproxyV2,
+    /// AWS Network Load Balancers: version 2, with `LOCAL` health checks and TLVs carrying the
+    /// VPC endpoint ID
+    AwsNlb,
+    /// AWS Classic Load Balancers with proxy protocol enabled: version 1, parsed strictly
+    AwsElbClassic,
+    /// Google Cloud TCP and SSL proxy load balancers: version 1, parsed strictly
+    GcpTcpProxy,
+}
+
+
+impl ProxyCompat {
+    /// The configuration this preset stands for
+    pub fn config(&self) -> ProxyConfig {
+        let tcp = &[Proto::Tcp4, Proto::Tcp6, Proto::Unknown];
+        let (version, families) = match *self {
+            ProxyCompat::HaproxyV1 => (ProxyProtocolVersion::V1, &tcp[..]),
+            ProxyCompat::HaproxyV2 => (ProxyProtocolVersion::V2, &[Proto::Tcp4, Proto::Tcp6, Proto::Unix, Proto::Unknown][..]),
+            ProxyCompat::AwsNlb => (ProxyProtocolVersion::V2, &tcp[..]),
+            ProxyCompat::AwsElbClassic => (ProxyProtocolVersion::V1, &tcp[..]),
+            ProxyCompat::GcpTcpProxy => (ProxyProtocolVersion::V1, &tcp[..]),
+        };
+        // none of these relay datagrams, or send anything the spec does not allow
+        let parse = ParseConfig::strict()
+            .allow_lowercase(false)
+            .allow_lf_terminator(false)
+            .allow_bracketed_ipv6(false)
+            .allow_extra_spaces(false)
+            .normalize_mapped_v4(false)
+            .max_v1_line_len(DEFAULT_MAX_V1_LINE_LEN)
+            .max_v2_block_len(DEFAULT_MAX_V2_BLOCK_LEN)
+            .max_header_len(None);
+        ProxyConfig::new(version)
+            .allowed_families(families)
+            .require_bound_family(false)
+            .unspecified_source(UnspecifiedSource::Accept)
+            .require_proxied_address(false)
+            .require_header_in_first_read(false)
+            .max_proxy_hops(1)
+            .peer_hop(PeerHop::Outermost)
+            .parse_config(parse)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use proxy_protocol::{ParseConfig, Proto, ProxyProtocolVersion, DEFAULT_MAX_V1_LINE_LEN, DEFAULT_MAX_V2_BLOCK_LEN};
+    use super::{ProxyCompat, PeerAddrFallback, FailureResponse, PeerHop, UnspecifiedSource};
+
+    /// Check what `compat` sets: `version`, `families`, and conformant parsing with the default
+    /// limits, leaving the deployment's settings at their defaults
+    fn check_compat(compat: ProxyCompat, version: ProxyProtocolVersion, families: &[Proto]) {
+        let config = compat.config();
+        assert_eq!(config.version, version);
+        assert_eq!(config.allowed_families.as_ref().map(|f| &f[..]), Some(families));
+        assert!(!config.require_bound_family);
+        assert_eq!(config.unspecified_source, UnspecifiedSource::Accept);
+        assert!(!config.require_proxied_address);
+        assert!(!config.require_header_in_first_read);
+        assert_eq!((config.max_proxy_hops, config.peer_hop), (1, PeerHop::Outermost));
+        assert_eq!(config.parse, ParseConfig {
+            reject_noncanonical_ports: true,
+            reject_trailing_fields: true,
+            reject_family_mismatch: true,
+            allow_lowercase: false,
+            allow_lf_terminator: false,
+            allow_bracketed_ipv6: false,
+            allow_extra_spaces: false,
+            reject_overlong_lines: true,
+            reject_dgram: true,
+            normalize_mapped_v4: false,
+            max_v1_line_len: DEFAULT_MAX_V1_LINE_LEN,
+            max_v2_block_len: DEFAULT_MAX_V2_BLOCK_LEN,
+            max_header_len: None,
+        });
+
+        assert!(config.registry.is_none());
+        assert_eq!(config.peer_addr_fallback, PeerAddrFallback::Error);
+        assert_eq!(config.failure_response, FailureResponse::Nothing);
+        assert!(!config.optional);
+        assert_eq!(config.grace_period, None);
+        assert!(!config.tcp_nodelay);
+        assert_eq!(config.tcp_keepalive, None);
+        assert_eq!((config.header_timeout, config.stream_read_timeout), (None, None));
+        assert!(!config.skip_header_timeouts);
+        assert!(!config.retain_raw_header);
+        assert!(config.trusted_proxies.is_none());
+        assert!(config.observer.is_none());
+        assert!(config.connection_limit.is_none());
+        assert!(config.rate_limit.is_none());
+    }
+
+    #[test]
+    fn test_compat_haproxy_v1() {
+        check_compat(ProxyCompat::HaproxyV1, ProxyProtocolVersion::V1, &[Proto::Tcp4, Proto::Tcp6, Proto::Unknown]);
+    }
+
+    #[test]
+    fn test_compat_haproxy_v2() {
+        check_compat(ProxyCompat::HaproxyV2, ProxyProtocolVersion::V2, &[Proto::Tcp4, Proto::Tcp6, Proto::Unix, Proto::Unknown]);
+    }
+
+    #[test]
+    fn test_compat_aws_nlb() {
+        check_compat(ProxyCompat::AwsNlb, ProxyProtocolVersion::V2, &[Proto::Tcp4, Proto::Tcp6, Proto::Unknown]);
+    }
+
+    #[test]
+    fn test_compat_aws_elb_classic() {
+        check_compat(ProxyCompat::AwsElbClassic, ProxyProtocolVersion::V1, &[Proto::Tcp4, Proto::Tcp6, Proto::Unknown]);
+    }
+
+    #[test]
+    fn test_compat_gcp_tcp_proxy() {
+        check_compat(ProxyCompat::GcpTcpProxy, ProxyProtocolVersion::V1, &[Proto::Tcp4, Proto::Tcp6, Proto::Unknown]);
+    }
+
+    #[test]
+    fn test_zero_timeouts() {
+        use std::time::Duration;
+        use super::ProxyConfig;
+
+        let zero = Some(Duration::from_secs(0));
+        let config = ProxyConfig::new(ProxyProtocolVersion::V2).header_timeout(zero).stream_read_timeout(zero);
+        assert_eq!((config.header_timeout, config.stream_read_timeout), (None, None));
+        let config = config.header_timeout(Some(Duration::from_millis(1))).stream_read_timeout(Some(Duration::from_secs(5)));
+        assert_eq!(config.header_timeout, Some(Duration::from_millis(1)));
+        assert_eq!(config.stream_read_timeout, Some(Duration::from_secs(5)));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_deserialize() {
+        use std::time::Duration;
+        use toml;
+        use super::ProxyConfig;
+
+        let config: ProxyConfig = toml::from_str(r#"
+            version = "V1"
+            optional = true
+            grace_period_ms = 250
+            allowed_families = ["tcp4", "tcp6", "unknown"]
+            peer_addr_fallback = { fixed = "192.0.2.1:0" }
+            failure_response = "canned"
+            unspecified_source = "downgrade"
+            require_proxied_address = true
+            require_header_in_first_read = true
+            max_proxy_hops = 2
+            peer_hop = "innermost"
+            tcp_nodelay = true
+            tcp_keepalive_ms = 60000
+            header_timeout_ms = 500
+            stream_read_timeout_ms = 30000
+            trusted_proxies = ["10.0.0.0/8", "fd00::1"]
+        "#).expect("should deserialize");
+        assert_eq!(config.version, ProxyProtocolVersion::V1);
+        assert!(config.optional);
+        assert_eq!(config.grace_period, Some(Duration::from_millis(250)));
+        assert_eq!(config.allowed_families, Some(vec![Proto::Tcp4, Proto::Tcp6, Proto::Unknown]));
+        assert_eq!(config.peer_addr_fallback, PeerAddrFallback::Fixed("192.0.2.1:0".parse().unwrap()));
+        assert_eq!(config.failure_response, FailureResponse::Canned);
+        assert_eq!(config.unspecified_source, UnspecifiedSource::Downgrade);
+        assert!(config.require_proxied_address && config.require_header_in_first_read);
+        assert_eq!((config.max_proxy_hops, config.peer_hop), (2, PeerHop::Innermost));
+        assert!(config.tcp_nodelay);
+        assert_eq!(config.tcp_keepalive, Some(Duration::from_secs(60)));
+        assert_eq!(config.header_timeout, Some(Duration::from_millis(500)));
+        assert_eq!(config.stream_read_timeout, Some(Duration::from_secs(30)));
+        let trusted = config.trusted_handle().expect("should have trusted proxies").get();
+        assert_eq!(trusted.cidrs(), &["10.0.0.0/8".parse().unwrap(), "fd00::1".parse().unwrap()][..]);
+
+        // everything but the version can be left out
+        let config: ProxyConfig = toml::from_str("version = \"any\"").expect("should deserialize");
+        assert_eq!(config.version, ProxyProtocolVersion::Any);
+        assert!(!config.optional);
+        assert_eq!(config.allowed_families, None);
+        assert_eq!(config.peer_addr_fallback, PeerAddrFallback::Error);
+        assert_eq!(config.header_timeout, None);
+        assert!(config.trusted_proxies.is_none());
+
+        let config: ProxyConfig = toml::from_str("version = \"v2\"\nheader_timeout_ms = 0\nstream_read_timeout_ms = 0").expect("should deserialize");
+        assert_eq!((config.header_timeout, config.stream_read_timeout), (None, None));
+
+        for bad in &["optional = true", "version = \"v2\"\ntrusted_proxies = [\"10.0.0.0/33\"]", "version = \"v3\"", "version = \"v2\"\nheader_timeout = 500"] {
+            assert!(toml::from_str::<ProxyConfig>(bad).is_err(), "{:?} should not deserialize", bad);
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use std::marker::PhantomData;
+
+use hyper::net::{HttpStream, NetworkStream, Fresh};
+use hyper::server::{Handler, Request, Response};
+
+use proxy_info::ProxyInfo;
+use proxy_stream::ProxyStream;
+
+
+/// A request handler which, in addition to the request and response, is given the `ProxyInfo`
+/// of the connection the request arrived on.
+///
+/// This is implemented for closures taking the same arguments, just as hyper's `Handler` is.
+pub trait ProxyHandler: Sync + Send {
+    /// Handle a request arriving on a connection whose PROXY header is described by `proxy`
+    /// (`None` if the connection did not send one, or is not a `ProxyStream` at all)
+    fn handle_with_proxy<'a, 'k>(&'a self, req: Request<'a, 'k>, res: Response<'a, Fresh>, proxy: Option<&ProxyInfo>);
+}
+
+
+impl<F> ProxyHandler for F where F: Fn(Request, Response<Fresh>, Option<&ProxyInfo>), F: Sync + Send {
+    fn handle_with_proxy<'a, 'k>(&'a self, req: Request<'a, 'k>, res: Response<'a, Fresh>, proxy: Option<&ProxyInfo>) {
+        self(req, res, proxy)
+    }
+}
+
+
+/// Adapter making a `ProxyHandler` usable as a plain hyper `Handler`, for servers built
+/// without Iron.
+///
+/// The `ProxyInfo` is found by downcasting the request's underlying stream to
+/// `ProxyStream<S>`, so `S` must be the stream type of the listener wrapped by the
+/// `ProxyListener` (`HttpStream` unless specified otherwise).
+///
+/// ```no_run
+/// # extern crate hyper;
+/// # extern crate hyper_networklistener_proxy;
+/// use hyper::net::HttpListener;
+/// use hyper::server::{Request, Response, Server};
+/// use hyper_networklistener_proxy::{ProxyAwareHandler, ProxyInfo, ProxyListener, ProxyProtocolVersion};
+///
+/// # fn main() {
+/// let listener = ProxyListener::new(HttpListener::new("127.0.0.1:8080").unwrap(), ProxyProtocolVersion::V2);
+/// let handler = ProxyAwareHandler::new(|_req: Request, res: Response, proxy: Option<&ProxyInfo>| {
+///     let dest = proxy.and_then(|p| p.destination_addr());
+///     res.send(format!("you connected to {:?}", dest).as_bytes()).unwrap();
+/// });
+/// Server::new(listener).handle(handler).unwrap();
+/// # }
+/// ```
+pub struct ProxyAwareHandler<H, S = HttpStream> {
+    handler: H,
+    _stream: PhantomData<fn() -> S>,
+}
+
+
+impl<H: ProxyHandler> ProxyAwareHandler<H> {
+    /// Wrap `handler`, for use with a `ProxyListener<HttpListener>`
+    pub fn new(handler: H) -> Self {
+        ProxyAwareHandler::for_stream(handler)
+    }
+}
+
+
+impl<H: ProxyHandler, S: NetworkStream> ProxyAwareHandler<H, S> {
+    /// Wrap `handler`, for use with a `ProxyListener` around a listener whose streams are of
+    /// type `S`
+    pub fn for_stream(handler: H) -> Self {
+        ProxyAwareHandler {
+            handler,
+            _stream: PhantomData,
+        }
+    }
+}
+
+
+impl<H: ProxyHandler, S: NetworkStream> Handler for ProxyAwareHandler<H, S> {
+    fn handle<'a, 'k>(&'a self, req: Request<'a, 'k>, res: Response<'a, Fresh>) {
+        let info = req.downcast_ref::<ProxyStream<S>>().and_then(|stream| stream.proxy_info().cloned());
+        self.handler.handle_with_proxy(req, res, info.as_ref())
+    }
+}
@@ -0,0 +1,305 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::SystemTime;
+
+use proxy_outbound::OutboundAddresses;
+use proxy_protocol::{Command, Proto, ProxyProtocolHeader, ProxyProtocolVersion, SslInfo, UnixPath};
+
+
+/// An owned snapshot of what was learned from the PROXY protocol header of a single
+/// connection.
+///
+/// Unlike the stream it was read from, a `ProxyInfo` is cheap to clone and can be handed to
+/// code which never sees the stream itself (see `ProxyRegistry`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyInfo {
+    header: ProxyProtocolHeader,
+    accepted_at: Option<SystemTime>,
+}
+
+impl ProxyInfo {
+    pub(crate) fn from_header(header: ProxyProtocolHeader) -> Self {
+        ProxyInfo {
+            header,
+            accepted_at: None,
+        }
+    }
+
+    /// Stamp the info with when its connection was accepted
+    pub(crate) fn accepted(mut self, at: Option<SystemTime>) -> Self {
+        self.accepted_at = at;
+        self
+    }
+
+    /// A copy with a different header, and the same timestamp
+    fn with_header(&self, header: ProxyProtocolHeader) -> Self {
+        ProxyInfo {
+            header,
+            accepted_at: self.accepted_at,
+        }
+    }
+
+    /// The header itself, for everything it said (see `ProxyProtocolHeader`)
+    pub fn header(&self) -> &ProxyProtocolHeader {
+        &self.header
+    }
+
+    /// The source (client) address claimed by the header, or `None` if the header did not
+    /// carry one (e.g., `PROXY UNKNOWN`)
+    pub fn source_addr(&self) -> Option<SocketAddr> {
+        self.header.source_addr()
+    }
+
+    /// The destination address the client originally connected to, or `None` if the header
+    /// did not carry one
+    pub fn destination_addr(&self) -> Option<SocketAddr> {
+        self.header.dest_addr()
+    }
+
+    /// The source socket path claimed by a version 2 `AF_UNIX` header, or `None` for any other
+    /// header
+    pub fn source_path(&self) -> Option<&UnixPath> {
+        self.header.source_path()
+    }
+
+    /// The destination socket path of a version 2 `AF_UNIX` header, or `None` for any other
+    /// header
+    pub fn destination_path(&self) -> Option<&UnixPath> {
+        self.header.dest_path()
+    }
+
+    /// The version of the PROXY protocol that the header was sent with (1 or 2)
+    pub fn version(&self) -> u8 {
+        self.header.version()
+    }
+
+    /// The protocol of the proxied connection
+    pub fn proto(&self) -> Proto {
+        self.header.proto()
+    }
+
+    /// Whether this was a version 2 `LOCAL` header, which proxies send on connections of their
+    /// own (such as health checks) rather than on behalf of a client
+    pub fn is_local(&self) -> bool {
+        self.header.command() == Command::Local
+    }
+
+    /// The `PP2_TYPE_SSL` TLV of a version 2 header, or `None` if there was none (including for
+    /// every version 1 header)
+    pub fn ssl_info(&self) -> Option<&SslInfo> {
+        self.header.ssl_info()
+    }
+
+    /// A copy with both addresses truncated for logging where full client addresses may not
+    /// be kept: IPv4 addresses are cut down to their first `v4_prefix` bits and IPv6 addresses
+    /// to their first `v6_prefix` bits, with the rest zeroed (so `anonymized(24, 48)` zeroes
+    /// the last octet of IPv4 addresses and the last 80 bits of IPv6 ones). IPv4-mapped IPv6
+    /// addresses are masked as the IPv4 addresses they map. Ports are left alone; chain
+    /// `without_ports` to drop them too. Headers without addresses (`PROXY UNKNOWN`, `AF_UNIX`)
+    /// are copied unchanged.
+    ///
+    /// ```
+    /// # use hyper_networklistener_proxy::StripProxy;
+    /// # use hyper_networklistener_proxy::ProxyProtocolVersion;
+    /// # let mut reader = StripProxy::new(&b"PROXY TCP4 192.0.2.77 10.0.0.1 56 443\r\n"[..], ProxyProtocolVersion::V1);
+    /// # let info = reader.header().unwrap().unwrap();
+    /// assert_eq!(info.anonymized(24, 48).to_kv_string(), "src=192.0.2.0:56 dst=10.0.0.0:443 ver=1 cmd=proxy proto=tcp4");
+    /// assert_eq!(info.anonymized(24, 48).without_ports().clf_client(), "192.0.2.0");
+    /// ```
+    pub fn anonymized(&self, v4_prefix: u8, v6_prefix: u8) -> ProxyInfo {
+        self.with_header(self.header.anonymized(v4_prefix, v6_prefix))
+    }
+
+    /// A copy with the ports of both addresses set to 0
+    pub fn without_ports(&self) -> ProxyInfo {
+        self.with_header(self.header.without_ports())
+    }
+
+    /// When the connection the header came from was accepted, by the wall clock; `None` for
+    /// headers which were not read by a listener (e.g., by `StripProxy`)
+    pub fn accepted_at(&self) -> Option<SystemTime> {
+        self.accepted_at
+    }
+
+    /// The header to send ahead of a connection relayed from `from` to a server which expects
+    /// one: for an accepted `TcpStream` (or `HttpStream`), a snapshot of its peer and local
+    /// addresses; for a `ProxyStream`, the header it was accepted with, falling back to the
+    /// socket's own addresses if that carried none. See `OutboundAddresses`.
+    ///
+    /// ```
+    /// # use std::io::Write;
+    /// # use std::net::{TcpListener, TcpStream};
+    /// use hyper_networklistener_proxy::{ProxyInfo, ProxyProtocolVersion};
+    ///
+    /// # fn main() -> std::io::Result<()> {
+    /// # let listener = TcpListener::bind("127.0.0.1:0")?;
+    /// # let _client = TcpStream::connect(listener.local_addr()?)?;
+    /// # let (accepted, _) = listener.accept()?;
+    /// # let mut upstream = Vec::new();
+    /// upstream.write_all(&ProxyInfo::for_outbound(&accepted)?.to_bytes(ProxyProtocolVersion::V1))?;
+    /// assert!(upstream.starts_with(b"PROXY TCP4 127.0.0.1 127.0.0.1 "));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn for_outbound<S: OutboundAddresses + ?Sized>(from: &S) -> io::Result<ProxyInfo> {
+        from.outbound_header()
+    }
+
+    /// Encode the header to send on to another server, as `version`, or, for `Any`, as the
+    /// version it was read in (2 for headers made from a socket by `for_outbound`). Version 1
+    /// can only carry TCP addresses, so anything else (including `LOCAL` headers) is encoded
+    /// as `PROXY UNKNOWN`. TLVs are not sent on.
+    pub fn to_bytes(&self, version: ProxyProtocolVersion) -> Vec<u8> {
+        self.header.to_bytes(version)
+    }
+
+    /// A copy with no TLVs, as it would be sent on by `to_bytes`
+    pub(crate) fn without_tlvs(&self) -> ProxyInfo {
+        self.with_header(self.header.without_tlvs())
+    }
+
+    /// Whether the header's addresses could have arrived on a socket bound to `bound`: IPv4
+    /// addresses (including IPv4-mapped IPv6 ones) on an IPv4 socket, and IPv6 addresses on
+    /// an IPv6 socket, which may also see IPv4 clients if it is dual-stack (bound to `::`).
+    /// Headers without addresses, and `LOCAL` ones whose addresses mean nothing, always could.
+    pub(crate) fn matches_family_of(&self, bound: SocketAddr) -> bool {
+        let source = match self.source_addr() {
+            Some(source) if self.header.command() != Command::Local => source.ip(),
+            _ => return true,
+        };
+        match (bound.ip(), source) {
+            (IpAddr::V4(_), IpAddr::V4(_)) | (IpAddr::V6(_), IpAddr::V6(_)) => true,
+            (IpAddr::V4(_), IpAddr::V6(source)) => source.to_ipv4_mapped().is_some(),
+            (IpAddr::V6(bound), IpAddr::V4(_)) => bound.is_unspecified() || bound.to_ipv4_mapped().is_some(),
+        }
+    }
+}
+
+
+/// Formatting for access logs.
+///
+/// The output of these methods is stable. Every field is a single token with no whitespace,
+/// quotes or other characters needing escaping: addresses are formatted as by `SocketAddr`
+/// (with IPv6 addresses in brackets), and anything the header did not carry (such as the
+/// addresses of `PROXY UNKNOWN` or a version 2 `LOCAL` header) is written as `-`. The socket
+/// paths of `AF_UNIX` headers are left out, since they could contain anything; their `src`
+/// and `dst` are `-` as well.
+impl ProxyInfo {
+    /// Space-separated `key=value` pairs, always in this order:
+    ///
+    /// * `src`: the source address and port
+    /// * `dst`: the destination address and port
+    /// * `ver`: `1` or `2`
+    /// * `cmd`: `proxy`, `local`, or `unknown` for headers which carry no addresses
+    /// * `proto`: `tcp4`, `tcp6`, `udp4`, `udp6`, `unix` or `unknown`
+    ///
+    /// ```
+    /// # use hyper_networklistener_proxy::StripProxy;
+    /// # use hyper_networklistener_proxy::ProxyProtocolVersion;
+    /// # let mut reader = StripProxy::new(&b"PROXY TCP4 1.2.3.4 10.0.0.1 56 443\r\n"[..], ProxyProtocolVersion::V1);
+    /// # let info = reader.header().unwrap().unwrap();
+    /// assert_eq!(info.to_kv_string(), "src=1.2.3.4:56 dst=10.0.0.1:443 ver=1 cmd=proxy proto=tcp4");
+    /// ```
+    pub fn to_kv_string(&self) -> String {
+        let command = match (self.header.proto(), self.header.command()) {
+            (Proto::Unknown, _) | (_, Command::Unspec) => "unknown",
+            (_, Command::Local) => "local",
+            (_, Command::Proxy) => "proxy",
+        };
+        format!("src={} dst={} ver={} cmd={} proto={}", format_addr(self.source_addr()),
+                format_addr(self.destination_addr()), self.version(), command, self.proto())
+    }
+
+    /// The remote host field of the Common Log Format: the source IP address without its
+    /// port (and IPv6 addresses without brackets), or `-`
+    pub fn clf_client(&self) -> String {
+        match self.source_addr() {
+            Some(addr) => addr.ip().to_string(),
+            None => "-".to_string(),
+        }
+    }
+}
+
+
+fn format_addr(addr: Option<SocketAddr>) -> String {
+    match addr {
+        Some(addr) => addr.to_string(),
+        None => "-".to_string(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use proxy_protocol::{read_proxy_protocol_v1, read_proxy_protocol_v2};
+    use super::ProxyInfo;
+
+    fn v1(line: &[u8]) -> ProxyInfo {
+        ProxyInfo::from_header(read_proxy_protocol_v1(&mut (line as &[u8])).expect("should parse"))
+    }
+
+    fn v2(header: &[u8]) -> ProxyInfo {
+        ProxyInfo::from_header(read_proxy_protocol_v2(&mut (header as &[u8])).expect("should parse"))
+    }
+
+    #[test]
+    fn test_log_formats() {
+        let cases = vec![
+            (v1(b"PROXY TCP4 1.2.3.4 10.0.0.1 56 443\r\n"),
+             "src=1.2.3.4:56 dst=10.0.0.1:443 ver=1 cmd=proxy proto=tcp4", "1.2.3.4"),
+            (v1(b"PROXY TCP6 2001:db8::1 2001:db8::2 56 443\r\n"),
+             "src=[2001:db8::1]:56 dst=[2001:db8::2]:443 ver=1 cmd=proxy proto=tcp6", "2001:db8::1"),
+            (v1(b"PROXY UNKNOWN\r\n"),
+             "src=- dst=- ver=1 cmd=unknown proto=unknown", "-"),
+            (v2(b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x11\x00\x0c\x01\x02\x03\x04\x0a\x00\x00\x01\x00\x38\x01\xbb"),
+             "src=1.2.3.4:56 dst=10.0.0.1:443 ver=2 cmd=proxy proto=tcp4", "1.2.3.4"),
+            (v2(b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x20\x11\x00\x0c\x01\x02\x03\x04\x0a\x00\x00\x01\x00\x38\x01\xbb"),
+             "src=- dst=- ver=2 cmd=local proto=tcp4", "-"),
+            (v2(b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x20\x00\x00\x00"),
+             "src=- dst=- ver=2 cmd=unknown proto=unknown", "-"),
+        ];
+        for (info, kv, clf) in cases {
+            assert_eq!(info.to_kv_string(), kv);
+            assert_eq!(info.clf_client(), clf);
+        }
+        assert!(!v1(b"PROXY UNKNOWN\r\n").is_local());
+        assert!(!v2(b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x11\x00\x0c\x01\x02\x03\x04\x0a\x00\x00\x01\x00\x38\x01\xbb").is_local());
+        assert!(v2(b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x20\x11\x00\x0c\x01\x02\x03\x04\x0a\x00\x00\x01\x00\x38\x01\xbb").is_local());
+        assert!(v2(b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x20\x00\x00\x00").is_local());
+    }
+
+    #[test]
+    fn test_anonymized() {
+        let cases = vec![
+            (b"PROXY TCP4 192.0.2.77 198.51.100.7 4124 443\r\n" as &[u8], 24, 48, "192.0.2.0:4124", "198.51.100.0:443"),
+            (b"PROXY TCP4 192.0.2.77 198.51.100.7 4124 443\r\n", 16, 48, "192.0.0.0:4124", "198.51.0.0:443"),
+            (b"PROXY TCP4 192.0.2.77 198.51.100.7 4124 443\r\n", 20, 48, "192.0.0.0:4124", "198.51.96.0:443"),
+            (b"PROXY TCP4 192.0.2.77 198.51.100.7 4124 443\r\n", 0, 0, "0.0.0.0:4124", "0.0.0.0:443"),
+            (b"PROXY TCP4 192.0.2.77 198.51.100.7 4124 443\r\n", 32, 0, "192.0.2.77:4124", "198.51.100.7:443"),
+            (b"PROXY TCP4 192.0.2.77 198.51.100.7 4124 443\r\n", 200, 0, "192.0.2.77:4124", "198.51.100.7:443"),
+            (b"PROXY TCP6 2001:db8:1234:5678:9abc:def0:1234:5678 2001:db8::2 4124 443\r\n", 0, 48, "[2001:db8:1234::]:4124", "[2001:db8::]:443"),
+            (b"PROXY TCP6 2001:db8:1234:5678:9abc:def0:1234:5678 2001:db8::2 4124 443\r\n", 0, 52, "[2001:db8:1234:5000::]:4124", "[2001:db8::]:443"),
+            (b"PROXY TCP6 2001:db8:1234:5678:9abc:def0:1234:5678 2001:db8::2 4124 443\r\n", 0, 128, "[2001:db8:1234:5678:9abc:def0:1234:5678]:4124", "[2001:db8::2]:443"),
+            // mapped addresses are masked by the IPv4 prefix, and keep their mapping
+            (b"PROXY TCP6 ::ffff:192.0.2.77 ::ffff:198.51.100.7 4124 443\r\n", 24, 0, "[::ffff:192.0.2.0]:4124", "[::ffff:198.51.100.0]:443"),
+        ];
+        for (line, v4_prefix, v6_prefix, src, dst) in cases {
+            let info = v1(line).anonymized(v4_prefix, v6_prefix);
+            assert_eq!(info.source_addr().unwrap().to_string(), src, "{:?} /{} /{}", line, v4_prefix, v6_prefix);
+            assert_eq!(info.destination_addr().unwrap().to_string(), dst, "{:?} /{} /{}", line, v4_prefix, v6_prefix);
+        }
+
+        let info = v2(b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x11\x00\x0c\x01\x02\x03\x04\x0a\x00\x00\x01\x00\x38\x01\xbb");
+        assert_eq!(info.anonymized(24, 48).without_ports().to_kv_string(), "src=1.2.3.0:0 dst=10.0.0.0:0 ver=2 cmd=proxy proto=tcp4");
+        assert_eq!(info.without_ports().to_kv_string(), "src=1.2.3.4:0 dst=10.0.0.1:0 ver=2 cmd=proxy proto=tcp4");
+
+        let mut unix = b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x31\x00\xd8".to_vec();
+        unix.resize(16 + 216, 0);
+        unix[16..19].copy_from_slice(b"/a\0");
+        unix[16 + 108..16 + 111].copy_from_slice(b"/b\0");
+        for info in &[v1(b"PROXY UNKNOWN\r\n"), v2(&unix)] {
+            assert_eq!(&info.anonymized(0, 0), info);
+            assert_eq!(&info.without_ports(), info);
+        }
+    }
+}
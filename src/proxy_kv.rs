@@ -0,0 +1,204 @@
+use std::net::SocketAddr;
+
+use log::{self, Level, Record};
+use log::kv::{self, Key, Source, Value, VisitSource};
+
+use proxy_info::ProxyInfo;
+use proxy_protocol::ProxyReadError;
+
+
+/// The fields of the header as structured log fields, for `log`'s key-value support: `pp.src`
+/// and `pp.dst` (addresses formatted as by `SocketAddr`), `pp.version` (`1` or `2`),
+/// `pp.local` (whether it was a `LOCAL` header), `pp.proto` (as in `ProxyInfo::to_kv_string`),
+/// and, for version 2 headers which carried them, `pp.tlv_len` and `pp.ssl` (whether the
+/// client connected over SSL/TLS). Fields the header has no value for are left out, rather
+/// than given a placeholder. Only available with the `log-kv` feature.
+///
+/// ```
+/// # extern crate log;
+/// # extern crate hyper_networklistener_proxy;
+/// # use hyper_networklistener_proxy::{ProxyProtocolVersion, StripProxy};
+/// use log::kv::Source;
+///
+/// # fn main() {
+/// # let mut reader = StripProxy::new(&b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n"[..], ProxyProtocolVersion::V1);
+/// # let info = reader.header().unwrap().unwrap();
+/// assert_eq!(info.get(log::kv::Key::from_str("pp.src")).map(|src| src.to_string()), Some("192.0.2.1:4124".to_owned()));
+/// assert_eq!(info.count(), 5);
+/// # }
+/// ```
+impl Source for ProxyInfo {
+    fn visit<'kvs>(&'kvs self, visitor: &mut dyn VisitSource<'kvs>) -> Result<(), kv::Error> {
+        let (source, dest) = self.header().addr_refs();
+        if let Some(source) = source {
+            visitor.visit_pair(Key::from_str("pp.src"), Value::from_display(source))?;
+        }
+        if let Some(dest) = dest {
+            visitor.visit_pair(Key::from_str("pp.dst"), Value::from_display(dest))?;
+        }
+        visitor.visit_pair(Key::from_str("pp.version"), Value::from(self.version()))?;
+        visitor.visit_pair(Key::from_str("pp.local"), Value::from(self.is_local()))?;
+        visitor.visit_pair(Key::from_str("pp.proto"), Value::from(self.proto().as_str()))?;
+        if self.header().tlv_len() > 0 {
+            visitor.visit_pair(Key::from_str("pp.tlv_len"), Value::from(self.header().tlv_len()))?;
+        }
+        if let Some(ssl) = self.ssl_info() {
+            visitor.visit_pair(Key::from_str("pp.ssl"), Value::from(ssl.client_ssl()))?;
+        }
+        Ok(())
+    }
+}
+
+
+/// The fields of a log record about one connection: its socket peer address (as `peer`),
+/// then either the header's fields or the reason it was refused (as `error`)
+struct ConnectionFields<'a> {
+    peer: Option<SocketAddr>,
+    info: Option<&'a ProxyInfo>,
+    error: Option<&'a ProxyReadError>,
+}
+
+impl<'a> Source for ConnectionFields<'a> {
+    fn visit<'kvs>(&'kvs self, visitor: &mut dyn VisitSource<'kvs>) -> Result<(), kv::Error> {
+        if let Some(ref peer) = self.peer {
+            visitor.visit_pair(Key::from_str("peer"), Value::from_display(peer))?;
+        }
+        if let Some(info) = self.info {
+            info.visit(visitor)?;
+        }
+        if let Some(error) = self.error {
+            visitor.visit_pair(Key::from_str("error"), Value::from_display(error))?;
+        }
+        Ok(())
+    }
+}
+
+
+/// Log, at debug level, that a connection from `peer` was accepted with `info`
+pub(crate) fn log_accepted(peer: Option<SocketAddr>, info: Option<&ProxyInfo>) {
+    log_connection(format_args!("accepted connection"), &ConnectionFields { peer, info, error: None });
+}
+
+/// Log, at debug level, that a connection from `peer` was refused
+pub(crate) fn log_refused(peer: Option<SocketAddr>, error: &ProxyReadError) {
+    log_connection(format_args!("refused connection"), &ConnectionFields { peer, info: None, error: Some(error) });
+}
+
+fn log_connection(message: ::std::fmt::Arguments, fields: &ConnectionFields) {
+    if !log_enabled!(Level::Debug) {
+        return;
+    }
+    log::logger().log(&Record::builder()
+        .args(message)
+        .level(Level::Debug)
+        .target(module_path!())
+        .module_path_static(Some(module_path!()))
+        .key_values(fields)
+        .build());
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::net::TcpStream;
+    use std::sync::{Mutex, Once};
+
+    use hyper::net::HttpListener;
+    use log::{self, Log, Metadata, Record};
+    use log::kv::{self, Key, Source, Value, VisitSource};
+
+    use proxy_info::ProxyInfo;
+    use proxy_listener::ProxyListener;
+    use proxy_protocol::{read_proxy_protocol_v1, read_proxy_protocol_v2, ProxyProtocolVersion};
+
+    #[derive(Default)]
+    struct Fields(Vec<(String, String)>);
+
+    impl<'kvs> VisitSource<'kvs> for Fields {
+        fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+            self.0.push((key.to_string(), value.to_string()));
+            Ok(())
+        }
+    }
+
+    fn fields<S: Source>(source: &S) -> Vec<(String, String)> {
+        let mut fields = Fields::default();
+        source.visit(&mut fields).expect("visit should succeed");
+        fields.0
+    }
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|&(key, value)| (key.to_owned(), value.to_owned())).collect()
+    }
+
+    #[test]
+    fn test_fields() {
+        let v1 = |line: &[u8]| ProxyInfo::from_header(read_proxy_protocol_v1(&mut &line[..]).expect("should parse"));
+        assert_eq!(fields(&v1(b"PROXY TCP6 2001:db8::1 2001:db8::2 4124 443\r\n")), pairs(&[
+            ("pp.src", "[2001:db8::1]:4124"), ("pp.dst", "[2001:db8::2]:443"), ("pp.version", "1"), ("pp.local", "false"), ("pp.proto", "tcp6"),
+        ]));
+        // left out, rather than given a placeholder
+        assert_eq!(fields(&v1(b"PROXY UNKNOWN\r\n")), pairs(&[("pp.version", "1"), ("pp.local", "false"), ("pp.proto", "unknown")]));
+        let ssl: &[u8] = b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x20\x11\x00\x14\xc0\x00\x02\x01\xc6\x33\x64\x07\x10\x1c\x01\xbb\x20\x00\x05\x01\x00\x00\x00\x00";
+        let info = ProxyInfo::from_header(read_proxy_protocol_v2(&mut &ssl[..]).expect("should parse"));
+        assert_eq!(fields(&info), pairs(&[
+            ("pp.version", "2"), ("pp.local", "true"), ("pp.proto", "tcp4"),
+            ("pp.tlv_len", "8"), ("pp.ssl", "true"),
+        ]));
+    }
+
+    /// A record's message and fields
+    type Logged = (String, Vec<(String, String)>);
+
+    /// Keeps every record logged by this module
+    struct Capture(Mutex<Vec<Logged>>);
+
+    impl Log for Capture {
+        fn enabled(&self, _metadata: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            if record.target() == module_path!().trim_end_matches("::tests") {
+                let fields = fields(&record.key_values());
+                self.0.lock().unwrap().push((record.args().to_string(), fields));
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    static CAPTURE: Capture = Capture(Mutex::new(Vec::new()));
+
+    #[test]
+    fn test_logged_on_accept() {
+        static INSTALL: Once = Once::new();
+        INSTALL.call_once(|| {
+            log::set_logger(&CAPTURE).expect("no other logger should be installed");
+            log::set_max_level(log::LevelFilter::Debug);
+        });
+        let mut listener = ProxyListener::new(HttpListener::new("127.0.0.1:0").unwrap(), ProxyProtocolVersion::V1);
+        let addr = listener.bound_addr().unwrap();
+        let mut good = TcpStream::connect(addr).unwrap();
+        good.write_all(b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n").unwrap();
+        let _accepted = listener.accept_detailed().expect("should accept");
+        let mut bad = TcpStream::connect(addr).unwrap();
+        bad.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        listener.accept_detailed().expect_err("should refuse");
+
+        let records = CAPTURE.0.lock().unwrap();
+        let from = |conn: &TcpStream| {
+            let peer = ("peer".to_owned(), conn.local_addr().unwrap().to_string());
+            records.iter().filter(|record| record.1.first() == Some(&peer)).cloned().collect::<Vec<_>>()
+        };
+        let accepted = from(&good);
+        assert_eq!(accepted.len(), 1);
+        assert_eq!(accepted[0].0, "accepted connection");
+        assert_eq!(&accepted[0].1[1..3], &pairs(&[("pp.src", "192.0.2.1:4124"), ("pp.dst", "198.51.100.7:443")])[..]);
+        let refused = from(&bad);
+        assert_eq!(refused.len(), 1);
+        assert_eq!(refused[0].0, "refused connection");
+        assert_eq!(refused[0].1[1].0, "error");
+    }
+}
@@ -0,0 +1,513 @@
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use proxy_protocol::mask_ip;
+
+
+/// How many sources a limiter tracks at once by default
+const DEFAULT_MAX_SOURCES: usize = 100_000;
+
+/// How many independently locked parts a `RateLimiter`'s buckets are split into, so that
+/// accepts on different threads rarely wait for each other
+const SHARDS: usize = 16;
+
+
+/// What a listener does with a connection which a limiter refuses. Whatever the policy, the
+/// listener's `ProxyObserver` is told with `on_limited`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LimitPolicy {
+    /// Fail the accept with `ProxyReadError::TooManyConnections` (or `RateLimited`), as for a
+    /// bad header, so that the refusal shows up wherever accept errors are logged
+    #[default]
+    Reject,
+    /// Close the connection and go on to accept the next one, without reporting anything
+    CloseSilently,
+    /// Reset the connection (where the inner stream is a TCP socket on unix; elsewhere, just
+    /// close it), and go on to accept the next one, without reporting anything
+    Reset,
+    /// Let the connection through, and only tell the observer that it would have been
+    /// refused; for trying out limits before enforcing them
+    DryRun,
+}
+
+
+/// The key a client's connections are counted under: IPv4-mapped IPv6 addresses count as the
+/// IPv4 addresses they map, and, with `ipv6_per_64`, IPv6 addresses by their /64
+fn client_key(addr: IpAddr, ipv6_per_64: bool) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None if ipv6_per_64 => mask_ip(addr, 32, 64),
+            None => addr,
+        },
+        addr => addr,
+    }
+}
+
+
+/// A cap on the number of open connections from each client, attached to a listener with
+/// `ProxyConfig::connection_limit`.
+///
+/// Clients are told apart by the effective peer address of their connections, i.e., the
+/// source address from the PROXY header rather than the load balancer's, so one client
+/// opening thousands of connections through the load balancer can be held back without
+/// holding back everyone else behind it. A connection counts against its client's limit from
+/// once its header has been read until the last clone of its `ProxyStream` is dropped.
+/// Version 2 `LOCAL` connections (the load balancer's own health checks) are not counted.
+///
+/// IPv4-mapped IPv6 addresses count as the IPv4 addresses they map; with `ipv6_per_64`, every
+/// IPv6 address in a /64 (which usually belongs to a single site) counts as one client. At
+/// most `max_sources` clients are tracked at once: once that many have connections open,
+/// connections from any other client are refused until some of them close.
+///
+/// Clones share the same counts, so one limiter can cover several listeners.
+///
+/// ```
+/// use hyper_networklistener_proxy::{ConnectionLimiter, LimitPolicy, ProxyConfig, ProxyProtocolVersion};
+///
+/// let limiter = ConnectionLimiter::new(64).ipv6_per_64(true).policy(LimitPolicy::CloseSilently);
+/// let config = ProxyConfig::new(ProxyProtocolVersion::V2).connection_limit(limiter.clone());
+/// assert_eq!(limiter.connections_from("192.0.2.1".parse().unwrap()), 0);
+/// ```
+#[derive(Debug, Clone)]
+pub struct ConnectionLimiter {
+    counts: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    max_per_source: usize,
+    max_sources: usize,
+    ipv6_per_64: bool,
+    policy: LimitPolicy,
+}
+
+
+impl ConnectionLimiter {
+    /// Allow each client up to `max_per_source` open connections
+    pub fn new(max_per_source: usize) -> Self {
+        ConnectionLimiter {
+            counts: Arc::default(),
+            max_per_source,
+            max_sources: DEFAULT_MAX_SOURCES,
+            ipv6_per_64: false,
+            policy: LimitPolicy::default(),
+        }
+    }
+
+    /// Track at most `max_sources` clients at once. Defaults to 100,000.
+    pub fn max_sources(mut self, max_sources: usize) -> Self {
+        self.max_sources = max_sources;
+        self
+    }
+
+    /// Count every IPv6 address in the same /64 as one client. Defaults to `false`.
+    pub fn ipv6_per_64(mut self, ipv6_per_64: bool) -> Self {
+        self.ipv6_per_64 = ipv6_per_64;
+        self
+    }
+
+    /// What to do with refused connections. Defaults to `LimitPolicy::Reject`.
+    pub fn policy(mut self, policy: LimitPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// The number of open connections counted against the client `addr` belongs to
+    pub fn connections_from(&self, addr: IpAddr) -> usize {
+        let key = client_key(addr, self.ipv6_per_64);
+        self.counts.lock().unwrap().get(&key).cloned().unwrap_or(0)
+    }
+
+    /// The number of clients with connections open
+    pub fn sources(&self) -> usize {
+        self.counts.lock().unwrap().len()
+    }
+
+    pub(crate) fn limit_policy(&self) -> LimitPolicy {
+        self.policy
+    }
+
+    /// Count a connection from `addr`, unless that would take its client over the limit (or
+    /// there are too many clients already); the connection stops counting when the returned
+    /// slot is dropped
+    pub(crate) fn acquire(&self, addr: IpAddr) -> Option<LimitSlot> {
+        self.acquire_up_to(addr, self.max_per_source)
+    }
+
+    /// Count a connection from `addr` whatever its client's count, as long as there are not
+    /// too many clients already; for dry runs
+    pub(crate) fn acquire_unlimited(&self, addr: IpAddr) -> Option<LimitSlot> {
+        self.acquire_up_to(addr, usize::MAX)
+    }
+
+    fn acquire_up_to(&self, addr: IpAddr, max_per_source: usize) -> Option<LimitSlot> {
+        let key = client_key(addr, self.ipv6_per_64);
+        let mut counts = self.counts.lock().unwrap();
+        if !counts.contains_key(&key) && counts.len() >= self.max_sources {
+            return None;
+        }
+        let count = counts.entry(key).or_insert(0);
+        if *count >= max_per_source {
+            if *count == 0 {
+                counts.remove(&key);
+            }
+            return None;
+        }
+        *count += 1;
+        Some(LimitSlot {
+            counts: Arc::clone(&self.counts),
+            key,
+        })
+    }
+}
+
+
+/// One connection counted by a `ConnectionLimiter`, until this is dropped
+#[derive(Debug)]
+pub(crate) struct LimitSlot {
+    counts: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    key: IpAddr,
+}
+
+
+impl Drop for LimitSlot {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap();
+        let remaining = match counts.get_mut(&self.key) {
+            Some(count) => {
+                *count -= 1;
+                *count
+            },
+            None => return,
+        };
+        if remaining == 0 {
+            counts.remove(&self.key);
+        }
+    }
+}
+
+
+/// A cap on the rate at which each client can open connections, attached to a listener with
+/// `ProxyConfig::rate_limit`.
+///
+/// Each client has a token bucket, which holds up to `burst` tokens and refills at `rate`
+/// tokens per second; every connection takes a token, and connections which find the bucket
+/// empty are refused. Clients are told apart as by `ConnectionLimiter` (by the source address
+/// from the PROXY header, optionally by IPv6 /64), and `LOCAL` connections are not counted.
+///
+/// At most `max_sources` buckets are kept: once there are that many, the least recently used
+/// is dropped to make room for a new one, which only forgets anything if that client has been
+/// busy recently enough for its bucket not to have refilled. The buckets are split between
+/// several locks, so accepts on different threads (or different listeners sharing the
+/// limiter) rarely wait on one another. Clones share the same buckets.
+///
+/// ```
+/// use hyper_networklistener_proxy::{LimitPolicy, ProxyConfig, ProxyProtocolVersion, RateLimiter};
+///
+/// // ten new connections a second per client, with bursts of up to fifty
+/// let limiter = RateLimiter::new(10.0, 50).policy(LimitPolicy::DryRun);
+/// let config = ProxyConfig::new(ProxyProtocolVersion::V2).rate_limit(limiter);
+/// ```
+#[derive(Clone)]
+pub struct RateLimiter {
+    shards: Arc<Vec<Mutex<Shard>>>,
+    rate: f64,
+    burst: f64,
+    max_sources: usize,
+    ipv6_per_64: bool,
+    policy: LimitPolicy,
+    clock: Arc<dyn Fn() -> Instant + Send + Sync>,
+}
+
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    // when `tokens` was last brought up to date, which is also when the bucket was last used
+    updated_at: Instant,
+    // the bucket's key in `Shard::by_use`
+    used: u64,
+}
+
+
+/// One lock's worth of a `RateLimiter`'s buckets
+#[derive(Debug, Default)]
+struct Shard {
+    buckets: HashMap<IpAddr, Bucket>,
+    // every bucket's key, by when it was last used, so that the least recently used can be
+    // found without looking through them all
+    by_use: BTreeMap<u64, IpAddr>,
+    next_use: u64,
+}
+
+
+impl RateLimiter {
+    /// Allow each client `rate` new connections per second on average, and up to `burst` at
+    /// once
+    pub fn new(rate: f64, burst: u32) -> Self {
+        RateLimiter {
+            shards: Arc::new((0..SHARDS).map(|_| Mutex::new(Shard::default())).collect()),
+            rate,
+            burst: f64::from(burst),
+            max_sources: DEFAULT_MAX_SOURCES,
+            ipv6_per_64: false,
+            policy: LimitPolicy::default(),
+            clock: Arc::new(Instant::now),
+        }
+    }
+
+    /// Keep at most `max_sources` buckets at once. Defaults to 100,000.
+    pub fn max_sources(mut self, max_sources: usize) -> Self {
+        self.max_sources = max_sources;
+        self
+    }
+
+    /// Count every IPv6 address in the same /64 as one client. Defaults to `false`.
+    pub fn ipv6_per_64(mut self, ipv6_per_64: bool) -> Self {
+        self.ipv6_per_64 = ipv6_per_64;
+        self
+    }
+
+    /// What to do with refused connections. Defaults to `LimitPolicy::Reject`.
+    pub fn policy(mut self, policy: LimitPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Tell the time with `clock` rather than `Instant::now`, e.g. to test refilling without
+    /// waiting for it
+    pub fn clock<F>(mut self, clock: F) -> Self where F: Fn() -> Instant + Send + Sync + 'static {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// How many connections the client `addr` belongs to could open right now
+    pub fn tokens(&self, addr: IpAddr) -> f64 {
+        let key = client_key(addr, self.ipv6_per_64);
+        let now = (self.clock)();
+        let shard = self.shard(key).lock().unwrap();
+        match shard.buckets.get(&key) {
+            Some(bucket) => self.refilled(bucket, now),
+            None => self.burst,
+        }
+    }
+
+    /// The number of buckets currently kept
+    pub fn sources(&self) -> usize {
+        self.shards.iter().map(|shard| shard.lock().unwrap().buckets.len()).sum()
+    }
+
+    pub(crate) fn limit_policy(&self) -> LimitPolicy {
+        self.policy
+    }
+
+    fn shard(&self, key: IpAddr) -> &Mutex<Shard> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % SHARDS]
+    }
+
+    fn refilled(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated_at);
+        (bucket.tokens + elapsed.as_secs_f64() * self.rate).min(self.burst)
+    }
+
+    /// Take a token from the bucket of the client `addr` belongs to; returns whether there was
+    /// one
+    pub(crate) fn allow(&self, addr: IpAddr) -> bool {
+        let key = client_key(addr, self.ipv6_per_64);
+        let now = (self.clock)();
+        let mut guard = self.shard(key).lock().unwrap();
+        let shard = &mut *guard;
+        let tokens = match shard.buckets.remove(&key) {
+            Some(bucket) => {
+                shard.by_use.remove(&bucket.used);
+                self.refilled(&bucket, now)
+            },
+            None => {
+                // each shard keeps its share of the buckets
+                let capacity = self.max_sources.div_ceil(SHARDS).max(1);
+                while shard.buckets.len() >= capacity {
+                    match shard.by_use.pop_first() {
+                        Some((_, oldest)) => shard.buckets.remove(&oldest),
+                        None => break,
+                    };
+                }
+                self.burst
+            },
+        };
+        let allowed = tokens >= 1.0;
+        let used = shard.next_use;
+        shard.next_use += 1;
+        shard.by_use.insert(used, key);
+        shard.buckets.insert(key, Bucket {
+            tokens: if allowed { tokens - 1.0 } else { tokens },
+            updated_at: now,
+            used,
+        });
+        allowed
+    }
+}
+
+
+// written by hand, since the clock is a closure and the buckets are behind locks
+impl fmt::Debug for RateLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RateLimiter")
+            .field("rate", &self.rate)
+            .field("burst", &self.burst)
+            .field("max_sources", &self.max_sources)
+            .field("ipv6_per_64", &self.ipv6_per_64)
+            .field("policy", &self.policy)
+            .finish()
+    }
+}
+
+
+/// A clock which only moves when told to, for tests
+#[cfg(test)]
+pub(crate) struct TestClock {
+    start: Instant,
+    offset: Mutex<::std::time::Duration>,
+}
+
+#[cfg(test)]
+impl TestClock {
+    pub(crate) fn new() -> Arc<Self> {
+        Arc::new(TestClock { start: Instant::now(), offset: Mutex::new(::std::time::Duration::from_secs(0)) })
+    }
+
+    pub(crate) fn now(&self) -> Instant {
+        self.start + *self.offset.lock().unwrap()
+    }
+
+    pub(crate) fn advance(&self, by: ::std::time::Duration) {
+        *self.offset.lock().unwrap() += by;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::time::Duration;
+
+    use super::{ConnectionLimiter, RateLimiter, TestClock};
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_acquire_and_release() {
+        let limiter = ConnectionLimiter::new(2);
+        let first = limiter.acquire(ip("192.0.2.1")).expect("should be under the limit");
+        let second = limiter.acquire(ip("::ffff:192.0.2.1")).expect("should be under the limit");
+        assert!(limiter.acquire(ip("192.0.2.1")).is_none());
+        assert_eq!(limiter.connections_from(ip("192.0.2.1")), 2);
+        assert!(limiter.acquire(ip("192.0.2.2")).is_some());
+        drop(first);
+        let third = limiter.acquire(ip("192.0.2.1")).expect("a slot should have been freed");
+        drop((second, third));
+        assert_eq!(limiter.sources(), 0);
+
+        assert!(ConnectionLimiter::new(0).acquire(ip("192.0.2.1")).is_none());
+        assert_eq!(ConnectionLimiter::new(0).sources(), 0);
+    }
+
+    #[test]
+    fn test_bounds() {
+        let limiter = ConnectionLimiter::new(1).max_sources(2);
+        let _a = limiter.acquire(ip("192.0.2.1")).unwrap();
+        let b = limiter.acquire(ip("192.0.2.2")).unwrap();
+        assert!(limiter.acquire(ip("192.0.2.3")).is_none(), "too many sources should be tracked");
+        drop(b);
+        assert!(limiter.acquire(ip("192.0.2.3")).is_some());
+
+        let per_address = ConnectionLimiter::new(1);
+        let _a = per_address.acquire(ip("2001:db8::1")).unwrap();
+        assert!(per_address.acquire(ip("2001:db8::2")).is_some());
+        let per_64 = ConnectionLimiter::new(1).ipv6_per_64(true);
+        let _a = per_64.acquire(ip("2001:db8::1")).unwrap();
+        assert!(per_64.acquire(ip("2001:db8::ffff:2")).is_none());
+        assert!(per_64.acquire(ip("2001:db8:0:1::1")).is_some());
+        assert_eq!(per_64.connections_from(ip("2001:db8::1234")), 1);
+
+        let unlimited = ConnectionLimiter::new(0).max_sources(1);
+        let _a = unlimited.acquire_unlimited(ip("192.0.2.1")).expect("dry runs should still count");
+        assert_eq!(unlimited.connections_from(ip("192.0.2.1")), 1);
+        assert!(unlimited.acquire_unlimited(ip("192.0.2.2")).is_none());
+    }
+
+    #[test]
+    fn test_rate_refill() {
+        let clock = TestClock::new();
+        let time = clock.clone();
+        let limiter = RateLimiter::new(2.0, 3).clock(move || time.now());
+        let client = ip("192.0.2.1");
+
+        for _ in 0..3 {
+            assert!(limiter.allow(client), "a burst should be allowed");
+        }
+        assert!(!limiter.allow(client));
+        assert!(limiter.allow(ip("192.0.2.2")), "other clients have buckets of their own");
+
+        clock.advance(Duration::from_millis(499));
+        assert!(!limiter.allow(client), "a token takes half a second to come back");
+        clock.advance(Duration::from_millis(1));
+        assert!(limiter.allow(client));
+        assert!(!limiter.allow(client));
+        assert_eq!(limiter.tokens(client), 0.0);
+
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(limiter.tokens(client), 3.0, "buckets should not fill past the burst");
+        for _ in 0..3 {
+            assert!(limiter.allow(client));
+        }
+        assert!(!limiter.allow(ip("::ffff:192.0.2.1")), "mapped addresses share the IPv4 bucket");
+
+        let per_64 = RateLimiter::new(1.0, 1).ipv6_per_64(true).clock(move || clock.now());
+        assert!(per_64.allow(ip("2001:db8::1")));
+        assert!(!per_64.allow(ip("2001:db8::2")));
+        assert!(per_64.allow(ip("2001:db8:0:1::1")));
+    }
+
+    #[test]
+    fn test_rate_bounded() {
+        let limiter = RateLimiter::new(1.0, 1).max_sources(16);
+        for host in 0..200u32 {
+            assert!(limiter.allow(IpAddr::from([198, 51, (host >> 8) as u8, host as u8])));
+        }
+        assert!(limiter.sources() <= 16, "kept {} buckets", limiter.sources());
+        // the most recent client's bucket is always kept
+        assert!(!limiter.allow(IpAddr::from([198, 51, 0, 199])));
+    }
+
+    #[test]
+    fn test_rate_evicts_least_recently_used() {
+        let clock = TestClock::new();
+        let now = clock.clone();
+        let limiter = RateLimiter::new(0.001, 2).max_sources(2 * super::SHARDS).clock(move || now.now());
+        // three clients sharing a shard, which has room for two
+        let first = IpAddr::from([198, 51, 100, 0]);
+        let same_shard: Vec<IpAddr> = (1..=255u8).map(|host| IpAddr::from([198, 51, 100, host]))
+            .filter(|&addr| ::std::ptr::eq(limiter.shard(addr), limiter.shard(first)))
+            .take(2)
+            .collect();
+        let (second, third) = (same_shard[0], same_shard[1]);
+
+        assert!(limiter.allow(first));
+        clock.advance(Duration::from_millis(1));
+        assert!(limiter.allow(second));
+        clock.advance(Duration::from_millis(1));
+        // using the first again makes the second the least recently used
+        assert!(limiter.allow(first));
+        clock.advance(Duration::from_millis(1));
+        assert!(limiter.allow(third));
+        assert!(limiter.tokens(first) < 0.1);
+        assert_eq!(limiter.tokens(second), 2.0);
+        assert!(limiter.tokens(third) < 1.1);
+    }
+}
@@ -1,31 +1,348 @@
+use std::any::type_name;
+use std::error::Error;
+use std::fmt;
 use std::io;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+#[cfg(unix)]
+use std::time::Instant;
 
 use hyper;
-use hyper::net::NetworkListener;
+use hyper::net::{NetworkListener, NetworkStream};
 
-use proxy_protocol::ProxyProtocolVersion;
-pub use proxy_stream::ProxyStream;
+use proxy_config::{ProxyConfig, ProxyCompat};
+#[cfg(feature = "log-kv")]
+use proxy_kv;
+use proxy_limit::LimitPolicy;
+use proxy_observer::{ConnectionLimited, LimitKind};
+use proxy_protocol::{ProxyProtocolVersion, ProxyReadError};
+pub use proxy_stream::{ProxiedAddrs, ProxyHttpStream, ProxyStream, ProxyState, ProxyWarning, ReadHalf, TryCloneStream, WriteHalf};
+#[cfg(unix)]
+use proxy_stream::poll_readable;
 
 
 #[derive(Clone)]
 /// An implementation of `NetworkListener` which reads the PROXY protocol (version specified
 /// by the `version` argument) after calling the `accept()` function from the container
 /// sub-listener
-pub struct ProxyListener<T: Clone> {
+///
+/// Only the parts which need it require the inner listener to be `Clone`: hyper requires it
+/// of every `NetworkListener` (so of anything this can be constructed from), but code which
+/// merely holds or inspects a `ProxyListener<T>` need not.
+pub struct ProxyListener<T> {
     inner: T,
-    version: ProxyProtocolVersion,
+    config: ProxyConfig,
+    // the last timeout passed to `set_read_timeout`, which accepted streams are left with
+    read_timeout: Option<Duration>,
+    // the inner listener's local address, as of construction or the last `local_addr` call
+    bound_addr: Option<SocketAddr>,
 }
 
 impl<T: NetworkListener+Clone> ProxyListener<T> {
     /// Construct a new `ProxyListener` from an already-construced listener (e.g.,
     /// `hyper::net::HttpListener`)
     pub fn new(listener: T, proxy_protocol_version: ProxyProtocolVersion) -> Self {
+        ProxyListener::from_config(listener, &ProxyConfig::new(proxy_protocol_version))
+    }
+
+    /// Construct a new `ProxyListener` from an already-constructed listener, configured for
+    /// the load balancer described by `compat`
+    pub fn for_compat(listener: T, compat: ProxyCompat) -> Self {
+        ProxyListener::from_config(listener, &compat.config())
+    }
+
+    /// Construct a new `ProxyListener` from an already-constructed listener and a full
+    /// `ProxyConfig`
+    pub fn from_config(mut listener: T, config: &ProxyConfig) -> Self {
+        let bound_addr = listener.local_addr().ok();
         ProxyListener {
             inner: listener,
-            version: proxy_protocol_version
+            config: config.clone(),
+            read_timeout: None,
+            bound_addr,
+        }
+    }
+
+    /// Accept a single connection, as `accept` does, but with an error which says whether
+    /// the listener itself failed or just the one connection
+    pub fn accept_detailed(&mut self) -> Result<ProxyStream<T::Stream>, AcceptError> {
+        self.accept_with_warnings().map(|(stream, _)| stream)
+    }
+
+    /// Accept a single connection, as `accept_detailed` does, along with anything about it
+    /// which was worth a warning but not worth failing the connection over
+    pub fn accept_with_warnings(&mut self) -> Result<(ProxyStream<T::Stream>, Vec<ProxyWarning>), AcceptError> {
+        loop {
+            let stream = self.accept_inner()?;
+            match self.read_header(stream) {
+                Err(ref e) if self.is_silenced(e) => continue,
+                result => return result,
+            }
+        }
+    }
+
+    /// Accept a connection from the inner listener, without reading its header
+    pub(crate) fn accept_inner(&mut self) -> Result<T::Stream, AcceptError> {
+        self.inner.accept().map_err(|e| match e {
+            hyper::Error::Io(e) => AcceptError::Listener(e),
+            e => AcceptError::Listener(io::Error::other(e)),
+        })
+    }
+
+    /// Read the header of a connection accepted by `accept_inner`
+    pub(crate) fn read_header(&self, mut stream: T::Stream) -> Result<(ProxyStream<T::Stream>, Vec<ProxyWarning>), AcceptError> {
+        let peer = stream.peer_addr().ok();
+        let mut warnings = Vec::new();
+        let result = ProxyStream::from_stream(stream, &self.config, self.read_timeout, &mut warnings)
+            .and_then(|stream| self.check_bound_family(stream))
+            .and_then(|stream| self.check_limits(stream));
+        #[cfg(feature = "log-kv")]
+        match result {
+            Ok(ref stream) => proxy_kv::log_accepted(peer, stream.proxy_info()),
+            Err(ref e) => proxy_kv::log_refused(peer, e),
+        }
+        if let (Ok(_), Some(observer)) = (&result, &self.config.observer) {
+            for warning in &warnings {
+                observer.0.on_warning(warning);
+            }
+        }
+        result
+            .map(|stream| (stream, warnings))
+            .map_err(|source| AcceptError::Connection { peer, source })
+    }
+
+    fn check_bound_family(&self, stream: ProxyStream<T::Stream>) -> Result<ProxyStream<T::Stream>, ProxyReadError> {
+        if !self.config.require_bound_family {
+            return Ok(stream);
+        }
+        match (stream.proxy_info(), self.bound_addr) {
+            (Some(info), Some(bound)) if !info.matches_family_of(bound) => Err(ProxyReadError::BoundFamilyMismatch(info.proto())),
+            _ => Ok(stream),
+        }
+    }
+
+    fn check_limits(&self, mut stream: ProxyStream<T::Stream>) -> Result<ProxyStream<T::Stream>, ProxyReadError> {
+        if self.config.connection_limit.is_none() && self.config.rate_limit.is_none() {
+            return Ok(stream);
+        }
+        if stream.proxy_info().map(|info| info.is_local()).unwrap_or(false) {
+            return Ok(stream);
+        }
+        let client = match stream.peer_addr() {
+            Ok(addr) => addr.ip(),
+            Err(_) => return Ok(stream),
+        };
+        if let Some(ref limiter) = self.config.rate_limit {
+            if !limiter.allow(client) && !self.let_through(&stream, client, LimitKind::Rate, limiter.limit_policy()) {
+                return Err(ProxyReadError::RateLimited(client));
+            }
+        }
+        if let Some(ref limiter) = self.config.connection_limit {
+            let slot = match limiter.acquire(client) {
+                Some(slot) => Some(slot),
+                None if self.let_through(&stream, client, LimitKind::Connections, limiter.limit_policy()) =>
+                    limiter.acquire_unlimited(client),
+                None => return Err(ProxyReadError::TooManyConnections(client)),
+            };
+            if let Some(slot) = slot {
+                stream.hold_limit(slot);
+            }
+        }
+        Ok(stream)
+    }
+
+    /// Tell the observer that `client` went over a limit, and arrange for the connection to be
+    /// reset if the policy says to. Returns whether the connection should be accepted anyway.
+    fn let_through(&self, stream: &ProxyStream<T::Stream>, client: IpAddr, limit: LimitKind, policy: LimitPolicy) -> bool {
+        let enforced = policy != LimitPolicy::DryRun;
+        if let Some(ref observer) = self.config.observer {
+            observer.0.on_limited(&ConnectionLimited { client, limit, enforced });
+        }
+        if policy == LimitPolicy::Reset {
+            // if the option cannot be set the connection is just closed, which is refusal enough
+            let _ = stream.reset_on_close();
+        }
+        !enforced
+    }
+
+    /// Whether `e` is a refusal which the configuration says to keep quiet about
+    pub(crate) fn is_silenced(&self, e: &AcceptError) -> bool {
+        let policy = match *e {
+            AcceptError::Connection { source: ProxyReadError::TooManyConnections(_), .. } =>
+                self.config.connection_limit.as_ref().map(|limiter| limiter.limit_policy()),
+            AcceptError::Connection { source: ProxyReadError::RateLimited(_), .. } =>
+                self.config.rate_limit.as_ref().map(|limiter| limiter.limit_policy()),
+            AcceptError::Connection { ref source, .. } if source.is_timeout() => return self.config.skip_header_timeouts,
+            _ => None,
+        };
+        matches!(policy, Some(LimitPolicy::CloseSilently) | Some(LimitPolicy::Reset))
+    }
+
+    /// Pass every stream this listener accepts through `f`, once its header has been read.
+    /// See `MapStreams`.
+    pub fn map_streams<F, S>(self, f: F) -> MapStreams<T, F>
+        where F: Fn(ProxyStream<T::Stream>) -> S + Clone, S: NetworkStream + Clone {
+        MapStreams { listener: self, f }
+    }
+
+    /// Construct a new `ProxyListener` from `listener`, as `new` does, which passes every
+    /// stream it accepts through `f` once its header has been read. This is shorthand for
+    /// `ProxyListener::new(listener, version).map_streams(f)`; see `MapStreams`.
+    pub fn wrapping<F, S>(listener: T, version: ProxyProtocolVersion, f: F) -> MapStreams<T, F>
+        where F: Fn(ProxyStream<T::Stream>) -> S + Clone, S: NetworkStream + Clone {
+        ProxyListener::new(listener, version).map_streams(f)
+    }
+}
+
+
+#[cfg(unix)]
+impl<T> ProxyListener<T> where T: NetworkListener + Clone + ::std::os::unix::io::AsRawFd {
+    /// Accept a single connection, as `accept_detailed` does, or return `Ok(None)` if none
+    /// arrives within `timeout`, so that accept loops can check whether they should stop.
+    ///
+    /// Only the wait for a connection is bounded by `timeout`; reading its header is bounded
+    /// by the header timeout, as usual. The listening socket is polled before the inner
+    /// listener's `accept` is called, so if clones of this listener are accepting on other
+    /// threads, one of them can take the connection in between, and this call then waits for
+    /// the next one. This is only available on unix, and for inner listeners with a file
+    /// descriptor to poll (as `HttpListener` has); elsewhere, set a read timeout on the inner
+    /// listener's socket, or accept on a thread of its own.
+    pub fn accept_timeout(&mut self, timeout: Duration) -> Result<Option<ProxyStream<T::Stream>>, AcceptError> {
+        let deadline = Instant::now() + timeout;
+        if !poll_readable(self.inner.as_raw_fd(), Some(deadline)).map_err(AcceptError::Listener)? {
+            return Ok(None);
+        }
+        self.accept_detailed().map(Some)
+    }
+}
+
+
+/// Why `ProxyListener::accept_detailed` failed
+#[derive(Debug)]
+pub enum AcceptError {
+    /// The inner listener failed to accept a connection, which may mean that the listening
+    /// socket itself is broken
+    Listener(io::Error),
+    /// A connection was accepted, but its PROXY header could not be read; the connection
+    /// has been dropped, and the listener is fine
+    Connection {
+        /// The socket peer of the connection, if it could be found
+        peer: Option<SocketAddr>,
+        /// What went wrong with the header
+        source: ProxyReadError,
+    },
+}
+
+
+impl fmt::Display for AcceptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AcceptError::Listener(ref e) => write!(f, "accepting a connection failed: {}", e),
+            AcceptError::Connection { peer: Some(peer), ref source } => write!(f, "reading the PROXY header from {} failed: {}", peer, source),
+            AcceptError::Connection { peer: None, ref source } => write!(f, "reading the PROXY header failed: {}", source),
+        }
+    }
+}
+
+
+impl Error for AcceptError {
+    fn description(&self) -> &str {
+        match *self {
+            AcceptError::Listener(_) => "accepting a connection failed",
+            AcceptError::Connection { .. } => "reading the PROXY header failed",
         }
     }
+
+    fn cause(&self) -> Option<&dyn Error> {
+        match *self {
+            AcceptError::Listener(ref e) => Some(e),
+            AcceptError::Connection { ref source, .. } => Some(source),
+        }
+    }
+}
+
+
+impl From<AcceptError> for hyper::Error {
+    fn from(e: AcceptError) -> Self {
+        match e {
+            AcceptError::Listener(e) => hyper::Error::Io(e),
+            AcceptError::Connection { source, .. } => source.into(),
+        }
+    }
+}
+
+
+/// The same as `ProxyListener::new(listener, version)`
+impl<T: NetworkListener+Clone> From<(T, ProxyProtocolVersion)> for ProxyListener<T> {
+    fn from((listener, version): (T, ProxyProtocolVersion)) -> Self {
+        ProxyListener::new(listener, version)
+    }
+}
+
+
+impl<T> ProxyListener<T> {
+    /// The local address the inner listener is bound to, as it was when this listener was
+    /// constructed (or at the last successful call to `local_addr`). Unlike `local_addr`,
+    /// this does not need a mutable reference. Returns `None` if the inner listener could not
+    /// report an address.
+    pub fn bound_addr(&self) -> Option<SocketAddr> {
+        self.bound_addr
+    }
+
+    /// The version of the PROXY protocol this listener expects
+    pub fn version(&self) -> ProxyProtocolVersion {
+        self.config.version
+    }
+
+    /// The inner listener
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    /// The inner listener, mutably (e.g., to change settings on it). Set timeouts through
+    /// this listener's `NetworkListener` methods rather than the inner listener's, though:
+    /// this listener needs to know the read timeout to restore it after a `header_timeout`.
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Unwrap the inner listener
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// Something to print with `{:?}` which shows the inner listener with its own `Debug`,
+    /// rather than just its type's name as this listener's `Debug` does (since `HttpListener`,
+    /// for one, is not `Debug`)
+    pub fn debug_inner<'a>(&'a self) -> impl fmt::Debug + 'a where T: fmt::Debug {
+        DebugInner(self)
+    }
+
+    fn fmt_with_inner(&self, f: &mut fmt::Formatter, inner: &dyn fmt::Debug) -> fmt::Result {
+        f.debug_struct("ProxyListener")
+            .field("version", &self.config.version)
+            .field("inner", inner)
+            .field("local_addr", &self.bound_addr)
+            .field("read_timeout", &self.read_timeout)
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+// written by hand so that the inner listener need not be Debug
+impl<T> fmt::Debug for ProxyListener<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_with_inner(f, &type_name::<T>())
+    }
+}
+
+
+struct DebugInner<'a, T: 'a>(&'a ProxyListener<T>);
+
+impl<'a, T: fmt::Debug> fmt::Debug for DebugInner<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt_with_inner(f, &self.0.inner)
+    }
 }
 
 
@@ -34,13 +351,25 @@ impl<T: NetworkListener+Clone> NetworkListener for ProxyListener<T> {
 
     /// Accept a single connection from this Listener
     fn accept(&mut self) -> hyper::Result<Self::Stream> {
-        let stream = self.inner.accept()?;
-        ProxyStream::from_stream(stream, self.version)
+        self.accept_detailed().map_err(hyper::Error::from)
     }
 
     /// Find out the local address we are bound to
     fn local_addr(&mut self) -> io::Result<SocketAddr> {
-        self.inner.local_addr()
+        let addr = self.inner.local_addr()?;
+        self.bound_addr = Some(addr);
+        Ok(addr)
+    }
+
+    /// Sets the read timeout for all streams that are accepted
+    fn set_read_timeout(&mut self, dur: Option<Duration>) {
+        self.read_timeout = dur;
+        self.inner.set_read_timeout(dur)
+    }
+
+    /// Sets the write timeout for all streams that are accepted
+    fn set_write_timeout(&mut self, dur: Option<Duration>) {
+        self.inner.set_write_timeout(dur)
     }
 }
 
@@ -54,14 +383,140 @@ impl<T> ::std::os::unix::io::AsRawFd for ProxyListener<T>
 }
 
 
+/// A `ProxyListener` which passes every stream it accepts through a function, constructed
+/// with `ProxyListener::wrapping` or `ProxyListener::map_streams`.
+///
+/// This is the place for stream wrappers which care about the client, such as per-client
+/// connection limits or accounting: the function is only called once the header has been
+/// read, so the `ProxyStream` it is given already reports the client's address from
+/// `peer_addr()`, and anything wrapping it only sees what follows the header.
+///
+/// Wrappers of the *listener* belong outside, around the `MapStreams` (or `ProxyListener`).
+/// A `ProxyListener` should only ever wrap the listener which accepts connections from the
+/// load balancer: a listener wrapper inside it only sees the load balancer's address, and if
+/// its streams buffer (or otherwise read ahead) the header can be read from under the parser.
+///
+/// ```
+/// # extern crate hyper;
+/// # extern crate hyper_networklistener_proxy;
+/// use std::io::{self, Read, Write};
+/// use std::net::SocketAddr;
+/// use std::time::Duration;
+///
+/// use hyper::net::{HttpListener, NetworkStream};
+/// use hyper_networklistener_proxy::{ProxyListener, ProxyProtocolVersion};
+///
+/// /// Logs how much each client sent, by the address from its PROXY header
+/// #[derive(Clone)]
+/// struct Metered<S> {
+///     inner: S,
+///     client: Option<SocketAddr>,
+///     read: usize,
+/// }
+///
+/// impl<S: NetworkStream> Read for Metered<S> {
+///     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+///         let n = self.inner.read(buf)?;
+///         self.read += n;
+///         Ok(n)
+///     }
+/// }
+///
+/// impl<S: NetworkStream> Write for Metered<S> {
+///     fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.inner.write(buf) }
+///     fn flush(&mut self) -> io::Result<()> { self.inner.flush() }
+/// }
+///
+/// impl<S: NetworkStream> NetworkStream for Metered<S> {
+///     fn peer_addr(&mut self) -> io::Result<SocketAddr> { self.inner.peer_addr() }
+///     fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> { self.inner.set_read_timeout(dur) }
+///     fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> { self.inner.set_write_timeout(dur) }
+/// }
+///
+/// # fn main() {
+/// let listener = ProxyListener::wrapping(
+///     HttpListener::new("127.0.0.1:0").unwrap(),
+///     ProxyProtocolVersion::V2,
+///     |mut stream| Metered { client: stream.peer_addr().ok(), inner: stream, read: 0 },
+/// );
+/// // `listener` can now be given to `hyper::Server::new` or `Iron::listen`, and wrapped
+/// // further in listener wrappers
+/// # drop(listener);
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct MapStreams<T, F> {
+    listener: ProxyListener<T>,
+    f: F,
+}
+
+
+impl<T, F> MapStreams<T, F> {
+    /// The underlying `ProxyListener`
+    pub fn get_ref(&self) -> &ProxyListener<T> {
+        &self.listener
+    }
+}
+
+
+// written by hand so that neither the inner listener nor the function need be Debug
+impl<T, F> fmt::Debug for MapStreams<T, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MapStreams")
+            .field("listener", &self.listener)
+            .field("f", &type_name::<F>())
+            .finish()
+    }
+}
+
+
+impl<T, F, S> NetworkListener for MapStreams<T, F>
+    where T: NetworkListener + Clone, F: Fn(ProxyStream<T::Stream>) -> S + Clone, S: NetworkStream + Clone {
+    type Stream = S;
+
+    fn accept(&mut self) -> hyper::Result<S> {
+        self.listener.accept().map(&self.f)
+    }
+
+    fn local_addr(&mut self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    fn set_read_timeout(&mut self, dur: Option<Duration>) {
+        self.listener.set_read_timeout(dur)
+    }
+
+    fn set_write_timeout(&mut self, dur: Option<Duration>) {
+        self.listener.set_write_timeout(dur)
+    }
+}
+
+
+#[cfg(unix)]
+impl<T, F> ::std::os::unix::io::AsRawFd for MapStreams<T, F>
+    where T: NetworkListener + Clone + ::std::os::unix::io::AsRawFd {
+    fn as_raw_fd(&self) -> ::std::os::unix::io::RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+
 #[cfg(test)]
 mod tests {
-    use hyper::net::{HttpListener, NetworkListener, NetworkStream};
-    use super::{ProxyListener, ProxyProtocolVersion};
+    use hyper;
+    use hyper::net::{HttpListener, HttpStream, NetworkListener, NetworkStream};
+    use proxy_config::{ProxyConfig, ProxyCompat, FailureResponse};
+    use proxy_limit::{ConnectionLimiter, LimitPolicy, RateLimiter, TestClock};
+    use proxy_protocol::{ParseConfig, Proto, ProxyReadError};
+    use proxy_observer::{ConnectionClosed, ConnectionLimited, LimitKind, ProxyObserver};
+    use proxy_trust::{TrustedHandle, TrustedProxies};
+    use super::{AcceptError, ProxyListener, ProxyProtocolVersion, ProxyState, ProxyWarning};
     use std::thread;
-    use std::sync::{Arc,Barrier,Mutex};
-    use std::net::{SocketAddr, TcpStream, Shutdown};
-    use std::io::{Write,Read};
+    use std::time::{Duration, SystemTime};
+    use std::sync::{mpsc,Arc,Barrier,Mutex};
+    use std::collections::HashMap;
+    use std::net::{IpAddr, SocketAddr, TcpStream, Shutdown};
+    use std::io::{self,Write,Read};
 
     #[derive(Debug, PartialEq, Eq)]
     struct BasicResult {
@@ -130,4 +585,889 @@ mod tests {
 
         handle.join().expect("must be able to join thread")
     }
+
+    #[test]
+    fn test_bound_addr() {
+        let inner = HttpListener::new("127.0.0.1:0").expect("should be able to bind");
+        let mut listener = ProxyListener::new(inner, ProxyProtocolVersion::V1);
+        let cached = listener.bound_addr().expect("should have cached the local addr");
+        assert_ne!(cached.port(), 0);
+        assert_eq!(cached, listener.local_addr().expect("should be able to find local addr"));
+        assert_eq!(listener.bound_addr(), Some(cached));
+    }
+
+    /// Bind a listener with the given config and accept a single connection on another
+    /// thread; the thread's result is whether the accept succeeded
+    fn accept_one(config: ProxyConfig) -> (SocketAddr, thread::JoinHandle<bool>) {
+        let inner = HttpListener::new("127.0.0.1:0").expect("should be able to bind");
+        let mut listener = ProxyListener::from_config(inner, &config);
+        let addr = listener.local_addr().expect("should be able to find local addr");
+        let handle = thread::spawn(move || listener.accept().is_ok());
+        (addr, handle)
+    }
+
+    #[test]
+    fn test_failure_response_http() {
+        let config = ProxyConfig::new(ProxyProtocolVersion::V1).failure_response(FailureResponse::Canned);
+        let (addr, handle) = accept_one(config);
+        let mut conn = TcpStream::connect(addr).expect("should be able to connect");
+        write!(&mut conn, "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").expect("write must succeed");
+        let mut response = String::new();
+        conn.read_to_string(&mut response).expect("read must succeed");
+        assert!(response.starts_with("HTTP/1.0 400 Bad Request\r\n"), "unexpected response {:?}", response);
+        assert!(response.ends_with("\r\n\r\nThis port expects the PROXY protocol; connect through the load balancer\n"));
+        assert!(!handle.join().unwrap());
+    }
+
+    #[test]
+    fn test_failure_response_custom() {
+        let config = ProxyConfig::new(ProxyProtocolVersion::V2)
+            .failure_response(FailureResponse::Custom(b"go away\n".to_vec()));
+        let (addr, handle) = accept_one(config);
+        let mut conn = TcpStream::connect(addr).expect("should be able to connect");
+        write!(&mut conn, "POST / HTTP/1.1\r\nHost: localhost\r\n\r\n").expect("write must succeed");
+        let mut response = String::new();
+        conn.read_to_string(&mut response).expect("read must succeed");
+        assert_eq!(response, "go away\n");
+        assert!(!handle.join().unwrap());
+    }
+
+    #[test]
+    fn test_failure_response_tls() {
+        let config = ProxyConfig::new(ProxyProtocolVersion::V2).failure_response(FailureResponse::Canned);
+        let (addr, handle) = accept_one(config);
+        let mut conn = TcpStream::connect(addr).expect("should be able to connect");
+        conn.write_all(b"\x16\x03\x01\x02\x00\x01\x00\x01\xfc\x03\x03\x00\x00\x00\x00\x00").expect("write must succeed");
+        let mut response = Vec::new();
+        conn.read_to_end(&mut response).expect("read must succeed");
+        assert_eq!(response, b"\x15\x03\x01\x00\x02\x02\x28");
+        assert!(!handle.join().unwrap());
+    }
+
+    #[test]
+    fn test_failure_response_garbage() {
+        let config = ProxyConfig::new(ProxyProtocolVersion::V1).failure_response(FailureResponse::Canned);
+        let (addr, handle) = accept_one(config);
+        let mut conn = TcpStream::connect(addr).expect("should be able to connect");
+        conn.write_all(b"\x00\x13\x37\xff garbage\r\n").expect("write must succeed");
+        let mut response = Vec::new();
+        conn.read_to_end(&mut response).expect("read must succeed");
+        assert_eq!(response, b"");
+        assert!(!handle.join().unwrap());
+    }
+
+    /// Like `accept_one`, but the thread reads the accepted stream to the end and returns
+    /// its peer address, state, and whatever it read
+    fn serve_one(config: ProxyConfig) -> (SocketAddr, thread::JoinHandle<(SocketAddr, ProxyState, String)>) {
+        let inner = HttpListener::new("127.0.0.1:0").expect("should be able to bind");
+        let mut listener = ProxyListener::from_config(inner, &config);
+        let addr = listener.local_addr().expect("should be able to find local addr");
+        let handle = thread::spawn(move || {
+            let mut conn = listener.accept().expect("should be able to accept a connection");
+            let peer_addr = conn.peer_addr().expect("should be able to call .peer_addr()");
+            let mut body = String::new();
+            conn.read_to_string(&mut body).expect("body read should succeed");
+            (peer_addr, conn.proxy_state(), body)
+        });
+        (addr, handle)
+    }
+
+    #[test]
+    fn test_optional_plain() {
+        let config = ProxyConfig::new(ProxyProtocolVersion::Any).optional(true);
+        let (addr, handle) = serve_one(config);
+        let mut conn = TcpStream::connect(addr).expect("should be able to connect");
+        let client_addr = conn.local_addr().unwrap();
+        write!(&mut conn, "GET / HTTP/1.1\r\n\r\n").expect("write must succeed");
+        conn.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(handle.join().unwrap(), (client_addr, ProxyState::Socket, "GET / HTTP/1.1\r\n\r\n".to_string()));
+    }
+
+    #[test]
+    fn test_optional_grace_period_silent_client() {
+        let config = ProxyConfig::new(ProxyProtocolVersion::V1)
+            .optional(true)
+            .grace_period(Some(Duration::from_millis(100)));
+        let (addr, handle) = serve_one(config);
+        let mut conn = TcpStream::connect(addr).expect("should be able to connect");
+        let client_addr = conn.local_addr().unwrap();
+        // a client waiting for the server to speak first, which eventually gives up waiting
+        thread::sleep(Duration::from_millis(300));
+        // 'P' would otherwise be taken for the start of a v1 header
+        write!(&mut conn, "PUT / HTTP/1.1\r\n\r\n").expect("write must succeed");
+        conn.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(handle.join().unwrap(), (client_addr, ProxyState::Socket, "PUT / HTTP/1.1\r\n\r\n".to_string()));
+    }
+
+    #[test]
+    fn test_optional_grace_period_proxied_client() {
+        let config = ProxyConfig::new(ProxyProtocolVersion::V1)
+            .optional(true)
+            .grace_period(Some(Duration::from_millis(100)));
+        let (addr, handle) = serve_one(config);
+        let mut conn = TcpStream::connect(addr).expect("should be able to connect");
+        write!(&mut conn, "PROXY TCP4 127.0.0.1 127.0.0.2 2020 3030\r\n").expect("write must succeed");
+        write!(&mut conn, "GET / HTTP/1.1\r\n\r\n").expect("write must succeed");
+        conn.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(handle.join().unwrap(), ("127.0.0.1:2020".parse().unwrap(), ProxyState::Proxied, "GET / HTTP/1.1\r\n\r\n".to_string()));
+    }
+
+    #[test]
+    fn test_optional_zero_grace_period() {
+        let config = ProxyConfig::new(ProxyProtocolVersion::V1)
+            .optional(true)
+            .grace_period(Some(Duration::from_secs(0)));
+        let inner = HttpListener::new("127.0.0.1:0").expect("should be able to bind");
+        let mut listener = ProxyListener::from_config(inner, &config);
+        let addr = listener.local_addr().expect("should be able to find local addr");
+
+        // a header which is already there is read
+        let mut conn = TcpStream::connect(addr).expect("should be able to connect");
+        write!(&mut conn, "PROXY TCP4 127.0.0.1 127.0.0.2 2020 3030\r\nGET / HTTP/1.1\r\n\r\n").expect("write must succeed");
+        conn.shutdown(Shutdown::Write).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        let mut accepted = listener.accept().expect("should be able to accept a connection");
+        let mut body = String::new();
+        accepted.read_to_string(&mut body).expect("body read should succeed");
+        assert_eq!((accepted.peer_addr().unwrap(), accepted.proxy_state()), ("127.0.0.1:2020".parse().unwrap(), ProxyState::Proxied));
+        assert_eq!(body, "GET / HTTP/1.1\r\n\r\n");
+
+        // a client which has sent nothing yet is taken to be a plain one at once
+        let mut conn = TcpStream::connect(addr).expect("should be able to connect");
+        let client_addr = conn.local_addr().unwrap();
+        let mut accepted = listener.accept().expect("should be able to accept a connection");
+        assert_eq!((accepted.peer_addr().unwrap(), accepted.proxy_state()), (client_addr, ProxyState::Socket));
+        write!(&mut conn, "PUT / HTTP/1.1\r\n\r\n").expect("write must succeed");
+        conn.shutdown(Shutdown::Write).unwrap();
+        let mut body = String::new();
+        accepted.read_to_string(&mut body).expect("body read should succeed");
+        assert_eq!(body, "PUT / HTTP/1.1\r\n\r\n");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_toml_config() {
+        use toml;
+
+        let config: ProxyConfig = toml::from_str(r#"
+            version = "v1"
+            optional = true
+            grace_period_ms = 100
+        "#).expect("should deserialize");
+        let (addr, handle) = serve_one(config.clone());
+        let mut conn = TcpStream::connect(addr).expect("should be able to connect");
+        write!(&mut conn, "PROXY TCP4 127.0.0.1 127.0.0.2 2020 3030\r\nGET / HTTP/1.1\r\n\r\n").expect("write must succeed");
+        conn.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(handle.join().unwrap(), ("127.0.0.1:2020".parse().unwrap(), ProxyState::Proxied, "GET / HTTP/1.1\r\n\r\n".to_string()));
+
+        let (addr, handle) = serve_one(config);
+        let mut conn = TcpStream::connect(addr).expect("should be able to connect");
+        let client_addr = conn.local_addr().unwrap();
+        thread::sleep(Duration::from_millis(300));
+        write!(&mut conn, "PUT / HTTP/1.1\r\n\r\n").expect("write must succeed");
+        conn.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(handle.join().unwrap(), (client_addr, ProxyState::Socket, "PUT / HTTP/1.1\r\n\r\n".to_string()));
+    }
+
+    #[test]
+    fn test_optional_plain_starting_with_p() {
+        let requests = [
+            "POST / HTTP/1.1\r\nContent-Length: 2\r\n\r\nhi",
+            "PUT / HTTP/1.1\r\n\r\n",
+            "PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n",
+            "PROXIED / HTTP/1.1\r\n\r\n",
+        ];
+        for version in &[ProxyProtocolVersion::V1, ProxyProtocolVersion::Any] {
+            for request in &requests {
+                let (addr, handle) = serve_one(ProxyConfig::new(*version).optional(true));
+                let mut conn = TcpStream::connect(addr).expect("should be able to connect");
+                let client_addr = conn.local_addr().unwrap();
+                conn.write_all(request.as_bytes()).expect("write must succeed");
+                conn.shutdown(Shutdown::Write).unwrap();
+                assert_eq!(handle.join().unwrap(), (client_addr, ProxyState::Socket, request.to_string()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_optional_plain_starting_with_cr() {
+        let (addr, handle) = serve_one(ProxyConfig::new(ProxyProtocolVersion::V2).optional(true));
+        let mut conn = TcpStream::connect(addr).expect("should be able to connect");
+        let client_addr = conn.local_addr().unwrap();
+        conn.write_all(b"\r\n\r\nGET / HTTP/1.1\r\n\r\n").expect("write must succeed");
+        conn.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(handle.join().unwrap(), (client_addr, ProxyState::Socket, "\r\n\r\nGET / HTTP/1.1\r\n\r\n".to_string()));
+    }
+
+    /// A stream wrapper which does nothing but pass calls through
+    #[derive(Clone)]
+    struct Counted<S>(S);
+
+    impl<S: NetworkStream> Read for Counted<S> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl<S: NetworkStream> Write for Counted<S> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.flush()
+        }
+    }
+
+    impl<S: NetworkStream> NetworkStream for Counted<S> {
+        fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+            self.0.peer_addr()
+        }
+
+        fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+            self.0.set_read_timeout(dur)
+        }
+
+        fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+            self.0.set_write_timeout(dur)
+        }
+    }
+
+    #[test]
+    fn test_wrapping_sees_client_addr() {
+        // counts connections per client, as a connection limiter would
+        let counts: Arc<Mutex<HashMap<IpAddr, usize>>> = Arc::new(Mutex::new(HashMap::new()));
+        let mut listener = {
+            let counts = Arc::clone(&counts);
+            ProxyListener::wrapping(HttpListener::new("127.0.0.1:0").unwrap(), ProxyProtocolVersion::V1, move |mut stream| {
+                let client = stream.peer_addr().expect("should have a peer addr").ip();
+                *counts.lock().unwrap().entry(client).or_insert(0) += 1;
+                Counted(stream)
+            })
+        };
+        let addr = listener.local_addr().expect("should be able to find local addr");
+        let handle = thread::spawn(move || {
+            let mut bodies = Vec::new();
+            for _ in 0..3 {
+                let mut conn = listener.accept().expect("should be able to accept a connection");
+                let mut body = String::new();
+                conn.read_to_string(&mut body).expect("body read should succeed");
+                bodies.push((conn.peer_addr().unwrap(), body));
+            }
+            bodies
+        });
+        for client in &["192.0.2.1 198.51.100.7 4124", "192.0.2.1 198.51.100.7 4125", "192.0.2.7 198.51.100.7 4124"] {
+            let mut conn = TcpStream::connect(addr).expect("should be able to connect");
+            write!(&mut conn, "PROXY TCP4 {} 443\r\nhello", client).expect("write must succeed");
+            conn.shutdown(Shutdown::Write).unwrap();
+        }
+        let bodies = handle.join().unwrap();
+        assert_eq!(bodies[1], ("192.0.2.1:4125".parse().unwrap(), "hello".to_string()));
+        let counts = counts.lock().unwrap();
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts[&"192.0.2.1".parse::<IpAddr>().unwrap()], 2);
+        assert_eq!(counts[&"192.0.2.7".parse::<IpAddr>().unwrap()], 1);
+    }
+
+    #[test]
+    fn test_trusted_proxies_updated_at_runtime() {
+        let trust = TrustedHandle::new(TrustedProxies::new(vec!["192.0.2.0/24".parse().unwrap()]));
+        let config = ProxyConfig::new(ProxyProtocolVersion::V1).trusted_proxies(trust.clone());
+        let inner = HttpListener::new("127.0.0.1:0").expect("should be able to bind");
+        let listener = ProxyListener::from_config(inner, &config);
+        let addr = listener.bound_addr().expect("should be able to find local addr");
+        let (accepted, accepted_rx) = mpsc::channel();
+        // updates must reach clones of the listener too, as hyper's workers each have one
+        let mut clone = listener.clone();
+        let handle = thread::spawn(move || {
+            for _ in 0..2 {
+                let mut conn = clone.accept().expect("should be able to accept a connection");
+                let mut body = String::new();
+                conn.read_to_string(&mut body).expect("body read should succeed");
+                accepted.send((conn.peer_addr().unwrap(), conn.proxy_state(), body)).unwrap();
+            }
+        });
+        let header = "PROXY TCP4 198.51.100.1 198.51.100.7 4124 443\r\n";
+
+        let mut conn = TcpStream::connect(addr).expect("should be able to connect");
+        let client_addr = conn.local_addr().unwrap();
+        write!(&mut conn, "{}body", header).expect("write must succeed");
+        drop(conn);
+        // the header of an untrusted connection is passed through as part of the body
+        assert_eq!(accepted_rx.recv().unwrap(), (client_addr, ProxyState::Socket, format!("{}body", header)));
+
+        trust.set(TrustedProxies::new(vec!["192.0.2.0/24".parse().unwrap(), "127.0.0.0/8".parse().unwrap()]));
+        let mut conn = TcpStream::connect(addr).expect("should be able to connect");
+        write!(&mut conn, "{}body", header).expect("write must succeed");
+        drop(conn);
+        assert_eq!(accepted_rx.recv().unwrap(), ("198.51.100.1:4124".parse().unwrap(), ProxyState::Proxied, "body".to_string()));
+        handle.join().unwrap();
+    }
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<ConnectionClosed>>);
+
+    impl ProxyObserver for Recorder {
+        fn on_close(&self, event: &ConnectionClosed) {
+            self.0.lock().unwrap().push(event.clone());
+        }
+    }
+
+    #[test]
+    fn test_byte_counts_on_close() {
+        let recorder = Arc::new(Recorder::default());
+        let config = ProxyConfig::new(ProxyProtocolVersion::V1).observer(recorder.clone());
+        let inner = HttpListener::new("127.0.0.1:0").expect("should be able to bind");
+        let mut listener = ProxyListener::from_config(inner, &config);
+        let addr = listener.local_addr().expect("should be able to find local addr");
+        let handle = thread::spawn(move || {
+            let mut conn = listener.accept().expect("should be able to accept a connection");
+            // reading from one clone and writing to another, as hyper does
+            let mut writer = conn.clone();
+            let mut body = Vec::new();
+            conn.read_to_end(&mut body).expect("body read should succeed");
+            writer.write_all(&[b'x'; 300]).expect("write must succeed");
+            assert_eq!((conn.bytes_read(), conn.bytes_written()), (1000, 0));
+            assert_eq!((writer.bytes_read(), writer.bytes_written()), (0, 300));
+            body.len()
+        });
+        let mut conn = TcpStream::connect(addr).expect("should be able to connect");
+        write!(&mut conn, "PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n").expect("write must succeed");
+        conn.write_all(&[b'a'; 1000]).expect("write must succeed");
+        conn.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(handle.join().unwrap(), 1000);
+        let mut response = Vec::new();
+        conn.read_to_end(&mut response).expect("read must succeed");
+        assert_eq!(response.len(), 300);
+
+        let events = recorder.0.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        let event = &events[0];
+        assert_eq!((event.bytes_read, event.bytes_written), (1000, 300));
+        assert_eq!(event.peer_addr, Some("192.0.2.1:4124".parse().unwrap()));
+        assert_eq!(event.info.as_ref().and_then(|info| info.source_addr()), event.peer_addr);
+        assert!(event.duration > Duration::from_secs(0));
+    }
+
+    #[test]
+    fn test_byte_counts_on_into_parts() {
+        let recorder = Arc::new(Recorder::default());
+        let config = ProxyConfig::new(ProxyProtocolVersion::V1).observer(recorder.clone());
+        let mut listener = ProxyListener::from_config(HttpListener::new("127.0.0.1:0").unwrap(), &config);
+        let addr = listener.bound_addr().unwrap();
+        let mut conn = TcpStream::connect(addr).expect("should be able to connect");
+        write!(&mut conn, "PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\nhello, world").expect("write must succeed");
+        let mut stream = listener.accept().expect("should be able to accept a connection");
+        let mut hello = [0u8; 5];
+        stream.read_exact(&mut hello).expect("read must succeed");
+        stream.write_all(b"hi").expect("write must succeed");
+
+        // taking the stream apart closes it as far as the observer is concerned
+        let (mut inner, _, pushback) = stream.into_parts();
+        assert!(pushback.is_empty());
+        {
+            let events = recorder.0.lock().unwrap();
+            assert_eq!(events.len(), 1);
+            assert_eq!((events[0].bytes_read, events[0].bytes_written), (5, 2));
+        }
+        // though not as far as the connection is
+        conn.shutdown(Shutdown::Write).unwrap();
+        let mut rest = Vec::new();
+        inner.read_to_end(&mut rest).expect("read must succeed");
+        assert_eq!(rest, b", world");
+        inner.write_all(b"!").expect("write must succeed");
+        drop(inner);
+        let mut response = Vec::new();
+        conn.read_to_end(&mut response).expect("read must succeed");
+        assert_eq!(response, b"hi!");
+        assert_eq!(recorder.0.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_accepted_at_on_close() {
+        let clock = TestClock::new();
+        let ticks = clock.clone();
+        let recorder = Arc::new(Recorder::default());
+        let config = ProxyConfig::new(ProxyProtocolVersion::V1).observer(recorder.clone()).clock(move || ticks.now());
+        let mut listener = ProxyListener::from_config(HttpListener::new("127.0.0.1:0").unwrap(), &config);
+        let addr = listener.bound_addr().unwrap();
+        let before = SystemTime::now();
+        let _client = connect_as(addr, "192.0.2.1");
+        let conn = listener.accept_detailed().expect("should accept");
+        clock.advance(Duration::from_secs(3600));
+        assert_eq!(conn.age(), Duration::from_secs(3600));
+        let stamp = conn.proxy_info().and_then(|info| info.accepted_at());
+        drop(conn);
+
+        let events = recorder.0.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].duration, Duration::from_secs(3600));
+        assert!(events[0].accepted_at >= before && events[0].accepted_at <= SystemTime::now());
+        assert_eq!(stamp, Some(events[0].accepted_at));
+    }
+
+    #[test]
+    fn test_compat_golden_headers() {
+        let nlb: &[u8] = b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x11\x00\x26\
+            \xc0\x00\x02\x01\xc6\x33\x64\x07\x10\x1c\x01\xbb\
+            \xea\x00\x17\x01vpce-08d2bf15fac5001c9";
+        let golden: Vec<(ProxyCompat, &[u8], &str)> = vec![
+            (ProxyCompat::HaproxyV1, b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n", "192.0.2.1:4124"),
+            (ProxyCompat::HaproxyV2, b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x11\x00\x0c\x0a\x0b\x0c\x0d\x7f\x00\x00\x01\x22\xb8\x27\x0f", "10.11.12.13:8888"),
+            (ProxyCompat::AwsNlb, nlb, "192.0.2.1:4124"),
+            (ProxyCompat::AwsElbClassic, b"PROXY TCP4 198.51.100.22 203.0.113.7 35646 80\r\n", "198.51.100.22:35646"),
+            (ProxyCompat::GcpTcpProxy, b"PROXY TCP4 192.0.2.1 198.51.100.1 15221 110\r\n", "192.0.2.1:15221"),
+        ];
+        for (compat, header, source) in golden {
+            let (addr, handle) = serve_one(compat.config());
+            let mut conn = TcpStream::connect(addr).expect("should be able to connect");
+            conn.write_all(header).expect("write must succeed");
+            conn.write_all(b"body").expect("write must succeed");
+            drop(conn);
+            let (peer_addr, state, body) = handle.join().unwrap();
+            assert_eq!(peer_addr, source.parse().unwrap(), "{:?}", compat);
+            assert_eq!(state, ProxyState::Proxied);
+            assert_eq!(body, "body");
+        }
+    }
+
+    #[test]
+    fn test_for_compat() {
+        let inner = HttpListener::new("127.0.0.1:0").expect("should be able to bind");
+        let listener = ProxyListener::for_compat(inner, ProxyCompat::AwsNlb);
+        assert_eq!(format!("{:?}", listener.config), format!("{:?}", ProxyCompat::AwsNlb.config()));
+    }
+
+    /// A listener whose socket is broken
+    #[derive(Clone, Debug)]
+    struct BrokenListener;
+
+    impl NetworkListener for BrokenListener {
+        type Stream = HttpStream;
+
+        fn accept(&mut self) -> hyper::Result<HttpStream> {
+            Err(hyper::Error::Io(io::Error::other("bad file descriptor")))
+        }
+
+        fn local_addr(&mut self) -> io::Result<SocketAddr> {
+            Err(io::Error::other("bad file descriptor"))
+        }
+    }
+
+    /// Generic over any inner listener at all
+    fn describe<T>(listener: &ProxyListener<T>) -> String {
+        format!("{:?} on {:?}, {:?}", listener.version(), listener.bound_addr(), listener)
+    }
+
+    /// Not `Clone`, so not a `NetworkListener` either
+    struct Opaque;
+
+    #[test]
+    fn test_no_clone_bound() {
+        let listener = ProxyListener::new(HttpListener::new("127.0.0.1:0").unwrap(), ProxyProtocolVersion::V1);
+        assert!(describe(&listener).starts_with(&format!("V1 on {:?}, ProxyListener {{", listener.bound_addr())));
+        let mapped = listener.map_streams(|stream| stream);
+        assert!(describe(mapped.get_ref()).starts_with("V1 on Some("));
+
+        let opaque = ProxyListener { inner: Opaque, config: ProxyConfig::new(ProxyProtocolVersion::V2), read_timeout: None, bound_addr: None };
+        assert!(describe(&opaque).starts_with("V2 on None, ProxyListener { version: V2, inner: \"hyper_networklistener_proxy::proxy_listener::tests::Opaque\""));
+        let Opaque = opaque.into_inner();
+    }
+
+    #[test]
+    fn test_from_pair() {
+        let inner = HttpListener::new("127.0.0.1:0").unwrap();
+        let listener = ProxyListener::from((inner, ProxyProtocolVersion::V2));
+        assert_eq!(listener.version(), ProxyProtocolVersion::V2);
+        assert!(listener.bound_addr().is_some());
+    }
+
+    #[test]
+    fn test_debug() {
+        let listener = ProxyListener::new(HttpListener::new("127.0.0.1:0").unwrap(), ProxyProtocolVersion::V2);
+        let printed = format!("{:?}", listener);
+        let expected = format!("ProxyListener {{ version: V2, inner: \"hyper::net::HttpListener\", local_addr: Some({}), ", listener.bound_addr().unwrap());
+        assert!(printed.starts_with(&expected), "unexpected {}", printed);
+        // no address when the inner listener has none, and its own Debug where it has one
+        let broken = ProxyListener::new(BrokenListener, ProxyProtocolVersion::Any);
+        assert!(format!("{:?}", broken).contains("inner: \"hyper_networklistener_proxy::proxy_listener::tests::BrokenListener\", local_addr: None,"));
+        assert!(format!("{:?}", broken.debug_inner()).starts_with("ProxyListener { version: Any, inner: BrokenListener, local_addr: None,"));
+    }
+
+    #[test]
+    fn test_accept_detailed_listener_error() {
+        let mut listener = ProxyListener::new(BrokenListener, ProxyProtocolVersion::V1);
+        match listener.accept_detailed() {
+            Err(AcceptError::Listener(e)) => assert_eq!(e.to_string(), "bad file descriptor"),
+            other => panic!("expected a listener error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_accept_detailed_connection_error() {
+        let inner = HttpListener::new("127.0.0.1:0").expect("should be able to bind");
+        let mut listener = ProxyListener::new(inner, ProxyProtocolVersion::V1);
+        let addr = listener.local_addr().expect("should be able to find local addr");
+        let handle = thread::spawn(move || listener.accept_detailed().map(|_| ()));
+        let mut conn = TcpStream::connect(addr).expect("should be able to connect");
+        conn.write_all(b"garbage garbage\r\n").expect("write must succeed");
+        match handle.join().unwrap() {
+            Err(AcceptError::Connection { peer, source: ProxyReadError::MissingLiteral(_) }) => {
+                assert_eq!(peer, Some(conn.local_addr().unwrap()));
+            },
+            other => panic!("expected a connection error, got {:?}", other),
+        }
+    }
+
+    #[derive(Default)]
+    struct WarningRecorder(Mutex<Vec<String>>);
+
+    impl ProxyObserver for WarningRecorder {
+        fn on_warning(&self, warning: &ProxyWarning) {
+            self.0.lock().unwrap().push(warning.to_string());
+        }
+    }
+
+    #[test]
+    fn test_accept_with_warnings() {
+        let inner = HttpListener::new("127.0.0.1:0").expect("should be able to bind");
+        let recorder = Arc::new(WarningRecorder::default());
+        let parse = ParseConfig::default().max_v2_block_len(8);
+        let config = ProxyConfig::new(ProxyProtocolVersion::V2).parse_config(parse).observer(recorder.clone());
+        let mut listener = ProxyListener::from_config(inner, &config);
+        let addr = listener.local_addr().expect("should be able to find local addr");
+        let handle = thread::spawn(move || {
+            let (mut conn, warnings) = listener.accept_with_warnings().expect("should accept despite the warnings");
+            let mut body = String::new();
+            conn.read_to_string(&mut body).expect("body read should succeed");
+            (conn.proxy_state(), body, warnings)
+        });
+        let mut conn = TcpStream::connect(addr).expect("should be able to connect");
+        // an AF_UNSPEC header, whose address block is nothing but TLVs, more than fit in the limit
+        conn.write_all(b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x00\x00\x0b\x04\x00\x01\x00\xe0\x00\x04abcd").expect("write must succeed");
+        conn.write_all(b"body").expect("write must succeed");
+        drop(conn);
+        let (state, body, warnings) = handle.join().unwrap();
+        assert_eq!(state, ProxyState::Socket);
+        assert_eq!(body, "body");
+        assert_eq!(warnings.len(), 2, "{:?}", warnings);
+        assert!(matches!(warnings[0], ProxyWarning::IgnoredTlvs(7)), "{:?}", warnings);
+        assert!(matches!(warnings[1], ProxyWarning::SocketPeerFallback), "{:?}", warnings);
+        // the observer is told the same
+        assert_eq!(*recorder.0.lock().unwrap(), warnings.iter().map(|w| w.to_string()).collect::<Vec<_>>());
+    }
+
+    /// Accept a single connection, carrying `header`, on a listener bound to `bind` which
+    /// requires headers to match its family
+    fn accept_on(bind: &str, header: &'static [u8]) -> Result<(), AcceptError> {
+        let inner = HttpListener::new(bind).expect("should be able to bind");
+        let config = ProxyConfig::new(ProxyProtocolVersion::V1).require_bound_family(true);
+        let mut listener = ProxyListener::from_config(inner, &config);
+        let mut addr = listener.bound_addr().expect("should have cached the local addr");
+        if addr.ip().is_unspecified() {
+            addr.set_ip("127.0.0.1".parse().unwrap());
+        }
+        let handle = thread::spawn(move || listener.accept_detailed().map(|_| ()));
+        let mut conn = TcpStream::connect(addr).expect("should be able to connect");
+        conn.write_all(header).expect("write must succeed");
+        handle.join().unwrap()
+    }
+
+    #[test]
+    fn test_require_bound_family() {
+        let v4 = b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n";
+        let v6 = b"PROXY TCP6 2001:db8::1 2001:db8::2 4124 443\r\n";
+        let mapped = b"PROXY TCP6 ::ffff:192.0.2.1 ::ffff:198.51.100.7 4124 443\r\n";
+        let unknown = b"PROXY UNKNOWN\r\n";
+
+        assert!(accept_on("127.0.0.1:0", v4).is_ok());
+        assert!(accept_on("127.0.0.1:0", mapped).is_ok());
+        assert!(accept_on("127.0.0.1:0", unknown).is_ok());
+        match accept_on("127.0.0.1:0", v6) {
+            Err(AcceptError::Connection { source: ProxyReadError::BoundFamilyMismatch(Proto::Tcp6), .. }) => {},
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        assert!(accept_on("[::1]:0", v6).is_ok());
+        match accept_on("[::1]:0", v4) {
+            Err(AcceptError::Connection { source: ProxyReadError::BoundFamilyMismatch(Proto::Tcp4), .. }) => {},
+            other => panic!("unexpected result {:?}", other),
+        }
+        // a dual-stack socket sees IPv4 clients as well
+        assert!(accept_on("[::]:0", v4).is_ok());
+        assert!(accept_on("[::]:0", v6).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_accept_timeout() {
+        let inner = HttpListener::new("127.0.0.1:0").expect("should be able to bind");
+        let mut listener = ProxyListener::new(inner, ProxyProtocolVersion::V1);
+        let addr = listener.bound_addr().expect("should have cached the local addr");
+
+        let start = ::std::time::Instant::now();
+        assert!(listener.accept_timeout(Duration::from_millis(50)).expect("should not fail").is_none());
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(50) && elapsed < Duration::from_secs(5), "{:?}", elapsed);
+
+        let mut conn = TcpStream::connect(addr).expect("should be able to connect");
+        conn.write_all(b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n").expect("write must succeed");
+        let mut stream = listener.accept_timeout(Duration::from_secs(5)).expect("should not fail").expect("should accept");
+        assert_eq!(stream.peer_addr().unwrap(), "192.0.2.1:4124".parse::<SocketAddr>().unwrap());
+    }
+
+    #[test]
+    fn test_accept_without_warnings() {
+        let inner = HttpListener::new("127.0.0.1:0").expect("should be able to bind");
+        let mut listener = ProxyListener::new(inner, ProxyProtocolVersion::V1);
+        let addr = listener.local_addr().expect("should be able to find local addr");
+        let handle = thread::spawn(move || listener.accept_with_warnings().map(|(_, warnings)| warnings.len()));
+        let mut conn = TcpStream::connect(addr).expect("should be able to connect");
+        conn.write_all(b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n").expect("write must succeed");
+        assert_eq!(handle.join().unwrap().expect("should accept"), 0);
+    }
+
+    /// Connect to `addr` as if through a load balancer, on behalf of `source`
+    fn connect_as(addr: SocketAddr, source: &str) -> TcpStream {
+        let mut conn = TcpStream::connect(addr).expect("should be able to connect");
+        write!(conn, "PROXY TCP4 {} 198.51.100.7 4124 443\r\n", source).expect("write must succeed");
+        conn
+    }
+
+    #[test]
+    fn test_connection_limit() {
+        const N: usize = 3;
+        let limiter = ConnectionLimiter::new(N);
+        let config = ProxyConfig::new(ProxyProtocolVersion::V1).connection_limit(limiter.clone());
+        let mut listener = ProxyListener::from_config(HttpListener::new("127.0.0.1:0").unwrap(), &config);
+        let addr = listener.bound_addr().unwrap();
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+
+        let mut conns = Vec::new();
+        let mut open = Vec::new();
+        for _ in 0..N {
+            conns.push(connect_as(addr, "192.0.2.1"));
+            open.push(listener.accept_detailed().expect("should be under the limit"));
+        }
+        conns.push(connect_as(addr, "192.0.2.1"));
+        match listener.accept_detailed() {
+            Err(AcceptError::Connection { source: ProxyReadError::TooManyConnections(ip), .. }) => assert_eq!(ip, client),
+            other => panic!("unexpected result {:?}", other),
+        }
+        conns.push(connect_as(addr, "192.0.2.2"));
+        let mut other = listener.accept_detailed().expect("other clients should get through");
+        assert_eq!(other.peer_addr().unwrap().ip().to_string(), "192.0.2.2");
+        assert_eq!(limiter.connections_from(client), N);
+
+        // a connection counts until the last clone of its stream is gone
+        let clone = open.pop().unwrap().clone();
+        assert_eq!(limiter.connections_from(client), N);
+        drop(clone);
+        assert_eq!(limiter.connections_from(client), N - 1);
+        conns.push(connect_as(addr, "192.0.2.1"));
+        open.push(listener.accept_detailed().expect("a place should have been freed"));
+
+        drop((open, other));
+        assert_eq!(limiter.sources(), 0);
+    }
+
+    #[test]
+    fn test_connection_limit_silent() {
+        let limiter = ConnectionLimiter::new(1).policy(LimitPolicy::CloseSilently);
+        let config = ProxyConfig::new(ProxyProtocolVersion::V1).connection_limit(limiter);
+        let mut listener = ProxyListener::from_config(HttpListener::new("127.0.0.1:0").unwrap(), &config);
+        let addr = listener.bound_addr().unwrap();
+
+        let _first = connect_as(addr, "192.0.2.1");
+        let _open = listener.accept_detailed().expect("should be under the limit");
+        let mut refused = connect_as(addr, "192.0.2.1");
+        let _other = connect_as(addr, "192.0.2.2");
+        // the refused connection is skipped over, rather than failing the accept
+        let mut accepted = listener.accept_detailed().expect("should accept the next connection");
+        assert_eq!(accepted.peer_addr().unwrap().ip().to_string(), "192.0.2.2");
+        let mut buf = [0u8; 1];
+        assert!(refused.read(&mut buf).map(|n| n == 0).unwrap_or(true), "the refused connection should be closed");
+    }
+
+    #[test]
+    fn test_inner_listener() {
+        let mut listener = ProxyListener::new(HttpListener::new("127.0.0.1:0").unwrap(), ProxyProtocolVersion::Any);
+        assert_eq!(listener.version(), ProxyProtocolVersion::Any);
+        let addr = listener.bound_addr().unwrap();
+        assert_eq!(listener.inner_mut().local_addr().unwrap(), addr);
+
+        listener.inner_mut().set_write_timeout(Some(Duration::from_secs(7)));
+        let _conn = connect_as(addr, "192.0.2.1");
+        let accepted = listener.accept_detailed().expect("should accept");
+        assert_eq!(accepted.get_ref().0.write_timeout().unwrap(), Some(Duration::from_secs(7)));
+        assert_eq!(listener.inner().clone().local_addr().unwrap(), addr);
+
+        let mut inner = listener.into_inner();
+        let _conn = TcpStream::connect(addr).unwrap();
+        // the unwrapped listener reads no header, so this connection can be accepted as is
+        inner.accept().expect("should accept");
+    }
+
+    #[test]
+    fn test_header_timeout() {
+        let config = ProxyConfig::new(ProxyProtocolVersion::V1).header_timeout(Some(Duration::from_millis(50)));
+        let mut listener = ProxyListener::from_config(HttpListener::new("127.0.0.1:0").unwrap(), &config);
+        let _silent = TcpStream::connect(listener.bound_addr().unwrap()).unwrap();
+        match listener.accept() {
+            Err(hyper::Error::Io(ref e)) if e.kind() == io::ErrorKind::TimedOut => {
+                let source = e.get_ref().and_then(|source| source.downcast_ref::<ProxyReadError>()).expect("should keep the read error");
+                assert!(source.is_timeout());
+            },
+            other => panic!("expected a TimedOut error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_skip_header_timeouts() {
+        let config = ProxyConfig::new(ProxyProtocolVersion::V1)
+            .header_timeout(Some(Duration::from_millis(50)))
+            .skip_header_timeouts(true);
+        let mut listener = ProxyListener::from_config(HttpListener::new("127.0.0.1:0").unwrap(), &config);
+        let addr = listener.bound_addr().unwrap();
+        let _silent = TcpStream::connect(addr).unwrap();
+        let _client = connect_as(addr, "192.0.2.1");
+        let mut accepted = listener.accept_detailed().expect("should skip the silent connection");
+        assert_eq!(accepted.peer_addr().unwrap().ip().to_string(), "192.0.2.1");
+
+        // other refusals are not skipped
+        let mut garbage = TcpStream::connect(addr).unwrap();
+        garbage.write_all(b"GARBAGE\r\n").unwrap();
+        listener.accept_detailed().expect_err("should refuse the garbage");
+    }
+
+    #[derive(Default)]
+    struct LimitRecorder(Mutex<Vec<ConnectionLimited>>);
+
+    impl ProxyObserver for LimitRecorder {
+        fn on_limited(&self, event: &ConnectionLimited) {
+            self.0.lock().unwrap().push(event.clone());
+        }
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let clock = TestClock::new();
+        let ticks = clock.clone();
+        let limiter = RateLimiter::new(1.0, 2).clock(move || ticks.now());
+        let recorder = Arc::new(LimitRecorder::default());
+        let config = ProxyConfig::new(ProxyProtocolVersion::V1).rate_limit(limiter).observer(recorder.clone());
+        let mut listener = ProxyListener::from_config(HttpListener::new("127.0.0.1:0").unwrap(), &config);
+        let addr = listener.bound_addr().unwrap();
+
+        let mut conns = Vec::new();
+        for _ in 0..2 {
+            conns.push(connect_as(addr, "192.0.2.1"));
+            listener.accept_detailed().expect("should be within the burst");
+        }
+        conns.push(connect_as(addr, "192.0.2.1"));
+        match listener.accept_detailed() {
+            Err(AcceptError::Connection { source: ProxyReadError::RateLimited(client), .. }) => assert_eq!(client.to_string(), "192.0.2.1"),
+            other => panic!("expected a rate limit error, got {:?}", other.map(|_| ())),
+        }
+        // other clients have their own buckets
+        conns.push(connect_as(addr, "192.0.2.2"));
+        listener.accept_detailed().expect("should accept another client");
+        clock.advance(Duration::from_secs(1));
+        conns.push(connect_as(addr, "192.0.2.1"));
+        listener.accept_detailed().expect("should accept once a token has refilled");
+
+        let events = recorder.0.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!((events[0].limit, events[0].enforced), (LimitKind::Rate, true));
+    }
+
+    #[test]
+    fn test_rate_limit_dry_run() {
+        let clock = TestClock::new();
+        let ticks = clock.clone();
+        let limiter = RateLimiter::new(1.0, 1).policy(LimitPolicy::DryRun).clock(move || ticks.now());
+        let recorder = Arc::new(LimitRecorder::default());
+        let config = ProxyConfig::new(ProxyProtocolVersion::V1)
+            .rate_limit(limiter)
+            .connection_limit(ConnectionLimiter::new(1).policy(LimitPolicy::DryRun))
+            .observer(recorder.clone());
+        let mut listener = ProxyListener::from_config(HttpListener::new("127.0.0.1:0").unwrap(), &config);
+        let addr = listener.bound_addr().unwrap();
+
+        let mut conns = Vec::new();
+        let mut streams = Vec::new();
+        for _ in 0..3 {
+            conns.push(connect_as(addr, "192.0.2.1"));
+            streams.push(listener.accept_detailed().expect("a dry run should refuse nothing"));
+        }
+        let events = recorder.0.lock().unwrap();
+        let seen: Vec<(LimitKind, bool)> = events.iter().map(|event| (event.limit, event.enforced)).collect();
+        assert_eq!(seen, vec![(LimitKind::Rate, false), (LimitKind::Connections, false),
+                              (LimitKind::Rate, false), (LimitKind::Connections, false)]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_rate_limit_reset() {
+        let limiter = RateLimiter::new(0.001, 1).policy(LimitPolicy::Reset);
+        let config = ProxyConfig::new(ProxyProtocolVersion::V1).rate_limit(limiter);
+        let mut listener = ProxyListener::from_config(HttpListener::new("127.0.0.1:0").unwrap(), &config);
+        let addr = listener.bound_addr().unwrap();
+
+        let _first = connect_as(addr, "192.0.2.1");
+        let _open = listener.accept_detailed().expect("should be within the burst");
+        let mut refused = connect_as(addr, "192.0.2.1");
+        let _other = connect_as(addr, "192.0.2.2");
+        // the refused connection is skipped over, as with `CloseSilently`
+        let mut accepted = listener.accept_detailed().expect("should accept the next connection");
+        assert_eq!(accepted.peer_addr().unwrap().ip().to_string(), "192.0.2.2");
+        let mut buf = [0u8; 1];
+        match refused.read(&mut buf) {
+            Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset => (),
+            other => panic!("the refused connection should be reset, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_errors_are_send_sync() {
+        fn assert_send_sync<E: ::std::error::Error + Send + Sync + 'static>() {}
+        assert_send_sync::<AcceptError>();
+        fn assert_send_sync_warning<W: ::std::fmt::Display + Send + Sync + 'static>() {}
+        assert_send_sync_warning::<ProxyWarning>();
+    }
+
+    #[cfg(unix)]
+    fn getsockopt<S: ::std::os::unix::io::AsRawFd>(socket: &S, level: ::libc::c_int, name: ::libc::c_int) -> ::libc::c_int {
+        let mut value: ::libc::c_int = 0;
+        let mut len = ::std::mem::size_of::<::libc::c_int>() as ::libc::socklen_t;
+        let ret = unsafe {
+            ::libc::getsockopt(socket.as_raw_fd(), level, name, &mut value as *mut ::libc::c_int as *mut ::libc::c_void, &mut len)
+        };
+        assert_eq!(ret, 0, "getsockopt failed: {}", io::Error::last_os_error());
+        value
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_socket_options() {
+        for &(nodelay, keepalive) in &[(false, None), (true, Some(Duration::from_secs(42)))] {
+            let config = ProxyConfig::new(ProxyProtocolVersion::V1)
+                .tcp_nodelay(nodelay)
+                .tcp_keepalive(keepalive);
+            let inner = HttpListener::new("127.0.0.1:0").expect("should be able to bind");
+            let mut listener = ProxyListener::from_config(inner, &config);
+            let addr = listener.local_addr().expect("should be able to find local addr");
+            let mut conn = TcpStream::connect(addr).expect("should be able to connect");
+            write!(&mut conn, "PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n").expect("write must succeed");
+            let stream = listener.accept().expect("should accept");
+            assert_eq!(getsockopt(&stream, ::libc::IPPROTO_TCP, ::libc::TCP_NODELAY) != 0, nodelay);
+            assert_eq!(getsockopt(&stream, ::libc::SOL_SOCKET, ::libc::SO_KEEPALIVE) != 0, keepalive.is_some());
+            #[cfg(target_os = "linux")]
+            {
+                if keepalive.is_some() {
+                    assert_eq!(getsockopt(&stream, ::libc::IPPROTO_TCP, ::libc::TCP_KEEPIDLE), 42);
+                }
+            }
+        }
+    }
 }
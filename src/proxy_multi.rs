@@ -0,0 +1,204 @@
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::Duration;
+
+use hyper;
+use hyper::net::NetworkListener;
+use libc;
+
+use proxy_listener::{AcceptError, ProxyListener};
+use proxy_stream::{poll_any_readable, ProxyStream};
+
+
+/// A `NetworkListener` which accepts connections from several `ProxyListener`s at once, so
+/// that one server (and one thread pool) can serve a service bound on several addresses.
+///
+/// Each listener keeps its own configuration, so, for example, a public address behind a
+/// load balancer which sends v2 headers and an internal address with no load balancer at all
+/// can be served together. `accept` waits for a connection on any of the listeners, taking
+/// them in turn when several have connections waiting, and reads its header as that
+/// listener is configured to.
+///
+/// To let several threads (such as hyper's workers, which each accept on a clone of the
+/// listener) wait on the same sockets without one of them blocking in `accept` after another
+/// took the connection it was woken for, the listening sockets are put into nonblocking
+/// mode; accepting on the listeners given to `new` directly will no longer block either.
+/// Only available on unix, and for listeners with file descriptors to poll (such as
+/// `HttpListener`).
+///
+/// ```
+/// # extern crate hyper;
+/// # extern crate hyper_networklistener_proxy;
+/// use hyper::net::HttpListener;
+/// use hyper_networklistener_proxy::{MultiProxyListener, ProxyListener, ProxyProtocolVersion};
+///
+/// # fn main() {
+/// let listener = MultiProxyListener::new(vec![
+///     ProxyListener::new(HttpListener::new("127.0.0.1:0").unwrap(), ProxyProtocolVersion::V2),
+///     ProxyListener::new(HttpListener::new("[::1]:0").unwrap(), ProxyProtocolVersion::V1),
+/// ]).expect("should be able to set up listeners");
+/// assert_eq!(listener.addrs().len(), 2);
+/// // `listener` can now be given to `hyper::Server::new` or `Iron::listen`
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct MultiProxyListener<T> {
+    listeners: Vec<ProxyListener<T>>,
+    // the listener to try first on the next accept, so that a busy one cannot starve the rest
+    next: usize,
+}
+
+
+impl<T> MultiProxyListener<T> where T: NetworkListener + Clone + AsRawFd, T::Stream: AsRawFd {
+    /// Accept connections from any of `listeners`, which must not be empty. Fails if the
+    /// listening sockets cannot be made nonblocking.
+    pub fn new(listeners: Vec<ProxyListener<T>>) -> io::Result<Self> {
+        if listeners.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "no listeners to accept from"));
+        }
+        for listener in &listeners {
+            set_nonblocking(listener.as_raw_fd(), true)?;
+        }
+        Ok(MultiProxyListener { listeners, next: 0 })
+    }
+
+    /// The listeners accepted from, in the order given to `new`
+    pub fn listeners(&self) -> &[ProxyListener<T>] {
+        &self.listeners
+    }
+
+    /// The local addresses of the listeners, in the order given to `new`, as reported by
+    /// `ProxyListener::bound_addr`; listeners which could not report one are left out
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        self.listeners.iter().filter_map(|listener| listener.bound_addr()).collect()
+    }
+
+    /// Accept a single connection from whichever listener has one first, as
+    /// `ProxyListener::accept_detailed` does
+    pub fn accept_detailed(&mut self) -> Result<ProxyStream<T::Stream>, AcceptError> {
+        let fds: Vec<RawFd> = self.listeners.iter().map(|listener| listener.as_raw_fd()).collect();
+        loop {
+            let ready = poll_any_readable(&fds, None).map_err(AcceptError::Listener)?;
+            let (count, first) = (self.listeners.len(), self.next);
+            for i in (0..count).map(|i| (first + i) % count).filter(|&i| ready[i]) {
+                let listener = &mut self.listeners[i];
+                let stream = match listener.accept_inner() {
+                    Ok(stream) => stream,
+                    // another thread took the connection
+                    Err(AcceptError::Listener(ref e)) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                    Err(e) => return Err(e),
+                };
+                self.next = (i + 1) % count;
+                // accepted sockets inherit nonblocking mode on some platforms
+                set_nonblocking(stream.as_raw_fd(), false).map_err(AcceptError::Listener)?;
+                match listener.read_header(stream) {
+                    Err(ref e) if listener.is_silenced(e) => continue,
+                    result => return result.map(|(stream, _)| stream),
+                }
+            }
+        }
+    }
+}
+
+
+fn set_nonblocking(fd: RawFd, nonblocking: bool) -> io::Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let flags = if nonblocking { flags | libc::O_NONBLOCK } else { flags & !libc::O_NONBLOCK };
+    if unsafe { libc::fcntl(fd, libc::F_SETFL, flags) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+
+// written by hand so that the inner listeners need not be Debug
+impl<T> fmt::Debug for MultiProxyListener<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MultiProxyListener")
+            .field("listeners", &self.listeners)
+            .field("next", &self.next)
+            .finish()
+    }
+}
+
+
+impl<T> NetworkListener for MultiProxyListener<T> where T: NetworkListener + Clone + AsRawFd, T::Stream: AsRawFd {
+    type Stream = ProxyStream<T::Stream>;
+
+    /// Accept a single connection from whichever listener has one first
+    fn accept(&mut self) -> hyper::Result<Self::Stream> {
+        self.accept_detailed().map_err(hyper::Error::from)
+    }
+
+    /// Find out the local address the first listener is bound to; see `addrs` for the rest
+    fn local_addr(&mut self) -> io::Result<SocketAddr> {
+        self.listeners[0].local_addr()
+    }
+
+    /// Sets the read timeout for all streams that are accepted, from every listener
+    fn set_read_timeout(&mut self, dur: Option<Duration>) {
+        for listener in &mut self.listeners {
+            listener.set_read_timeout(dur);
+        }
+    }
+
+    /// Sets the write timeout for all streams that are accepted, from every listener
+    fn set_write_timeout(&mut self, dur: Option<Duration>) {
+        for listener in &mut self.listeners {
+            listener.set_write_timeout(dur);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::net::TcpStream;
+
+    use hyper::net::{HttpListener, NetworkListener, NetworkStream};
+
+    use proxy_listener::ProxyListener;
+    use proxy_protocol::ProxyProtocolVersion;
+    use super::MultiProxyListener;
+
+    fn listener(version: ProxyProtocolVersion) -> ProxyListener<HttpListener> {
+        ProxyListener::new(HttpListener::new("127.0.0.1:0").unwrap(), version)
+    }
+
+    #[test]
+    fn test_addrs() {
+        assert!(MultiProxyListener::<HttpListener>::new(Vec::new()).is_err());
+        let (first, second) = (listener(ProxyProtocolVersion::V1), listener(ProxyProtocolVersion::V2));
+        let expected = vec![first.bound_addr().unwrap(), second.bound_addr().unwrap()];
+        let mut multi = MultiProxyListener::new(vec![first, second]).unwrap();
+        assert_eq!(multi.addrs(), expected);
+        assert_eq!(multi.local_addr().unwrap(), expected[0]);
+    }
+
+    #[test]
+    fn test_round_robin() {
+        // with connections waiting on both, neither listener is taken twice in a row
+        let mut multi = MultiProxyListener::new(vec![listener(ProxyProtocolVersion::V1), listener(ProxyProtocolVersion::V1)]).unwrap();
+        let addrs = multi.addrs();
+        let mut clients = Vec::new();
+        for (i, &addr) in addrs.iter().chain(addrs.iter()).enumerate() {
+            let mut conn = TcpStream::connect(addr).expect("should be able to connect");
+            write!(conn, "PROXY TCP4 192.0.2.{} 198.51.100.7 4124 {}\r\n", i, addr.port()).unwrap();
+            clients.push(conn);
+        }
+        let mut ports = Vec::new();
+        for _ in 0..4 {
+            let mut stream = multi.accept().expect("should accept");
+            ports.push(stream.proxy_info().unwrap().destination_addr().unwrap().port());
+            assert!(stream.peer_addr().unwrap().ip().to_string().starts_with("192.0.2."));
+        }
+        let (a, b) = (addrs[0].port(), addrs[1].port());
+        assert!(ports == vec![a, b, a, b] || ports == vec![b, a, b, a], "accepted from {:?}", ports);
+    }
+}
@@ -0,0 +1,168 @@
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+use proxy_config::Clock;
+use proxy_info::ProxyInfo;
+use proxy_stream::ProxyWarning;
+#[cfg(feature = "rdns")]
+use proxy_rdns::ReverseDns;
+
+
+/// Hooks for following the connections accepted by a `ProxyListener`, attached with
+/// `ProxyConfig::observer`.
+///
+/// Every method has a default implementation which does nothing, so implementations only
+/// need to provide the ones they care about. Hooks are called synchronously from whichever
+/// thread triggered them, so they should be quick.
+pub trait ProxyObserver: Send + Sync {
+    /// A connection was closed, i.e., the last clone of its `ProxyStream` (and of any halves
+    /// it was split into) was dropped
+    fn on_close(&self, _event: &ConnectionClosed) {}
+
+    /// A connection was over the listener's `ConnectionLimiter` or `RateLimiter`, and was
+    /// refused (or, in a dry run, would have been)
+    fn on_limited(&self, _event: &ConnectionLimited) {}
+
+    /// A connection was accepted with something worth a warning, as returned by
+    /// `ProxyListener::accept_with_warnings` (and passed here whichever way it was accepted)
+    fn on_warning(&self, _warning: &ProxyWarning) {}
+}
+
+
+/// What `ProxyObserver::on_close` is told about a connection
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ConnectionClosed {
+    /// What was learned from the PROXY header, or `None` if there was none
+    pub info: Option<ProxyInfo>,
+    /// The address the stream reported from `peer_addr()`, if it had one
+    pub peer_addr: Option<SocketAddr>,
+    /// Bytes read from the connection after the header, through every clone of the stream
+    pub bytes_read: u64,
+    /// Bytes written to the connection, through every clone of the stream
+    pub bytes_written: u64,
+    /// How long the connection was open for, from just after it was accepted
+    pub duration: Duration,
+    /// When the connection was accepted, by the wall clock
+    pub accepted_at: SystemTime,
+    /// The name of the client the header came from, if `ProxyConfig::reverse_dns` is set and
+    /// had found it by the time the connection closed
+    pub client_name: Option<String>,
+}
+
+
+/// What `ProxyObserver::on_limited` is told about a connection
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ConnectionLimited {
+    /// The client's address, as the connection would have reported from `peer_addr()`
+    pub client: IpAddr,
+    /// Which limit the connection was over
+    pub limit: LimitKind,
+    /// Whether the connection was refused, i.e., the limiter's policy was not
+    /// `LimitPolicy::DryRun`
+    pub enforced: bool,
+}
+
+
+/// Which of a listener's limits a connection was over
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitKind {
+    /// The client already had as many connections open as its `ConnectionLimiter` allows
+    Connections,
+    /// The client had opened connections faster than its `RateLimiter` allows
+    Rate,
+}
+
+
+/// A `ProxyObserver` as held by a `ProxyConfig`, which needs to be `Debug`
+#[derive(Clone)]
+pub(crate) struct Observer(pub(crate) Arc<dyn ProxyObserver>);
+
+impl fmt::Debug for Observer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Observer")
+    }
+}
+
+
+/// The part of an accepted connection shared between all clones of its stream, which adds up
+/// their byte counts and reports the connection to the observer once the last of them is gone
+pub(crate) struct ConnectionTracker {
+    observer: Arc<dyn ProxyObserver>,
+    info: Option<ProxyInfo>,
+    peer_addr: Option<SocketAddr>,
+    accepted_at: Instant,
+    accepted_wall: SystemTime,
+    clock: Option<Clock>,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    #[cfg(feature = "rdns")]
+    reverse_dns: Option<ReverseDns>,
+}
+
+
+impl ConnectionTracker {
+    /// Track a connection accepted at `accepted_at` by `clock`, and at `accepted_wall` by the
+    /// wall clock
+    pub(crate) fn new(observer: &Observer, info: Option<ProxyInfo>, peer_addr: Option<SocketAddr>,
+                      accepted_at: Instant, accepted_wall: SystemTime, clock: Option<Clock>) -> Self {
+        ConnectionTracker {
+            observer: Arc::clone(&observer.0),
+            info,
+            peer_addr,
+            accepted_at,
+            accepted_wall,
+            clock,
+            bytes_read: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            #[cfg(feature = "rdns")]
+            reverse_dns: None,
+        }
+    }
+
+    /// Report the name of the connection's client, if `reverse_dns` has it by the time the
+    /// connection closes
+    #[cfg(feature = "rdns")]
+    pub(crate) fn with_reverse_dns(mut self, reverse_dns: Option<ReverseDns>) -> Self {
+        self.reverse_dns = reverse_dns;
+        self
+    }
+
+    #[cfg(feature = "rdns")]
+    fn client_name(&self) -> Option<String> {
+        let source = self.info.as_ref().and_then(|info| info.source_addr())?;
+        self.reverse_dns.as_ref()?.lookup_cached(source.ip())
+    }
+
+    #[cfg(not(feature = "rdns"))]
+    fn client_name(&self) -> Option<String> {
+        None
+    }
+
+    /// Add to the connection's totals
+    pub(crate) fn add(&self, read: u64, written: u64) {
+        self.bytes_read.fetch_add(read, Ordering::Relaxed);
+        self.bytes_written.fetch_add(written, Ordering::Relaxed);
+    }
+}
+
+
+impl Drop for ConnectionTracker {
+    fn drop(&mut self) {
+        let client_name = self.client_name();
+        let event = ConnectionClosed {
+            info: self.info.take(),
+            peer_addr: self.peer_addr,
+            bytes_read: *self.bytes_read.get_mut(),
+            bytes_written: *self.bytes_written.get_mut(),
+            duration: Clock::now(self.clock.as_ref()).saturating_duration_since(self.accepted_at),
+            accepted_at: self.accepted_wall,
+            client_name,
+        };
+        self.observer.on_close(&event);
+    }
+}
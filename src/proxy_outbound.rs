@@ -0,0 +1,104 @@
+use std::io;
+use std::net::TcpStream;
+
+use hyper::net::{HttpStream, NetworkStream};
+
+use proxy_info::ProxyInfo;
+use proxy_protocol::{Proto, ProxyProtocolHeader};
+use proxy_stream::ProxyStream;
+
+
+/// A connection whose addresses can be passed on to another server in a PROXY header, with
+/// `ProxyInfo::for_outbound`, so that code relaying connections to servers which expect a header
+/// does not need to care whether it accepted them with or without one.
+pub trait OutboundAddresses {
+    /// The header describing where the connection came from and was going to
+    fn outbound_header(&self) -> io::Result<ProxyInfo>;
+}
+
+
+/// A snapshot of the socket's addresses, with the peer as the source and the local address as
+/// the destination, in a version 2 `PROXY` header
+impl OutboundAddresses for TcpStream {
+    fn outbound_header(&self) -> io::Result<ProxyInfo> {
+        Ok(ProxyInfo::from_header(ProxyProtocolHeader::for_addrs(self.peer_addr()?, self.local_addr()?)))
+    }
+}
+
+
+impl OutboundAddresses for HttpStream {
+    fn outbound_header(&self) -> io::Result<ProxyInfo> {
+        self.0.outbound_header()
+    }
+}
+
+
+/// The header the stream was accepted with, without its TLVs, so that the next server sees the
+/// same client; or, if there was none, or it carried no addresses (`UNKNOWN`) or was only from
+/// the proxy itself (`LOCAL`), the inner stream's own addresses
+impl<T: NetworkStream + OutboundAddresses> OutboundAddresses for ProxyStream<T> {
+    fn outbound_header(&self) -> io::Result<ProxyInfo> {
+        match self.proxy_info() {
+            Some(info) if !info.is_local() && info.proto() != Proto::Unknown => Ok(info.without_tlvs()),
+            _ => self.inner_stream().outbound_header(),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+
+    use hyper::net::HttpListener;
+
+    use proxy_info::ProxyInfo;
+    use proxy_listener::ProxyListener;
+    use proxy_protocol::{Proto, ProxyProtocolVersion, read_proxy_protocol_v2};
+    use super::OutboundAddresses;
+
+    #[test]
+    fn test_snapshot_tcp_stream() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+
+        let info = ProxyInfo::for_outbound(&accepted).expect("should have addresses");
+        assert_eq!(info.source_addr(), Some(client.local_addr().unwrap()));
+        assert_eq!(info.destination_addr(), Some(listener.local_addr().unwrap()));
+        assert_eq!((info.version(), info.proto(), info.is_local()), (2, Proto::Tcp4, false));
+        let line = format!("PROXY TCP4 127.0.0.1 127.0.0.1 {} {}\r\n", client.local_addr().unwrap().port(), listener.local_addr().unwrap().port());
+        assert_eq!(info.to_bytes(ProxyProtocolVersion::V1), line.into_bytes());
+        let encoded = info.to_bytes(ProxyProtocolVersion::Any);
+        let decoded = read_proxy_protocol_v2(&mut &encoded[..]).expect("should parse");
+        assert_eq!(ProxyInfo::from_header(decoded), info);
+    }
+
+    fn accept_with(header: &[u8]) -> (TcpStream, ProxyInfo) {
+        let mut listener = ProxyListener::new(HttpListener::new("127.0.0.1:0").unwrap(), ProxyProtocolVersion::V1);
+        let mut client = TcpStream::connect(listener.bound_addr().unwrap()).unwrap();
+        client.write_all(header).unwrap();
+        let stream = listener.accept_detailed().expect("should accept");
+        // through a trait object, as relays handling several kinds of stream might
+        let info = ProxyInfo::for_outbound(&stream as &dyn OutboundAddresses).expect("should have addresses");
+        (client, info)
+    }
+
+    #[test]
+    fn test_propagate_proxy_stream() {
+        let line = b"PROXY TCP6 2001:db8::1 2001:db8::2 4124 443\r\n";
+        let (_client, info) = accept_with(line);
+        assert_eq!(info.source_addr(), Some("[2001:db8::1]:4124".parse().unwrap()));
+        assert_eq!(info.version(), 1);
+        assert_eq!(info.to_bytes(ProxyProtocolVersion::Any), line.to_vec());
+    }
+
+    #[test]
+    fn test_fallback_for_unknown() {
+        let (client, info) = accept_with(b"PROXY UNKNOWN\r\n");
+        assert_eq!(info.source_addr(), Some(client.local_addr().unwrap()));
+        assert_eq!(info.destination_addr(), Some(client.peer_addr().unwrap()));
+        assert_eq!(info.proto(), Proto::Tcp4);
+    }
+}
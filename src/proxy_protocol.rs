@@ -1,9 +1,13 @@
-use std::fmt::{self, Display, Debug, Formatter};
+use std::cmp::Ordering;
+use std::fmt::{self, Display, Debug, Formatter, Write};
 use std::error::Error;
 use std::io::{self,Read};
 use std::net::{SocketAddr,IpAddr,Ipv4Addr,Ipv6Addr,AddrParseError};
 use std::str::Utf8Error;
 use std::num::ParseIntError;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::task::Poll;
 
 use hyper;
 use byteorder::{NetworkEndian,ByteOrder};
@@ -20,26 +24,218 @@ pub enum ProxyProtocolVersion {
 }
 
 
+/// Parses `v1`, `v2` or `any` (or `1` or `2`), ignoring case
+impl FromStr for ProxyProtocolVersion {
+    type Err = ParseVersionError;
+
+    fn from_str(s: &str) -> ::std::result::Result<Self, Self::Err> {
+        match &s.to_ascii_lowercase()[..] {
+            "v1" | "1" => Ok(ProxyProtocolVersion::V1),
+            "v2" | "2" => Ok(ProxyProtocolVersion::V2),
+            "any" => Ok(ProxyProtocolVersion::Any),
+            _ => Err(ParseVersionError(s.to_owned())),
+        }
+    }
+}
+
+
+#[cfg(feature = "serde")]
+impl<'de> ::serde::Deserialize<'de> for ProxyProtocolVersion {
+    fn deserialize<D: ::serde::Deserializer<'de>>(deserializer: D) -> ::std::result::Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(::serde::de::Error::custom)
+    }
+}
+
+
+/// A string which does not name a `ProxyProtocolVersion`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseVersionError(String);
+
+impl Display for ParseVersionError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "unknown PROXY protocol version {:?} (expected v1, v2 or any)", self.0)
+    }
+}
+
+impl Error for ParseVersionError {}
+
+
+/// Why a PROXY header could not be read off of a connection
+///
+/// This is `Send + Sync + 'static`, as is everything it wraps, so it converts into a boxed
+/// error with `?` like any other error:
+///
+/// ```
+/// use std::error::Error;
+/// use hyper_networklistener_proxy::{ProxyProtocolVersion, StripProxy};
+///
+/// fn client_of(bytes: &[u8]) -> Result<String, Box<dyn Error + Send + Sync>> {
+///     let mut reader = StripProxy::new(bytes, ProxyProtocolVersion::V2);
+///     let info = reader.header()?.ok_or("no header")?;
+///     Ok(format!("{:?}", info.source_addr()))
+/// }
+///
+/// fn parse_datagram(bytes: &[u8]) -> Result<usize, Box<dyn Error + Send + Sync>> {
+///     let (_, payload) = hyper_networklistener_proxy::proxy_protocol::parse_v2_datagram(bytes)?;
+///     Ok(payload.len())
+/// }
+///
+/// assert!(client_of(b"GET / HTTP/1.1\r\n").is_err());
+/// let err = parse_datagram(b"\r\n\r\n\0\r\nQUIT\n\x21\x12").unwrap_err();
+/// assert!(err.downcast_ref::<hyper_networklistener_proxy::ProxyReadError>().is_some());
+/// ```
 #[derive(Debug)]
-pub(crate) enum ProxyReadError {
+pub enum ProxyReadError {
+    /// A version 1 header ended before all of its fields
     MissingField,
-    MissingLiteral,
-    InvalidProtocol,
-    MissingCrlf,
+    /// The header did not start with the `PROXY` literal (version 1) or the signature
+    /// (version 2), or a version 1 header named an unknown protocol; gives what had been read
+    /// of the header by then (the line, without its CRLF, for version 1)
+    MissingLiteral(Vec<u8>),
+    /// A version 2 header had an invalid command, address family, transport or length; gives
+    /// the command nibble, and the family and transport nibbles, exactly as they were sent
+    InvalidProtocol {
+        /// The low nibble of the 13th byte
+        command: u8,
+        /// The high nibble of the 14th byte
+        family: u8,
+        /// The low nibble of the 14th byte
+        transport: u8,
+    },
+    /// A version 2 `PROXY` header's address block (whose declared length is given) was too
+    /// short to hold the addresses of its family (also given), so there were no addresses to
+    /// report
+    ShortAddressBlock(Proto, usize),
+    /// A header went on past the most bytes allowed for it: a version 1 line with no CRLF
+    /// within `ParseConfig::max_v1_line_len` bytes, or any header longer than
+    /// `ParseConfig::max_header_len`. No more of it is read once that is known.
+    HeaderTooLong {
+        /// How many bytes of header were allowed
+        limit: usize,
+        /// How many bytes of the header had been read when it was given up on, counting from
+        /// its first byte: all of them for a version 1 line, and the fixed header (whose
+        /// length field gave it away) for version 2
+        read: usize,
+    },
+    /// The connection (or datagram) ended part way through the header; gives how many bytes
+    /// of the header the parser was reading up to, and how many it got, counting from the
+    /// header's first byte. Version 1 lines are read a byte at a time until their CRLF, so
+    /// for them `needed` is always one more than `got`.
+    UnexpectedEof {
+        /// How many bytes there would have been, had the read succeeded
+        needed: usize,
+        /// How many bytes were read before the end
+        got: usize,
+    },
+    /// The first byte could not start a header of any version
     MissingFirstByte,
-    BadVersion,
+    /// The connection started with a TLS handshake record (`0x16 0x03 0x0X`, as a ClientHello
+    /// is sent in) rather than a header, which usually means a TLS load balancer has been
+    /// pointed at the listener. Reading `ProxyProtocolVersion::V2` or `Any`, which give up
+    /// after the first byte, takes a first byte of `0x16` to be enough.
+    LooksLikeTls,
+    /// The connection started with an HTTP/1.1 request line, whose method is given, rather
+    /// than a header, which usually means the client (or the load balancer in front of it)
+    /// is not sending the PROXY protocol at all
+    LooksLikeHttp(&'static str),
+    /// A version 2 header declared a version other than 2, which is given
+    BadVersion(u8),
+    /// The source address of a version 1 header could not be parsed
     BadSourceAddress(AddrParseError),
+    /// The source port of a version 1 header could not be parsed
     BadSourcePort(ParseIntError),
+    /// The destination address of a version 1 header could not be parsed
     BadDestAddress(AddrParseError),
+    /// The destination port of a version 1 header could not be parsed
     BadDestPort(ParseIntError),
+    /// The header declared an address family which the listener does not allow (see
+    /// `ProxyConfig::allowed_families`)
+    FamilyNotAllowed(Proto),
+    /// The header's addresses are not of the same family as the address the listener is
+    /// bound to (see `ProxyConfig::require_bound_family`)
+    BoundFamilyMismatch(Proto),
+    /// The header's source address was unspecified or had port 0, and the listener rejects
+    /// those (see `ProxyConfig::unspecified_source`)
+    UnspecifiedSource(SocketAddr),
+    /// The header carried no client address (a `LOCAL` or unspecified one gives its
+    /// command, and a Unix socket one `Command::Proxy`), and the listener requires one (see
+    /// `ProxyConfig::require_proxied_address`)
+    NoClientAddress(Command),
+    /// A version 1 header failed one of the conformance checks enabled in its `ParseConfig`,
+    /// which is named
+    NotConformant(&'static str),
+    /// A version 1 header carried an address of the other family than its protocol (an IPv6
+    /// address on a `TCP4` line, or an IPv4 one on a `TCP6` line); gives the protocol and which
+    /// address it was (see `ParseConfig::reject_family_mismatch`)
+    AddressFamilyMismatch(Proto, &'static str),
+    /// A version 1 line was longer than the spec allows for its protocol (see
+    /// `ParseConfig::reject_overlong_lines`); gives the protocol, the line's length and the
+    /// most allowed, counting the CRLF
+    LineTooLong(Proto, usize, usize),
+    /// The client already had as many connections open as the listener's `ConnectionLimiter`
+    /// allows (see `ProxyConfig::connection_limit`)
+    TooManyConnections(IpAddr),
+    /// The client had opened connections faster than the listener's `RateLimiter` allows (see
+    /// `ProxyConfig::rate_limit`)
+    RateLimited(IpAddr),
+    /// The first read off the connection held only part of a header, and the listener
+    /// requires the whole of it there (see `ProxyConfig::require_header_in_first_read`);
+    /// gives how many bytes the read did hold
+    SplitHeader(usize),
+    /// A TLV of a version 2 header ran past the end of the address block (or there were too
+    /// few bytes left in it for a TLV's type and length); gives the offset of its type byte,
+    /// counting from the header's first byte
+    MalformedTlv(usize),
+    /// Reading from the connection failed, or it was closed before a whole header arrived
     Io(io::Error),
+    /// A version 1 header was not valid UTF-8
     Utf8(Utf8Error),
 }
 
 
 impl Display for ProxyReadError {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        <Self as Debug>::fmt(self, f)
+        match *self {
+            ProxyReadError::FamilyNotAllowed(proto) => write!(f, "address family {:?} is not allowed on this listener", proto),
+            ProxyReadError::BoundFamilyMismatch(proto) => write!(f, "address family {:?} does not match the listener's bound address", proto),
+            ProxyReadError::UnspecifiedSource(addr) => write!(f, "source address {} is unspecified or has port 0", addr),
+            ProxyReadError::NoClientAddress(command) => write!(f, "{:?} header carried no client address, and this listener requires one", command),
+            ProxyReadError::NotConformant(check) => write!(f, "header failed a conformance check: {}", check),
+            ProxyReadError::AddressFamilyMismatch(proto, field) => write!(f, "{} is not of the {:?} family", field, proto),
+            ProxyReadError::LineTooLong(proto, len, max) => write!(f, "{:?} line of {} bytes is longer than the {} allowed", proto, len, max),
+            ProxyReadError::HeaderTooLong { limit, read } => write!(f, "header longer than the {} bytes allowed (gave up after {})", limit, read),
+            ProxyReadError::InvalidProtocol { command, family, transport } => write!(f, "invalid version 2 header (command={}, family={}, transport={})", command, family, transport),
+            ProxyReadError::BadVersion(version) => write!(f, "unsupported header version (version={})", version),
+            ProxyReadError::MissingLiteral(ref bytes) => {
+                f.write_str("missing PROXY literal or signature; got")?;
+                for byte in bytes {
+                    write!(f, " {:02x}", byte)?;
+                }
+                Ok(())
+            },
+            ProxyReadError::LooksLikeTls => f.write_str("peer appears to be speaking TLS, not PROXY protocol"),
+            ProxyReadError::LooksLikeHttp(method) => write!(f, "peer appears to be sending an HTTP {} request, not PROXY protocol", method),
+            ProxyReadError::UnexpectedEof { needed, got } => write!(f, "connection closed after {} bytes of the header, with {} needed", got, needed),
+            ProxyReadError::ShortAddressBlock(proto, len) => write!(f, "address block of {} bytes is too short for {:?} addresses", len, proto),
+            ProxyReadError::TooManyConnections(addr) => write!(f, "too many connections from {}", addr),
+            ProxyReadError::SplitHeader(read) => write!(f, "header did not arrive whole in the first read ({} bytes)", read),
+            ProxyReadError::MalformedTlv(at) => write!(f, "TLV at byte {} runs past the end of the address block", at),
+            ProxyReadError::RateLimited(addr) => write!(f, "connections from {} are over the rate limit", addr),
+            ProxyReadError::Io(ref e) if self.is_timeout() => write!(f, "timed out reading the header: {}", e),
+            _ => <Self as Debug>::fmt(self, f),
+        }
+    }
+}
+
+impl ProxyReadError {
+    /// Whether the connection timed out before sending its whole header, i.e., it failed to
+    /// read with `TimedOut`, or `WouldBlock` (as reads past a socket's read timeout do on unix)
+    pub fn is_timeout(&self) -> bool {
+        match *self {
+            ProxyReadError::Io(ref e) => e.kind() == io::ErrorKind::TimedOut || e.kind() == io::ErrorKind::WouldBlock,
+            _ => false,
+        }
     }
 }
 
@@ -48,12 +244,14 @@ impl Error for ProxyReadError {
         "error reading PROXY protocol header on stream"
     }
 
-    fn cause(&self) -> Option<&Error> {
+    fn cause(&self) -> Option<&dyn Error> {
         match *self {
             ProxyReadError::Io(ref err) => Some(err),
             ProxyReadError::Utf8(ref err) => Some(err),
             ProxyReadError::BadSourceAddress(ref err) => Some(err),
+            ProxyReadError::BadSourcePort(ref err) => Some(err),
             ProxyReadError::BadDestAddress(ref err) => Some(err),
+            ProxyReadError::BadDestPort(ref err) => Some(err),
             _ => None,
         }
     }
@@ -77,302 +275,2635 @@ impl From<Utf8Error> for ProxyReadError {
 }
 
 
-impl Into<hyper::Error> for ProxyReadError {
-    fn into(self) -> hyper::Error {
-        match self {
+impl From<ProxyReadError> for io::Error {
+    fn from(e: ProxyReadError) -> Self {
+        match e {
+            // the timeout's error itself is still there, as the source
+            e if e.is_timeout() => io::Error::new(io::ErrorKind::TimedOut, e),
+            ProxyReadError::Io(e) => e,
+            e @ ProxyReadError::UnexpectedEof { .. } => io::Error::new(io::ErrorKind::UnexpectedEof, e),
+            e @ ProxyReadError::FamilyNotAllowed(_) | e @ ProxyReadError::BoundFamilyMismatch(_) | e @ ProxyReadError::UnspecifiedSource(_) | e @ ProxyReadError::NoClientAddress(_) | e @ ProxyReadError::TooManyConnections(_) | e @ ProxyReadError::RateLimited(_) => io::Error::new(io::ErrorKind::PermissionDenied, e),
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+
+impl From<ProxyReadError> for hyper::Error {
+    fn from(e: ProxyReadError) -> Self {
+        match e {
+            e if e.is_timeout() => hyper::Error::Io(e.into()),
             ProxyReadError::Io(e) => hyper::Error::Io(e),
             ProxyReadError::Utf8(e) => hyper::Error::Utf8(e),
-            ProxyReadError::BadVersion => hyper::Error::Version,
+            ProxyReadError::BadVersion(_) => hyper::Error::Version,
+            e @ ProxyReadError::UnexpectedEof { .. } => hyper::Error::Io(e.into()),
+            e @ ProxyReadError::FamilyNotAllowed(_) | e @ ProxyReadError::BoundFamilyMismatch(_) | e @ ProxyReadError::UnspecifiedSource(_) | e @ ProxyReadError::NoClientAddress(_) | e @ ProxyReadError::TooManyConnections(_) | e @ ProxyReadError::RateLimited(_) => hyper::Error::Io(e.into()),
             _ => hyper::Error::Header,
         }
     }
 }
 
 
-#[derive(Debug, PartialEq, Eq)]
-pub(crate) enum Proto {
+/// How strictly to parse headers, beyond what is needed to make sense of them, and how large
+/// a version 2 header may be.
+///
+/// The default is neither strict nor lenient. `strict()` turns on every conformance check, for
+/// listeners whose senders are known to follow the spec to the letter. `lenient()` turns on
+/// every accommodation for senders which do not, instead. Individual settings can be adjusted
+/// with the builder-style methods below. Most of them only apply to version 1 headers.
+///
+/// ```
+/// use hyper_networklistener_proxy::{ParseConfig, ProxyConfig, ProxyProtocolVersion};
+///
+/// let config = ProxyConfig::new(ProxyProtocolVersion::V1)
+///     .parse_config(ParseConfig::lenient().allow_bracketed_ipv6(false));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Deserialize), serde(default, deny_unknown_fields))]
+pub struct ParseConfig {
+    pub(crate) reject_noncanonical_ports: bool,
+    pub(crate) reject_trailing_fields: bool,
+    pub(crate) reject_family_mismatch: bool,
+    pub(crate) allow_lowercase: bool,
+    pub(crate) allow_lf_terminator: bool,
+    pub(crate) allow_bracketed_ipv6: bool,
+    pub(crate) allow_extra_spaces: bool,
+    pub(crate) reject_overlong_lines: bool,
+    pub(crate) reject_dgram: bool,
+    pub(crate) normalize_mapped_v4: bool,
+    pub(crate) max_v1_line_len: usize,
+    pub(crate) max_v2_block_len: usize,
+    pub(crate) max_header_len: Option<usize>,
+}
+
+
+/// The default for `ParseConfig::max_v1_line_len`: 107 bytes, the longest the spec allows
+pub const DEFAULT_MAX_V1_LINE_LEN: usize = 107;
+
+
+/// The default for `ParseConfig::max_v2_block_len`: 16 KiB
+pub const DEFAULT_MAX_V2_BLOCK_LEN: usize = 16 * 1024;
+
+
+impl Default for ParseConfig {
+    fn default() -> Self {
+        ParseConfig {
+            reject_noncanonical_ports: false,
+            reject_trailing_fields: false,
+            reject_family_mismatch: true,
+            allow_lowercase: false,
+            allow_lf_terminator: false,
+            allow_bracketed_ipv6: false,
+            allow_extra_spaces: false,
+            reject_overlong_lines: false,
+            reject_dgram: false,
+            normalize_mapped_v4: false,
+            max_v1_line_len: DEFAULT_MAX_V1_LINE_LEN,
+            max_v2_block_len: DEFAULT_MAX_V2_BLOCK_LEN,
+            max_header_len: None,
+        }
+    }
+}
+
+
+impl ParseConfig {
+    /// Every conformance check, and no accommodations
+    pub fn strict() -> Self {
+        ParseConfig::default()
+            .reject_noncanonical_ports(true)
+            .reject_trailing_fields(true)
+            .reject_family_mismatch(true)
+            .reject_overlong_lines(true)
+            .reject_dgram(true)
+    }
+
+    /// Every accommodation, and no conformance checks
+    pub fn lenient() -> Self {
+        ParseConfig::default()
+            .allow_lowercase(true)
+            .allow_lf_terminator(true)
+            .allow_bracketed_ipv6(true)
+            .allow_extra_spaces(true)
+    }
+
+    /// Reject ports with leading zeros (`080`) or a sign (`+80`). Defaults to `false`.
+    pub fn reject_noncanonical_ports(mut self, reject: bool) -> Self {
+        self.reject_noncanonical_ports = reject;
+        self
+    }
+
+    /// Reject headers with anything after the destination port. Defaults to `false`, which
+    /// ignores any extra fields.
+    pub fn reject_trailing_fields(mut self, reject: bool) -> Self {
+        self.reject_trailing_fields = reject;
+        self
+    }
+
+    /// Reject `TCP4` headers carrying IPv6 addresses and `TCP6` headers carrying IPv4
+    /// addresses, with `AddressFamilyMismatch`. Defaults to `true`, since the spec forbids
+    /// them, and even by `lenient()`.
+    pub fn reject_family_mismatch(mut self, reject: bool) -> Self {
+        self.reject_family_mismatch = reject;
+        self
+    }
+
+    /// Accept the `PROXY` literal and the protocol name in any case (`proxy tcp4`). Defaults
+    /// to `false`.
+    pub fn allow_lowercase(mut self, allow: bool) -> Self {
+        self.allow_lowercase = allow;
+        self
+    }
+
+    /// Accept headers terminated by a bare LF rather than a CRLF. Defaults to `false`.
+    pub fn allow_lf_terminator(mut self, allow: bool) -> Self {
+        self.allow_lf_terminator = allow;
+        self
+    }
+
+    /// Accept IPv6 addresses in brackets (`[::1]`). Defaults to `false`.
+    pub fn allow_bracketed_ipv6(mut self, allow: bool) -> Self {
+        self.allow_bracketed_ipv6 = allow;
+        self
+    }
+
+    /// Accept runs of spaces between fields, and spaces or tabs before the terminator.
+    /// Defaults to `false`, which rejects them with `NotConformant`. (Whatever follows
+    /// `UNKNOWN` is never looked at, so may be spaced however the sender likes.) Lines are still
+    /// limited to `max_v1_line_len` bytes, spaces and all.
+    pub fn allow_extra_spaces(mut self, allow: bool) -> Self {
+        self.allow_extra_spaces = allow;
+        self
+    }
+
+    /// Reject `TCP4` lines longer than 56 bytes and `TCP6` lines longer than 104, counting the
+    /// CRLF, which are the longest the spec allows for each, with `LineTooLong`. Defaults to
+    /// `false`, which accepts any line up to `max_v1_line_len`.
+    pub fn reject_overlong_lines(mut self, reject: bool) -> Self {
+        self.reject_overlong_lines = reject;
+        self
+    }
+
+    /// Reject version 2 headers declaring the `DGRAM` transport on stream connections, where
+    /// it makes no sense. Defaults to `false`, which reports them as `Proto::Udp4` and
+    /// `Proto::Udp6` (see `ProxyProtocolHeader::transport`), for proxies which relay datagrams
+    /// over a stream. Datagrams parsed with `parse_v2_datagram` always accept `DGRAM`.
+    pub fn reject_dgram(mut self, reject: bool) -> Self {
+        self.reject_dgram = reject;
+        self
+    }
+
+    /// Report IPv4-mapped IPv6 addresses (`::ffff:192.0.2.1`), as sent by proxies listening on
+    /// dual-stack sockets, as the IPv4 addresses they map, in both `TCP6` lines and `AF_INET6`
+    /// blocks. IPv4-compatible addresses (`::192.0.2.1`) are left alone, as is the header's
+    /// `Proto`, which is still the family it declared. Defaults to `false`.
+    pub fn normalize_mapped_v4(mut self, normalize: bool) -> Self {
+        self.normalize_mapped_v4 = normalize;
+        self
+    }
+
+    /// The longest version 1 line to scan for a CRLF, counting the CRLF. Lines with no CRLF
+    /// within it are rejected with `HeaderTooLong`. Defaults to `DEFAULT_MAX_V1_LINE_LEN`, the
+    /// 107 bytes the spec allows; raise it only for senders which pad their lines past that.
+    /// Each line is buffered as it arrives, so a client has to send as many bytes as the
+    /// limit allows to make the listener hold that many.
+    pub fn max_v1_line_len(mut self, len: usize) -> Self {
+        self.max_v1_line_len = len;
+        self
+    }
+
+    /// The longest address block (the addresses and any TLVs after them) to accept in a
+    /// version 2 header. Longer ones are rejected with `InvalidProtocol` before any of the
+    /// block is read, since it is buffered whole, unless the header's address family is
+    /// `UNSPEC`, when the block holds no addresses and is skipped instead: its TLVs are split
+    /// off as it goes by, keeping as many as fit in this many bytes and only checking the
+    /// rest fit in the block. Defaults to `DEFAULT_MAX_V2_BLOCK_LEN`,
+    /// which leaves plenty of room for the TLVs load balancers attach (AWS's VPC endpoint ID,
+    /// for one); the format itself allows up to 65535.
+    pub fn max_v2_block_len(mut self, len: usize) -> Self {
+        self.max_v2_block_len = len;
+        self
+    }
+
+    /// The most bytes a header of either version may take, counting from its first byte, on
+    /// top of the limits above. A version 1 line is scanned for its CRLF this far at most, and
+    /// a version 2 header whose length field makes it any longer is rejected as soon as the
+    /// fixed header has been read, so a client can tie a connection up for no more than this
+    /// many bytes before it is turned away with `HeaderTooLong`. Defaults to `None`, which
+    /// leaves each version to its own limit: `max_v1_line_len`, and the 16 byte fixed header
+    /// plus `max_v2_block_len`.
+    pub fn max_header_len(mut self, len: Option<usize>) -> Self {
+        self.max_header_len = len;
+        self
+    }
+
+    /// The longest version 1 line to scan for a CRLF, under both limits
+    fn v1_line_limit(&self) -> usize {
+        self.max_header_len.map_or(self.max_v1_line_len, |max| max.min(self.max_v1_line_len))
+    }
+
+    /// The longest header of `version` these limits allow (though a longer `UNSPEC` one may
+    /// still be skipped)
+    pub(crate) fn longest_header(&self, version: ProxyProtocolVersion) -> usize {
+        let v2 = self.max_header_len.map_or(16 + self.max_v2_block_len, |max| max.min(16 + self.max_v2_block_len));
+        match version {
+            ProxyProtocolVersion::V1 => self.v1_line_limit(),
+            ProxyProtocolVersion::V2 => v2,
+            ProxyProtocolVersion::Any => v2.max(self.v1_line_limit()),
+        }
+    }
+}
+
+
+/// The address family declared by a PROXY header (see `ProxyProtocolHeader::proto`), which
+/// tells a client which really connected over IPv6 from one whose header had no addresses
+/// at all:
+///
+/// ```
+/// use hyper_networklistener_proxy::{Proto, ProxyProtocolVersion, StripProxy};
+///
+/// let family = |bytes: &[u8]| {
+///     let mut reader = StripProxy::new(bytes, ProxyProtocolVersion::V1);
+///     match reader.header().unwrap().unwrap().header().proto() {
+///         Proto::Tcp4 | Proto::Udp4 => "v4",
+///         Proto::Tcp6 | Proto::Udp6 => "v6",
+///         Proto::Unix | Proto::Unknown => "other",
+///     }
+/// };
+/// assert_eq!(family(b"PROXY TCP6 2001:db8::1 2001:db8::2 4124 443\r\n"), "v6");
+/// assert_eq!(family(b"PROXY UNKNOWN\r\n"), "other");
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Deserialize), serde(rename_all = "lowercase"))]
+pub enum Proto {
+    /// `TCP4` in version 1, `AF_INET` over `STREAM` in version 2
     Tcp4,
+    /// `TCP6` in version 1, `AF_INET6` over `STREAM` in version 2
     Tcp6,
+    /// `AF_INET` over `DGRAM` in version 2 (unless `ParseConfig::reject_dgram` is set)
+    Udp4,
+    /// `AF_INET6` over `DGRAM` in version 2 (unless `ParseConfig::reject_dgram` is set)
+    Udp6,
+    /// `AF_UNIX` over `STREAM` or `DGRAM` in version 2. Such headers carry socket paths (see
+    /// `ProxyInfo::source_path`) rather than socket addresses.
     Unix,
+    /// `UNKNOWN` in version 1; `AF_UNSPEC`, or any family in a `LOCAL` header whose address
+    /// block is too short for it, in version 2
     Unknown
 }
 
 
-#[derive(Debug, PartialEq, Eq)]
-pub(crate) struct ProxyProtocolHeader {
+impl Proto {
+    /// The lowercase name of the family, as written in configuration (see
+    /// `ProxyConfig::allowed_families`) and log fields: `tcp4`, `tcp6`, `udp4`, `udp6`, `unix`
+    /// or `unknown`. Unlike the `Debug` output, these will not change.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Proto::Tcp4 => "tcp4",
+            Proto::Tcp6 => "tcp6",
+            Proto::Udp4 => "udp4",
+            Proto::Udp6 => "udp6",
+            Proto::Unix => "unix",
+            Proto::Unknown => "unknown",
+        }
+    }
+}
+
+
+/// The family's name from `as_str`
+impl Display for Proto {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+
+/// The transport declared by a PROXY header (see `ProxyProtocolHeader::transport`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Transport {
+    /// `TCP4` or `TCP6` in version 1, `STREAM` in version 2
+    Stream,
+    /// `DGRAM` in version 2
+    Dgram,
+}
+
+
+/// The address of a unix socket, as carried by a version 2 `AF_UNIX` header.
+///
+/// On the wire each address is a 108-byte `sun_path` field. A field starting with a NUL byte
+/// holds a Linux abstract socket name, which is everything after that byte up to the last
+/// non-NUL byte (so names may contain NULs, but cannot end with one); any other field holds a
+/// path, ending at the first NUL. A field of nothing but NULs stands for an unnamed socket,
+/// and is an empty `Pathname`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum UnixPath {
+    /// A socket bound to a path in the filesystem
+    Pathname(PathBuf),
+    /// A socket in the Linux abstract namespace, without the leading NUL
+    Abstract(Vec<u8>),
+}
+
+
+/// The size of a `sun_path` field in a version 2 header
+const UNIX_PATH_LEN: usize = 108;
+
+
+impl UnixPath {
+    /// Decode a `sun_path` field; only the first 108 bytes of `field` are looked at
+    pub fn from_bytes(field: &[u8]) -> Self {
+        let field = &field[..field.len().min(UNIX_PATH_LEN)];
+        match field.split_first() {
+            Some((&0, name)) if name.iter().any(|&b| b != 0) => {
+                let end = name.iter().rposition(|&b| b != 0).map(|i| i + 1).unwrap_or(0);
+                UnixPath::Abstract(name[..end].to_vec())
+            },
+            _ => {
+                let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+                UnixPath::Pathname(path_from_bytes(&field[..end]))
+            },
+        }
+    }
+
+    /// Encode as a `sun_path` field, padded with NULs; `None` if the path or name is too long
+    /// to fit (108 bytes for a path, 107 for an abstract name), or cannot be decoded again
+    /// (a path containing a NUL, or an abstract name which is empty or ends with a NUL)
+    pub fn to_bytes(&self) -> Option<[u8; 108]> {
+        let mut field = [0u8; UNIX_PATH_LEN];
+        match *self {
+            UnixPath::Pathname(ref path) => {
+                let bytes = path_to_bytes(path)?;
+                if bytes.len() > UNIX_PATH_LEN || bytes.contains(&0) {
+                    return None;
+                }
+                field[..bytes.len()].copy_from_slice(&bytes);
+            },
+            UnixPath::Abstract(ref name) => {
+                if name.is_empty() || name.len() > UNIX_PATH_LEN - 1 || name.last() == Some(&0) {
+                    return None;
+                }
+                field[1..name.len() + 1].copy_from_slice(name);
+            },
+        }
+        Some(field)
+    }
+}
+
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    PathBuf::from(OsStr::from_bytes(bytes))
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+}
+
+#[cfg(unix)]
+fn path_to_bytes(path: &Path) -> Option<Vec<u8>> {
+    use std::os::unix::ffi::OsStrExt;
+    Some(path.as_os_str().as_bytes().to_vec())
+}
+
+#[cfg(not(unix))]
+fn path_to_bytes(path: &Path) -> Option<Vec<u8>> {
+    path.to_str().map(|path| path.as_bytes().to_vec())
+}
+
+
+/// The `PP2_TYPE_SSL` TLV of a version 2 header, which a TLS-terminating proxy sends to say how
+/// the client connected to it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SslInfo {
+    client: u8,
+    verify: u32,
+}
+
+
+const PP2_TYPE_SSL: u8 = 0x20;
+const PP2_CLIENT_SSL: u8 = 0x01;
+const PP2_CLIENT_CERT_CONN: u8 = 0x02;
+const PP2_CLIENT_CERT_SESS: u8 = 0x04;
+
+
+impl SslInfo {
+    /// Decode the value of a `PP2_TYPE_SSL` TLV, or `None` if it is too short to hold the
+    /// `client` and `verify` fields. Any sub-TLVs after them are ignored.
+    pub fn from_bytes(value: &[u8]) -> Option<Self> {
+        if value.len() < 5 {
+            return None;
+        }
+        Some(SslInfo {
+            client: value[0],
+            verify: NetworkEndian::read_u32(&value[1..5]),
+        })
+    }
+
+    /// Whether the client connected over SSL/TLS (`PP2_CLIENT_SSL`)
+    pub fn client_ssl(&self) -> bool {
+        self.client & PP2_CLIENT_SSL != 0
+    }
+
+    /// Whether the client presented a certificate over this connection (`PP2_CLIENT_CERT_CONN`)
+    pub fn client_cert_conn(&self) -> bool {
+        self.client & PP2_CLIENT_CERT_CONN != 0
+    }
+
+    /// Whether the client presented a certificate at least once over the TLS session this
+    /// connection belongs to (`PP2_CLIENT_CERT_SESS`)
+    pub fn client_cert_sess(&self) -> bool {
+        self.client & PP2_CLIENT_CERT_SESS != 0
+    }
+
+    /// The `verify` field: zero if the client presented a certificate and it was verified,
+    /// non-zero otherwise
+    pub fn verify(&self) -> u32 {
+        self.verify
+    }
+
+    /// Whether the client connected over SSL/TLS and presented a certificate which the proxy
+    /// verified
+    pub fn cert_verified(&self) -> bool {
+        self.client_ssl() && (self.client_cert_conn() || self.client_cert_sess()) && self.verify == 0
+    }
+}
+
+
+/// A TLV (type-length-value) field from the end of a version 2 header's address block, as
+/// listed by `ProxyProtocolHeader::tlvs`. Proxies use them to pass on what they know about the
+/// connection beyond its addresses: the TLS details in `PP2_TYPE_SSL` (`0x20`, decoded as
+/// `SslInfo`), the SNI host name in `PP2_TYPE_AUTHORITY` (`0x02`), a connection ID in
+/// `PP2_TYPE_UNIQUE_ID` (`0x05`), and types of their own from `0xe0` on.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Tlv {
+    /// The type byte
+    pub type_: u8,
+    /// The value, which may be empty
+    pub value: Vec<u8>,
+}
+
+
+/// The `SslInfo` of the first `PP2_TYPE_SSL` TLV among `tlvs`
+fn find_ssl(tlvs: &[Tlv]) -> Option<SslInfo> {
+    tlvs.iter().find(|tlv| tlv.type_ == PP2_TYPE_SSL).and_then(|tlv| SslInfo::from_bytes(&tlv.value))
+}
+
+/// Split the TLVs at the end of an address block, which start at byte `at` of the header, in
+/// the order they were sent. Each has to fit in the block, which was read in full.
+fn parse_tlvs(tlvs: &[u8], at: usize) -> Result<Vec<Tlv>> {
+    let mut parsed = Vec::new();
+    let mut fields = FieldReader(tlvs);
+    while !fields.0.is_empty() {
+        let offset = at + tlvs.len() - fields.0.len();
+        let tlv = fields.u8().and_then(|type_| {
+            let len = fields.u16()? as usize;
+            fields.take(len).map(|value| Tlv { type_, value: value.to_vec() })
+        });
+        parsed.push(tlv.ok_or(ProxyReadError::MalformedTlv(offset))?);
+    }
+    Ok(parsed)
+}
+
+
+/// A PROXY header, as read off of a connection. For one which was accepted, see
+/// `ProxyStream::proxy_header` (or `ProxyInfo::header`).
+///
+/// ```
+/// # extern crate hyper;
+/// # extern crate hyper_networklistener_proxy;
+/// use std::io::Write;
+/// use std::net::TcpStream;
+/// use hyper::net::HttpListener;
+/// use hyper_networklistener_proxy::{Command, Proto, ProxyListener, ProxyProtocolVersion};
+///
+/// # fn main() {
+/// let mut listener = ProxyListener::new(HttpListener::new("127.0.0.1:0").unwrap(), ProxyProtocolVersion::V1);
+/// let mut client = TcpStream::connect(listener.bound_addr().unwrap()).unwrap();
+/// client.write_all(b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n").unwrap();
+///
+/// let stream = listener.accept_detailed().unwrap();
+/// let header = stream.proxy_header().unwrap();
+/// assert_eq!((header.version(), header.proto(), header.command()), (1, Proto::Tcp4, Command::Proxy));
+/// assert_eq!(header.dest_addr(), Some("198.51.100.7:443".parse().unwrap()));
+/// # }
+/// ```
+///
+/// Headers can be used as `HashMap` and `BTreeMap` keys (e.g., to count connections per pair
+/// of addresses). They are ordered by version, then `Proto`, then source and destination
+/// address (with no address first, then IPv4 before IPv6), then socket paths, then `Command`,
+/// and last by their TLVs; the enums are ordered as their variants are listed. This order
+/// will not change within a major version.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ProxyProtocolHeader {
     version: u8,
     proto: Proto,
     command: Command,
     source_addr: Option<SocketAddr>,
     dest_addr: Option<SocketAddr>,
+    source_path: Option<UnixPath>,
+    dest_path: Option<UnixPath>,
+    // bytes of the version 2 address block past the addresses (i.e., TLVs), of which only
+    // PP2_TYPE_SSL is looked at
+    tlv_len: usize,
+    ssl: Option<SslInfo>,
+    tlvs: Vec<Tlv>,
 }
 
 
 impl ProxyProtocolHeader {
     fn new(version: u8, proto: Proto, source_addr: SocketAddr, dest_addr: SocketAddr) -> Self {
         ProxyProtocolHeader {
-            version: version,
-            proto: proto,
+            version,
+            proto,
             source_addr: Some(source_addr),
             dest_addr: Some(dest_addr),
-            command: Command::Proxy
+            source_path: None,
+            dest_path: None,
+            command: Command::Proxy,
+            tlv_len: 0,
+            ssl: None,
+            tlvs: Vec::new(),
         }
     }
 
     fn new_with_command(version: u8, proto: Proto, command: Command, source_addr: SocketAddr, dest_addr: SocketAddr) -> Self {
         ProxyProtocolHeader {
-            version: version,
-            proto: proto,
+            version,
+            proto,
             source_addr: Some(source_addr),
             dest_addr: Some(dest_addr),
-            command: command
+            source_path: None,
+            dest_path: None,
+            command,
+            tlv_len: 0,
+            ssl: None,
+            tlvs: Vec::new(),
+        }
+    }
+
+    fn new_unix(version: u8, command: Command, source_path: UnixPath, dest_path: UnixPath) -> Self {
+        ProxyProtocolHeader {
+            version,
+            proto: Proto::Unix,
+            source_addr: None,
+            dest_addr: None,
+            source_path: Some(source_path),
+            dest_path: Some(dest_path),
+            command,
+            tlv_len: 0,
+            ssl: None,
+            tlvs: Vec::new(),
         }
     }
 
     fn new_unknown(version: u8) -> Self {
         ProxyProtocolHeader {
-            version: version,
+            version,
             proto: Proto::Unknown,
             source_addr: None,
             dest_addr: None,
+            source_path: None,
+            dest_path: None,
             command: Command::Unspec,
+            tlv_len: 0,
+            ssl: None,
+            tlvs: Vec::new(),
         }
     }
+
+    /// The same header, less its addresses, as a `LOCAL` one is reported
+    fn without_addresses(mut self) -> Self {
+        self.source_addr = None;
+        self.dest_addr = None;
+        self.source_path = None;
+        self.dest_path = None;
+        self
+    }
+
+    /// This header as if it had been `PROXY UNKNOWN`, keeping its TLVs
+    pub(crate) fn downgraded(mut self) -> Self {
+        self.proto = Proto::Unknown;
+        self.without_addresses()
+    }
+
+    /// Rewrite IPv4-mapped addresses as the IPv4 addresses they map
+    fn with_mapped_v4_normalized(mut self) -> Self {
+        let unmap = |addr: SocketAddr| match addr.ip() {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(addr, |v4| SocketAddr::new(IpAddr::V4(v4), addr.port())),
+            IpAddr::V4(_) => addr,
+        };
+        self.source_addr = self.source_addr.map(unmap);
+        self.dest_addr = self.dest_addr.map(unmap);
+        self
+    }
+
+    /// The same header, with the TLVs `tlvs`, which start at byte `at` of the header
+    fn with_tlvs(mut self, tlvs: &[u8], at: usize) -> Result<Self> {
+        self.tlvs = parse_tlvs(tlvs, at)?;
+        self.tlv_len = tlvs.len();
+        self.ssl = find_ssl(&self.tlvs);
+        Ok(self)
+    }
 }
 
 
 impl ProxyProtocolHeader {
-    pub(crate) fn source_addr(&self) -> Option<SocketAddr> {
+    /// The source (client) address, or `None` if the header did not carry one (e.g.,
+    /// `PROXY UNKNOWN`, or an `AF_UNIX` header)
+    pub fn source_addr(&self) -> Option<SocketAddr> {
         self.source_addr
     }
-}
 
+    /// The destination address the client connected to, or `None` if the header did not carry
+    /// one
+    pub fn dest_addr(&self) -> Option<SocketAddr> {
+        self.dest_addr
+    }
 
-/// Read from a Reader into the given buffer.
-fn read_to_crlf<R: Read>(r: &mut R, buf: &mut [u8]) -> Result<usize> {
-    let mut found_crlf_at = None;
-    // read until we either exceed the buf or find a CRLF. SO INEFFICIENT
-    for i in 0..107 {
-        r.read_exact(&mut buf[i..i+1])?;
-        if i > 1  && buf[i-1] == 13u8 && buf[i] == 10u8 {
-            found_crlf_at = Some(i-1);
-            break;
-        }
+    /// The source and destination addresses, borrowed, for log fields which need to borrow
+    /// their values
+    #[cfg(feature = "log-kv")]
+    pub(crate) fn addr_refs(&self) -> (Option<&SocketAddr>, Option<&SocketAddr>) {
+        (self.source_addr.as_ref(), self.dest_addr.as_ref())
     }
-    if let Some(end_idx) = found_crlf_at {
-        Ok(end_idx)
-    } else {
-        Err(ProxyReadError::MissingCrlf)
+
+    /// The source path of an `AF_UNIX` header
+    pub fn source_path(&self) -> Option<&UnixPath> {
+        self.source_path.as_ref()
     }
-}
 
-fn parse_proxy_protocol_v1_after_first_byte(buf: &[u8]) -> Result<ProxyProtocolHeader> {
-    let mut fields = buf.split(|&f| f == 32u8).map(|f| ::std::str::from_utf8(f));
-    if fields.next() != Some(Ok("ROXY")) {
-        return Err(ProxyReadError::MissingLiteral);
+    /// The source and destination paths of an `AF_UNIX` header between sockets in the
+    /// filesystem (an unnamed socket having an empty path), or `None` for any other header,
+    /// including those with an abstract socket on either end; see `source_path` and
+    /// `dest_path` for those
+    pub fn unix_addrs(&self) -> Option<(PathBuf, PathBuf)> {
+        match (&self.source_path, &self.dest_path) {
+            (&Some(UnixPath::Pathname(ref source)), &Some(UnixPath::Pathname(ref dest))) => Some((source.clone(), dest.clone())),
+            _ => None,
+        }
     }
-    let proto = match fields.next().ok_or(ProxyReadError::MissingField)?? {
-        "TCP4" => Proto::Tcp4,
-        "TCP6" => Proto::Tcp6,
-        "UNKNOWN" => Proto::Unknown,
-        _ => return Err(ProxyReadError::MissingLiteral),
-    };
-    if proto == Proto::Unknown {
-        return Ok(ProxyProtocolHeader::new_unknown(1));
+
+    /// The destination path of an `AF_UNIX` header
+    pub fn dest_path(&self) -> Option<&UnixPath> {
+        self.dest_path.as_ref()
     }
-    let source_address: IpAddr = fields.next().ok_or(ProxyReadError::MissingField)??.parse().map_err(ProxyReadError::BadSourceAddress)?;
-    let dest_address: IpAddr = fields.next().ok_or(ProxyReadError::MissingField)??.parse().map_err(ProxyReadError::BadDestAddress)?;
-    let source_port: u16 = fields.next().ok_or(ProxyReadError::MissingField)??.parse().map_err(ProxyReadError::BadSourcePort)?;
-    let dest_port: u16 = fields.next().ok_or(ProxyReadError::MissingField)??.parse().map_err(ProxyReadError::BadDestPort)?;
-    Ok(ProxyProtocolHeader::new(1, proto, SocketAddr::new(source_address, source_port), SocketAddr::new(dest_address, dest_port)))
-}
 
-pub(crate) fn read_proxy_protocol_v1<R: Read>(r: &mut R) -> Result<ProxyProtocolHeader> {
-    // this is the longest that the PROXY header can be
-    let mut buf = [0u8; 107];
-    let buf_len = read_to_crlf(r, &mut buf)?;
-    if buf[0] != 0x50 { // P as in P-ROXY
-        return Err(ProxyReadError::MissingLiteral);
+    /// Which version of the protocol the header was sent in: 1 or 2
+    pub fn version(&self) -> u8 {
+        self.version
     }
-    parse_proxy_protocol_v1_after_first_byte(&buf[1..buf_len])
-}
 
+    /// The address family and transport the header declared
+    pub fn proto(&self) -> Proto {
+        self.proto
+    }
 
-#[derive(Debug,PartialEq,Eq)]
-pub(crate) enum Command {
-    Local,
-    Proxy,
-    Unspec,
-}
+    /// The transport the header declared, as also given by `proto`; `None` for headers
+    /// without addresses, and for `AF_UNIX` ones, whose transport is not kept
+    pub fn transport(&self) -> Option<Transport> {
+        match self.proto {
+            Proto::Tcp4 | Proto::Tcp6 => Some(Transport::Stream),
+            Proto::Udp4 | Proto::Udp6 => Some(Transport::Dgram),
+            Proto::Unix | Proto::Unknown => None,
+        }
+    }
 
-#[derive(Debug,PartialEq,Eq)]
-enum AddressFamily {
-    Unspec,
-    Inet,
-    Inet6,
-    Unix
-}
+    /// Whether the connection was relayed for a client, or made by the proxy itself
+    pub fn command(&self) -> Command {
+        self.command
+    }
 
+    /// How many bytes past the addresses a version 2 header carried, the TLVs listed by `tlvs`
+    pub fn tlv_len(&self) -> usize {
+        self.tlv_len
+    }
 
-#[derive(Debug,PartialEq,Eq)]
-enum TransportFamily {
-    Unspec,
-    Stream,
-    Dgram
-}
+    /// The TLVs a version 2 header carried after its addresses, in the order they were sent,
+    /// whatever their types. Empty for version 1 headers. Of an `UNSPEC` address block too
+    /// long to buffer, only those which fit in `ParseConfig::max_v2_block_len` are kept, so
+    /// they may then account for less than `tlv_len`.
+    pub fn tlvs(&self) -> &[Tlv] {
+        &self.tlvs
+    }
 
+    /// The `PP2_TYPE_SSL` TLV of a version 2 header, if it sent one
+    pub fn ssl_info(&self) -> Option<&SslInfo> {
+        self.ssl.as_ref()
+    }
 
-fn slice_to_ipv6addr(slice: &[u8]) -> Ipv6Addr {
-    let o1 = NetworkEndian::read_u16(&slice[0..2]);
-    let o2 = NetworkEndian::read_u16(&slice[2..4]);
-    let o3 = NetworkEndian::read_u16(&slice[4..6]);
-    let o4 = NetworkEndian::read_u16(&slice[6..8]);
-    let o5 = NetworkEndian::read_u16(&slice[8..12]);
-    let o6 = NetworkEndian::read_u16(&slice[8..12]);
-    let o7 = NetworkEndian::read_u16(&slice[12..14]);
-    let o8 = NetworkEndian::read_u16(&slice[14..16]);
-    Ipv6Addr::new(o1, o2, o3, o4, o5, o6, o7, o8)
-}
+    /// A copy with both addresses cut down to their first `v4_prefix` bits (for IPv4
+    /// addresses, including IPv4-mapped IPv6 ones) or `v6_prefix` bits (for other IPv6
+    /// addresses), and the rest zeroed. Headers without addresses are copied unchanged.
+    pub(crate) fn anonymized(&self, v4_prefix: u8, v6_prefix: u8) -> Self {
+        let mask = |addr: Option<SocketAddr>| addr.map(|mut addr| {
+            addr.set_ip(mask_ip(addr.ip(), v4_prefix, v6_prefix));
+            addr
+        });
+        ProxyProtocolHeader {
+            source_addr: mask(self.source_addr),
+            dest_addr: mask(self.dest_addr),
+            ..self.clone()
+        }
+    }
 
+    /// A copy with the ports of both addresses set to 0
+    pub(crate) fn without_ports(&self) -> Self {
+        let zero = |addr: Option<SocketAddr>| addr.map(|mut addr| {
+            addr.set_port(0);
+            addr
+        });
+        ProxyProtocolHeader {
+            source_addr: zero(self.source_addr),
+            dest_addr: zero(self.dest_addr),
+            ..self.clone()
+        }
+    }
 
-fn read_proxy_protocol_v2_after_first_byte<R: Read>(r: &mut R, header_buf_already_read: &[u8]) -> Result<ProxyProtocolHeader> {
-    let mut header_buf = [0u8;16];
-    let bytes_read = header_buf_already_read.len();
-    if bytes_read < 16 {
-        r.read_exact(&mut header_buf[bytes_read..])?;
+    /// A version 2 `PROXY` header for a connection from `source` to `dest`. If only one of
+    /// them is IPv4, it is converted to an IPv4-mapped IPv6 address, so that both addresses
+    /// are of the family the header declares.
+    pub(crate) fn for_addrs(source: SocketAddr, dest: SocketAddr) -> Self {
+        match (source, dest) {
+            (SocketAddr::V4(_), SocketAddr::V4(_)) => ProxyProtocolHeader::new(2, Proto::Tcp4, source, dest),
+            _ => {
+                let v6 = |addr: SocketAddr| SocketAddr::new(IpAddr::V6(to_ipv6(addr.ip())), addr.port());
+                ProxyProtocolHeader::new(2, Proto::Tcp6, v6(source), v6(dest))
+            },
+        }
     }
-    header_buf[0..bytes_read].copy_from_slice(header_buf_already_read);
-    if &header_buf[0..12] != b"\x0D\x0A\x0D\x0A\x00\x0D\x0A\x51\x55\x49\x54\x0A" {
-        return Err(ProxyReadError::MissingLiteral);
+
+    /// A copy without any TLVs, which `to_bytes` would not encode anyway
+    pub(crate) fn without_tlvs(&self) -> Self {
+        ProxyProtocolHeader {
+            tlv_len: 0,
+            ssl: None,
+            tlvs: Vec::new(),
+            ..self.clone()
+        }
     }
-    let protocol_version = (header_buf[12] & 0xf0) >> 4;
-    if protocol_version != 2 {
-        return Err(ProxyReadError::BadVersion);
+
+    /// Encode the header as `version`, or, for `Any`, as the version it was read as. Version
+    /// 1 can only carry TCP addresses, so anything else is encoded as `PROXY UNKNOWN`. TLVs
+    /// are never encoded.
+    pub(crate) fn to_bytes(&self, version: ProxyProtocolVersion) -> Vec<u8> {
+        match (version, self.version) {
+            (ProxyProtocolVersion::V1, _) | (ProxyProtocolVersion::Any, 1) => self.to_v1_bytes(),
+            _ => self.to_v2_bytes(),
+        }
     }
-    let command = match header_buf[12] & 0x0f {
-        0x00 => Command::Local,
-        0x01 => Command::Proxy,
-        _ => return Err(ProxyReadError::InvalidProtocol),
-    };
-    let af = match (header_buf[13] & 0xf0) >> 4 {
-        0x00 => AddressFamily::Unspec,
-        0x01 => AddressFamily::Inet,
-        0x02 => AddressFamily::Inet6,
-        0x03 => AddressFamily::Unix,
-        _ => return Err(ProxyReadError::InvalidProtocol),
-    };
-    let transport = match header_buf[13] & 0x0f {
-        0x00 => TransportFamily::Unspec,
-        0x01 => TransportFamily::Stream,
-        0x02 => TransportFamily::Dgram,
-        _ => return Err(ProxyReadError::InvalidProtocol),
-    };
-    let addrlen = NetworkEndian::read_u16(&header_buf[14..16]) as usize;
-    let mut addr_buf = [0u8; 216];
-    if addrlen > 216 {
-        return Err(ProxyReadError::InvalidProtocol);
+
+    fn to_v1_bytes(&self) -> Vec<u8> {
+        format!("{}\r\n", self).into_bytes()
     }
-    r.read_exact(&mut addr_buf[0..addrlen])?;
-    let addr_buf = &addr_buf[0..addrlen];
-    let (source, dest) = match af {
-        AddressFamily::Inet => {
-            let source_addr = IpAddr::from(Ipv4Addr::from(NetworkEndian::read_u32(&addr_buf[0..4])));
-            let dest_addr = IpAddr::from(Ipv4Addr::from(NetworkEndian::read_u32(&addr_buf[4..8])));
-            let source_port = NetworkEndian::read_u16(&addr_buf[8..10]);
-            let dest_port = NetworkEndian::read_u16(&addr_buf[10..12]);
-            (SocketAddr::new(source_addr, source_port), SocketAddr::new(dest_addr, dest_port))
-        },
-        AddressFamily::Inet6 => {
-            let source_addr = IpAddr::from(slice_to_ipv6addr(&addr_buf[0..16]));
-            let dest_addr = IpAddr::from(slice_to_ipv6addr(&addr_buf[16..32]));
-            let source_port = NetworkEndian::read_u16(&addr_buf[32..34]);
-            let dest_port = NetworkEndian::read_u16(&addr_buf[34..36]);
-            (SocketAddr::new(source_addr, source_port), SocketAddr::new(dest_addr, dest_port))
-        },
-        AddressFamily::Unix | AddressFamily::Unspec => {
-            return Ok(ProxyProtocolHeader::new_unknown(protocol_version))
-        }
-    };
-    if transport != TransportFamily::Stream {
-        return Err(ProxyReadError::InvalidProtocol);
-    }
-    Ok(ProxyProtocolHeader::new_with_command(
-        protocol_version,
-        match af {
-            AddressFamily::Inet => Proto::Tcp4,
-            AddressFamily::Inet6 => Proto::Tcp6,
-            AddressFamily::Unix => Proto::Unix,
-            AddressFamily::Unspec => unreachable!()
-        },
-        command,
-        source,
-        dest
-    ))
-}
 
-pub(crate) fn read_proxy_protocol_v2<R: Read>(r: &mut R) -> Result<ProxyProtocolHeader> {
-    let mut header_buf = [0u8; 16];
-    r.read_exact(&mut header_buf)?;
-    if header_buf[0] != 0x0d {
-        return Err(ProxyReadError::MissingLiteral);
+    fn to_v2_bytes(&self) -> Vec<u8> {
+        let mut block = Vec::new();
+        let family = match (self.proto, self.source_addr, self.dest_addr, &self.source_path, &self.dest_path) {
+            (Proto::Unix, _, _, Some(source), Some(dest)) => match (source.to_bytes(), dest.to_bytes()) {
+                (Some(source), Some(dest)) => {
+                    block.extend_from_slice(&source);
+                    block.extend_from_slice(&dest);
+                    0x31
+                },
+                // paths too long for the header are sent as no address at all
+                _ => 0x00,
+            },
+            (Proto::Unknown, _, _, _, _) | (Proto::Unix, _, _, _, _) => 0x00,
+            (proto, Some(source), Some(dest), _, _) => {
+                let transport = if proto == Proto::Udp4 || proto == Proto::Udp6 { 0x02 } else { 0x01 };
+                let family = match (source.ip(), dest.ip()) {
+                    (IpAddr::V4(s), IpAddr::V4(d)) => {
+                        block.extend_from_slice(&s.octets());
+                        block.extend_from_slice(&d.octets());
+                        0x10
+                    },
+                    (s, d) => {
+                        block.extend_from_slice(&to_ipv6(s).octets());
+                        block.extend_from_slice(&to_ipv6(d).octets());
+                        0x20
+                    },
+                };
+                block.extend_from_slice(&source.port().to_be_bytes());
+                block.extend_from_slice(&dest.port().to_be_bytes());
+                family | transport
+            },
+            _ => 0x00,
+        };
+        let mut out = Vec::with_capacity(16 + block.len());
+        out.extend_from_slice(V2_SIGNATURE);
+        out.push(if self.command == Command::Local { 0x20 } else { 0x21 });
+        out.push(family);
+        out.extend_from_slice(&(block.len() as u16).to_be_bytes());
+        out.extend_from_slice(&block);
+        out
     }
-    read_proxy_protocol_v2_after_first_byte(r, &header_buf)
 }
 
 
-pub(crate) fn read_proxy_protocol_any<R: Read>(r: &mut R) -> Result<ProxyProtocolHeader> {
-    let mut first_byte = [0u8; 1];
-    r.read_exact(&mut first_byte)?;
-    if first_byte[0] == 0x0d {
-        read_proxy_protocol_v2_after_first_byte(r, &first_byte)
-    } else if first_byte[0] == 0x50 {
-        let mut buf = [0u8; 107];
-        let buf_len = read_to_crlf(r, &mut buf)?;
-        parse_proxy_protocol_v1_after_first_byte(&buf[..buf_len])
-    } else {
-        Err(ProxyReadError::MissingFirstByte)
+fn to_ipv6(addr: IpAddr) -> Ipv6Addr {
+    match addr {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+        IpAddr::V6(v6) => v6,
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::read_proxy_protocol_v1;
-    use super::read_proxy_protocol_v2;
-    use super::read_proxy_protocol_any;
-    use super::Proto;
-    use super::ProxyProtocolHeader;
 
-    #[test]
-    fn test_proxy_protocol_v1_spec_vectors() { 
-        let vectors = vec![
-            (b"PROXY TCP4 255.255.255.255 255.255.255.255 65535 65535\r\n".to_vec(), ProxyProtocolHeader::new(1, Proto::Tcp4, "255.255.255.255:65535".parse().unwrap(), "255.255.255.255:65535".parse().unwrap())),
-            (b"PROXY TCP6 ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff 65535 65535\r\n".to_vec(), ProxyProtocolHeader::new(1, Proto::Tcp6, "[ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff]:65535".parse().unwrap(), "[ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff]:65535".parse().unwrap())),
-            (b"PROXY UNKNOWN\r\n".to_vec(), ProxyProtocolHeader::new_unknown(1)),
-            (b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\n".to_vec(), ProxyProtocolHeader::new(1, Proto::Tcp4, "192.168.0.1:56324".parse().unwrap(), "192.168.0.11:443".parse().unwrap())),
+/// Encoding of headers for `ProxyStream::into_raw_parts`, which, unlike `to_bytes`, keeps
+/// everything that was learned from the header (including its TLVs and `SslInfo`). The
+/// format is only meant to be read back by the same version of this crate.
+impl ProxyProtocolHeader {
+    pub(crate) fn encode(&self, out: &mut Vec<u8>) {
+        out.push(self.version);
+        out.push(match self.command {
+            Command::Local => 0,
+            Command::Proxy => 1,
+            Command::Unspec => 2,
+        });
+        out.push(match self.proto {
+            Proto::Tcp4 => 0,
+            Proto::Tcp6 => 1,
+            Proto::Udp4 => 2,
+            Proto::Udp6 => 3,
+            Proto::Unix => 4,
+            Proto::Unknown => 5,
+        });
+        encode_addr(out, self.source_addr);
+        encode_addr(out, self.dest_addr);
+        for path in &[&self.source_path, &self.dest_path] {
+            match path.as_ref().and_then(|path| path.to_bytes()) {
+                Some(field) => {
+                    out.push(1);
+                    out.extend_from_slice(&field);
+                },
+                None => out.push(0),
+            }
+        }
+        out.extend_from_slice(&(self.tlv_len as u32).to_be_bytes());
+        match self.ssl {
+            Some(ssl) => {
+                out.push(1);
+                out.push(ssl.client);
+                out.extend_from_slice(&ssl.verify.to_be_bytes());
+            },
+            None => out.push(0),
+        }
+        out.extend_from_slice(&(self.tlvs.len() as u32).to_be_bytes());
+        for tlv in &self.tlvs {
+            out.push(tlv.type_);
+            out.extend_from_slice(&(tlv.value.len() as u16).to_be_bytes());
+            out.extend_from_slice(&tlv.value);
+        }
+    }
+
+    /// Read back a header written by `encode`, or `None` if `fields` does not start with one
+    pub(crate) fn decode(fields: &mut FieldReader) -> Option<Self> {
+        let version = fields.u8()?;
+        let command = match fields.u8()? {
+            0 => Command::Local,
+            1 => Command::Proxy,
+            2 => Command::Unspec,
+            _ => return None,
+        };
+        let proto = match fields.u8()? {
+            0 => Proto::Tcp4,
+            1 => Proto::Tcp6,
+            2 => Proto::Udp4,
+            3 => Proto::Udp6,
+            4 => Proto::Unix,
+            5 => Proto::Unknown,
+            _ => return None,
+        };
+        let source_addr = fields.addr()?;
+        let dest_addr = fields.addr()?;
+        let mut path = || match fields.u8()? {
+            0 => Some(None),
+            1 => fields.take(UNIX_PATH_LEN).map(|field| Some(UnixPath::from_bytes(field))),
+            _ => None,
+        };
+        let (source_path, dest_path) = (path()?, path()?);
+        let tlv_len = fields.u32()? as usize;
+        let ssl = match fields.u8()? {
+            0 => None,
+            1 => Some(SslInfo { client: fields.u8()?, verify: fields.u32()? }),
+            _ => return None,
+        };
+        let mut tlvs = Vec::new();
+        for _ in 0..fields.u32()? {
+            let type_ = fields.u8()?;
+            let len = fields.u16()? as usize;
+            tlvs.push(Tlv { type_, value: fields.take(len)?.to_vec() });
+        }
+        Some(ProxyProtocolHeader { version, proto, command, source_addr, dest_addr, source_path, dest_path, tlv_len, ssl, tlvs })
+    }
+}
+
+
+pub(crate) fn encode_addr(out: &mut Vec<u8>, addr: Option<SocketAddr>) {
+    match addr.map(|addr| (addr.ip(), addr.port())) {
+        Some((IpAddr::V4(ip), port)) => {
+            out.push(4);
+            out.extend_from_slice(&ip.octets());
+            out.extend_from_slice(&port.to_be_bytes());
+        },
+        Some((IpAddr::V6(ip), port)) => {
+            out.push(6);
+            out.extend_from_slice(&ip.octets());
+            out.extend_from_slice(&port.to_be_bytes());
+        },
+        None => out.push(0),
+    }
+}
+
+
+/// Reads the fields written by `ProxyProtocolHeader::encode` and `encode_addr` back out of a
+/// buffer; every method returns `None` once it runs out
+pub(crate) struct FieldReader<'a>(pub(crate) &'a [u8]);
+
+impl<'a> FieldReader<'a> {
+    pub(crate) fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (field, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(field)
+    }
+
+    pub(crate) fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|field| field[0])
+    }
+
+    pub(crate) fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(NetworkEndian::read_u16)
+    }
+
+    pub(crate) fn u32(&mut self) -> Option<u32> {
+        self.take(4).map(NetworkEndian::read_u32)
+    }
+
+    pub(crate) fn u64(&mut self) -> Option<u64> {
+        self.take(8).map(NetworkEndian::read_u64)
+    }
+
+    pub(crate) fn u128(&mut self) -> Option<u128> {
+        self.take(16).map(NetworkEndian::read_u128)
+    }
+
+    /// An address written by `encode_addr`: `Some(None)` if it was written as absent
+    pub(crate) fn addr(&mut self) -> Option<Option<SocketAddr>> {
+        let ip = match self.u8()? {
+            0 => return Some(None),
+            4 => IpAddr::V4(Ipv4Addr::from(NetworkEndian::read_u32(self.take(4)?))),
+            6 => IpAddr::V6(Ipv6Addr::from(self.u128()?)),
+            _ => return None,
+        };
+        let port = self.u16()?;
+        Some(Some(SocketAddr::new(ip, port)))
+    }
+}
+
+
+pub(crate) fn mask_ip(addr: IpAddr, v4_prefix: u8, v6_prefix: u8) -> IpAddr {
+    let mask_v4 = |addr: Ipv4Addr| {
+        let keep = u32::MAX.checked_shl(32 - u32::from(v4_prefix.min(32))).unwrap_or(0);
+        Ipv4Addr::from(u32::from(addr) & keep)
+    };
+    match addr {
+        IpAddr::V4(v4) => IpAddr::V4(mask_v4(v4)),
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V6(mask_v4(v4).to_ipv6_mapped()),
+            None => {
+                let keep = u128::MAX.checked_shl(128 - u32::from(v6_prefix.min(128))).unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from(u128::from(v6) & keep))
+            },
+        },
+    }
+}
+
+
+/// Fill `buf` from `r`, where `already` bytes of the header came before it. Running out of
+/// bytes is an `UnexpectedEof`, counted from the start of the header.
+fn read_header_bytes<R: Read>(r: &mut R, buf: &mut [u8], already: usize) -> Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match r.read(&mut buf[filled..]) {
+            Ok(0) => return Err(ProxyReadError::UnexpectedEof { needed: already + buf.len(), got: already + filled }),
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+
+/// Read what is left of `block` from `r`, a chunk at a time
+fn skip_header_bytes<R: Read>(r: &mut R, block: &mut SkippedBlock) -> Result<()> {
+    let mut chunk = [0u8; 512];
+    while block.left > 0 {
+        let n = block.left.min(chunk.len());
+        read_header_bytes(r, &mut chunk[..n], block.end - block.left).map_err(|e| match e {
+            ProxyReadError::UnexpectedEof { got, .. } => ProxyReadError::UnexpectedEof { needed: block.end, got },
+            e => e,
+        })?;
+        block.skip(&chunk[..n])?;
+    }
+    Ok(())
+}
+
+
+/// Read from a Reader into the given buffer, up to a CRLF (or, if `allow_lf`, a bare LF),
+/// where the first `filled` bytes of the buffer have been read into it already. Stops as
+/// soon as the line turns out to be an HTTP request line instead. Returns the length of the
+/// line and of its terminator.
+fn read_to_crlf<R: Read>(r: &mut R, buf: &mut Vec<u8>, limit: usize, allow_lf: bool) -> Result<(usize, usize)> {
+    let mut found_crlf_at = None;
+    // read until we either exceed the limit or find a CRLF. SO INEFFICIENT
+    for i in 0..limit {
+        if i >= buf.len() {
+            let mut byte = [0u8; 1];
+            read_header_bytes(r, &mut byte, i)?;
+            buf.push(byte[0]);
+        }
+        if buf[i] == b' ' {
+            if let Some(method) = http_method(&buf[..=i]) {
+                return Err(ProxyReadError::LooksLikeHttp(method));
+            }
+        }
+        if i > 1  && buf[i-1] == 13u8 && buf[i] == 10u8 {
+            found_crlf_at = Some((i-1, 2));
+            break;
+        }
+        if allow_lf && i > 1 && buf[i] == 10u8 {
+            found_crlf_at = Some((i, 1));
+            break;
+        }
+    }
+    if let Some(found) = found_crlf_at {
+        Ok(found)
+    } else {
+        Err(ProxyReadError::HeaderTooLong { limit, read: limit })
+    }
+}
+
+/// The check failed by lines with runs of spaces, unless `ParseConfig::allow_extra_spaces`
+const EXTRA_SPACES: &str = "fields separated by other than a single space";
+
+/// The longest a `TCP4` line can be, with the CRLF
+const V1_TCP4_MAX_LEN: usize = 56;
+/// The longest a `TCP6` line can be, with the CRLF
+const V1_TCP6_MAX_LEN: usize = 104;
+
+/// Parse a v1 `line`, without its terminator of `terminator_len` bytes, other than its first
+/// byte, which has been checked already
+fn parse_proxy_protocol_v1_after_first_byte(line: &[u8], terminator_len: usize, config: &ParseConfig) -> Result<ProxyProtocolHeader> {
+    let missing_literal = || ProxyReadError::MissingLiteral(line.to_vec());
+    let buf = line.get(1..).unwrap_or(&[]);
+    let line_len = line.len() + terminator_len;
+    let buf = if config.allow_extra_spaces {
+        let end = buf.iter().rposition(|&b| b != b' ' && b != b'\t').map(|i| i + 1).unwrap_or(0);
+        &buf[..end]
+    } else {
+        buf
+    };
+    // runs of spaces separate fields just as single ones do, unless they are rejected below
+    let mut fields = buf.split(|&f| f == 32u8).filter(|f| !f.is_empty()).map(|f| ::std::str::from_utf8(f));
+    let literal = fields.next().ok_or_else(missing_literal)??;
+    if literal != "ROXY" && !(config.allow_lowercase && literal.eq_ignore_ascii_case("ROXY")) {
+        return Err(missing_literal());
+    }
+    let proto = fields.next().ok_or(ProxyReadError::MissingField)??;
+    let proto = [("TCP4", Proto::Tcp4), ("TCP6", Proto::Tcp6), ("UNKNOWN", Proto::Unknown)].iter()
+        .find(|&&(name, _)| proto == name || (config.allow_lowercase && proto.eq_ignore_ascii_case(name)))
+        .map(|&(_, proto)| proto)
+        .ok_or_else(missing_literal)?;
+    if !config.allow_extra_spaces {
+        // after UNKNOWN, anything goes; otherwise every field must be followed by exactly one
+        // space, bar the last, which must be followed by the CRLF
+        let extra = match proto {
+            Proto::Unknown => buf.get(5) == Some(&b' '),
+            _ => buf.split(|&f| f == 32u8).any(|f| f.is_empty()),
+        };
+        if extra {
+            return Err(ProxyReadError::NotConformant(EXTRA_SPACES));
+        }
+    }
+    if config.reject_overlong_lines {
+        let (len, max) = (line_len, if proto == Proto::Tcp4 { V1_TCP4_MAX_LEN } else { V1_TCP6_MAX_LEN });
+        if proto != Proto::Unknown && len > max {
+            return Err(ProxyReadError::LineTooLong(proto, len, max));
+        }
+    }
+    // senders may follow UNKNOWN with anything (often the addresses they could not vouch
+    // for), which receivers must ignore, so the rest of the line is not even decoded
+    if proto == Proto::Unknown {
+        return Ok(ProxyProtocolHeader::new_unknown(1));
+    }
+    let source_address = parse_v1_address(fields.next().ok_or(ProxyReadError::MissingField)??, config).map_err(ProxyReadError::BadSourceAddress)?;
+    let dest_address = parse_v1_address(fields.next().ok_or(ProxyReadError::MissingField)??, config).map_err(ProxyReadError::BadDestAddress)?;
+    if config.reject_family_mismatch {
+        let expect_v4 = proto == Proto::Tcp4;
+        if source_address.is_ipv4() != expect_v4 {
+            return Err(ProxyReadError::AddressFamilyMismatch(proto, V1_FIELD_NAMES[2]));
+        }
+        if dest_address.is_ipv4() != expect_v4 {
+            return Err(ProxyReadError::AddressFamilyMismatch(proto, V1_FIELD_NAMES[3]));
+        }
+    }
+    let source_port = parse_v1_port(fields.next().ok_or(ProxyReadError::MissingField)??, config, ProxyReadError::BadSourcePort)?;
+    let dest_port = parse_v1_port(fields.next().ok_or(ProxyReadError::MissingField)??, config, ProxyReadError::BadDestPort)?;
+    if config.reject_trailing_fields && fields.next().is_some() {
+        return Err(ProxyReadError::NotConformant("fields after the destination port"));
+    }
+    let header = ProxyProtocolHeader::new(1, proto, SocketAddr::new(source_address, source_port), SocketAddr::new(dest_address, dest_port));
+    Ok(if config.normalize_mapped_v4 { header.with_mapped_v4_normalized() } else { header })
+}
+
+fn parse_v1_address(field: &str, config: &ParseConfig) -> ::std::result::Result<IpAddr, AddrParseError> {
+    if config.allow_bracketed_ipv6 && field.starts_with('[') && field.ends_with(']') && field.len() >= 2 {
+        return field[1..field.len() - 1].parse::<Ipv6Addr>().map(IpAddr::V6);
+    }
+    field.parse()
+}
+
+fn parse_v1_port<F: Fn(ParseIntError) -> ProxyReadError>(field: &str, config: &ParseConfig, err: F) -> Result<u16> {
+    let port = field.parse().map_err(err)?;
+    if config.reject_noncanonical_ports && (field.starts_with('+') || (field.starts_with('0') && field != "0")) {
+        return Err(ProxyReadError::NotConformant("a port with a sign or leading zeros"));
+    }
+    Ok(port)
+}
+
+pub(crate) fn read_proxy_protocol_v1<R: Read>(r: &mut R) -> Result<ProxyProtocolHeader> {
+    read_proxy_protocol_v1_with(r, &ParseConfig::default())
+}
+
+pub(crate) fn read_proxy_protocol_v1_with<R: Read>(r: &mut R, config: &ParseConfig) -> Result<ProxyProtocolHeader> {
+    let mut first_byte = [0u8; 1];
+    read_header_bytes(r, &mut first_byte, 0)?;
+    read_proxy_protocol_v1_after_first_byte(r, first_byte[0], config)
+}
+
+/// Read the rest of a v1 line whose first byte has already been read
+fn read_proxy_protocol_v1_after_first_byte<R: Read>(r: &mut R, first_byte: u8, config: &ParseConfig) -> Result<ProxyProtocolHeader> {
+    // this is the longest that the PROXY header can be, though never too short to tell TLS
+    let limit = config.v1_line_limit().max(3);
+    // the buffer grows past the longest line the spec allows only for lines which do
+    let mut buf = Vec::with_capacity(limit.min(DEFAULT_MAX_V1_LINE_LEN));
+    buf.push(first_byte);
+    // a TLS record header can be told apart after three bytes, so there is no need to read
+    // on for a CRLF which will never come
+    if first_byte == 0x16 {
+        buf.resize(3, 0);
+        read_header_bytes(r, &mut buf[1..3], 1)?;
+        if looks_like_tls(&buf) {
+            return Err(ProxyReadError::LooksLikeTls);
+        }
+    }
+    let (buf_len, terminator_len) = read_to_crlf(r, &mut buf, limit, config.allow_lf_terminator)?;
+    if !is_v1_first_byte(buf[0], config) {
+        return Err(ProxyReadError::MissingLiteral(buf[..buf_len].to_vec()));
+    }
+    parse_proxy_protocol_v1_after_first_byte(&buf[..buf_len], terminator_len, config)
+}
+
+/// Whether `byte` is the P in P-ROXY
+fn is_v1_first_byte(byte: u8, config: &ParseConfig) -> bool {
+    byte == 0x50 || (config.allow_lowercase && byte == b'p')
+}
+
+
+/// A guess at what sort of client sent some bytes which were not a PROXY header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ClientGuess {
+    Http,
+    Tls,
+    Unknown,
+}
+
+
+const HTTP_REQUEST_PREFIXES: &[&[u8]] = &[
+    b"GET ", b"HEAD ", b"POST ", b"PUT ", b"DELETE ", b"CONNECT ", b"OPTIONS ", b"TRACE ",
+    b"PATCH ", b"PRI * HTTP/2.0",
+];
+
+
+/// The HTTP/1.1 methods, each followed by the space which ends it in a request line
+const HTTP_METHODS: [&str; 9] = [
+    "GET ", "HEAD ", "POST ", "PUT ", "DELETE ", "CONNECT ", "OPTIONS ", "TRACE ", "PATCH ",
+];
+
+
+/// The method of the HTTP request line which `prefix` starts with, if it starts with one
+fn http_method(prefix: &[u8]) -> Option<&'static str> {
+    HTTP_METHODS.iter()
+        .find(|method| prefix.starts_with(method.as_bytes()))
+        .map(|method| method.trim_end())
+}
+
+
+/// Whether `prefix` starts with a TLS handshake record, as sent by a client starting with
+/// ClientHello
+fn looks_like_tls(prefix: &[u8]) -> bool {
+    prefix.len() >= 3 && prefix[0] == 0x16 && prefix[1] == 0x03 && prefix[2] <= 0x04
+}
+
+
+/// Guess what kind of client sent `prefix` (the bytes read before parsing failed)
+pub(crate) fn guess_client(prefix: &[u8]) -> ClientGuess {
+    if HTTP_REQUEST_PREFIXES.iter().any(|method| prefix.starts_with(method)) {
+        ClientGuess::Http
+    } else if looks_like_tls(prefix) {
+        ClientGuess::Tls
+    } else {
+        ClientGuess::Unknown
+    }
+}
+
+
+/// What a PROXY header says the connection is
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash,PartialOrd,Ord)]
+pub enum Command {
+    /// Made by the proxy itself (e.g., a health check) rather than relayed for a client; a
+    /// version 2 `LOCAL` header. Whatever addresses it carries are skipped, so the connection's
+    /// own endpoints are used, but its family and TLVs are still reported.
+    Local,
+    /// Relayed for the client whose addresses the header carries
+    Proxy,
+    /// Relayed for a client the proxy could not describe: a version 1 `PROXY UNKNOWN`. (A
+    /// version 2 header with an unspecified address family keeps the command it was sent
+    /// with, and says that it has no address with `Proto::Unknown`.)
+    Unspec,
+}
+
+/// Bytes shown as a hexdump when printed with `{:?}`: sixteen to a line, each line starting
+/// with its offset and ending with the printable ASCII characters among them. Handy for
+/// logging headers kept with `ProxyConfig::retain_raw_header`.
+///
+/// ```
+/// use hyper_networklistener_proxy::HexDump;
+///
+/// let dump = format!("{:?}", HexDump(b"PROXY UNKNOWN\r\n"));
+/// assert_eq!(dump, "00000000  50 52 4f 58 59 20 55 4e 4b 4e 4f 57 4e 0d 0a     |PROXY UNKNOWN..|");
+/// ```
+#[derive(Clone,Copy,PartialEq,Eq)]
+pub struct HexDump<'a>(pub &'a [u8]);
+
+impl<'a> Debug for HexDump<'a> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for (i, line) in self.0.chunks(16).enumerate() {
+            if i > 0 {
+                f.write_char('\n')?;
+            }
+            write!(f, "{:08x} ", i * 16)?;
+            for byte in line {
+                write!(f, " {:02x}", byte)?;
+            }
+            for _ in line.len()..16 {
+                f.write_str("   ")?;
+            }
+            f.write_str("  |")?;
+            for &byte in line {
+                f.write_char(if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })?;
+            }
+            f.write_char('|')?;
+        }
+        Ok(())
+    }
+}
+
+
+// by hand, so that the fields can stay in the order `Debug` has always printed them in
+impl PartialOrd for ProxyProtocolHeader {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ProxyProtocolHeader {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.version.cmp(&other.version)
+            .then_with(|| self.proto.cmp(&other.proto))
+            .then_with(|| self.source_addr.cmp(&other.source_addr))
+            .then_with(|| self.dest_addr.cmp(&other.dest_addr))
+            .then_with(|| self.source_path.cmp(&other.source_path))
+            .then_with(|| self.dest_path.cmp(&other.dest_path))
+            .then_with(|| self.command.cmp(&other.command))
+            .then_with(|| (self.tlv_len, self.ssl, &self.tlvs).cmp(&(other.tlv_len, other.ssl, &other.tlvs)))
+    }
+}
+
+
+/// The header as a version 1 line, without the CRLF: e.g., `PROXY TCP4 192.0.2.1 198.51.100.7
+/// 4124 443`. Whatever version 1 cannot carry (`LOCAL` headers, unix sockets, UDP, and
+/// unspecified addresses) is written as `PROXY UNKNOWN`, just as `to_bytes` would send it, so
+/// the line always parses back.
+impl Display for ProxyProtocolHeader {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match (&self.command, self.proto, self.source_addr, self.dest_addr) {
+            (Command::Proxy, Proto::Tcp4, Some(source), Some(dest)) | (Command::Proxy, Proto::Tcp6, Some(source), Some(dest)) => {
+                match (source.ip(), dest.ip()) {
+                    (IpAddr::V4(s), IpAddr::V4(d)) => write!(f, "PROXY TCP4 {} {} {} {}", s, d, source.port(), dest.port()),
+                    (s, d) => write!(f, "PROXY TCP6 {} {} {} {}", to_ipv6(s), to_ipv6(d), source.port(), dest.port()),
+                }
+            },
+            _ => f.write_str("PROXY UNKNOWN"),
+        }
+    }
+}
+
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum AddressFamily {
+    Unspec,
+    Inet,
+    Inet6,
+    Unix
+}
+
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum TransportFamily {
+    Unspec,
+    Stream,
+    Dgram
+}
+
+
+
+
+fn read_proxy_protocol_v2_after_first_byte<R: Read>(r: &mut R, header_buf_already_read: &[u8], config: &ParseConfig) -> Result<ProxyProtocolHeader> {
+    let header = read_proxy_protocol_v2_with_transports(r, header_buf_already_read, !config.reject_dgram, config.max_v2_block_len, config.max_header_len)?;
+    Ok(if config.normalize_mapped_v4 { header.with_mapped_v4_normalized() } else { header })
+}
+
+/// Read a v2 header, of which `header_buf_already_read` has already been read; `DGRAM`
+/// transports are only accepted if `allow_dgram` is set, address blocks only up to
+/// `max_block_len` bytes, and whole headers only up to `max_header_len`
+fn read_proxy_protocol_v2_with_transports<R: Read>(r: &mut R, header_buf_already_read: &[u8], allow_dgram: bool, max_block_len: usize, max_header_len: Option<usize>) -> Result<ProxyProtocolHeader> {
+    let transport_allowed = |transport: &TransportFamily| {
+        *transport == TransportFamily::Stream || (allow_dgram && *transport == TransportFamily::Dgram)
+    };
+    let mut header_buf = [0u8;16];
+    let bytes_read = header_buf_already_read.len().min(16);
+    if bytes_read < 16 {
+        read_header_bytes(r, &mut header_buf[bytes_read..], bytes_read)?;
+    }
+    header_buf[0..bytes_read].copy_from_slice(&header_buf_already_read[..bytes_read]);
+    if &header_buf[0..12] != V2_SIGNATURE {
+        return Err(bad_signature(&header_buf));
+    }
+    let protocol_version = (header_buf[12] & 0xf0) >> 4;
+    if protocol_version != 2 {
+        return Err(ProxyReadError::BadVersion(protocol_version));
+    }
+    let invalid = || ProxyReadError::InvalidProtocol {
+        command: header_buf[12] & 0x0f,
+        family: (header_buf[13] & 0xf0) >> 4,
+        transport: header_buf[13] & 0x0f,
+    };
+    let command = match header_buf[12] & 0x0f {
+        0x00 => Command::Local,
+        0x01 => Command::Proxy,
+        _ => return Err(invalid()),
+    };
+    let af = match (header_buf[13] & 0xf0) >> 4 {
+        0x00 => AddressFamily::Unspec,
+        0x01 => AddressFamily::Inet,
+        0x02 => AddressFamily::Inet6,
+        0x03 => AddressFamily::Unix,
+        _ => return Err(invalid()),
+    };
+    let transport = match header_buf[13] & 0x0f {
+        0x00 => TransportFamily::Unspec,
+        0x01 => TransportFamily::Stream,
+        0x02 => TransportFamily::Dgram,
+        _ => return Err(invalid()),
+    };
+    let addrlen = NetworkEndian::read_u16(&header_buf[14..16]) as usize;
+    match max_header_len {
+        Some(limit) if 16 + addrlen > limit => return Err(ProxyReadError::HeaderTooLong { limit, read: 16 }),
+        _ => {},
+    }
+    if addrlen > max_block_len && af == AddressFamily::Unspec {
+        // there are no addresses to read in the block, so rather than buffer it whole it is
+        // skipped, keeping what TLVs there is room for
+        let mut block = SkippedBlock::new(protocol_version, command, addrlen, max_block_len);
+        skip_header_bytes(r, &mut block)?;
+        return block.finish();
+    }
+    if addrlen > max_block_len {
+        return Err(invalid());
+    }
+    let mut addr_buf = vec![0u8; addrlen];
+    read_header_bytes(r, &mut addr_buf, 16)?;
+    let addr_buf = &addr_buf[..];
+    // the family's protocol, given the transport; Unix sockets are reported as Unix whatever
+    // it is, and the transport of the other families is checked once their addresses are read
+    let proto = match (af, transport) {
+        (AddressFamily::Inet, TransportFamily::Dgram) => Proto::Udp4,
+        (AddressFamily::Inet6, TransportFamily::Dgram) => Proto::Udp6,
+        (AddressFamily::Inet, _) => Proto::Tcp4,
+        (AddressFamily::Inet6, _) => Proto::Tcp6,
+        (AddressFamily::Unix, _) => Proto::Unix,
+        (AddressFamily::Unspec, _) => Proto::Unknown,
+    };
+    let needed = match af {
+        AddressFamily::Inet => 12,
+        AddressFamily::Inet6 => 36,
+        AddressFamily::Unix => 2 * UNIX_PATH_LEN,
+        AddressFamily::Unspec => 0,
+    };
+    if addrlen < needed {
+        // the addresses of a LOCAL header are ignored anyway, so it is only an error to be
+        // missing them from a PROXY one
+        if command == Command::Local {
+            let mut header = ProxyProtocolHeader::new_unknown(protocol_version);
+            header.command = Command::Local;
+            return Ok(header);
+        }
+        return Err(ProxyReadError::ShortAddressBlock(proto, addrlen));
+    }
+    // what is left of the block after `len` bytes, which is nothing if it is no longer
+    let after = |len: usize| addr_buf.get(len..).unwrap_or(&[]);
+    let (source, dest) = match af {
+        AddressFamily::Inet | AddressFamily::Inet6 => decode_v2_addrs(addr_buf, af == AddressFamily::Inet6)
+            .ok_or(ProxyReadError::ShortAddressBlock(proto, addrlen))?,
+        AddressFamily::Unix => {
+            if !transport_allowed(&transport) {
+                return Err(invalid());
+            }
+            // only the first 108 bytes of each are looked at
+            let source = UnixPath::from_bytes(addr_buf);
+            let dest = UnixPath::from_bytes(after(UNIX_PATH_LEN));
+            let header = ProxyProtocolHeader::new_unix(protocol_version, command, source, dest)
+                .with_tlvs(after(2 * UNIX_PATH_LEN), 16 + 2 * UNIX_PATH_LEN)?;
+            return Ok(if command == Command::Local { header.without_addresses() } else { header });
+        },
+        AddressFamily::Unspec => {
+            // the command is kept as sent (LOCAL is how proxies mark their own health
+            // checks), and the `Unknown` protocol says there is no address
+            let mut header = ProxyProtocolHeader::new_unknown(protocol_version);
+            header.command = command;
+            return header.with_tlvs(addr_buf, 16);
+        }
+    };
+    if !transport_allowed(&transport) {
+        return Err(invalid());
+    }
+    // the addresses of a LOCAL header are the proxy's own, so they are skipped (along with
+    // everything else in the block) and the connection's real endpoints are used instead
+    let header = ProxyProtocolHeader::new_with_command(protocol_version, proto, command, source, dest)
+        .with_tlvs(after(needed), 16 + needed)?;
+    Ok(if command == Command::Local { header.without_addresses() } else { header })
+}
+
+
+/// An `UNSPEC` address block too long to buffer (see `ParseConfig::max_v2_block_len`), which
+/// is split into TLVs as it goes by. As many of them are kept as fit in the limit; the rest
+/// are dropped, though still checked to fit in the block.
+#[derive(Debug, Clone)]
+struct SkippedBlock {
+    header: ProxyProtocolHeader,
+    // where the block ends, counting from the header's first byte, and how much of it is left
+    end: usize,
+    left: usize,
+    // the type and length of the TLV being read, as much of them as has arrived, and where
+    // its type byte was
+    head: Vec<u8>,
+    start: usize,
+    // how much of its value is still to come, and the value so far if it is being kept
+    value_left: usize,
+    value: Option<Vec<u8>>,
+    // how many more bytes of TLVs may be kept
+    room: usize,
+    malformed: bool,
+}
+
+impl SkippedBlock {
+    /// The block of `addrlen` bytes after a fixed header of `version` and `command`, keeping
+    /// up to `keep` bytes of its TLVs
+    fn new(version: u8, command: Command, addrlen: usize, keep: usize) -> Self {
+        let mut header = ProxyProtocolHeader::new_unknown(version);
+        header.command = command;
+        header.tlv_len = addrlen;
+        SkippedBlock {
+            header,
+            end: 16 + addrlen,
+            left: addrlen,
+            head: Vec::with_capacity(3),
+            start: 16,
+            value_left: 0,
+            value: None,
+            room: keep,
+            malformed: false,
+        }
+    }
+
+    /// Take the next `bytes` of the block, of which there are no more than are left. A TLV
+    /// which runs past the end of the block fails as soon as its length has arrived.
+    fn skip(&mut self, mut bytes: &[u8]) -> Result<()> {
+        if self.malformed {
+            return Err(ProxyReadError::MalformedTlv(self.start));
+        }
+        self.left -= bytes.len();
+        while !bytes.is_empty() {
+            if self.head.len() < 3 {
+                let n = (3 - self.head.len()).min(bytes.len());
+                self.head.extend_from_slice(&bytes[..n]);
+                bytes = &bytes[n..];
+                if self.head.len() < 3 {
+                    break;
+                }
+                let len = NetworkEndian::read_u16(&self.head[1..3]) as usize;
+                if self.start + 3 + len > self.end {
+                    self.malformed = true;
+                    return Err(ProxyReadError::MalformedTlv(self.start));
+                }
+                self.value_left = len;
+                self.value = if 3 + len <= self.room {
+                    self.room -= 3 + len;
+                    Some(Vec::with_capacity(len))
+                } else {
+                    None
+                };
+            } else {
+                let n = self.value_left.min(bytes.len());
+                if let Some(ref mut value) = self.value {
+                    value.extend_from_slice(&bytes[..n]);
+                }
+                self.value_left -= n;
+                bytes = &bytes[n..];
+            }
+            if self.value_left == 0 {
+                if let Some(value) = self.value.take() {
+                    self.header.tlvs.push(Tlv { type_: self.head[0], value });
+                }
+                self.head.clear();
+                self.start = self.end - self.left - bytes.len();
+            }
+        }
+        Ok(())
+    }
+
+    /// The header, once the whole block has been skipped
+    fn finish(&self) -> Result<ProxyProtocolHeader> {
+        if self.malformed || !self.head.is_empty() {
+            return Err(ProxyReadError::MalformedTlv(self.start));
+        }
+        let mut header = self.header.clone();
+        header.ssl = find_ssl(&header.tlvs);
+        Ok(header)
+    }
+}
+
+/// The length of the address block of the version 2 fixed header `fixed`, if it is one which
+/// `read_proxy_protocol_v2_with_transports` skips rather than buffers
+fn skipped_block_len(fixed: &[u8], config: &ParseConfig) -> Option<usize> {
+    if fixed.len() != 16 || &fixed[..12] != V2_SIGNATURE || fixed[13] >> 4 != 0 {
+        return None;
+    }
+    let addrlen = NetworkEndian::read_u16(&fixed[14..16]) as usize;
+    if addrlen > config.max_v2_block_len { Some(addrlen) } else { None }
+}
+
+/// Decode the source and destination addresses and ports at the start of an `AF_INET` (or,
+/// if `v6`, `AF_INET6`) address block, or `None` if it is too short to hold them
+fn decode_v2_addrs(block: &[u8], v6: bool) -> Option<(SocketAddr, SocketAddr)> {
+    let mut fields = FieldReader(block);
+    let (source, dest) = if v6 {
+        (IpAddr::from(Ipv6Addr::from(fields.u128()?)), IpAddr::from(Ipv6Addr::from(fields.u128()?)))
+    } else {
+        (IpAddr::from(Ipv4Addr::from(fields.u32()?)), IpAddr::from(Ipv4Addr::from(fields.u32()?)))
+    };
+    Some((SocketAddr::new(source, fields.u16()?), SocketAddr::new(dest, fields.u16()?)))
+}
+
+/// Parse a version 2 header off the front of a single datagram, as sent by load balancers
+/// which prepend one to the first datagram of each flow. Returns the parsed header (TLVs and
+/// all; wrap it with `ProxyInfo::from_header` for the summary a `ProxyStream` gives) and the
+/// rest of the datagram.
+///
+/// `DGRAM` transports are always accepted (and reported as `Proto::Udp4` or `Proto::Udp6`),
+/// whatever `ParseConfig::reject_dgram` says. The header must be entirely contained in `buf`; a truncated one is an
+/// `UnexpectedEof` error, since a datagram has no continuation to wait for.
+///
+/// ```
+/// use hyper_networklistener_proxy::proxy_protocol::parse_v2_datagram;
+///
+/// let datagram = b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x12\x00\x0c\
+///     \xc0\x00\x02\x01\xc6\x33\x64\x07\x10\x1c\x01\xbb\
+///     payload";
+/// let (header, payload) = parse_v2_datagram(datagram).unwrap();
+/// assert_eq!(header.source_addr(), Some("192.0.2.1:4124".parse().unwrap()));
+/// assert_eq!(payload, b"payload");
+/// ```
+pub fn parse_v2_datagram(buf: &[u8]) -> ::std::result::Result<(ProxyProtocolHeader, &[u8]), ProxyReadError> {
+    let mut rest = buf;
+    let header = read_proxy_protocol_v2_with_transports(&mut rest, &[], true, DEFAULT_MAX_V2_BLOCK_LEN, None)?;
+    Ok((header, rest))
+}
+
+pub(crate) fn read_proxy_protocol_v2<R: Read>(r: &mut R) -> Result<ProxyProtocolHeader> {
+    read_proxy_protocol_v2_with(r, &ParseConfig::default())
+}
+
+pub(crate) fn read_proxy_protocol_v2_with<R: Read>(r: &mut R, config: &ParseConfig) -> Result<ProxyProtocolHeader> {
+    // the signature is checked as it arrives, so that a peer sending anything else is turned
+    // away at the first byte which gives it away, rather than once it has sent 16 of them
+    let mut first_byte = [0u8; 1];
+    read_header_bytes(r, &mut first_byte, 0)?;
+    if first_byte[0] != 0x0d {
+        return Err(not_a_header(r, first_byte[0]));
+    }
+    let signature = read_v2_signature(r, first_byte[0])?;
+    read_proxy_protocol_v2_after_first_byte(r, &signature, config)
+}
+
+
+/// The literal which every header of the given version (`Any` meaning either version)
+/// starting with `first_byte` begins with, or `None` if no such header starts with `first_byte`
+pub(crate) fn header_literal(first_byte: u8, version: ProxyProtocolVersion, config: &ParseConfig) -> Option<&'static [u8]> {
+    let first_byte = if is_v1_first_byte(first_byte, config) { 0x50 } else { first_byte };
+    match (version, first_byte) {
+        (ProxyProtocolVersion::V1, 0x50) | (ProxyProtocolVersion::Any, 0x50) => Some(V1_LITERAL),
+        (ProxyProtocolVersion::V2, 0x0d) | (ProxyProtocolVersion::Any, 0x0d) => Some(V2_SIGNATURE),
+        _ => None,
+    }
+}
+
+
+/// Read the rest of a header of either version whose first byte has already been read
+fn read_proxy_protocol_after_first_byte<R: Read>(r: &mut R, first_byte: u8, config: &ParseConfig) -> Result<ProxyProtocolHeader> {
+    if first_byte == 0x0d {
+        let signature = read_v2_signature(r, first_byte)?;
+        read_proxy_protocol_v2_after_first_byte(r, &signature, config)
+    } else if is_v1_first_byte(first_byte, config) {
+        read_proxy_protocol_v1_after_first_byte(r, first_byte, config)
+    } else {
+        Err(not_a_header(r, first_byte))
+    }
+}
+
+
+/// The error for a connection whose `first_byte` cannot start a header of the version being
+/// read: a TLS record header is given away by its first byte, and an HTTP request line is
+/// read on for (see `sniff_http`)
+fn not_a_header<R: Read>(r: &mut R, first_byte: u8) -> ProxyReadError {
+    if first_byte == 0x16 {
+        ProxyReadError::LooksLikeTls
+    } else {
+        sniff_http(r, first_byte)
+    }
+}
+
+
+/// Read the rest of the version 2 signature whose first byte has already been read, a byte at
+/// a time, so that a peer which sent something else (a lone CR, say) is turned away as soon
+/// as it diverges, instead of once all 16 bytes of a fixed header have arrived, which they
+/// may never do
+fn read_v2_signature<R: Read>(r: &mut R, first_byte: u8) -> Result<[u8; 12]> {
+    let mut signature = [first_byte; 12];
+    for i in 1..signature.len() {
+        read_header_bytes(r, &mut signature[i..i + 1], i)?;
+        if signature[i] != V2_SIGNATURE[i] {
+            return Err(ProxyReadError::MissingLiteral(signature[..=i].to_vec()));
+        }
+    }
+    Ok(signature)
+}
+
+
+/// The error for a version 2 fixed header which does not start with the signature
+fn bad_signature(header_buf: &[u8]) -> ProxyReadError {
+    if looks_like_tls(header_buf) {
+        ProxyReadError::LooksLikeTls
+    } else if let Some(method) = http_method(header_buf) {
+        ProxyReadError::LooksLikeHttp(method)
+    } else {
+        ProxyReadError::MissingLiteral(header_buf.to_vec())
+    }
+}
+
+
+/// The error for a connection whose first byte cannot start a header of either version: read
+/// on for as long as it could still be an HTTP request line, so that those can be told apart
+fn sniff_http<R: Read>(r: &mut R, first_byte: u8) -> ProxyReadError {
+    let mut buf = [first_byte; 8];
+    let mut len = 1;
+    loop {
+        if let Some(method) = http_method(&buf[..len]) {
+            return ProxyReadError::LooksLikeHttp(method);
+        }
+        if len == buf.len() || !HTTP_METHODS.iter().any(|method| method.as_bytes().starts_with(&buf[..len])) {
+            return ProxyReadError::MissingFirstByte;
+        }
+        if let Err(e) = read_header_bytes(r, &mut buf[len..len + 1], len) {
+            return e;
+        }
+        len += 1;
+    }
+}
+
+
+#[cfg(test)]
+pub(crate) fn read_proxy_protocol_any<R: Read>(r: &mut R) -> Result<ProxyProtocolHeader> {
+    read_proxy_protocol_any_with(r, &ParseConfig::default())
+}
+
+pub(crate) fn read_proxy_protocol_any_with<R: Read>(r: &mut R, config: &ParseConfig) -> Result<ProxyProtocolHeader> {
+    let mut first_byte = [0u8; 1];
+    read_header_bytes(r, &mut first_byte, 0)?;
+    read_proxy_protocol_after_first_byte(r, first_byte[0], config)
+}
+
+/// Parse a header of `version` off the front of `buf`, returning it and its length; a `buf`
+/// which ends part way through the header is an `UnexpectedEof`
+fn parse_prefix(buf: &[u8], version: ProxyProtocolVersion, config: &ParseConfig) -> Result<(ProxyProtocolHeader, usize)> {
+    let mut rest = buf;
+    let header = match version {
+        ProxyProtocolVersion::V1 => read_proxy_protocol_v1_with(&mut rest, config),
+        ProxyProtocolVersion::V2 => read_proxy_protocol_v2_with(&mut rest, config),
+        ProxyProtocolVersion::Any => read_proxy_protocol_any_with(&mut rest, config),
+    }?;
+    Ok((header, buf.len() - rest.len()))
+}
+
+
+/// Parse a version 1 header off the front of `buf`, with the default `ParseConfig` (see
+/// `parse_with`)
+pub fn parse_v1(buf: &[u8]) -> Poll<::std::result::Result<(ProxyProtocolHeader, usize), ProxyReadError>> {
+    parse_with(buf, ProxyProtocolVersion::V1, &ParseConfig::default())
+}
+
+
+/// Parse a version 2 header off the front of `buf`, with the default `ParseConfig` (see
+/// `parse_with`)
+pub fn parse_v2(buf: &[u8]) -> Poll<::std::result::Result<(ProxyProtocolHeader, usize), ProxyReadError>> {
+    parse_with(buf, ProxyProtocolVersion::V2, &ParseConfig::default())
+}
+
+
+/// Parse a header of either version off the front of `buf`, with the default `ParseConfig`
+/// (see `parse_with`)
+pub fn parse_any(buf: &[u8]) -> Poll<::std::result::Result<(ProxyProtocolHeader, usize), ProxyReadError>> {
+    parse_with(buf, ProxyProtocolVersion::Any, &ParseConfig::default())
+}
+
+
+/// Parse a header of `version` off the front of `buf`, for servers which do their own reads
+/// (and for picking apart captures). Returns the header and how many bytes of `buf` it took;
+/// the rest is the connection's own data. A `buf` which ends part way through the header is
+/// `Pending`, rather than an error, so that the caller can try again once it has more. This
+/// is the parser behind `HeaderParser`, and so behind every listener.
+///
+/// No `buf` makes it panic, whatever it holds and whatever the `config`: bytes which cannot
+/// start a header are always an error, and those which might are `Pending`.
+///
+/// ```
+/// use std::task::Poll;
+/// use hyper_networklistener_proxy::proxy_protocol::parse_any;
+///
+/// let buf = b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\nGET / HTTP/1.1\r\n";
+/// assert!(parse_any(&buf[..20]).is_pending());
+/// match parse_any(buf) {
+///     Poll::Ready(Ok((header, len))) => {
+///         assert_eq!(header.source_addr(), Some("192.0.2.1:4124".parse().unwrap()));
+///         assert_eq!(&buf[len..], b"GET / HTTP/1.1\r\n");
+///     },
+///     other => panic!("should parse, got {:?}", other),
+/// }
+/// ```
+pub fn parse_with(buf: &[u8], version: ProxyProtocolVersion, config: &ParseConfig) -> Poll<::std::result::Result<(ProxyProtocolHeader, usize), ProxyReadError>> {
+    match parse_prefix(buf, version, config) {
+        Err(ProxyReadError::UnexpectedEof { .. }) => Poll::Pending,
+        result => Poll::Ready(result),
+    }
+}
+
+
+/// A parser for a single header which is handed its bytes as they arrive, for connections
+/// read without blocking. It keeps whatever part of the header it has been given between
+/// calls, and never takes more than the header: the bytes after it, if any arrive in the same
+/// read, are left for the caller.
+///
+/// Bytes already read are given to `advance`; `feed` reads them from a `Read` itself, a
+/// header's worth at most, stopping at the first `WouldBlock`. No more than
+/// `ParseConfig::longest_header` bytes are kept: an `UNSPEC` address block too long for
+/// `ParseConfig::max_v2_block_len` is counted off and dropped as it arrives, as the
+/// `read_proxy_protocol_*` functions do. Either may be called as many
+/// times as it takes. Once a header (or an error) has been returned the parser is done with,
+/// and calling it again returns the same result without taking any more bytes.
+///
+/// ```
+/// use std::task::Poll;
+/// use hyper_networklistener_proxy::{HeaderParser, ProxyProtocolVersion};
+///
+/// let mut parser = HeaderParser::new(ProxyProtocolVersion::Any);
+/// assert!(parser.advance(b"PROXY TCP4 192.0.2.1 ").is_pending());
+/// let (header, used) = match parser.advance(b"198.51.100.7 4124 443\r\nGET / HTTP/1.1\r\n") {
+///     Poll::Ready(result) => result.unwrap(),
+///     Poll::Pending => unreachable!(),
+/// };
+/// assert_eq!(header.source_addr(), Some("192.0.2.1:4124".parse().unwrap()));
+/// assert_eq!(used, 23);
+/// ```
+#[derive(Debug, Clone)]
+pub struct HeaderParser {
+    version: ProxyProtocolVersion,
+    config: ParseConfig,
+    buf: Vec<u8>,
+    // how many bytes the header is known to need, which is as many as are worth having before
+    // parsing it again
+    needed: usize,
+    // the address block being skipped, once the fixed header has said it will be, which
+    // stays here once it has been
+    skipping: Option<SkippedBlock>,
+}
+
+
+impl HeaderParser {
+    /// A parser for a header of `version`, with the default `ParseConfig`
+    pub fn new(version: ProxyProtocolVersion) -> Self {
+        HeaderParser {
+            version,
+            config: ParseConfig::default(),
+            buf: Vec::new(),
+            needed: 1,
+            skipping: None,
+        }
+    }
+
+    /// Parse the header according to `config`
+    pub fn parse_config(mut self, config: ParseConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// How many bytes of the header the parser is holding: all it has been given so far, bar
+    /// those of a skipped address block
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Whether the parser is holding no bytes, as it is before it is given any
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// Take as much of `input` as the header needs. Once it is complete, returns the header
+    /// and how many bytes of `input` it took; anything after them is the connection's own
+    /// data. Until then, all of `input` has been taken, and it is `Pending`.
+    pub fn advance(&mut self, input: &[u8]) -> Poll<::std::result::Result<(ProxyProtocolHeader, usize), ProxyReadError>> {
+        let mut used = 0;
+        loop {
+            if let Some(ref mut block) = self.skipping {
+                let take = block.left.min(input.len() - used);
+                if let Err(e) = block.skip(&input[used..used + take]) {
+                    return Poll::Ready(Err(e));
+                }
+                used += take;
+                if block.left > 0 {
+                    return Poll::Pending;
+                }
+                return Poll::Ready(block.finish().map(|header| (header, used)));
+            }
+            let take = (self.needed - self.buf.len()).min(input.len() - used);
+            self.buf.extend_from_slice(&input[used..used + take]);
+            used += take;
+            if self.buf.len() < self.needed {
+                return Poll::Pending;
+            }
+            match self.parse() {
+                Ok(Some(header)) => return Poll::Ready(Ok((header, used))),
+                Ok(None) => {},
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+        }
+    }
+
+    /// Read from `r` until the header is complete, returning it, or until `r` fails with
+    /// `WouldBlock`, returning `None`. Reading stops at the end of the header, so (unlike with
+    /// `advance`) `r` is left at the start of the connection's own data. A connection which
+    /// is closed part way through the header is an `UnexpectedEof`.
+    pub fn feed<R: Read>(&mut self, r: &mut R) -> ::std::result::Result<Option<ProxyProtocolHeader>, ProxyReadError> {
+        loop {
+            if let Some(ref mut block) = self.skipping {
+                if block.left == 0 || block.malformed {
+                    return block.finish().map(Some);
+                }
+                let mut chunk = [0u8; 512];
+                let len = block.left.min(chunk.len());
+                match r.read(&mut chunk[..len]) {
+                    Ok(0) => return Err(ProxyReadError::UnexpectedEof { needed: block.end, got: block.end - block.left }),
+                    Ok(n) => block.skip(&chunk[..n])?,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                    Err(e) => return Err(e.into()),
+                }
+                continue;
+            }
+            if self.buf.len() == self.needed {
+                if let Some(header) = self.parse()? {
+                    return Ok(Some(header));
+                }
+                if self.skipping.is_some() {
+                    continue;
+                }
+            }
+            let filled = self.buf.len();
+            self.buf.resize(self.needed, 0);
+            let n = match r.read(&mut self.buf[filled..]) {
+                Ok(n) => n,
+                Err(e) => {
+                    self.buf.truncate(filled);
+                    match e.kind() {
+                        io::ErrorKind::Interrupted => continue,
+                        io::ErrorKind::WouldBlock => return Ok(None),
+                        _ => return Err(e.into()),
+                    }
+                },
+            };
+            self.buf.truncate(filled + n);
+            if n == 0 {
+                return Err(ProxyReadError::UnexpectedEof { needed: self.needed, got: filled });
+            }
+        }
+    }
+
+    /// Parse the bytes given so far, returning `None` (and noting how many are needed) if
+    /// they are not yet a whole header
+    fn parse(&mut self) -> Result<Option<ProxyProtocolHeader>> {
+        match parse_prefix(&self.buf, self.version, &self.config) {
+            Ok((header, _)) => Ok(Some(header)),
+            // the parsers report how far they were reading to, counting from the start of
+            // the header, when they run out
+            Err(ProxyReadError::UnexpectedEof { needed, .. }) => {
+                // having got through the fixed header of one whose block the parsers skip
+                if let Some(addrlen) = skipped_block_len(&self.buf, &self.config).filter(|&len| needed == 16 + len) {
+                    let command = if self.buf[12] & 0x0f == 0 { Command::Local } else { Command::Proxy };
+                    self.skipping = Some(SkippedBlock::new(self.buf[12] >> 4, command, addrlen, self.config.max_v2_block_len));
+                    return Ok(None);
+                }
+                // which no header within the limits is
+                let limit = self.config.longest_header(self.version).max(16);
+                if needed > limit {
+                    return Err(ProxyReadError::HeaderTooLong { limit, read: self.buf.len() });
+                }
+                self.needed = needed.max(self.buf.len() + 1);
+                Ok(None)
+            },
+            Err(e) => Err(e),
+        }
+    }
+}
+
+const V1_FIELD_NAMES: [&str; 6] = [
+    "PROXY literal", "protocol", "source address", "destination address", "source port",
+    "destination port",
+];
+
+const V1_FIELD_EXPECTATIONS: [&str; 6] = [
+    "\"PROXY\"", "\"TCP4\", \"TCP6\" or \"UNKNOWN\"", "an IPv4 or IPv6 address",
+    "an IPv4 or IPv6 address", "a decimal port number from 0 to 65535",
+    "a decimal port number from 0 to 65535",
+];
+
+/// The bytes every version 1 header starts with
+pub const PROXY_V1_PREFIX: &[u8] = b"PROXY ";
+
+/// The signature every version 2 header starts with
+pub const PROXY_V2_SIGNATURE: [u8; 12] = *b"\x0D\x0A\x0D\x0A\x00\x0D\x0A\x51\x55\x49\x54\x0A";
+
+pub(crate) const V1_LITERAL: &[u8] = PROXY_V1_PREFIX;
+
+const V2_SIGNATURE: &[u8; 12] = &PROXY_V2_SIGNATURE;
+
+
+/// What `detect_version` made of the start of a stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DetectedVersion {
+    /// It starts with `PROXY_V1_PREFIX`
+    V1,
+    /// It starts with `PROXY_V2_SIGNATURE`
+    V2,
+    /// What there is of it so far could start either, so more is needed to tell
+    NeedMoreData,
+}
+
+
+/// Tell from however many of a stream's first bytes are in `prefix` (e.g., as peeked with
+/// `MSG_PEEK`) which version of header it starts with, if any: `None` means it cannot start a
+/// header at all, and `NeedMoreData` that `prefix` is too short to tell (which an empty one
+/// always is). Only the literal and signature are looked at, so a stream detected as either
+/// version may still fail to parse; and headers in lowercase (see
+/// `ParseConfig::allow_lowercase`) are not detected.
+///
+/// ```
+/// use hyper_networklistener_proxy::proxy_protocol::{detect_version, DetectedVersion};
+///
+/// assert_eq!(detect_version(b"PROXY TCP4 "), Some(DetectedVersion::V1));
+/// assert_eq!(detect_version(b"\r\n"), Some(DetectedVersion::NeedMoreData));
+/// assert_eq!(detect_version(b"GET / HTTP/1.1\r\n"), None);
+/// ```
+pub fn detect_version(prefix: &[u8]) -> Option<DetectedVersion> {
+    [(PROXY_V1_PREFIX, DetectedVersion::V1), (&PROXY_V2_SIGNATURE[..], DetectedVersion::V2)].iter()
+        .find(|&&(literal, _)| prefix.iter().zip(literal).all(|(a, b)| a == b))
+        .map(|&(literal, version)| if prefix.len() < literal.len() { DetectedVersion::NeedMoreData } else { version })
+}
+
+
+/// Describe, for a human, how `buf` fares when parsed as a PROXY header of the given version:
+/// which version was detected, and either what the header contained or which field was being
+/// read when parsing failed, what was expected there, what was found instead, and at which
+/// byte offset.
+///
+/// The output spans several lines and is meant for troubleshooting captures from
+/// misbehaving senders (see the `proxy_decode` example); its exact wording is not stable.
+pub fn explain(buf: &[u8], version: ProxyProtocolVersion) -> String {
+    let mut out = String::new();
+    if looks_like_tls(buf) {
+        let _ = writeln!(out, "version: none; the bytes start with a TLS handshake record\nerror at byte 0: {}", ProxyReadError::LooksLikeTls);
+        return out;
+    }
+    if let Some(method) = http_method(buf) {
+        let _ = writeln!(out, "version: none; the bytes start with an HTTP request line\nerror at byte 0: {}", ProxyReadError::LooksLikeHttp(method));
+        return out;
+    }
+    let detected = match version {
+        ProxyProtocolVersion::V1 => {
+            out.push_str("version: v1 (as configured)\n");
+            1
+        },
+        ProxyProtocolVersion::V2 => {
+            out.push_str("version: v2 (as configured)\n");
+            2
+        },
+        ProxyProtocolVersion::Any => match buf.first() {
+            Some(&0x0d) => {
+                out.push_str("version: v2 (detected from first byte 0x0d)\n");
+                2
+            },
+            Some(&0x50) => {
+                out.push_str("version: v1 (detected from first byte 'P')\n");
+                1
+            },
+            Some(&b) => {
+                let _ = writeln!(out, "version: unknown; first byte {} is neither 'P' (v1) nor 0x0d (v2)", describe_byte(b));
+                return out;
+            },
+            None => {
+                out.push_str("version: unknown; there are no bytes\n");
+                return out;
+            },
+        },
+    };
+    if detected == 1 {
+        explain_v1(buf, &mut out);
+    } else {
+        explain_v2(buf, &mut out);
+    }
+    out
+}
+
+
+fn describe_byte(b: u8) -> String {
+    if b.is_ascii_graphic() {
+        format!("0x{:02x} ('{}')", b, b as char)
+    } else {
+        format!("0x{:02x}", b)
+    }
+}
+
+
+fn explain_success(header: &ProxyProtocolHeader, consumed: usize, out: &mut String) {
+    let _ = writeln!(out, "result: parsed a {} byte header", consumed);
+    let _ = writeln!(out, "  command: {:?}", header.command);
+    let _ = writeln!(out, "  protocol: {:?}", header.proto);
+    match (header.source_addr, header.dest_addr) {
+        (Some(source), Some(dest)) => {
+            let _ = writeln!(out, "  source: {}", source);
+            let _ = writeln!(out, "  destination: {}", dest);
+        },
+        _ => {
+            out.push_str("  no addresses; the connection's own addresses should be used\n");
+        },
+    }
+}
+
+
+fn explain_v1(buf: &[u8], out: &mut String) {
+    let mut rest = buf;
+    let result = read_proxy_protocol_v1(&mut rest);
+    let consumed = buf.len() - rest.len();
+    let err = match result {
+        Ok(header) => return explain_success(&header, consumed, out),
+        Err(err) => err,
+    };
+    let line_end = match err {
+        ProxyReadError::Io(_) | ProxyReadError::UnexpectedEof { .. } => {
+            let _ = writeln!(out, "error at byte {}: ran out of bytes while looking for the CRLF which ends the line", consumed);
+            return;
+        },
+        ProxyReadError::HeaderTooLong { limit, .. } => {
+            let _ = writeln!(out, "error at byte {}: no CRLF within the first {} bytes (the longest a v1 line may be)", consumed, limit);
+            return;
+        },
+        _ => consumed.saturating_sub(2),
+    };
+    if let ProxyReadError::NotConformant(EXTRA_SPACES) = err {
+        let at = (0..line_end).find(|&i| buf[i] == b' ' && (i + 1 == line_end || buf[i + 1] == b' ')).unwrap_or(0);
+        let _ = writeln!(out, "error at byte {}: extra space", at);
+        let _ = writeln!(out, "  expected: exactly one space between fields, and none before the CRLF");
+        return;
+    }
+    // split the line into fields just as the parser does, remembering where each one starts
+    let mut fields = Vec::new();
+    let mut start = 0;
+    for (i, &b) in buf[..line_end].iter().enumerate() {
+        if b == b' ' {
+            if i > start {
+                fields.push((start, &buf[start..i]));
+            }
+            start = i + 1;
+        }
+    }
+    if line_end > start || fields.is_empty() {
+        fields.push((start, &buf[start..line_end]));
+    }
+    let failed_field = match err {
+        ProxyReadError::MissingLiteral(_) if fields[0].1 != b"PROXY" => 0,
+        ProxyReadError::MissingLiteral(_) => 1,
+        ProxyReadError::BadSourceAddress(_) => 2,
+        ProxyReadError::BadDestAddress(_) => 3,
+        ProxyReadError::AddressFamilyMismatch(_, field) => V1_FIELD_NAMES.iter().position(|&name| name == field).unwrap_or(2),
+        ProxyReadError::BadSourcePort(_) => 4,
+        ProxyReadError::BadDestPort(_) => 5,
+        ProxyReadError::Utf8(_) => fields.iter().position(|&(_, f)| ::std::str::from_utf8(f).is_err()).unwrap_or(0),
+        _ => fields.len(),
+    };
+    if failed_field >= fields.len() || failed_field >= V1_FIELD_NAMES.len() {
+        let _ = writeln!(out, "error at byte {}: the line ends after {} fields", line_end, fields.len());
+        let missing = fields.len().min(V1_FIELD_NAMES.len() - 1);
+        let _ = writeln!(out, "  expected field {} ({}): {}", missing + 1, V1_FIELD_NAMES[missing], V1_FIELD_EXPECTATIONS[missing]);
+        return;
+    }
+    let (offset, field) = fields[failed_field];
+    let _ = writeln!(out, "error at byte {}: bad {} (field {})", offset, V1_FIELD_NAMES[failed_field], failed_field + 1);
+    let _ = writeln!(out, "  expected: {}", V1_FIELD_EXPECTATIONS[failed_field]);
+    let _ = writeln!(out, "  found: {:?} (bytes {}..{})", String::from_utf8_lossy(field), offset, offset + field.len());
+    match err {
+        ProxyReadError::BadSourceAddress(ref e) | ProxyReadError::BadDestAddress(ref e) => {
+            let _ = writeln!(out, "  cause: {}", e);
+        },
+        ProxyReadError::BadSourcePort(ref e) | ProxyReadError::BadDestPort(ref e) => {
+            let _ = writeln!(out, "  cause: {}", e);
+        },
+        ProxyReadError::AddressFamilyMismatch(proto, _) => {
+            let _ = writeln!(out, "  cause: {} lines carry only {} addresses", if proto == Proto::Tcp4 { "TCP4" } else { "TCP6" }, if proto == Proto::Tcp4 { "IPv4" } else { "IPv6" });
+        },
+        _ => {},
+    }
+}
+
+
+fn explain_v2(buf: &[u8], out: &mut String) {
+    if let Some(i) = buf.iter().zip(V2_SIGNATURE.iter()).position(|(a, b)| a != b) {
+        let _ = writeln!(out, "error at byte {}: bad signature", i);
+        let _ = writeln!(out, "  expected: 0x{:02x}", V2_SIGNATURE[i]);
+        let _ = writeln!(out, "  found: {}", describe_byte(buf[i]));
+        return;
+    }
+    if buf.len() < 16 {
+        let _ = writeln!(out, "error at byte {}: ran out of bytes in the 16 byte fixed header", buf.len());
+        return;
+    }
+    let version = buf[12] >> 4;
+    let command = buf[12] & 0x0f;
+    let family = buf[13] >> 4;
+    let transport = buf[13] & 0x0f;
+    let addrlen = NetworkEndian::read_u16(&buf[14..16]) as usize;
+    let _ = writeln!(out, "fixed header: version {}, command {}, family {}, transport {}, address block {} bytes", version, command, family, transport, addrlen);
+    if version != 2 {
+        let _ = writeln!(out, "error at byte 12: bad version (high nibble)");
+        let _ = writeln!(out, "  expected: 2");
+        let _ = writeln!(out, "  found: {}", version);
+        return;
+    }
+    if command > 1 {
+        let _ = writeln!(out, "error at byte 12: bad command (low nibble)");
+        let _ = writeln!(out, "  expected: 0 (LOCAL) or 1 (PROXY)");
+        let _ = writeln!(out, "  found: {}", command);
+        return;
+    }
+    if family > 3 {
+        let _ = writeln!(out, "error at byte 13: bad address family (high nibble)");
+        let _ = writeln!(out, "  expected: 0 (UNSPEC), 1 (INET), 2 (INET6) or 3 (UNIX)");
+        let _ = writeln!(out, "  found: {}", family);
+        return;
+    }
+    if transport > 2 {
+        let _ = writeln!(out, "error at byte 13: bad transport (low nibble)");
+        let _ = writeln!(out, "  expected: 0 (UNSPEC), 1 (STREAM) or 2 (DGRAM)");
+        let _ = writeln!(out, "  found: {}", transport);
+        return;
+    }
+    // an UNSPEC block is skipped rather than buffered, so may be as long as it likes
+    if addrlen > DEFAULT_MAX_V2_BLOCK_LEN && family != 0 {
+        let _ = writeln!(out, "error at byte 14: address block too long");
+        let _ = writeln!(out, "  expected: at most {} bytes", DEFAULT_MAX_V2_BLOCK_LEN);
+        let _ = writeln!(out, "  found: {} bytes", addrlen);
+        return;
+    }
+    // LOCAL headers are accepted with any address block, since theirs is ignored
+    let needed = match (command, family) {
+        (0, _) => 0,
+        (_, 1) => 12,
+        (_, 2) => 36,
+        (_, 3) => 2 * UNIX_PATH_LEN,
+        _ => 0,
+    };
+    if addrlen < needed {
+        let _ = writeln!(out, "error at byte 14: address block too short for the address family");
+        let _ = writeln!(out, "  expected: at least {} bytes", needed);
+        let _ = writeln!(out, "  found: {} bytes", addrlen);
+        return;
+    }
+    if buf.len() < 16 + addrlen {
+        let _ = writeln!(out, "error at byte {}: ran out of bytes in the address block", buf.len());
+        let _ = writeln!(out, "  expected: {} bytes at bytes 16..{}", addrlen, 16 + addrlen);
+        let _ = writeln!(out, "  found: {} bytes", buf.len() - 16);
+        return;
+    }
+    let mut rest = buf;
+    match read_proxy_protocol_v2(&mut rest) {
+        Ok(header) => explain_success(&header, buf.len() - rest.len(), out),
+        Err(ProxyReadError::InvalidProtocol { .. }) if transport != 1 => {
+            let _ = writeln!(out, "error at byte 13: unsupported transport (low nibble)");
+            let _ = writeln!(out, "  expected: 1 (STREAM) or 2 (DGRAM)");
+            let _ = writeln!(out, "  found: {}", transport);
+        },
+        Err(ProxyReadError::MalformedTlv(at)) => {
+            let _ = writeln!(out, "error at byte {}: TLV runs past the end of the address block", at);
+            let _ = writeln!(out, "  expected: TLVs ending by byte {}", 16 + addrlen);
+            let _ = writeln!(out, "  found: {} bytes left for this one", 16 + addrlen - at);
+        },
+        Err(err) => {
+            let _ = writeln!(out, "error: {}", err);
+        },
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::SocketAddr;
+    use std::task::Poll;
+
+    use super::read_proxy_protocol_v1;
+    use super::read_proxy_protocol_v2;
+    use super::read_proxy_protocol_any;
+    use super::{Command, Proto, Transport};
+    use super::{FieldReader, HexDump, ProxyProtocolHeader, Tlv};
+    use super::{guess_client, ClientGuess};
+    use super::explain;
+    use super::ProxyProtocolVersion;
+    use super::UnixPath;
+    use super::{parse_v2_datagram, ProxyReadError};
+    use super::{read_proxy_protocol_any_with, read_proxy_protocol_v1_with, read_proxy_protocol_v2_with, ParseConfig};
+    use super::{parse_any, parse_prefix, parse_v1, parse_v2, parse_with, HeaderParser};
+    use super::{detect_version, DetectedVersion, PROXY_V1_PREFIX, PROXY_V2_SIGNATURE};
+
+    #[test]
+    fn test_proxy_protocol_v1_spec_vectors() { 
+        let vectors = vec![
+            (b"PROXY TCP4 255.255.255.255 255.255.255.255 65535 65535\r\n".to_vec(), ProxyProtocolHeader::new(1, Proto::Tcp4, "255.255.255.255:65535".parse().unwrap(), "255.255.255.255:65535".parse().unwrap())),
+            (b"PROXY TCP6 ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff 65535 65535\r\n".to_vec(), ProxyProtocolHeader::new(1, Proto::Tcp6, "[ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff]:65535".parse().unwrap(), "[ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff]:65535".parse().unwrap())),
+            (b"PROXY UNKNOWN\r\n".to_vec(), ProxyProtocolHeader::new_unknown(1)),
+            (b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\n".to_vec(), ProxyProtocolHeader::new(1, Proto::Tcp4, "192.168.0.1:56324".parse().unwrap(), "192.168.0.11:443".parse().unwrap())),
+        ];
+        for (bytestr, expected) in vectors {
+            let r = read_proxy_protocol_v1(&mut bytestr.as_slice()).expect("Should parse");
+            assert_eq!(r, expected);
+        }
+    }
+
+    #[test]
+    fn test_proxy_protocol_v1_unknown_with_fields() {
+        let longest = b"PROXY UNKNOWN ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff 65535 65535\r\n";
+        assert_eq!(longest.len(), 107);
+        let lines: Vec<&[u8]> = vec![
+            b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\n",
+            b"PROXY UNKNOWN 192.0.2.1 198.51.100.7 4124 443\r\n",
+            longest,
+            // nothing in the rest of the line has to make sense, or even be UTF-8
+            b"PROXY UNKNOWN 192.0.2.1\r\n",
+            b"PROXY UNKNOWN not.an.address also-not 99999 -1 extra fields\r\n",
+            b"PROXY UNKNOWN \xff\xfe\r\n",
+            b"PROXY UNKNOWN \r\n",
+        ];
+        for line in lines {
+            for config in &[ParseConfig::default(), ParseConfig::strict(), ParseConfig::lenient()] {
+                let mut bytes = line.to_vec();
+                bytes.extend_from_slice(b"GET / HTTP/1.1\r\n");
+                let mut rest = &bytes[..];
+                let header = read_proxy_protocol_any_with(&mut rest, config).expect("should parse");
+                assert_eq!(header, ProxyProtocolHeader::new_unknown(1), "for {:?}", String::from_utf8_lossy(line));
+                assert_eq!(rest, b"GET / HTTP/1.1\r\n", "should consume exactly {:?}", String::from_utf8_lossy(line));
+            }
+        }
+        // but the line is still limited to 107 bytes
+        let mut too_long = longest[..105].to_vec();
+        too_long.extend_from_slice(b"5\r\n");
+        match read_proxy_protocol_v1(&mut &too_long[..]) {
+            Err(ProxyReadError::HeaderTooLong { limit: 107, read: 107 }) => {},
+            other => panic!("expected HeaderTooLong, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_proto_names() {
+        let protos = [Proto::Tcp4, Proto::Tcp6, Proto::Udp4, Proto::Udp6, Proto::Unix, Proto::Unknown];
+        let names: Vec<String> = protos.iter().map(|proto| proto.to_string()).collect();
+        assert_eq!(names, ["tcp4", "tcp6", "udp4", "udp6", "unix", "unknown"]);
+        for proto in &protos {
+            assert_eq!(proto.as_str(), proto.to_string());
+        }
+    }
+
+    #[test]
+    fn test_max_v1_line_len() {
+        // an appliance padding its lines with spaces, to 150 bytes with the CRLF
+        let mut padded = b"PROXY TCP4 192.0.2.1 198.51.100.7".to_vec();
+        padded.resize(140, b' ');
+        padded.extend_from_slice(b"4124 443\r\n");
+        assert_eq!(padded.len(), 150);
+        let spaced = ParseConfig::default().allow_extra_spaces(true);
+        let expected = ProxyProtocolHeader::new(1, Proto::Tcp4, "192.0.2.1:4124".parse().unwrap(), "198.51.100.7:443".parse().unwrap());
+
+        // the spec's limit still applies by default, and a line just within it parses
+        let mut at_limit = padded[..33].to_vec();
+        at_limit.resize(97, b' ');
+        at_limit.extend_from_slice(b"4124 443\r\n");
+        assert_eq!(at_limit.len(), 107);
+        assert_eq!(read_proxy_protocol_v1_with(&mut &at_limit[..], &spaced).expect("should parse"), expected);
+        match read_proxy_protocol_v1_with(&mut &padded[..], &spaced) {
+            Err(ProxyReadError::HeaderTooLong { limit: 107, read: 107 }) => {},
+            other => panic!("expected HeaderTooLong, got {:?}", other),
+        }
+
+        // but can be raised
+        for &len in &[150, 512] {
+            let raised = spaced.max_v1_line_len(len);
+            for parsed in [read_proxy_protocol_v1_with(&mut &padded[..], &raised), read_proxy_protocol_any_with(&mut &padded[..], &raised)] {
+                assert_eq!(parsed.expect("should parse"), expected);
+            }
+        }
+        match read_proxy_protocol_v1_with(&mut &padded[..], &spaced.max_v1_line_len(149)) {
+            Err(ProxyReadError::HeaderTooLong { limit: 149, read: 149 }) => {},
+            other => panic!("expected HeaderTooLong, got {:?}", other),
+        }
+
+        // and nothing past the limit is read, however much there is
+        let mut endless = b"PROXY TCP4 ".to_vec();
+        endless.resize(1 << 20, b' ');
+        let mut rest = &endless[..];
+        match read_proxy_protocol_any_with(&mut rest, &spaced.max_v1_line_len(512)) {
+            Err(ProxyReadError::HeaderTooLong { limit: 512, read: 512 }) => {},
+            other => panic!("expected HeaderTooLong, got {:?}", other),
+        }
+        assert_eq!(endless.len() - rest.len(), 512);
+        // nor is a buffer of the limit's size set aside before any of it arrives
+        assert_eq!(read_proxy_protocol_v1_with(&mut &padded[..], &spaced.max_v1_line_len(usize::MAX)).expect("should parse"), expected);
+        match read_proxy_protocol_v1_with(&mut &b"PROXY TCP4 "[..], &spaced.max_v1_line_len(usize::MAX)) {
+            Err(ProxyReadError::UnexpectedEof { needed: 12, got: 11 }) => {},
+            other => panic!("expected UnexpectedEof, got {:?}", other),
+        }
+        assert_eq!(ProxyReadError::HeaderTooLong { limit: 512, read: 512 }.to_string(), "header longer than the 512 bytes allowed (gave up after 512)");
+    }
+
+    #[test]
+    fn test_max_header_len() {
+        let v1 = b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n".to_vec();
+        let v2 = padded_v2(40);
+        for (bytes, versions) in [(&v1, &[ProxyProtocolVersion::V1, ProxyProtocolVersion::Any]), (&v2, &[ProxyProtocolVersion::V2, ProxyProtocolVersion::Any])] {
+            let mut trailing = bytes.clone();
+            trailing.extend_from_slice(b"GET / HTTP/1.1\r\n");
+            for &version in versions {
+                // a header of exactly the limit parses, and leaves everything after it
+                let exact = ParseConfig::default().max_header_len(Some(bytes.len()));
+                assert_eq!(parse_with(&trailing, version, &exact).map(|r| r.map(|(_, len)| len).ok()), Poll::Ready(Some(bytes.len())));
+
+                // one byte over is rejected, having read no more than the limit (for v1) or
+                // the fixed header (for v2)
+                let limit = bytes.len() - 1;
+                let short = ParseConfig::default().max_header_len(Some(limit));
+                let read = if bytes == &v1 { limit } else { 16 };
+                let mut rest = &trailing[..];
+                let result = match version {
+                    ProxyProtocolVersion::V1 => read_proxy_protocol_v1_with(&mut rest, &short),
+                    ProxyProtocolVersion::V2 => read_proxy_protocol_v2_with(&mut rest, &short),
+                    ProxyProtocolVersion::Any => read_proxy_protocol_any_with(&mut rest, &short),
+                };
+                match result {
+                    Err(ProxyReadError::HeaderTooLong { limit: l, read: r }) if (l, r) == (limit, read) => {},
+                    other => panic!("expected HeaderTooLong {{ limit: {}, read: {} }} for {:?}, got {:?}", limit, read, version, other),
+                }
+                assert_eq!(trailing.len() - rest.len(), read, "for {:?}", version);
+
+                // the same from a parser fed a byte at a time
+                let mut parser = HeaderParser::new(version).parse_config(short);
+                let result = trailing.chunks(1).map(|chunk| parser.advance(chunk)).find(|poll| poll.is_ready());
+                assert!(matches!(result, Some(Poll::Ready(Err(ProxyReadError::HeaderTooLong { .. })))), "{:?}", result);
+            }
+        }
+
+        // a v2 limit too small for even the fixed header turns each one down once it is read
+        match read_proxy_protocol_v2_with(&mut &v2[..], &ParseConfig::default().max_header_len(Some(15))) {
+            Err(ProxyReadError::HeaderTooLong { limit: 15, read: 16 }) => {},
+            other => panic!("expected HeaderTooLong, got {:?}", other),
+        }
+        // and the tighter of the two limits on a v1 line is the one which applies
+        let loose = ParseConfig::default().max_header_len(Some(500));
+        assert!(matches!(read_proxy_protocol_v1_with(&mut &[&b"PROXY TCP4 "[..], &[b' '; 200]].concat()[..], &loose), Err(ProxyReadError::HeaderTooLong { limit: 107, read: 107 })));
+    }
+
+    #[test]
+    fn test_proxy_protocol_v1_failure_cases() {
+        read_proxy_protocol_v1(&mut (b"" as &[u8])).expect_err("should not parse");
+        read_proxy_protocol_v1(&mut (b"\r\n" as &[u8])).expect_err("should not parse");
+        read_proxy_protocol_v1(&mut (b"proxy tcp4 255.255.255.255 255.255.255.255 0 0\r\n" as &[u8])).expect_err("should not parse");
+    }
+
+    #[test]
+    fn test_proxy_protocol_v2_vectors() {
+        let vectors = vec![
+            (b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x11\x00\x0c\x0a\x0b\x0c\x0d\x7f\x00\x00\x01\x22\xb8\x27\x0f".to_vec(), ProxyProtocolHeader::new(2, Proto::Tcp4, "10.11.12.13:8888".parse().unwrap(), "127.0.0.1:9999".parse().unwrap())),
+            (b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x21\x00\x24\xfd\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x22\xb8\x27\x0f".to_vec(), ProxyProtocolHeader::new(2, Proto::Tcp6, "[fd00::1]:8888".parse().unwrap(), "[::1]:9999".parse().unwrap())),
+            // a different value in every hextet
+            (b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x21\x00\x24\x20\x01\x0d\xb8\x00\x01\x00\x02\x00\x03\x00\x04\x00\x05\x00\x06\x20\x01\x0d\xb8\x00\x0a\x00\x0b\x00\x0c\x00\x0d\x00\x0e\x00\x0f\x10\x1c\x01\xbb".to_vec(), ProxyProtocolHeader::new(2, Proto::Tcp6, "[2001:db8:1:2:3:4:5:6]:4124".parse().unwrap(), "[2001:db8:a:b:c:d:e:f]:443".parse().unwrap())),
+        ];
+        for (bytestr, expected) in vectors {
+            let r = read_proxy_protocol_v2(&mut bytestr.as_slice()).expect("Should parse");
+            assert_eq!(r, expected);
+        }
+    }
+
+    #[test]
+    fn test_proxy_protocol_v2_ipv6_round_trip() {
+        let addrs = ["[2001:db8:1:2:3:4:5:6]:1", "[::ffff:192.0.2.1]:4124", "[fe80::1:2:0:0:3]:80", "[1:2:3:4:5:6:7:8]:65535"];
+        for source in &addrs {
+            for dest in &addrs {
+                let header = ProxyProtocolHeader::for_addrs(source.parse().unwrap(), dest.parse().unwrap());
+                let parsed = read_proxy_protocol_v2(&mut &header.to_bytes(ProxyProtocolVersion::V2)[..]).expect("should parse");
+                assert_eq!((parsed.source_addr(), parsed.dest_addr()), (header.source_addr(), header.dest_addr()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_proxy_protocol_v2_local_vectors() {
+        let mut unspec_local = ProxyProtocolHeader::new_unknown(2);
+        unspec_local.command = Command::Local;
+        let local = |proto: Proto, tlvs: &[u8], at: usize| ProxyProtocolHeader {
+            proto,
+            ..unspec_local.clone()
+        }.with_tlvs(tlvs, at).unwrap();
+        let mut unix = b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x20\x31\x00\xd8".to_vec();
+        unix.extend_from_slice(&[b'a'; 216]);
+        let vectors = vec![
+            (b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x20\x00\x00\x00".to_vec(), unspec_local.clone()),
+            // the address block of an unspecified family holds nothing but TLVs
+            (b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x20\x00\x00\x04\x04\x00\x01\x00".to_vec(), unspec_local.clone().with_tlvs(b"\x04\x00\x01\x00", 16).unwrap()),
+            // addresses are skipped, but the family and TLVs are not
+            (b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x20\x11\x00\x0c\x0a\x0b\x0c\x0d\x7f\x00\x00\x01\x22\xb8\x27\x0f".to_vec(), local(Proto::Tcp4, b"", 28)),
+            (b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x20\x11\x00\x14\x0a\x0b\x0c\x0d\x7f\x00\x00\x01\x22\xb8\x27\x0f\x20\x00\x05\x01\x00\x00\x00\x00".to_vec(), local(Proto::Tcp4, b"\x20\x00\x05\x01\x00\x00\x00\x00", 28)),
+            (b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x20\x21\x00\x24\x20\x01\x0d\xb8\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x20\x01\x0d\xb8\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x02\x10\x1c\x01\xbb".to_vec(), local(Proto::Tcp6, b"", 52)),
+            (unix, local(Proto::Unix, b"", 232)),
+        ];
+        for (bytestr, expected) in vectors {
+            let mut rest = &bytestr[..];
+            let r = read_proxy_protocol_v2(&mut rest).expect("Should parse");
+            assert_eq!(r.command(), Command::Local);
+            assert_eq!((r.source_addr(), r.dest_addr(), r.source_path(), r.dest_path()), (None, None, None, None));
+            assert_eq!(r, expected);
+            assert_eq!(rest, b"", "the whole block should be consumed");
+        }
+    }
+
+    #[test]
+    fn test_hex_dump() {
+        let header = b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x20\x00\x00\x00\x7f";
+        assert_eq!(format!("{:?}", HexDump(header)), "\
+00000000  0d 0a 0d 0a 00 0d 0a 51 55 49 54 0a 20 00 00 00  |.......QUIT. ...|
+00000010  7f                                               |.|");
+        assert_eq!(format!("{:?}", HexDump(b"")), "");
+    }
+
+    #[test]
+    fn test_to_bytes_round_trip() {
+        let tcp4 = ("192.0.2.1:4124".parse().unwrap(), "198.51.100.7:443".parse().unwrap());
+        let tcp6 = ("[2001:db8::1]:4124".parse().unwrap(), "[2001:db8::2]:443".parse().unwrap());
+        let headers = vec![
+            ProxyProtocolHeader::new(1, Proto::Tcp4, tcp4.0, tcp4.1),
+            ProxyProtocolHeader::new(1, Proto::Tcp6, tcp6.0, tcp6.1),
+            ProxyProtocolHeader::new_unknown(1),
+            ProxyProtocolHeader::new(2, Proto::Tcp4, tcp4.0, tcp4.1),
+            ProxyProtocolHeader::new(2, Proto::Tcp6, tcp6.0, tcp6.1),
+            ProxyProtocolHeader::new_with_command(2, Proto::Tcp4, Command::Local, tcp4.0, tcp4.1),
+            ProxyProtocolHeader::new_unix(2, Command::Proxy, UnixPath::Pathname("/run/lb.sock".into()), UnixPath::Abstract(b"backend".to_vec())),
+            ProxyProtocolHeader { command: Command::Proxy, ..ProxyProtocolHeader::new_unknown(2) },
         ];
-        for (bytestr, expected) in vectors {
-            let r = read_proxy_protocol_v1(&mut bytestr.as_slice()).expect("Should parse");
-            assert_eq!(r, expected);
+        for header in headers {
+            let encoded = header.to_bytes(ProxyProtocolVersion::Any);
+            // the addresses of a LOCAL header are sent, but are not read back
+            let expected = if header.command() == Command::Local { header.clone().without_addresses() } else { header };
+            assert_eq!(read_proxy_protocol_any(&mut &encoded[..]).expect("should parse"), expected, "from {:?}", encoded);
         }
+        // what version 1 cannot carry is sent as UNKNOWN
+        let local = ProxyProtocolHeader::new_with_command(2, Proto::Tcp4, Command::Local, tcp4.0, tcp4.1);
+        assert_eq!(local.to_bytes(ProxyProtocolVersion::V1), b"PROXY UNKNOWN\r\n".to_vec());
+        assert_eq!(ProxyProtocolHeader::new(1, Proto::Tcp4, tcp4.0, tcp4.1).to_bytes(ProxyProtocolVersion::V2)[12..14], [0x21, 0x11]);
+        // and an UNKNOWN one as PROXY with no address
+        assert_eq!(ProxyProtocolHeader::new_unknown(1).to_bytes(ProxyProtocolVersion::V2)[12..14], [0x21, 0x00]);
     }
 
     #[test]
-    fn test_proxy_protocol_v1_failure_cases() {
-        read_proxy_protocol_v1(&mut (b"" as &[u8])).expect_err("should not parse");
-        read_proxy_protocol_v1(&mut (b"\r\n" as &[u8])).expect_err("should not parse");
-        read_proxy_protocol_v1(&mut (b"proxy tcp4 255.255.255.255 255.255.255.255 0 0\r\n" as &[u8])).expect_err("should not parse");
+    fn test_display_round_trip() {
+        let tcp4 = ("192.0.2.1:4124".parse().unwrap(), "198.51.100.7:443".parse().unwrap());
+        let tcp6 = ("[2001:db8::1]:4124".parse().unwrap(), "[::ffff:192.0.2.9]:443".parse().unwrap());
+        let cases = vec![
+            (ProxyProtocolHeader::new(1, Proto::Tcp4, tcp4.0, tcp4.1), "PROXY TCP4 192.0.2.1 198.51.100.7 4124 443"),
+            (ProxyProtocolHeader::new(2, Proto::Tcp6, tcp6.0, tcp6.1), "PROXY TCP6 2001:db8::1 ::ffff:192.0.2.9 4124 443"),
+            (ProxyProtocolHeader::for_addrs(tcp4.0, tcp6.0), "PROXY TCP6 ::ffff:192.0.2.1 2001:db8::1 4124 4124"),
+            (ProxyProtocolHeader::new_unknown(1), "PROXY UNKNOWN"),
+            (ProxyProtocolHeader::new_with_command(2, Proto::Tcp4, Command::Local, tcp4.0, tcp4.1), "PROXY UNKNOWN"),
+            (ProxyProtocolHeader::new_unix(2, Command::Proxy, UnixPath::Pathname("/run/lb.sock".into()), UnixPath::Pathname("/run/app.sock".into())), "PROXY UNKNOWN"),
+        ];
+        for (header, line) in cases {
+            assert_eq!(header.to_string(), line);
+            let parsed = read_proxy_protocol_v1(&mut format!("{}\r\n", header).as_bytes()).expect("should parse");
+            assert_eq!(parsed.to_string(), line);
+            match (header.command(), header.proto()) {
+                (Command::Proxy, Proto::Tcp4) | (Command::Proxy, Proto::Tcp6) => {
+                    assert_eq!((parsed.proto(), parsed.source_addr(), parsed.dest_addr()), (header.proto(), header.source_addr(), header.dest_addr()));
+                },
+                _ => assert_eq!(parsed, ProxyProtocolHeader::new_unknown(1)),
+            }
+        }
+        // Debug is unchanged
+        assert!(format!("{:?}", ProxyProtocolHeader::new_unknown(1)).starts_with("ProxyProtocolHeader {"));
     }
 
     #[test]
-    fn test_proxy_protocol_v2_vectors() {
-        let vectors = vec![
-            (b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x11\x00\x0c\x0a\x0b\x0c\x0d\x7f\x00\x00\x01\x22\xb8\x27\x0f".to_vec(), ProxyProtocolHeader::new(2, Proto::Tcp4, "10.11.12.13:8888".parse().unwrap(), "127.0.0.1:9999".parse().unwrap())),
-            (b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x21\x00\x24\xfd\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x22\xb8\x27\x0f".to_vec(), ProxyProtocolHeader::new(2, Proto::Tcp6, "[fd00::1]:8888".parse().unwrap(), "[::1]:9999".parse().unwrap()))
-        ];
-        for (bytestr, expected) in vectors {
-            let r = read_proxy_protocol_v2(&mut bytestr.as_slice()).expect("Should parse");
-            assert_eq!(r, expected);
+    fn test_headers_as_map_keys() {
+        use std::collections::{BTreeMap, HashMap};
+        use std::net::{Ipv4Addr, SocketAddr};
+
+        let mut hashed = HashMap::new();
+        let mut sorted = BTreeMap::new();
+        for i in 0..3000u32 {
+            // 100 pairs of addresses, each sent 10 times in each version, and unknown headers
+            let pair = i % 100;
+            let source = SocketAddr::new(Ipv4Addr::new(192, 0, 2, pair as u8).into(), 4000 + pair as u16);
+            let dest: SocketAddr = "198.51.100.7:443".parse().unwrap();
+            let bytes = match i % 3 {
+                0 => format!("PROXY TCP4 {} {} {} {}\r\n", source.ip(), dest.ip(), source.port(), dest.port()).into_bytes(),
+                1 => ProxyProtocolHeader::for_addrs(source, dest).to_bytes(ProxyProtocolVersion::V2),
+                _ => b"PROXY UNKNOWN\r\n".to_vec(),
+            };
+            let header = read_proxy_protocol_any(&mut &bytes[..]).expect("should parse");
+            *hashed.entry(header.clone()).or_insert(0) += 1;
+            *sorted.entry(header).or_insert(0) += 1;
         }
+        // the same addresses in the other version are another key, but every UNKNOWN is the same
+        assert_eq!(hashed.len(), 201);
+        assert_eq!(sorted.len(), 201);
+        assert_eq!(hashed[&ProxyProtocolHeader::new_unknown(1)], 1000);
+        assert!(hashed.iter().filter(|&(header, _)| header.proto() == Proto::Tcp4).all(|(_, &count)| count == 10));
+        assert_eq!(sorted.keys().cloned().collect::<Vec<_>>(), {
+            let mut keys = hashed.keys().cloned().collect::<Vec<_>>();
+            keys.sort();
+            keys
+        });
+
+        let tcp4 = |source: &str| ProxyProtocolHeader::new(1, Proto::Tcp4, source.parse().unwrap(), "198.51.100.7:443".parse().unwrap());
+        let tcp6 = ProxyProtocolHeader::new(1, Proto::Tcp6, "[2001:db8::1]:4124".parse().unwrap(), "[2001:db8::2]:443".parse().unwrap());
+        let local = ProxyProtocolHeader::new_with_command(2, Proto::Tcp4, Command::Local, "192.0.2.1:4124".parse().unwrap(), "198.51.100.7:443".parse().unwrap());
+        let proxy = ProxyProtocolHeader::new(2, Proto::Tcp4, "192.0.2.1:4124".parse().unwrap(), "198.51.100.7:443".parse().unwrap());
+        let mut headers = vec![proxy.clone(), ProxyProtocolHeader::new_unknown(1), tcp6.clone(), local.clone(), tcp4("192.0.2.9:1"), tcp4("192.0.2.1:4124")];
+        headers.sort();
+        assert_eq!(headers, vec![tcp4("192.0.2.1:4124"), tcp4("192.0.2.9:1"), tcp6, ProxyProtocolHeader::new_unknown(1), local, proxy]);
+    }
+
+    #[test]
+    fn test_for_addrs_mixed_families() {
+        let header = ProxyProtocolHeader::for_addrs("192.0.2.1:4124".parse().unwrap(), "[2001:db8::2]:443".parse().unwrap());
+        assert_eq!(header.proto(), Proto::Tcp6);
+        assert_eq!(header.source_addr(), Some("[::ffff:192.0.2.1]:4124".parse().unwrap()));
+        assert_eq!(header.to_bytes(ProxyProtocolVersion::V1), b"PROXY TCP6 ::ffff:192.0.2.1 2001:db8::2 4124 443\r\n".to_vec());
     }
 
     #[test]
@@ -381,6 +2912,246 @@ mod tests {
         read_proxy_protocol_v2(&mut (b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a" as &[u8])).expect_err("should not parse");
     }
 
+    #[test]
+    fn test_truncated_headers() {
+        let v1 = b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n";
+        for &len in &[0, 1, 5, 6, 20, v1.len() - 1] {
+            let truncated = &v1[..len];
+            for result in [read_proxy_protocol_v1(&mut &truncated[..]), read_proxy_protocol_any(&mut &truncated[..])] {
+                match result {
+                    Err(ProxyReadError::UnexpectedEof { needed, got }) => assert_eq!((needed, got), (len + 1, len)),
+                    other => panic!("expected UnexpectedEof after {} bytes, got {:?}", len, other),
+                }
+            }
+        }
+
+        let v2 = b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x11\x00\x0c\x0a\x0b\x0c\x0d\x7f\x00\x00\x01\x22\xb8\x27\x0f";
+        for &(len, needed) in &[(1, 16), (11, 16), (12, 16), (15, 16), (16, 28), (20, 28), (27, 28)] {
+            let truncated = &v2[..len];
+            // the signature is checked a byte at a time
+            let needed = if len < 12 { len + 1 } else { needed };
+            for result in [read_proxy_protocol_v2(&mut &truncated[..]), read_proxy_protocol_any(&mut &truncated[..])] {
+                match result {
+                    Err(ProxyReadError::UnexpectedEof { needed: n, got }) => assert_eq!((n, got), (needed, len)),
+                    other => panic!("expected UnexpectedEof after {} bytes, got {:?}", len, other),
+                }
+            }
+        }
+        match read_proxy_protocol_v2(&mut &b""[..]) {
+            Err(ProxyReadError::UnexpectedEof { needed: 1, got: 0 }) => {},
+            other => panic!("expected UnexpectedEof, got {:?}", other),
+        }
+
+        // still an UnexpectedEof to code which only sees I/O errors
+        let err = read_proxy_protocol_v1(&mut &v1[..10]).unwrap_err();
+        assert_eq!(err.to_string(), "connection closed after 10 bytes of the header, with 11 needed");
+        assert_eq!(::std::io::Error::from(err).kind(), ::std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_proxy_protocol_v2_reserved_values() {
+        let valid = b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x11\x00\x0c\x0a\x0b\x0c\x0d\x7f\x00\x00\x01\x22\xb8\x27\x0f";
+        let with = |byte12: u8, byte13: u8| {
+            let mut bytes = valid.to_vec();
+            bytes[12] = byte12;
+            bytes[13] = byte13;
+            read_proxy_protocol_v2(&mut &bytes[..]).expect_err("should not parse")
+        };
+        for version in (0..16).filter(|&v| v != 2) {
+            let err = with(version << 4 | 0x01, 0x11);
+            assert!(matches!(err, ProxyReadError::BadVersion(v) if v == version), "{:?}", err);
+            assert!(err.to_string().contains(&format!("version={}", version)), "{}", err);
+        }
+        for command in 2..16 {
+            let err = with(0x20 | command, 0x11);
+            assert!(matches!(err, ProxyReadError::InvalidProtocol { command: c, family: 1, transport: 1 } if c == command), "{:?}", err);
+            assert!(err.to_string().contains(&format!("command={}", command)), "{}", err);
+        }
+        for family in 4..16 {
+            let err = with(0x21, family << 4 | 0x01);
+            assert!(matches!(err, ProxyReadError::InvalidProtocol { command: 1, family: f, transport: 1 } if f == family), "{:?}", err);
+            assert!(err.to_string().contains(&format!("family={}", family)), "{}", err);
+        }
+        for transport in 3..16 {
+            let err = with(0x21, 0x10 | transport);
+            assert!(matches!(err, ProxyReadError::InvalidProtocol { command: 1, family: 1, transport: t } if t == transport), "{:?}", err);
+            assert!(err.to_string().contains(&format!("transport={}", transport)), "{}", err);
+        }
+    }
+
+    /// A version 2 header from 192.0.2.1:4124 to 198.51.100.7:443, with an SSL TLV, a VPC
+    /// endpoint TLV and `padding` bytes of NOOP TLV after the addresses
+    fn padded_v2(padding: usize) -> Vec<u8> {
+        let mut header = b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x11\x00\x00\xc0\x00\x02\x01\xc6\x33\x64\x07\x10\x1c\x01\xbb".to_vec();
+        header.extend_from_slice(b"\x20\x00\x05\x01\x00\x00\x00\x00");
+        header.extend_from_slice(b"\xea\x00\x17\x01vpce-08d2bf15fefb65986");
+        header.extend_from_slice(&[0x04, (padding >> 8) as u8, padding as u8]);
+        header.resize(header.len() + padding, 0);
+        let len = (header.len() - 16) as u16;
+        header[14..16].copy_from_slice(&len.to_be_bytes());
+        header
+    }
+
+    #[test]
+    fn test_proxy_protocol_v2_large_blocks() {
+        for &padding in &[300, 1000, 16 * 1024 - 50] {
+            let mut bytes = padded_v2(padding);
+            bytes.extend_from_slice(b"GET /");
+            let mut rest = &bytes[..];
+            let header = read_proxy_protocol_any(&mut rest).expect("should parse");
+            assert_eq!(header.source_addr(), Some("192.0.2.1:4124".parse().unwrap()));
+            assert_eq!(header.dest_addr(), Some("198.51.100.7:443".parse().unwrap()));
+            assert!(header.ssl_info().unwrap().client_ssl());
+            assert_eq!(header.tlv_len, bytes.len() - 16 - 12 - 5);
+            assert_eq!(rest, b"GET /", "with {} bytes of padding", padding);
+        }
+
+        // past the cap, nothing more than the fixed header is read
+        let bytes = padded_v2(16 * 1024);
+        let mut rest = &bytes[..];
+        match read_proxy_protocol_v2(&mut rest) {
+            Err(ProxyReadError::InvalidProtocol { .. }) => {},
+            other => panic!("expected InvalidProtocol, got {:?}", other),
+        }
+        assert_eq!(rest.len(), bytes.len() - 16);
+
+        let small = ParseConfig::default().max_v2_block_len(256);
+        read_proxy_protocol_any_with(&mut &padded_v2(200)[..], &small).expect("should parse");
+        match read_proxy_protocol_any_with(&mut &padded_v2(300)[..], &small) {
+            Err(ProxyReadError::InvalidProtocol { .. }) => {},
+            other => panic!("expected InvalidProtocol, got {:?}", other),
+        }
+        assert!(explain(&padded_v2(300), ProxyProtocolVersion::V2).contains("parsed"), "{}", explain(&padded_v2(300), ProxyProtocolVersion::V2));
+    }
+
+    /// A version 2 `PROXY` header with an unspecified family, whose `len` byte address block
+    /// is all TLVs: an SSL TLV (if there is room) and a NOOP TLV padding out the rest
+    fn unspec_v2(len: usize) -> Vec<u8> {
+        let mut header = b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x00".to_vec();
+        header.extend_from_slice(&(len as u16).to_be_bytes());
+        if len >= 11 {
+            header.extend_from_slice(b"\x20\x00\x05\x01\x00\x00\x00\x00");
+            let padding = len - 11;
+            header.extend_from_slice(&[0x04, (padding >> 8) as u8, padding as u8]);
+        }
+        header.resize(16 + len, 0);
+        header
+    }
+
+    #[test]
+    fn test_proxy_protocol_v2_unspec_blocks() {
+        for &len in &[0, 32, 1024] {
+            let mut bytes = unspec_v2(len);
+            bytes.extend_from_slice(b"GET /");
+            for parse in [read_proxy_protocol_v2, read_proxy_protocol_any] {
+                let mut rest = &bytes[..];
+                let header = parse(&mut rest).expect("should parse");
+                assert_eq!((header.version(), header.proto(), header.command()), (2, Proto::Unknown, Command::Proxy));
+                assert_eq!((header.source_addr(), header.dest_addr()), (None, None));
+                assert_eq!(header.tlv_len, len);
+                assert_eq!(header.ssl_info().map(|ssl| ssl.client_ssl()), if len > 0 { Some(true) } else { None });
+                assert_eq!(rest, b"GET /", "the {} byte block should be consumed exactly", len);
+            }
+        }
+
+        // past the cap, the block is skipped unbuffered, up to the longest the format allows,
+        // keeping the TLVs there is room for (here, the SSL one but not the padding)
+        let small = ParseConfig::default().max_v2_block_len(256);
+        for &len in &[1024, 65535] {
+            let mut bytes = unspec_v2(len);
+            bytes.extend_from_slice(b"GET /");
+            for config in &[small, ParseConfig::default()] {
+                let mut rest = &bytes[..];
+                let header = read_proxy_protocol_v2_with(&mut rest, config).expect("should parse");
+                assert_eq!((header.proto(), header.command(), header.tlv_len), (Proto::Unknown, Command::Proxy, len));
+                assert_eq!(rest, b"GET /");
+                if len > config.max_v2_block_len {
+                    assert_eq!(header.tlvs().iter().map(|tlv| tlv.type_).collect::<Vec<_>>(), [0x20]);
+                } else {
+                    assert_eq!(header.tlvs().len(), 2);
+                }
+                assert!(header.ssl_info().unwrap().client_ssl());
+                let mut parser = HeaderParser::new(ProxyProtocolVersion::V2).parse_config(*config);
+                assert_eq!(parser.feed(&mut &bytes[..]).expect("should parse"), Some(header));
+            }
+            let local = [&bytes[..12], b"\x20", &bytes[13..]].concat();
+            assert_eq!(read_proxy_protocol_v2_with(&mut &local[..], &small).expect("should parse").command(), Command::Local);
+            assert!(explain(&bytes[..16 + len], ProxyProtocolVersion::V2).contains("parsed"), "{}", explain(&bytes[..16 + len], ProxyProtocolVersion::V2));
+        }
+        let truncated = &unspec_v2(1024)[..1000];
+        match read_proxy_protocol_v2_with(&mut &truncated[..], &small) {
+            Err(ProxyReadError::UnexpectedEof { needed: 1040, got: 1000 }) => {},
+            other => panic!("expected UnexpectedEof, got {:?}", other),
+        }
+
+        // many TLVs, kept in order until they no longer fit, whichever way the bytes arrive
+        let skipped = |tlvs: &[u8]| {
+            let mut bytes = b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x00".to_vec();
+            bytes.extend_from_slice(&(tlvs.len() as u16).to_be_bytes());
+            bytes.extend_from_slice(tlvs);
+            bytes
+        };
+        let many: Vec<u8> = (0..600u16).flat_map(|i| [0xe0, 0, 2, (i >> 8) as u8, i as u8]).collect();
+        let bytes = skipped(&many);
+        let header = read_proxy_protocol_v2_with(&mut &bytes[..], &small).expect("should parse");
+        assert_eq!(header.tlvs().len(), 51);
+        assert!(header.tlvs().iter().enumerate().all(|(i, tlv)| tlv.value == (i as u16).to_be_bytes()));
+        assert_eq!(header.tlv_len(), 3000);
+        let mut parser = HeaderParser::new(ProxyProtocolVersion::Any).parse_config(small);
+        let parsed = bytes.chunks(7).map(|chunk| parser.advance(chunk)).find(|poll| poll.is_ready());
+        assert!(matches!(parsed, Some(Poll::Ready(Ok((ref h, _)))) if *h == header));
+
+        // and those which run past the block fail, kept or not, as does a tail too short for
+        // a type and length
+        let overruns: Vec<(Vec<u8>, usize)> = vec![
+            ([&many[..], b"\x04\x00\x05abcd"].concat(), 3016),
+            ([&many[..], b"\x04\x00"].concat(), 3016),
+            ([&many[..], b"\x04"].concat(), 3016),
+            ([&b"\xe0\x00\x02ab\x04\x01\x40"[..], &[0; 300]].concat(), 21),
+            ([&b"\x04\xff\xff"[..], &[0; 300]].concat(), 16),
+        ];
+        for (tlvs, at) in overruns {
+            let bytes = skipped(&tlvs);
+            assert!(matches!(read_proxy_protocol_v2_with(&mut &bytes[..], &small), Err(ProxyReadError::MalformedTlv(a)) if a == at));
+            let mut parser = HeaderParser::new(ProxyProtocolVersion::V2).parse_config(small);
+            assert!(matches!(parser.advance(&bytes), Poll::Ready(Err(ProxyReadError::MalformedTlv(a))) if a == at));
+            let mut parser = HeaderParser::new(ProxyProtocolVersion::V2).parse_config(small);
+            assert!(matches!(parser.feed(&mut &bytes[..]), Err(ProxyReadError::MalformedTlv(a)) if a == at));
+        }
+    }
+
+    #[test]
+    fn test_proxy_protocol_v2_short_address_blocks() {
+        let families: &[(u8, usize, Proto)] = &[
+            (0x11, 12, Proto::Tcp4),
+            (0x21, 36, Proto::Tcp6),
+            (0x31, 216, Proto::Unix),
+        ];
+        for &(family, needed, proto) in families {
+            for addrlen in 0..needed {
+                // filled with something other than zeros, so nothing could pass for an address
+                let mut bytes = vec![0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a, 0x21, family];
+                bytes.extend_from_slice(&(addrlen as u16).to_be_bytes());
+                bytes.resize(16 + addrlen, 0x7f);
+                bytes.extend_from_slice(b"GET /");
+                let mut rest = &bytes[..];
+                match read_proxy_protocol_v2(&mut rest) {
+                    Err(ProxyReadError::ShortAddressBlock(p, len)) => assert_eq!((p, len), (proto, addrlen)),
+                    other => panic!("expected ShortAddressBlock for family {:#x} with {} bytes, got {:?}", family, addrlen, other),
+                }
+                assert!(explain(&bytes, ProxyProtocolVersion::V2).contains("too short"));
+
+                // LOCAL headers ignore their addresses, so they are fine without them
+                bytes[12] = 0x20;
+                let mut rest = &bytes[..];
+                let header = read_proxy_protocol_v2(&mut rest).expect("should parse");
+                assert_eq!((header.command(), header.proto(), header.source_addr()), (Command::Local, Proto::Unknown, None));
+                assert_eq!(rest, b"GET /");
+            }
+        }
+        assert_eq!(ProxyReadError::ShortAddressBlock(Proto::Tcp4, 4).to_string(), "address block of 4 bytes is too short for Tcp4 addresses");
+    }
+
     #[test]
     fn test_proxy_protocol_any() {
         let vectors = vec![
@@ -392,4 +3163,1003 @@ mod tests {
             assert_eq!(r, expected);
         }
     }
+
+    /// Serves its bytes, and then fails every read as a socket with nothing more to read
+    /// would, past its read timeout
+    struct BlockingReader<'a>(&'a [u8]);
+
+    impl<'a> ::std::io::Read for BlockingReader<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> ::std::io::Result<usize> {
+            if self.0.is_empty() {
+                return Err(::std::io::ErrorKind::WouldBlock.into());
+            }
+            self.0.read(buf)
+        }
+    }
+
+    #[test]
+    fn test_proxy_protocol_any_bad_signature() {
+        let signature = b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a";
+        for &diverges_at in &[2, 5, 11] {
+            let mut sent = signature[..diverges_at].to_vec();
+            sent.push(b'X');
+            match read_proxy_protocol_any(&mut BlockingReader(&sent)) {
+                Err(ProxyReadError::MissingLiteral(seen)) => assert_eq!(seen, sent),
+                other => panic!("expected MissingLiteral at byte {}, got {:?}", diverges_at, other),
+            }
+        }
+        // a lone CR is waited on, since it could still be a header
+        match read_proxy_protocol_any(&mut BlockingReader(b"\r")) {
+            Err(ref e) if e.is_timeout() => {},
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+        let err = read_proxy_protocol_any(&mut BlockingReader(b"\r\n\r\n\r")).unwrap_err();
+        assert_eq!(err.to_string(), "missing PROXY literal or signature; got 0d 0a 0d 0a 0d");
+    }
+
+    #[test]
+    fn test_proxy_protocol_v2_fails_fast() {
+        // short greetings from peers which then wait for a reply, each of which gives itself
+        // away at some byte; nothing after that is read, so the reader never blocks
+        let greetings: &[(&[u8], usize)] = &[
+            (b"\x00\x01\x02", 1),
+            (b"hi\n", 1),
+            (b"\x16\x03\x01", 1),
+            (b"\r\nX", 3),
+            (b"\r\n\r\n\x00\r\nQUIT\r", 12),
+            (b"PROXY TCP4 ", 2),
+        ];
+        for &(greeting, consumed) in greetings {
+            let mut reader = BlockingReader(greeting);
+            match read_proxy_protocol_v2(&mut reader) {
+                Err(ProxyReadError::MissingFirstByte) | Err(ProxyReadError::MissingLiteral(_)) | Err(ProxyReadError::LooksLikeTls) => {},
+                other => panic!("expected {:?} to be turned away, got {:?}", HexDump(greeting), other),
+            }
+            assert_eq!(greeting.len() - reader.0.len(), consumed, "for {:?}", HexDump(greeting));
+        }
+        match read_proxy_protocol_v2(&mut BlockingReader(b"\r\nX")) {
+            Err(ProxyReadError::MissingLiteral(seen)) => assert_eq!(seen, b"\r\nX"),
+            other => panic!("expected MissingLiteral, got {:?}", other),
+        }
+        // but a prefix of the signature, however short, is waited on
+        for len in 1..12 {
+            match read_proxy_protocol_v2(&mut BlockingReader(&PROXY_V2_SIGNATURE[..len])) {
+                Err(ref e) if e.is_timeout() => {},
+                other => panic!("expected a timeout after {} bytes, got {:?}", len, other),
+            }
+        }
+    }
+
+    /// Headers of each kind, with the versions they can be parsed as
+    fn parser_vectors() -> Vec<(Vec<u8>, &'static [ProxyProtocolVersion])> {
+        let (v1, v2, any): (&[_], &[_], &[_]) = (&[ProxyProtocolVersion::V1, ProxyProtocolVersion::Any], &[ProxyProtocolVersion::V2, ProxyProtocolVersion::Any], &[ProxyProtocolVersion::Any]);
+        let mut unix = b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x31\x00\xd8".to_vec();
+        unix.extend_from_slice(&[b'a'; 216]);
+        vec![
+            (b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n".to_vec(), v1),
+            (b"PROXY TCP6 2001:db8::1 2001:db8::2 4124 443\r\n".to_vec(), v1),
+            (b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\n".to_vec(), v1),
+            (b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x11\x00\x0c\xc0\x00\x02\x01\xc6\x33\x64\x07\x10\x1c\x01\xbb".to_vec(), v2),
+            (b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x20\x00\x00\x00".to_vec(), v2),
+            (unix, v2),
+            (padded_v2(1000), v2),
+            (unspec_v2(32), v2),
+            // and failures, which should fail just as they do when read all at once
+            (b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 99999\r\n".to_vec(), v1),
+            (b"GET / HTTP/1.1\r\n".to_vec(), any),
+            (b"\x16\x03\x01\x02\x00\x01\x00\x01\xfc\x03\x03".to_vec(), v1),
+            (b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x31\x11\x00\x0c".to_vec(), v2),
+        ]
+    }
+
+    /// Split `bytes` into chunks of pseudo-random sizes from 1 to 16, differing by `seed`
+    fn random_chunks(bytes: &[u8], seed: u64) -> Vec<&[u8]> {
+        let mut state = seed * 2 + 1;
+        let mut chunks = Vec::new();
+        let mut rest = bytes;
+        while !rest.is_empty() {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            let (chunk, after) = rest.split_at((1 + (state >> 60) as usize).min(rest.len()));
+            chunks.push(chunk);
+            rest = after;
+        }
+        chunks
+    }
+
+    /// Give `chunks` (then `GET /`) to a parser for `version` in turn, returning what it made
+    /// of them and the bytes it left
+    fn advance_by(chunks: &[&[u8]], version: ProxyProtocolVersion) -> (::std::result::Result<ProxyProtocolHeader, ProxyReadError>, Vec<u8>) {
+        let mut parser = HeaderParser::new(version);
+        let payload: &[u8] = b"GET /";
+        for (i, chunk) in chunks.iter().chain(Some(&payload)).enumerate() {
+            if let Poll::Ready(result) = parser.advance(chunk) {
+                let rest = result.as_ref().map(|&(_, used)| {
+                    let mut rest = chunk[used..].to_vec();
+                    for later in chunks.iter().chain(Some(&payload)).skip(i + 1) {
+                        rest.extend_from_slice(later);
+                    }
+                    rest
+                }).unwrap_or_default();
+                match (parser.advance(b"more"), &result) {
+                    (Poll::Ready(Ok((again, 0))), Ok((header, _))) => assert_eq!(&again, header),
+                    (Poll::Ready(Err(again)), Err(e)) => assert_eq!(again.to_string(), e.to_string()),
+                    (again, _) => panic!("should be done with, got {:?}", again),
+                }
+                return (result.map(|(header, _)| header), rest);
+            }
+        }
+        panic!("should have finished with {:?}", chunks);
+    }
+
+    #[test]
+    fn test_header_parser_advance() {
+        for (bytes, versions) in parser_vectors() {
+            for &version in versions {
+                let mut all = bytes.clone();
+                all.extend_from_slice(b"GET /");
+                let expected = parse_prefix(&all, version, &ParseConfig::default()).map(|(header, _)| header);
+                let bytewise: Vec<&[u8]> = bytes.chunks(1).collect();
+                let mut splits = vec![vec![&bytes[..]], bytewise];
+                splits.extend((0..20).map(|seed| random_chunks(&bytes, seed)));
+                for chunks in splits {
+                    let (parsed, rest) = advance_by(&chunks, version);
+                    match (&parsed, &expected) {
+                        (Ok(parsed), Ok(expected)) => {
+                            assert_eq!(parsed, expected);
+                            assert_eq!(rest, b"GET /", "should take exactly the header");
+                        },
+                        (Err(parsed), Err(expected)) => assert_eq!(parsed.to_string(), expected.to_string()),
+                        _ => panic!("{:?} as {:?} in {:?}: {:?}, but read at once {:?}", HexDump(&bytes), version, chunks, parsed, expected),
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_slice_parsers() {
+        for (bytes, versions) in parser_vectors() {
+            let mut all = bytes.clone();
+            all.extend_from_slice(b"GET /");
+            for &version in versions {
+                let parse = |buf: &[u8]| match version {
+                    ProxyProtocolVersion::V1 => parse_v1(buf),
+                    ProxyProtocolVersion::V2 => parse_v2(buf),
+                    ProxyProtocolVersion::Any => parse_any(buf),
+                };
+                let expected = match version {
+                    ProxyProtocolVersion::V1 => read_proxy_protocol_v1(&mut &all[..]),
+                    ProxyProtocolVersion::V2 => read_proxy_protocol_v2(&mut &all[..]),
+                    ProxyProtocolVersion::Any => read_proxy_protocol_any(&mut &all[..]),
+                };
+                match (parse(&all), &expected) {
+                    (Poll::Ready(Ok((header, len))), Ok(expected)) => {
+                        assert_eq!(&header, expected);
+                        assert_eq!(&all[len..], b"GET /", "should take exactly the header");
+                        // every prefix of a header needs more, however far it got
+                        for end in 0..bytes.len() {
+                            assert!(parse(&bytes[..end]).is_pending(), "{:?} as {:?} should need more", HexDump(&bytes[..end]), version);
+                        }
+                    },
+                    (Poll::Ready(Err(e)), Err(expected)) => assert_eq!(e.to_string(), expected.to_string()),
+                    (parsed, _) => panic!("{:?} as {:?}: {:?}, but read {:?}", HexDump(&bytes), version, parsed, expected),
+                }
+            }
+        }
+        assert!(parse_v1(b"").is_pending());
+        match parse_v2(b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n") {
+            Poll::Ready(Err(ProxyReadError::MissingFirstByte)) => {},
+            other => panic!("expected MissingFirstByte, got {:?}", other),
+        }
+        let lenient = ParseConfig::lenient();
+        assert!(parse_with(b"proxy tcp4 192.0.2.1 198.51.100.7 4124 443\n", ProxyProtocolVersion::V1, &lenient).is_ready());
+        // where the default is still looking for a CRLF
+        assert!(parse_v1(b"proxy tcp4 192.0.2.1 198.51.100.7 4124 443\n").is_pending());
+    }
+
+    /// Reads from each of its chunks in turn, failing with `WouldBlock` before each
+    struct Trickle<'a>(Vec<&'a [u8]>, bool);
+
+    impl<'a> ::std::io::Read for Trickle<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> ::std::io::Result<usize> {
+            self.1 = !self.1;
+            if self.1 && !self.0.is_empty() {
+                return Err(::std::io::ErrorKind::WouldBlock.into());
+            }
+            match self.0.first_mut() {
+                Some(chunk) => {
+                    let n = chunk.read(buf)?;
+                    if chunk.is_empty() {
+                        self.0.remove(0);
+                    }
+                    Ok(n)
+                },
+                None => Ok(0),
+            }
+        }
+    }
+
+    #[test]
+    fn test_header_parser_feed() {
+        for (bytes, versions) in parser_vectors() {
+            for &version in versions {
+                let expected = parse_prefix(&bytes, version, &ParseConfig::default()).map(|(header, _)| header);
+                for seed in 0..10 {
+                    let mut all = bytes.clone();
+                    all.extend_from_slice(b"GET /");
+                    let mut r = Trickle(random_chunks(&all, seed), false);
+                    let mut parser = HeaderParser::new(version);
+                    let mut would_block = 0;
+                    let parsed = loop {
+                        match parser.feed(&mut r) {
+                            Ok(Some(header)) => break Ok(header),
+                            Ok(None) => would_block += 1,
+                            Err(e) => break Err(e),
+                        }
+                    };
+                    assert!(would_block > 0);
+                    match (&parsed, &expected) {
+                        (Ok(parsed), Ok(expected)) => {
+                            assert_eq!(parsed, expected);
+                            assert_eq!(parser.len(), bytes.len());
+                            let mut rest = Vec::new();
+                            while let Err(e) = r.read_to_end(&mut rest) {
+                                assert_eq!(e.kind(), ::std::io::ErrorKind::WouldBlock);
+                            }
+                            assert_eq!(rest, b"GET /", "should read exactly the header");
+                        },
+                        (Err(parsed), Err(expected)) => assert_eq!(parsed.to_string(), expected.to_string()),
+                        _ => panic!("{:?} as {:?}: {:?}, but read at once {:?}", HexDump(&bytes), version, parsed, expected),
+                    }
+                }
+            }
+        }
+
+        // a connection closed part way through is counted as the blocking parsers count it
+        let mut parser = HeaderParser::new(ProxyProtocolVersion::V2);
+        assert!(parser.is_empty());
+        let header = padded_v2(100);
+        assert!(parser.advance(&header[..10]).is_pending());
+        match parser.feed(&mut &header[10..40]) {
+            Err(ProxyReadError::UnexpectedEof { needed, got: 40 }) => assert_eq!(needed, header.len()),
+            other => panic!("expected UnexpectedEof, got {:?}", other),
+        }
+        let mut lenient = HeaderParser::new(ProxyProtocolVersion::V1).parse_config(ParseConfig::lenient());
+        assert!(lenient.advance(b"proxy tcp4 192.0.2.1 198.51.100.7 4124 443\n").is_ready());
+    }
+
+    #[test]
+    fn test_header_parser_skips_unspec_blocks() {
+        /// A reader which notes the most it was asked to read at once
+        struct Widest<'a>(&'a [u8], usize);
+
+        impl<'a> Read for Widest<'a> {
+            fn read(&mut self, buf: &mut [u8]) -> ::std::io::Result<usize> {
+                self.1 = self.1.max(buf.len());
+                self.0.read(buf)
+            }
+        }
+
+        let mut bytes = unspec_v2(65535);
+        bytes.extend_from_slice(b"GET /");
+        for &version in &[ProxyProtocolVersion::V2, ProxyProtocolVersion::Any] {
+            let mut r = Widest(&bytes, 0);
+            let mut parser = HeaderParser::new(version);
+            let header = parser.feed(&mut r).expect("should parse").expect("should not block");
+            assert_eq!((header.proto(), header.command(), header.tlv_len()), (Proto::Unknown, Command::Proxy, 65535));
+            assert_eq!(r.0, b"GET /", "should read exactly the header");
+            assert!(r.1 <= 512, "read {} bytes at once", r.1);
+            assert_eq!(parser.len(), 16);
+            // and again, without reading any more
+            assert_eq!(parser.feed(&mut r).expect("should parse"), Some(header.clone()));
+            assert_eq!(r.0, b"GET /");
+
+            let mut parser = HeaderParser::new(version);
+            let mut used = 0;
+            for chunk in bytes.chunks(1000) {
+                match parser.advance(chunk) {
+                    Poll::Ready(result) => {
+                        used += result.expect("should parse").1;
+                        break;
+                    },
+                    Poll::Pending => used += chunk.len(),
+                }
+                assert!(parser.len() <= 16, "holding {} bytes", parser.len());
+            }
+            assert_eq!(used, 16 + 65535);
+            assert!(matches!(parser.advance(b"more"), Poll::Ready(Ok((ref h, 0))) if *h == header));
+
+            let truncated = &bytes[..40000];
+            match HeaderParser::new(version).feed(&mut &truncated[..]) {
+                Err(ProxyReadError::UnexpectedEof { needed: 65551, got: 40000 }) => {},
+                other => panic!("expected UnexpectedEof, got {:?}", other),
+            }
+        }
+        // though one longer than max_header_len is refused before any of it is skipped
+        let mut parser = HeaderParser::new(ProxyProtocolVersion::V2).parse_config(ParseConfig::default().max_header_len(Some(20)));
+        assert!(matches!(parser.advance(&unspec_v2(65535)), Poll::Ready(Err(ProxyReadError::HeaderTooLong { .. }))));
+    }
+
+    #[test]
+    fn test_detect_version() {
+        for &(literal, version) in &[(PROXY_V1_PREFIX, DetectedVersion::V1), (&PROXY_V2_SIGNATURE[..], DetectedVersion::V2)] {
+            let mut header = literal.to_vec();
+            header.extend_from_slice(b"\x21\x11 and the rest");
+            for len in 0..literal.len() {
+                assert_eq!(detect_version(&literal[..len]), Some(DetectedVersion::NeedMoreData), "{:?}", HexDump(&literal[..len]));
+                // and as soon as one byte is off, it is neither
+                let mut wrong = literal[..=len].to_vec();
+                wrong[len] ^= 0x80;
+                assert_eq!(detect_version(&wrong), None, "{:?}", HexDump(&wrong));
+            }
+            for len in literal.len()..header.len() {
+                assert_eq!(detect_version(&header[..len]), Some(version));
+            }
+        }
+        for prefix in [&b"PROXY"[..], b"\r", b"\r\n\r\n\x00"] {
+            assert_eq!(detect_version(prefix), Some(DetectedVersion::NeedMoreData));
+        }
+        for prefix in [&b"PROXYX"[..], b"proxy ", b"\x16\x03\x01", b"GET / HTTP/1.1\r\n", b"\r\n\r\n\r"] {
+            assert_eq!(detect_version(prefix), None);
+        }
+        assert_eq!(ProxyProtocolHeader::new_unknown(2).to_bytes(ProxyProtocolVersion::V2)[..12], PROXY_V2_SIGNATURE);
+    }
+
+    #[test]
+    fn test_guess_client() {
+        assert_eq!(guess_client(b"GET / HTTP/1.1\r\n"), ClientGuess::Http);
+        assert_eq!(guess_client(b"POST /upload HTTP/1.1\r\n"), ClientGuess::Http);
+        assert_eq!(guess_client(b"PRI * HTTP/2.0\r\n\r\nSM\r\n"), ClientGuess::Http);
+        assert_eq!(guess_client(b"\x16\x03\x01\x02\x00\x01\x00\x01\xfc\x03\x03"), ClientGuess::Tls);
+        assert_eq!(guess_client(b"GETTY"), ClientGuess::Unknown);
+        assert_eq!(guess_client(b"\x00\xff\x13\x37"), ClientGuess::Unknown);
+        assert_eq!(guess_client(b""), ClientGuess::Unknown);
+    }
+
+    #[test]
+    fn test_tls_client_hello() {
+        // the starts of ClientHellos as sent by curl (a TLS 1.0 record, since the version in
+        // the record header is kept low for middleboxes) and by an old TLS 1.2 only client
+        let mut hellos = vec![
+            b"\x16\x03\x01\x02\x00\x01\x00\x01\xfc\x03\x03".to_vec(),
+            b"\x16\x03\x03\x00\xdc\x01\x00\x00\xd8\x03\x03".to_vec(),
+        ];
+        for hello in &mut hellos {
+            // the client random and the start of the session ID
+            hello.extend((0..32).map(|i| i as u8 * 7));
+            hello.extend_from_slice(b"\x20\x9d\x0e\x4f\xb1\xa2\x2d\xc4\x31\x51\x8d\x0d\x0a");
+        }
+        for hello in &hellos {
+            // each reader stops where it would have anyway, or sooner
+            for &(version, consumed) in &[(ProxyProtocolVersion::V1, 3), (ProxyProtocolVersion::V2, 1), (ProxyProtocolVersion::Any, 1)] {
+                let mut rest = &hello[..];
+                let result = match version {
+                    ProxyProtocolVersion::V1 => read_proxy_protocol_v1(&mut rest),
+                    ProxyProtocolVersion::V2 => read_proxy_protocol_v2(&mut rest),
+                    ProxyProtocolVersion::Any => read_proxy_protocol_any(&mut rest),
+                };
+                match result {
+                    Err(ProxyReadError::LooksLikeTls) => {},
+                    other => panic!("expected LooksLikeTls from {:?}, got {:?}", version, other),
+                }
+                assert_eq!(hello.len() - rest.len(), consumed, "{:?}", version);
+                assert!(explain(hello, version).contains("peer appears to be speaking TLS"), "{}", explain(hello, version));
+            }
+            match parse_v2_datagram(hello) {
+                Err(ProxyReadError::LooksLikeTls) => {},
+                other => panic!("expected LooksLikeTls, got {:?}", other),
+            }
+        }
+        assert_eq!(ProxyReadError::LooksLikeTls.to_string(), "peer appears to be speaking TLS, not PROXY protocol");
+
+        // other record types are not mistaken for a handshake, and are read as before
+        match read_proxy_protocol_v1(&mut &b"\x16\x05\x01 not a record\r\n"[..]) {
+            Err(ProxyReadError::MissingLiteral(_)) => {},
+            other => panic!("expected MissingLiteral, got {:?}", other),
+        }
+        match read_proxy_protocol_v1(&mut &b"\x16\r\n"[..]) {
+            Err(ProxyReadError::MissingLiteral(_)) => {},
+            other => panic!("expected MissingLiteral, got {:?}", other),
+        }
+        match read_proxy_protocol_v2(&mut &b"\x15\x03\x03\x00\x02\x02\x28 an alert, padded"[..]) {
+            Err(ProxyReadError::MissingFirstByte) => {},
+            other => panic!("expected MissingFirstByte, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_http_requests() {
+        for method in &["GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH"] {
+            let request = format!("{} / HTTP/1.1\r\nHost: localhost\r\n\r\n", method);
+            let request = request.as_bytes();
+            // every reader stops at the space after the method
+            for &(version, consumed) in &[(ProxyProtocolVersion::V1, method.len() + 1), (ProxyProtocolVersion::V2, method.len() + 1), (ProxyProtocolVersion::Any, method.len() + 1)] {
+                let mut rest = request;
+                let result = match version {
+                    ProxyProtocolVersion::V1 => read_proxy_protocol_v1(&mut rest),
+                    ProxyProtocolVersion::V2 => read_proxy_protocol_v2(&mut rest),
+                    ProxyProtocolVersion::Any => read_proxy_protocol_any(&mut rest),
+                };
+                match result {
+                    Err(ProxyReadError::LooksLikeHttp(m)) => assert_eq!(m, *method),
+                    other => panic!("expected LooksLikeHttp from {:?} for {}, got {:?}", version, method, other),
+                }
+                assert_eq!(request.len() - rest.len(), consumed, "{:?} for {}", version, method);
+                assert!(explain(request, version).contains(&format!("sending an HTTP {} request", method)), "{}", explain(request, version));
+            }
+        }
+
+        // words which only start like methods are not requests
+        for line in &[&b"GETTY\r\n"[..], b"PUTTING\r\n", b"get / HTTP/1.1\r\n"] {
+            match read_proxy_protocol_any(&mut &line[..]) {
+                Err(ProxyReadError::MissingFirstByte) | Err(ProxyReadError::MissingLiteral(_)) => {},
+                other => panic!("expected {:?} not to look like HTTP, got {:?}", String::from_utf8_lossy(line), other),
+            }
+        }
+        assert_eq!(ProxyReadError::LooksLikeHttp("GET").to_string(), "peer appears to be sending an HTTP GET request, not PROXY protocol");
+    }
+
+    #[test]
+    fn test_explain_success() {
+        assert_eq!(explain(b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\nGET /", ProxyProtocolVersion::Any), "\
+version: v1 (detected from first byte 'P')
+result: parsed a 44 byte header
+  command: Proxy
+  protocol: Tcp4
+  source: 192.0.2.1:4124
+  destination: 198.51.100.7:443
+");
+    }
+
+    #[test]
+    fn test_explain_bad_literal() {
+        assert_eq!(explain(b"PROXI TCP4 192.0.2.1 198.51.100.7 4124 443\r\n", ProxyProtocolVersion::V1), "\
+version: v1 (as configured)
+error at byte 0: bad PROXY literal (field 1)
+  expected: \"PROXY\"
+  found: \"PROXI\" (bytes 0..5)
+");
+        assert_eq!(explain(b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x55\x0a\x21\x11\x00\x0c", ProxyProtocolVersion::Any), "\
+version: v2 (detected from first byte 0x0d)
+error at byte 10: bad signature
+  expected: 0x54
+  found: 0x55 ('U')
+");
+        assert_eq!(explain(b"get / HTTP/1.1\r\n", ProxyProtocolVersion::Any), "\
+version: unknown; first byte 0x67 ('g') is neither 'P' (v1) nor 0x0d (v2)
+");
+        assert_eq!(explain(b"GET / HTTP/1.1\r\n", ProxyProtocolVersion::Any), "\
+version: none; the bytes start with an HTTP request line
+error at byte 0: peer appears to be sending an HTTP GET request, not PROXY protocol
+");
+    }
+
+    #[test]
+    fn test_explain_bad_port() {
+        assert_eq!(explain(b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 65536\r\n", ProxyProtocolVersion::V1), "\
+version: v1 (as configured)
+error at byte 39: bad destination port (field 6)
+  expected: a decimal port number from 0 to 65535
+  found: \"65536\" (bytes 39..44)
+  cause: number too large to fit in target type
+");
+        assert_eq!(explain(b"PROXY TCP4 192.0.2.1 198.51.100.7 4124\r\n", ProxyProtocolVersion::V1), "\
+version: v1 (as configured)
+error at byte 38: the line ends after 5 fields
+  expected field 6 (destination port): a decimal port number from 0 to 65535
+");
+    }
+
+    #[test]
+    fn test_explain_short_v2_address_block() {
+        assert_eq!(explain(b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x11\x00\x0c\x0a\x0b\x0c\x0d\x7f\x00", ProxyProtocolVersion::V2), "\
+version: v2 (as configured)
+fixed header: version 2, command 1, family 1, transport 1, address block 12 bytes
+error at byte 22: ran out of bytes in the address block
+  expected: 12 bytes at bytes 16..28
+  found: 6 bytes
+");
+        assert_eq!(explain(b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x11\x00\x04\x0a\x0b\x0c\x0d", ProxyProtocolVersion::V2), "\
+version: v2 (as configured)
+fixed header: version 2, command 1, family 1, transport 1, address block 4 bytes
+error at byte 14: address block too short for the address family
+  expected: at least 12 bytes
+  found: 4 bytes
+");
+        assert_eq!(explain(&tcp4_with_tlvs(b"\x04\x00\x00\x02\x00\x09abc"), ProxyProtocolVersion::V2), "\
+version: v2 (as configured)
+fixed header: version 2, command 1, family 1, transport 1, address block 21 bytes
+error at byte 31: TLV runs past the end of the address block
+  expected: TLVs ending by byte 37
+  found: 6 bytes left for this one
+");
+    }
+
+    fn unix_header(source: &UnixPath, dest: &UnixPath) -> Vec<u8> {
+        let mut header = b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x31\x00\xd8".to_vec();
+        header.extend_from_slice(&source.to_bytes().expect("should encode"));
+        header.extend_from_slice(&dest.to_bytes().expect("should encode"));
+        header
+    }
+
+    #[test]
+    fn test_unix_paths_round_trip() {
+        let long_name: Vec<u8> = (1..108).map(|i| i as u8).collect();
+        let paths = vec![
+            UnixPath::Pathname("/run/lb.sock".into()),
+            UnixPath::Pathname("".into()),
+            UnixPath::Abstract(b"lb".to_vec()),
+            UnixPath::Abstract(b"with\0nul".to_vec()),
+            UnixPath::Abstract(long_name),
+        ];
+        for source in &paths {
+            let dest = UnixPath::Abstract(b"backend".to_vec());
+            let header = read_proxy_protocol_v2(&mut &unix_header(source, &dest)[..]).expect("should parse");
+            assert_eq!(header.proto, Proto::Unix);
+            assert_eq!(header.source_path(), Some(source));
+            assert_eq!(header.dest_path(), Some(&dest));
+            assert_eq!(header.source_addr(), None);
+        }
+    }
+
+    #[test]
+    fn test_unix_addrs() {
+        let block = |source: &[u8], dest: &[u8]| {
+            let mut header = b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x31\x00\xd8".to_vec();
+            for field in &[source, dest] {
+                let start = header.len();
+                header.extend_from_slice(field);
+                header.resize(start + 108, 0);
+            }
+            read_proxy_protocol_v2(&mut &header[..]).expect("should parse")
+        };
+        let header = block(b"/run/haproxy/frontend.sock", b"/run/app.sock");
+        assert_eq!(header.unix_addrs(), Some(("/run/haproxy/frontend.sock".into(), "/run/app.sock".into())));
+        assert_eq!((header.source_addr(), header.dest_addr()), (None, None));
+        // an unterminated path takes up the whole field
+        let header = block(&[b'a'; 108], b"");
+        assert_eq!(header.unix_addrs(), Some((String::from_utf8(vec![b'a'; 108]).unwrap().into(), "".into())));
+        // abstract sockets have no path
+        assert_eq!(block(b"\0lb", b"/run/app.sock").unix_addrs(), None);
+        assert_eq!(ProxyProtocolHeader::new_unknown(2).unix_addrs(), None);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            let header = block(b"/run/\xff\xfe.sock", b"/run/app.sock");
+            let (source, _) = header.unix_addrs().expect("should have paths");
+            assert_eq!(source.as_os_str().as_bytes(), b"/run/\xff\xfe.sock");
+        }
+    }
+
+    #[test]
+    fn test_unix_path_fields() {
+        // trailing NUL padding is not part of an abstract name, and a path ends at its NUL
+        let mut field = [0u8; 108];
+        field[1..4].copy_from_slice(b"abc");
+        assert_eq!(UnixPath::from_bytes(&field), UnixPath::Abstract(b"abc".to_vec()));
+        field[0] = b'x';
+        assert_eq!(UnixPath::from_bytes(&field), UnixPath::Pathname("xabc".into()));
+        // a full-length path need not be NUL-terminated
+        let full = [b'a'; 108];
+        assert_eq!(UnixPath::from_bytes(&full).to_bytes(), Some(full));
+
+        assert_eq!(UnixPath::Abstract(vec![b'a'; 108]).to_bytes(), None);
+        assert_eq!(UnixPath::Abstract(Vec::new()).to_bytes(), None);
+        assert_eq!(UnixPath::Abstract(b"nul\0".to_vec()).to_bytes(), None);
+        assert_eq!(UnixPath::Pathname("a\0b".into()).to_bytes(), None);
+    }
+
+    #[test]
+    fn test_parse_v2_datagram() {
+        let mut datagram = b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x12\x00\x0c\x0a\x0b\x0c\x0d\x7f\x00\x00\x01\x22\xb8\x00\x35".to_vec();
+        datagram.extend_from_slice(b"\x12\x34\x01\x00 a DNS query");
+        let (header, payload) = parse_v2_datagram(&datagram).expect("should parse");
+        assert_eq!(header, ProxyProtocolHeader::new(2, Proto::Udp4, "10.11.12.13:8888".parse().unwrap(), "127.0.0.1:53".parse().unwrap()));
+        assert_eq!(header.transport(), Some(Transport::Dgram));
+        assert_eq!(payload, b"\x12\x34\x01\x00 a DNS query");
+
+        // TLVs are kept on the header
+        let mut datagram = b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x12\x00\x13\x0a\x0b\x0c\x0d\x7f\x00\x00\x01\x22\xb8\x00\x35".to_vec();
+        datagram.extend_from_slice(b"\x05\x00\x04\xde\xad\xbe\xef payload");
+        let (header, payload) = parse_v2_datagram(&datagram).expect("should parse");
+        assert_eq!(header.tlvs().len(), 1);
+        assert_eq!(header.tlvs()[0].value, b"\xde\xad\xbe\xef");
+        assert_eq!(payload, b" payload");
+
+        // even when DGRAM is refused on streams
+        match read_proxy_protocol_any_with(&mut &datagram[..], &ParseConfig::strict()) {
+            Err(ProxyReadError::InvalidProtocol { .. }) => {},
+            other => panic!("expected InvalidProtocol, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_proxy_protocol_v2_dgram_on_streams() {
+        let vectors = vec![
+            (b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x12\x00\x0c\x0a\x0b\x0c\x0d\x7f\x00\x00\x01\x22\xb8\x00\x35".to_vec(),
+             ProxyProtocolHeader::new(2, Proto::Udp4, "10.11.12.13:8888".parse().unwrap(), "127.0.0.1:53".parse().unwrap())),
+            (b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x22\x00\x24\x20\x01\x0d\xb8\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x20\x01\x0d\xb8\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x35\x10\x1c\x00\x35".to_vec(),
+             ProxyProtocolHeader::new(2, Proto::Udp6, "[2001:db8::1]:4124".parse().unwrap(), "[2001:db8::35]:53".parse().unwrap())),
+        ];
+        for (bytestr, expected) in vectors {
+            let r = read_proxy_protocol_v2(&mut bytestr.as_slice()).expect("should parse");
+            assert_eq!(r.transport(), Some(Transport::Dgram));
+            assert_eq!(r, expected);
+            assert_eq!(read_proxy_protocol_any(&mut bytestr.as_slice()).expect("should parse"), expected);
+            assert_eq!(r.to_bytes(ProxyProtocolVersion::V2), bytestr);
+
+            let strict = ParseConfig::default().reject_dgram(true);
+            match read_proxy_protocol_any_with(&mut bytestr.as_slice(), &strict) {
+                Err(ProxyReadError::InvalidProtocol { .. }) => {},
+                other => panic!("expected InvalidProtocol, got {:?}", other),
+            }
+        }
+        let stream = read_proxy_protocol_v2(&mut &b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x11\x00\x0c\x0a\x0b\x0c\x0d\x7f\x00\x00\x01\x22\xb8\x27\x0f"[..]).expect("should parse");
+        assert_eq!(stream.transport(), Some(Transport::Stream));
+        assert_eq!(ProxyProtocolHeader::new_unknown(2).transport(), None);
+    }
+
+    #[test]
+    fn test_parse_v2_datagram_truncated() {
+        let datagram = b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x12\x00\x0c\x0a\x0b\x0c\x0d\x7f\x00";
+        match parse_v2_datagram(datagram) {
+            Err(ProxyReadError::UnexpectedEof { needed: 28, got: 22 }) => {},
+            other => panic!("expected an UnexpectedEof, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_version_from_str() {
+        assert_eq!("v1".parse(), Ok(ProxyProtocolVersion::V1));
+        assert_eq!("V2".parse(), Ok(ProxyProtocolVersion::V2));
+        assert_eq!("2".parse(), Ok(ProxyProtocolVersion::V2));
+        assert_eq!("Any".parse(), Ok(ProxyProtocolVersion::Any));
+        assert!("v3".parse::<ProxyProtocolVersion>().is_err());
+        assert!("".parse::<ProxyProtocolVersion>().is_err());
+    }
+
+    #[test]
+    fn test_errors_are_send_sync() {
+        fn assert_send_sync<E: ::std::error::Error + Send + Sync + 'static>() {}
+        assert_send_sync::<ProxyReadError>();
+        assert_send_sync::<super::ParseVersionError>();
+        let boxed: Box<dyn (::std::error::Error) + Send + Sync> = ProxyReadError::MissingLiteral(Vec::new()).into();
+        assert!(boxed.downcast_ref::<ProxyReadError>().is_some());
+    }
+
+    #[test]
+    fn test_ssl_tlv() {
+        let header = |tlvs: &[u8]| read_proxy_protocol_v2(&mut &tcp4_with_tlvs(tlvs)[..]).expect("should parse");
+        let verified = header(b"\x20\x00\x05\x03\x00\x00\x00\x00");
+        let ssl = verified.ssl_info().expect("should have SSL TLV");
+        assert!(ssl.client_ssl() && ssl.client_cert_conn() && !ssl.client_cert_sess());
+        assert_eq!(ssl.verify(), 0);
+        assert!(ssl.cert_verified());
+        assert_eq!(verified.tlv_len(), 8);
+
+        // after a NOOP TLV, and with a sub-TLV (PP2_SUBTYPE_SSL_VERSION) of its own
+        let failed = header(b"\x04\x00\x01\x00\x20\x00\x0c\x05\x00\x00\x00\x01\x21\x00\x04TLS3");
+        let ssl = failed.ssl_info().expect("should have SSL TLV");
+        assert!(ssl.client_cert_sess());
+        assert!(!ssl.cert_verified());
+
+        // TLS without a client certificate
+        assert!(!header(b"\x20\x00\x05\x01\x00\x00\x00\x00").ssl_info().unwrap().cert_verified());
+        assert_eq!(header(b"").ssl_info(), None);
+        assert_eq!(header(b"\x04\x00\x01\x00").ssl_info(), None);
+        // an SSL TLV too short for its fields, and one running past the block
+        assert_eq!(header(b"\x20\x00\x02\x03\x00").ssl_info(), None);
+        let truncated = read_proxy_protocol_v2(&mut &tcp4_with_tlvs(b"\x20\x00\x09\x03\x00\x00\x00\x00")[..]);
+        assert!(matches!(truncated, Err(ProxyReadError::MalformedTlv(28))));
+        assert_eq!(read_proxy_protocol_v1(&mut &b"PROXY TCP4 1.2.3.4 5.6.7.8 80 90\r\n"[..]).unwrap().ssl_info(), None);
+    }
+
+    /// A version 2 TCP over IPv4 header, ending with `tlvs` (which start at byte 28)
+    fn tcp4_with_tlvs(tlvs: &[u8]) -> Vec<u8> {
+        let mut buf = b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x11".to_vec();
+        buf.extend_from_slice(&(12 + tlvs.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&[1, 2, 3, 4, 10, 0, 0, 1, 0, 56, 1, 187]);
+        buf.extend_from_slice(tlvs);
+        buf
+    }
+
+    #[test]
+    fn test_tlvs() {
+        let tlv = |type_: u8, value: &[u8]| Tlv { type_, value: value.to_vec() };
+        let parse = |tlvs: &[u8]| read_proxy_protocol_v2(&mut &tcp4_with_tlvs(tlvs)[..]);
+
+        let none = parse(b"").expect("should parse");
+        assert_eq!(none.tlvs(), &[]);
+        assert_eq!(none.tlv_len(), 0);
+        assert_eq!(parse(b"\x02\x00\x0bexample.com").unwrap().tlvs(), &[tlv(0x02, b"example.com")]);
+
+        // in the order sent, unknown and repeated types included
+        let many = parse(b"\xe0\x00\x02hi\x05\x00\x00\x04\x00\x01\x00\x20\x00\x05\x01\x00\x00\x00\x00\xe0\x00\x01!").unwrap();
+        assert_eq!(many.tlvs(), &[
+            tlv(0xe0, b"hi"),
+            tlv(0x05, b""),
+            tlv(0x04, b"\x00"),
+            tlv(0x20, b"\x01\x00\x00\x00\x00"),
+            tlv(0xe0, b"!"),
+        ]);
+        assert_eq!(many.tlv_len(), 24);
+        assert!(many.ssl_info().is_some());
+        let mut encoded = Vec::new();
+        many.encode(&mut encoded);
+        assert_eq!(ProxyProtocolHeader::decode(&mut FieldReader(&encoded)).as_ref(), Some(&many));
+        assert_eq!(many.without_tlvs().tlvs(), &[]);
+
+        // a value running past the block, and bytes too few for a type and length, at the
+        // offset of the TLV they would have been
+        assert!(matches!(parse(b"\x02\x00\x0cexample.com"), Err(ProxyReadError::MalformedTlv(28))));
+        assert!(matches!(parse(b"\x04\x00\x00\xe0\x00"), Err(ProxyReadError::MalformedTlv(31))));
+        assert!(matches!(parse(b"\x04\x00\x00\xe0"), Err(ProxyReadError::MalformedTlv(31))));
+        let mut unspec = b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x00\x00\x04\x04\x00\x02\x00".to_vec();
+        assert!(matches!(read_proxy_protocol_v2(&mut &unspec[..]), Err(ProxyReadError::MalformedTlv(16))));
+        unspec[18] = 1;
+        assert_eq!(read_proxy_protocol_v2(&mut &unspec[..]).unwrap().tlvs(), &[tlv(0x04, b"\x00")]);
+        assert_eq!(read_proxy_protocol_v1(&mut &b"PROXY TCP4 1.2.3.4 5.6.7.8 80 90\r\n"[..]).unwrap().tlvs(), &[]);
+    }
+
+    #[test]
+    fn test_parse_config_presets() {
+        let default = ParseConfig::default();
+        let strict = ParseConfig::strict();
+        let lenient = ParseConfig::lenient();
+        let flags = |c: ParseConfig| [c.reject_noncanonical_ports, c.reject_trailing_fields, c.reject_family_mismatch,
+                                      c.reject_overlong_lines, c.reject_dgram, c.allow_lowercase,
+                                      c.allow_lf_terminator, c.allow_bracketed_ipv6, c.allow_extra_spaces];
+        assert_eq!(flags(default), [false, false, true, false, false, false, false, false, false]);
+        assert_eq!(flags(strict), [true, true, true, true, true, false, false, false, false]);
+        assert_eq!(flags(lenient), [false, false, true, false, false, true, true, true, true]);
+        assert_eq!(flags(lenient.allow_bracketed_ipv6(false).reject_trailing_fields(true)),
+                   [false, true, true, false, false, true, true, false, true]);
+    }
+
+    #[test]
+    fn test_overlong_lines() {
+        let strict = ParseConfig::default().reject_overlong_lines(true);
+        let tcp4_max = b"PROXY TCP4 255.255.255.255 255.255.255.255 65535 65535\r\n";
+        let tcp6_max = b"PROXY TCP6 ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff 65535 65535\r\n";
+        assert_eq!((tcp4_max.len(), tcp6_max.len()), (56, 104));
+        // one byte over, from padding a port with a zero, and three over from a trailing field
+        let tcp4_over = b"PROXY TCP4 255.255.255.255 255.255.255.255 065535 65535\r\n";
+        let tcp6_over = b"PROXY TCP6 ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff 65535 065535\r\n";
+        let tcp4_trailing = b"PROXY TCP4 255.255.255.255 255.255.255.255 65535 65535 x\r\n";
+        let tcp6_trailing = b"PROXY TCP6 ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff 65535 65535 x\r\n";
+        for line in &[&tcp4_max[..], &tcp6_max[..], b"PROXY TCP4 1.2.3.4 5.6.7.8 80 90\r\n",
+                      b"PROXY UNKNOWN ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff 65535 65535\r\n"] {
+            read_proxy_protocol_v1_with(&mut &line[..], &strict).expect("should parse");
+        }
+        let cases: Vec<(&[u8], Proto, usize, usize)> = vec![
+            (tcp4_over, Proto::Tcp4, 57, 56),
+            (tcp4_trailing, Proto::Tcp4, 58, 56),
+            (tcp6_over, Proto::Tcp6, 105, 104),
+            (tcp6_trailing, Proto::Tcp6, 106, 104),
+        ];
+        for (line, proto, len, max) in cases {
+            match read_proxy_protocol_v1_with(&mut &line[..], &strict) {
+                Err(ProxyReadError::LineTooLong(p, l, m)) => assert_eq!((p, l, m), (proto, len, max)),
+                other => panic!("expected LineTooLong for {:?}, got {:?}", String::from_utf8_lossy(line), other),
+            }
+            // but they are fine by default
+            read_proxy_protocol_v1(&mut &line[..]).expect("should parse");
+        }
+        assert_eq!(ProxyReadError::LineTooLong(Proto::Tcp4, 57, 56).to_string(), "Tcp4 line of 57 bytes is longer than the 56 allowed");
+
+        // a bare LF counts as the one byte it is, leaving room for one more before it
+        let lf = strict.allow_lf_terminator(true);
+        for &(over, max) in &[(&tcp4_over[..], 56), (&tcp6_over[..], 104)] {
+            let at_max = [&over[..over.len() - 2], b"\n"].concat();
+            assert_eq!(at_max.len(), max);
+            read_proxy_protocol_v1_with(&mut &at_max[..], &lf).expect("should parse");
+            assert!(matches!(parse_with(&at_max, ProxyProtocolVersion::V1, &lf), Poll::Ready(Ok(_))));
+            let past_max = [&over[..over.len() - 2], b" x\n"].concat();
+            match read_proxy_protocol_v1_with(&mut &past_max[..], &lf) {
+                Err(ProxyReadError::LineTooLong(_, len, m)) => assert_eq!((len, m), (max + 2, max)),
+                other => panic!("expected LineTooLong for {:?}, got {:?}", String::from_utf8_lossy(&past_max), other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_strict_v1_errors() {
+        // each near-valid line, and the check it fails under strict(); all parse by default
+        let cases: Vec<(&[u8], &str)> = vec![
+            (b"PROXY TCP4 1.2.3.4 5.6.7.8 +80 90\r\n", "a port with a sign or leading zeros"),
+            (b"PROXY TCP4 1.2.3.4 5.6.7.8 80 0090\r\n", "a port with a sign or leading zeros"),
+            (b"PROXY TCP4 1.2.3.4 5.6.7.8 80 90 extra\r\n", "fields after the destination port"),
+        ];
+        for (line, check) in cases {
+            read_proxy_protocol_v1(&mut &line[..]).expect("should parse by default");
+            match read_proxy_protocol_v1_with(&mut &line[..], &ParseConfig::strict()) {
+                Err(ProxyReadError::NotConformant(c)) => assert_eq!(c, check, "for {:?}", String::from_utf8_lossy(line)),
+                other => panic!("expected {:?} for {:?}, got {:?}", check, String::from_utf8_lossy(line), other),
+            }
+        }
+        // lowercase tokens and extra spaces are refused by default already, just as strictly
+        read_proxy_protocol_v1_with(&mut &b"PROXY tcp4 1.2.3.4 5.6.7.8 80 90\r\n"[..], &ParseConfig::strict()).expect_err("should not parse");
+        for line in &[&b"PROXY TCP4 1.2.3.4 5.6.7.8  80 90\r\n"[..], b"PROXY TCP4 1.2.3.4 5.6.7.8 80 90 \r\n"] {
+            for config in &[ParseConfig::default(), ParseConfig::strict()] {
+                match read_proxy_protocol_v1_with(&mut &line[..], config) {
+                    Err(ProxyReadError::NotConformant(c)) => assert_eq!(c, "fields separated by other than a single space"),
+                    other => panic!("expected NotConformant, got {:?}", other),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_v1_family_mismatch() {
+        let cases: Vec<(&[u8], Proto, &str)> = vec![
+            (b"PROXY TCP4 ::1 ::2 80 81\r\n", Proto::Tcp4, "source address"),
+            (b"PROXY TCP6 192.0.2.1 198.51.100.7 80 81\r\n", Proto::Tcp6, "source address"),
+            (b"PROXY TCP4 192.0.2.1 ::2 80 81\r\n", Proto::Tcp4, "destination address"),
+            (b"PROXY TCP6 ::1 198.51.100.7 80 81\r\n", Proto::Tcp6, "destination address"),
+            (b"PROXY TCP4 ::ffff:192.0.2.1 198.51.100.7 80 81\r\n", Proto::Tcp4, "source address"),
+        ];
+        for (line, proto, field) in cases {
+            for config in &[ParseConfig::default(), ParseConfig::strict(), ParseConfig::lenient()] {
+                match read_proxy_protocol_v1_with(&mut &line[..], config) {
+                    Err(ProxyReadError::AddressFamilyMismatch(p, f)) => assert_eq!((p, f), (proto, field)),
+                    other => panic!("expected AddressFamilyMismatch for {:?}, got {:?}", String::from_utf8_lossy(line), other),
+                }
+            }
+            let loose = ParseConfig::default().reject_family_mismatch(false);
+            let header = read_proxy_protocol_v1_with(&mut &line[..], &loose).expect("should parse without the check");
+            assert_eq!(header.proto(), proto);
+        }
+        // IPv4-mapped addresses are IPv6 addresses
+        read_proxy_protocol_v1(&mut &b"PROXY TCP6 ::ffff:192.0.2.1 2001:db8::2 80 81\r\n"[..]).expect("should parse");
+        assert_eq!(ProxyReadError::AddressFamilyMismatch(Proto::Tcp4, "destination address").to_string(), "destination address is not of the Tcp4 family");
+        assert_eq!(explain(b"PROXY TCP4 192.0.2.1 ::2 80 81\r\n", ProxyProtocolVersion::V1), "\
+version: v1 (as configured)
+error at byte 21: bad destination address (field 4)
+  expected: an IPv4 or IPv6 address
+  found: \"::2\" (bytes 21..24)
+  cause: TCP4 lines carry only IPv4 addresses
+");
+    }
+
+    #[test]
+    fn test_lenient_v1_legacy_lines() {
+        let lenient = ParseConfig::default().allow_lf_terminator(true).allow_extra_spaces(true);
+        let expected = ProxyProtocolHeader::new(1, Proto::Tcp4, "10.1.2.3:51234".parse().unwrap(), "10.0.0.1:443".parse().unwrap());
+        let lines: Vec<&[u8]> = vec![
+            b"PROXY TCP4 10.1.2.3 10.0.0.1 51234 443\n",
+            b"PROXY TCP4 10.1.2.3  10.0.0.1 51234 443\n",
+            b"PROXY  TCP4   10.1.2.3 10.0.0.1  51234 443\r\n",
+            b"PROXY TCP4 10.1.2.3 10.0.0.1 51234 443 \n",
+            b"PROXY TCP4 10.1.2.3 10.0.0.1 51234 443 \t \r\n",
+        ];
+        for line in lines {
+            // with a payload which looks like the end of another line, which must be left alone
+            let mut bytes = line.to_vec();
+            bytes.extend_from_slice(b"GET / HTTP/1.1\r\nX: 1 2\n\r\n");
+            let mut rest = &bytes[..];
+            let header = read_proxy_protocol_any_with(&mut rest, &lenient).expect("should parse");
+            assert_eq!(header, expected, "for {:?}", String::from_utf8_lossy(line));
+            assert_eq!(rest, b"GET / HTTP/1.1\r\nX: 1 2\n\r\n");
+            for config in &[ParseConfig::default(), ParseConfig::strict()] {
+                read_proxy_protocol_v1_with(&mut &line[..], config).expect_err("should not parse");
+            }
+        }
+
+        // still no more than 107 bytes, however they are spaced
+        let mut long = b"PROXY TCP4 10.1.2.3 10.0.0.1".to_vec();
+        long.resize(100, b' ');
+        long.extend_from_slice(b"51234 443\n");
+        match read_proxy_protocol_v1_with(&mut &long[..], &lenient) {
+            Err(ProxyReadError::HeaderTooLong { limit: 107, read: 107 }) => {},
+            other => panic!("expected HeaderTooLong, got {:?}", other),
+        }
+        // and a stray CR is not whitespace to be trimmed
+        read_proxy_protocol_v1_with(&mut &b"PROXY TCP4 10.1.2.3 10.0.0.1 51234 443\r\r\n"[..], &lenient).expect_err("should not parse");
+        assert_eq!(explain(b"PROXY TCP4 10.1.2.3  10.0.0.1 51234 443\r\n", ProxyProtocolVersion::V1), "\
+version: v1 (as configured)
+error at byte 19: extra space
+  expected: exactly one space between fields, and none before the CRLF
+");
+    }
+
+    #[test]
+    fn test_parse_config_v1() {
+        let (default, strict, lenient) = (ParseConfig::default(), ParseConfig::strict(), ParseConfig::lenient());
+        // (header, parses under default, under strict, under lenient)
+        let cases: Vec<(&[u8], bool, bool, bool)> = vec![
+            (b"PROXY TCP4 1.2.3.4 5.6.7.8 80 90\r\n", true, true, true),
+            (b"proxy tcp4 1.2.3.4 5.6.7.8 80 90\r\n", false, false, true),
+            (b"PROXY TCP4 1.2.3.4 5.6.7.8 80 90\n", false, false, true),
+            (b"PROXY TCP6 [::1] [::2] 80 90\r\n", false, false, true),
+            (b"PROXY TCP4 1.2.3.4 5.6.7.8 080 90\r\n", true, false, true),
+            (b"PROXY TCP4 1.2.3.4 5.6.7.8 +80 90\r\n", true, false, true),
+            (b"PROXY TCP4 1.2.3.4 5.6.7.8 80 90 extra\r\n", true, false, true),
+            (b"PROXY TCP4 ::1 ::2 80 90\r\n", false, false, false),
+            (b"PROXY UNKNOWN whatever follows\r\n", true, true, true),
+            (b"PROXY TCP4 1.2.3.4  5.6.7.8 80 90\r\n", false, false, true),
+            (b"PROXY  TCP4 1.2.3.4 5.6.7.8 80 90\r\n", false, false, true),
+            (b"PROXY TCP4 1.2.3.4 5.6.7.8 80 90 \r\n", false, false, true),
+            (b"PROXY TCP4 1.2.3.4 5.6.7.8 80 90\t\n", false, false, true),
+            (b"PROXY  UNKNOWN\r\n", false, false, true),
+            (b"PROXY UNKNOWN  anything  at all \r\n", true, true, true),
+        ];
+        for (header, d, s, l) in cases {
+            for &(config, expected) in &[(&default, d), (&strict, s), (&lenient, l)] {
+                let parsed = read_proxy_protocol_v1_with(&mut (header as &[u8]), config);
+                assert_eq!(parsed.is_ok(), expected, "{:?} under {:?}: {:?}", String::from_utf8_lossy(header), config, parsed);
+            }
+        }
+        let header = read_proxy_protocol_any_with(&mut &b"proxy tcp6 [::1] [::2] 80 90\n"[..], &lenient).expect("should parse");
+        assert_eq!(header.source_addr(), Some("[::1]:80".parse().unwrap()));
+        assert_eq!(header.dest_addr(), Some("[::2]:90".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_bracketed_ipv6() {
+        let (source, dest) = ("[2001:db8::1]:1234".parse().unwrap(), "[2001:db8::2]:443".parse().unwrap());
+        let lines: &[&[u8]] = &[
+            b"PROXY TCP6 [2001:db8::1] 2001:db8::2 1234 443\r\n",
+            b"PROXY TCP6 2001:db8::1 [2001:db8::2] 1234 443\r\n",
+            b"PROXY TCP6 [2001:db8::1] [2001:db8::2] 1234 443\r\n",
+        ];
+        for line in lines {
+            let header = read_proxy_protocol_v1_with(&mut &line[..], &ParseConfig::lenient()).expect("should parse");
+            assert_eq!((header.source_addr(), header.dest_addr()), (Some(source), Some(dest)));
+            for config in &[ParseConfig::default(), ParseConfig::strict()] {
+                match read_proxy_protocol_v1_with(&mut &line[..], config) {
+                    Err(ProxyReadError::BadSourceAddress(_)) | Err(ProxyReadError::BadDestAddress(_)) => {},
+                    other => panic!("expected a bad address for {:?}, got {:?}", String::from_utf8_lossy(line), other),
+                }
+            }
+        }
+        // half a pair of brackets (or brackets around an IPv4 address) is never an address
+        let malformed: &[(&[u8], bool)] = &[
+            (b"PROXY TCP6 [2001:db8::1 2001:db8::2 1234 443\r\n", true),
+            (b"PROXY TCP6 2001:db8::1] 2001:db8::2 1234 443\r\n", true),
+            (b"PROXY TCP6 2001:db8::1 [2001:db8::2 1234 443\r\n", false),
+            (b"PROXY TCP6 2001:db8::1 2001:db8::2] 1234 443\r\n", false),
+            (b"PROXY TCP6 [] 2001:db8::2 1234 443\r\n", true),
+            (b"PROXY TCP4 [192.0.2.1] 198.51.100.7 1234 443\r\n", true),
+        ];
+        for &(line, source) in malformed {
+            match (read_proxy_protocol_v1_with(&mut &line[..], &ParseConfig::lenient()), source) {
+                (Err(ProxyReadError::BadSourceAddress(_)), true) | (Err(ProxyReadError::BadDestAddress(_)), false) => {},
+                (other, _) => panic!("expected a bad address for {:?}, got {:?}", String::from_utf8_lossy(line), other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_normalize_mapped_v4() {
+        let normalize = ParseConfig::default().normalize_mapped_v4(true);
+        // (source, destination, source after normalizing)
+        let cases = [
+            ("::ffff:203.0.113.9", "::ffff:198.51.100.7", "203.0.113.9"),
+            ("::203.0.113.9", "::ffff:198.51.100.7", "[::203.0.113.9]"),
+            ("2001:db8::1", "::ffff:198.51.100.7", "[2001:db8::1]"),
+        ];
+        for &(source, dest, normalized) in &cases {
+            let source_addr = SocketAddr::new(source.parse().unwrap(), 4124);
+            let dest_addr = SocketAddr::new(dest.parse().unwrap(), 443);
+            let v1 = format!("PROXY TCP6 {} {} 4124 443\r\n", source, dest).into_bytes();
+            let v2 = ProxyProtocolHeader::new(2, Proto::Tcp6, source_addr, dest_addr).to_bytes(ProxyProtocolVersion::V2);
+            let parsed = [
+                read_proxy_protocol_v1_with(&mut &v1[..], &normalize).expect("should parse"),
+                read_proxy_protocol_v2_with(&mut &v2[..], &normalize).expect("should parse"),
+                read_proxy_protocol_any_with(&mut &v1[..], &normalize).expect("should parse"),
+                read_proxy_protocol_any_with(&mut &v2[..], &normalize).expect("should parse"),
+            ];
+            for header in &parsed {
+                assert_eq!(header.source_addr(), Some(format!("{}:4124", normalized).parse().unwrap()), "{}", source);
+                assert_eq!(header.dest_addr(), Some("198.51.100.7:443".parse().unwrap()));
+                assert_eq!(header.proto(), Proto::Tcp6);
+            }
+            // off by default
+            let header = read_proxy_protocol_v1_with(&mut &v1[..], &ParseConfig::default()).expect("should parse");
+            assert_eq!((header.source_addr(), header.dest_addr()), (Some(source_addr), Some(dest_addr)));
+            let header = read_proxy_protocol_v2_with(&mut &v2[..], &ParseConfig::default()).expect("should parse");
+            assert_eq!((header.source_addr(), header.dest_addr()), (Some(source_addr), Some(dest_addr)));
+        }
+    }
 }
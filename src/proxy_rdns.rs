@@ -0,0 +1,320 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+
+/// How many lookups can be waiting for the resolver thread before more are dropped
+const QUEUE_LEN: usize = 256;
+
+
+/// Resolves client addresses to hostnames on a background thread, and caches the results, so
+/// that connection logs can show names without anything waiting on DNS.
+///
+/// `resolve` queues a lookup and returns at once, and `lookup_cached` only ever consults the
+/// cache; neither blocks on the resolver. Attached with `ProxyConfig::reverse_dns`, the source
+/// address of every accepted connection is queued, and `ConnectionClosed::client_name` is
+/// filled in from the cache when the connection closes, if the name has been found by then.
+///
+/// Results (including failures to find a name, which are not retried until they expire) are
+/// kept for `ttl`. At most `capacity` are kept at once; once the cache is full, expired
+/// results are dropped to make room, and then the oldest. If lookups are queued faster than
+/// the resolver can keep up with, the excess are dropped, and can be queued again later.
+///
+/// Clones share the same cache and resolver thread, which exits once every clone has been
+/// dropped. Only available with the `rdns` feature.
+#[derive(Clone)]
+pub struct ReverseDns {
+    shared: Arc<Mutex<Cache>>,
+    queue: SyncSender<IpAddr>,
+}
+
+
+struct Cache {
+    ttl: Duration,
+    capacity: usize,
+    entries: HashMap<IpAddr, Entry>,
+    // queued or being resolved, so that they are not queued twice
+    pending: HashSet<IpAddr>,
+}
+
+
+struct Entry {
+    name: Option<String>,
+    resolved_at: Instant,
+}
+
+
+impl Cache {
+    fn get(&self, addr: IpAddr) -> Option<&Entry> {
+        self.entries.get(&addr).filter(|entry| entry.resolved_at.elapsed() < self.ttl)
+    }
+
+    fn insert(&mut self, addr: IpAddr, name: Option<String>) {
+        self.pending.remove(&addr);
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(&addr) {
+            let ttl = self.ttl;
+            self.entries.retain(|_, entry| entry.resolved_at.elapsed() < ttl);
+        }
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(&addr) {
+            let oldest = self.entries.iter().min_by_key(|&(_, entry)| entry.resolved_at).map(|(&addr, _)| addr);
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(addr, Entry { name, resolved_at: Instant::now() });
+    }
+}
+
+
+impl ReverseDns {
+    /// Resolve addresses with the system resolver (`getnameinfo(3)`), keeping up to `capacity`
+    /// results for `ttl` each. Only available on unix; elsewhere, see `with_resolver`.
+    #[cfg(unix)]
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        ReverseDns::with_resolver(system::resolve, ttl, capacity)
+    }
+
+    /// Resolve addresses with `resolver`, which is called on the background thread and
+    /// returns `None` if an address has no name, keeping up to `capacity` results for `ttl`
+    /// each
+    pub fn with_resolver<F>(resolver: F, ttl: Duration, capacity: usize) -> Self
+        where F: Fn(IpAddr) -> Option<String> + Send + 'static {
+        let shared = Arc::new(Mutex::new(Cache {
+            ttl,
+            capacity,
+            entries: HashMap::new(),
+            pending: HashSet::new(),
+        }));
+        let (queue, queued) = mpsc::sync_channel::<IpAddr>(QUEUE_LEN);
+        let cache = Arc::clone(&shared);
+        thread::Builder::new()
+            .name("reverse-dns".to_string())
+            .spawn(move || {
+                for addr in queued {
+                    let name = resolver(addr);
+                    cache.lock().unwrap().insert(addr, name);
+                }
+            })
+            .expect("should be able to start the resolver thread");
+        ReverseDns { shared, queue }
+    }
+
+    /// The name of `addr`, if it has already been found; never waits for the resolver
+    pub fn lookup_cached(&self, addr: IpAddr) -> Option<String> {
+        self.shared.lock().unwrap().get(addr).and_then(|entry| entry.name.clone())
+    }
+
+    /// Queue a lookup of `addr`, unless a result for it is already cached or on its way;
+    /// never waits for the resolver
+    pub fn resolve(&self, addr: IpAddr) {
+        {
+            let mut cache = self.shared.lock().unwrap();
+            if cache.get(addr).is_some() || !cache.pending.insert(addr) {
+                return;
+            }
+        }
+        match self.queue.try_send(addr) {
+            Ok(()) => {},
+            Err(TrySendError::Full(addr)) | Err(TrySendError::Disconnected(addr)) => {
+                self.shared.lock().unwrap().pending.remove(&addr);
+            },
+        }
+    }
+}
+
+
+// written by hand, since the cache is behind a lock
+impl fmt::Debug for ReverseDns {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let cache = self.shared.lock().unwrap();
+        f.debug_struct("ReverseDns")
+            .field("ttl", &cache.ttl)
+            .field("capacity", &cache.capacity)
+            .field("cached", &cache.entries.len())
+            .field("pending", &cache.pending.len())
+            .finish()
+    }
+}
+
+
+#[cfg(unix)]
+mod system {
+    use std::ffi::CStr;
+    use std::mem;
+    use std::net::IpAddr;
+    use std::ptr;
+
+    use libc;
+
+    /// The name `getnameinfo(3)` finds for `addr`, if any
+    pub fn resolve(addr: IpAddr) -> Option<String> {
+        // NI_MAXHOST
+        let mut host = [0 as libc::c_char; 1025];
+        let ret = unsafe {
+            match addr {
+                IpAddr::V4(v4) => {
+                    let mut sa: libc::sockaddr_in = mem::zeroed();
+                    sa.sin_family = libc::AF_INET as libc::sa_family_t;
+                    sa.sin_addr.s_addr = u32::from(v4).to_be();
+                    libc::getnameinfo(&sa as *const _ as *const libc::sockaddr, mem::size_of_val(&sa) as libc::socklen_t,
+                                      host.as_mut_ptr(), host.len() as libc::socklen_t, ptr::null_mut(), 0, libc::NI_NAMEREQD)
+                },
+                IpAddr::V6(v6) => {
+                    let mut sa: libc::sockaddr_in6 = mem::zeroed();
+                    sa.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                    sa.sin6_addr.s6_addr = v6.octets();
+                    libc::getnameinfo(&sa as *const _ as *const libc::sockaddr, mem::size_of_val(&sa) as libc::socklen_t,
+                                      host.as_mut_ptr(), host.len() as libc::socklen_t, ptr::null_mut(), 0, libc::NI_NAMEREQD)
+                },
+            }
+        };
+        if ret != 0 {
+            return None;
+        }
+        unsafe { CStr::from_ptr(host.as_ptr()) }.to_str().ok().map(str::to_owned)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::net::{IpAddr, TcpStream};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use hyper::net::HttpListener;
+
+    use proxy_config::ProxyConfig;
+    use proxy_listener::ProxyListener;
+    use proxy_observer::{ConnectionClosed, ProxyObserver};
+    use proxy_protocol::ProxyProtocolVersion;
+    use super::ReverseDns;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    /// A resolver naming every address `host-<addr>`, and counting its calls
+    fn counting() -> (ReverseDns, Arc<AtomicUsize>) {
+        counting_with(Duration::from_secs(60), 16)
+    }
+
+    fn counting_with(ttl: Duration, capacity: usize) -> (ReverseDns, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&calls);
+        let rdns = ReverseDns::with_resolver(move |addr| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Some(format!("host-{}", addr))
+        }, ttl, capacity);
+        (rdns, calls)
+    }
+
+    /// Wait (for a while) for the resolver to have found `addr`
+    fn cached(rdns: &ReverseDns, addr: IpAddr) -> Option<String> {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(5) {
+            if let Some(name) = rdns.lookup_cached(addr) {
+                return Some(name);
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        None
+    }
+
+    #[test]
+    fn test_hits_and_misses() {
+        let (rdns, calls) = counting();
+        assert_eq!(rdns.lookup_cached(ip("192.0.2.1")), None);
+        assert_eq!(calls.load(Ordering::SeqCst), 0, "a cache lookup should not resolve anything");
+
+        rdns.resolve(ip("192.0.2.1"));
+        assert_eq!(cached(&rdns, ip("192.0.2.1")), Some("host-192.0.2.1".to_string()));
+        rdns.resolve(ip("192.0.2.1"));
+        rdns.clone().resolve(ip("192.0.2.1"));
+        assert_eq!(rdns.lookup_cached(ip("192.0.2.1")), Some("host-192.0.2.1".to_string()));
+        assert_eq!(calls.load(Ordering::SeqCst), 1, "cached names should not be resolved again");
+        assert_eq!(rdns.lookup_cached(ip("2001:db8::1")), None);
+
+        // failures are cached too
+        let rdns = ReverseDns::with_resolver(|_| None, Duration::from_secs(60), 16);
+        rdns.resolve(ip("192.0.2.1"));
+        let start = Instant::now();
+        while rdns.shared.lock().unwrap().get(ip("192.0.2.1")).is_none() && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(rdns.shared.lock().unwrap().get(ip("192.0.2.1")).is_some());
+        assert_eq!(rdns.lookup_cached(ip("192.0.2.1")), None);
+    }
+
+    #[test]
+    fn test_ttl_and_capacity() {
+        let (rdns, calls) = counting_with(Duration::from_millis(50), 16);
+        rdns.resolve(ip("192.0.2.1"));
+        assert!(cached(&rdns, ip("192.0.2.1")).is_some());
+        thread::sleep(Duration::from_millis(80));
+        assert_eq!(rdns.lookup_cached(ip("192.0.2.1")), None, "expired names should not be returned");
+        rdns.resolve(ip("192.0.2.1"));
+        assert!(cached(&rdns, ip("192.0.2.1")).is_some());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        let (rdns, _) = counting_with(Duration::from_secs(60), 2);
+        for addr in &["192.0.2.1", "192.0.2.2", "192.0.2.3"] {
+            rdns.resolve(ip(addr));
+            assert!(cached(&rdns, ip(addr)).is_some());
+            thread::sleep(Duration::from_millis(2));
+        }
+        assert_eq!(rdns.shared.lock().unwrap().entries.len(), 2);
+        assert_eq!(rdns.lookup_cached(ip("192.0.2.1")), None, "the oldest name should have made room");
+        assert!(rdns.lookup_cached(ip("192.0.2.3")).is_some());
+    }
+
+    struct Closed(Mutex<Vec<ConnectionClosed>>);
+
+    impl ProxyObserver for Closed {
+        fn on_close(&self, event: &ConnectionClosed) {
+            self.0.lock().unwrap().push(event.clone());
+        }
+    }
+
+    #[test]
+    fn test_accept_does_not_wait() {
+        // the resolver does not answer until it is told to
+        let (release, released) = mpsc::channel::<()>();
+        let released = Mutex::new(released);
+        let rdns = ReverseDns::with_resolver(move |addr| {
+            released.lock().unwrap().recv().ok();
+            Some(format!("host-{}", addr))
+        }, Duration::from_secs(60), 16);
+        let closed = Arc::new(Closed(Mutex::new(Vec::new())));
+        let config = ProxyConfig::new(ProxyProtocolVersion::V1).reverse_dns(rdns.clone()).observer(closed.clone());
+        let mut listener = ProxyListener::from_config(HttpListener::new("127.0.0.1:0").unwrap(), &config);
+        let addr = listener.bound_addr().unwrap();
+
+        let mut first = TcpStream::connect(addr).unwrap();
+        first.write_all(b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n").unwrap();
+        let first = listener.accept_detailed().expect("should accept while the lookup is pending");
+        let mut second = TcpStream::connect(addr).unwrap();
+        second.write_all(b"PROXY TCP4 192.0.2.1 198.51.100.7 4125 443\r\n").unwrap();
+        let second = listener.accept_detailed().expect("should accept while the lookup is pending");
+        assert_eq!(rdns.lookup_cached(ip("192.0.2.1")), None);
+
+        drop(first);
+        release.send(()).unwrap();
+        assert_eq!(cached(&rdns, ip("192.0.2.1")), Some("host-192.0.2.1".to_string()));
+        drop(second);
+        let closed = closed.0.lock().unwrap();
+        let names: Vec<_> = closed.iter().map(|event| event.client_name.clone()).collect();
+        assert_eq!(names, vec![None, Some("host-192.0.2.1".to_string())]);
+    }
+}
@@ -0,0 +1,230 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use proxy_info::ProxyInfo;
+
+
+/// A shared table mapping the effective peer address of each open connection to its
+/// `ProxyInfo`.
+///
+/// Frameworks such as Iron only give request handlers the peer address of the connection
+/// (`request.remote_addr`), so everything else in the PROXY header is out of reach from a
+/// handler. When a registry is attached with `ProxyConfig::registry`, the listener records
+/// every accepted connection here, keyed by the same address that the stream reports from
+/// `peer_addr()`, and handlers can call `lookup(request.remote_addr)` to get the rest.
+///
+/// Entries are evicted when the last clone of the corresponding `ProxyStream` is dropped or,
+/// if a TTL was configured with `with_ttl`, once they are older than the TTL. Expired entries
+/// are no longer returned by `lookup`, and are swept (oldest first, so without looking at
+/// the others) when the next connection is registered.
+///
+/// # Ambiguity
+///
+/// The key is whatever source address the sender of the header claimed, so two open
+/// connections can end up with the same key (a buggy or malicious upstream, or several load
+/// balancers reusing the same source ports). When that happens the most recently accepted
+/// connection wins and replaces the older entry. Each entry is tagged with a generation
+/// counter, and a stream only evicts the entry carrying its own generation, so when the older
+/// connection closes it does not remove the newer connection's entry. While both connections
+/// are open, lookups from either one return the newer connection's `ProxyInfo`.
+#[derive(Debug, Clone, Default)]
+pub struct ProxyRegistry {
+    state: Arc<Mutex<RegistryState>>,
+    ttl: Option<Duration>,
+}
+
+
+#[derive(Debug, Default)]
+struct RegistryState {
+    entries: HashMap<SocketAddr, RegistryEntry>,
+    // the key of every entry, by generation, which is also the order they were inserted in
+    by_generation: BTreeMap<u64, SocketAddr>,
+    next_generation: u64,
+}
+
+
+#[derive(Debug)]
+struct RegistryEntry {
+    info: ProxyInfo,
+    generation: u64,
+    inserted_at: Instant,
+}
+
+
+impl ProxyRegistry {
+    /// Construct an empty registry whose entries live exactly as long as their connections
+    pub fn new() -> Self {
+        ProxyRegistry::default()
+    }
+
+    /// Construct an empty registry whose entries are additionally discarded once they are
+    /// older than `ttl`, even if the connection is still open. This bounds the damage from
+    /// streams which are leaked rather than dropped.
+    pub fn with_ttl(ttl: Duration) -> Self {
+        ProxyRegistry {
+            state: Arc::default(),
+            ttl: Some(ttl),
+        }
+    }
+
+    /// Look up the `ProxyInfo` of the open connection whose peer address is `addr`
+    pub fn lookup(&self, addr: SocketAddr) -> Option<ProxyInfo> {
+        let state = self.state.lock().unwrap();
+        state.entries.get(&addr)
+            .filter(|entry| !self.is_expired(entry))
+            .map(|entry| entry.info.clone())
+    }
+
+    /// The number of entries currently held, including any which have expired but have not
+    /// been swept yet
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    /// Whether the registry currently holds no entries
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn is_expired(&self, entry: &RegistryEntry) -> bool {
+        match self.ttl {
+            Some(ttl) => entry.inserted_at.elapsed() >= ttl,
+            None => false,
+        }
+    }
+
+    pub(crate) fn register(&self, addr: SocketAddr, info: ProxyInfo) -> Registration {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        if self.ttl.is_some() {
+            while let Some((&generation, &oldest)) = state.by_generation.first_key_value() {
+                if !state.entries.get(&oldest).is_some_and(|entry| self.is_expired(entry)) {
+                    break;
+                }
+                state.by_generation.remove(&generation);
+                state.entries.remove(&oldest);
+            }
+        }
+        let generation = state.next_generation;
+        state.next_generation += 1;
+        state.by_generation.insert(generation, addr);
+        let replaced = state.entries.insert(addr, RegistryEntry {
+            info,
+            generation,
+            inserted_at: Instant::now(),
+        });
+        if let Some(replaced) = replaced {
+            state.by_generation.remove(&replaced.generation);
+        }
+        Registration {
+            registry: self.clone(),
+            addr,
+            generation,
+        }
+    }
+
+    fn evict(&self, addr: SocketAddr, generation: u64) {
+        let mut state = self.state.lock().unwrap();
+        let owned = state.entries.get(&addr).map(|entry| entry.generation == generation).unwrap_or(false);
+        if owned {
+            state.entries.remove(&addr);
+            state.by_generation.remove(&generation);
+        }
+    }
+}
+
+
+/// Ownership of a single registry entry; the entry is evicted when this is dropped
+#[derive(Debug)]
+pub(crate) struct Registration {
+    registry: ProxyRegistry,
+    addr: SocketAddr,
+    generation: u64,
+}
+
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.registry.evict(self.addr, self.generation);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use proxy_info::ProxyInfo;
+    use proxy_protocol::read_proxy_protocol_v1;
+    use super::ProxyRegistry;
+
+    fn info(line: &[u8]) -> ProxyInfo {
+        ProxyInfo::from_header(read_proxy_protocol_v1(&mut (line as &[u8])).expect("should parse"))
+    }
+
+    #[test]
+    fn test_lookup_and_evict() {
+        let registry = ProxyRegistry::new();
+        let addr = "192.0.2.1:4124".parse().unwrap();
+        let registration = registry.register(addr, info(b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n"));
+        let found = registry.lookup(addr).expect("should be registered");
+        assert_eq!(found.destination_addr(), Some("198.51.100.7:443".parse().unwrap()));
+        assert!(registry.lookup("192.0.2.1:4125".parse().unwrap()).is_none());
+        drop(registration);
+        assert!(registry.lookup(addr).is_none());
+        assert!(registry.is_empty());
+    }
+
+    #[test]
+    fn test_most_recent_wins() {
+        let registry = ProxyRegistry::new();
+        let addr = "192.0.2.1:4124".parse().unwrap();
+        let older = registry.register(addr, info(b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n"));
+        let newer = registry.register(addr, info(b"PROXY TCP4 192.0.2.1 198.51.100.8 4124 443\r\n"));
+        assert_eq!(registry.lookup(addr).unwrap().destination_addr(), Some("198.51.100.8:443".parse().unwrap()));
+        // the older connection closing must not evict the newer connection's entry
+        drop(older);
+        assert_eq!(registry.lookup(addr).unwrap().destination_addr(), Some("198.51.100.8:443".parse().unwrap()));
+        drop(newer);
+        assert!(registry.is_empty());
+    }
+
+    #[test]
+    fn test_ttl() {
+        let registry = ProxyRegistry::with_ttl(Duration::from_millis(20));
+        let addr = "192.0.2.1:4124".parse().unwrap();
+        let _leaked = registry.register(addr, info(b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n"));
+        assert!(registry.lookup(addr).is_some());
+        thread::sleep(Duration::from_millis(40));
+        assert!(registry.lookup(addr).is_none());
+        // expired entries are swept on the next registration
+        let other = "192.0.2.2:4124".parse().unwrap();
+        let _current = registry.register(other, info(b"PROXY TCP4 192.0.2.2 198.51.100.7 4124 443\r\n"));
+        assert_eq!(registry.len(), 1);
+    }
+
+    #[test]
+    fn test_ttl_sweeps_oldest_first() {
+        let registry = ProxyRegistry::with_ttl(Duration::from_millis(50));
+        let older = "192.0.2.1:4124".parse().unwrap();
+        let replaced = "192.0.2.2:4124".parse().unwrap();
+        let _older = registry.register(older, info(b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n"));
+        let _replaced = registry.register(replaced, info(b"PROXY TCP4 192.0.2.2 198.51.100.7 4124 443\r\n"));
+        let closed = registry.register("192.0.2.3:4124".parse().unwrap(), info(b"PROXY TCP4 192.0.2.3 198.51.100.7 4124 443\r\n"));
+        drop(closed);
+        thread::sleep(Duration::from_millis(70));
+        // re-registering the second address makes its entry young again
+        let _renewed = registry.register(replaced, info(b"PROXY TCP4 192.0.2.2 198.51.100.8 4124 443\r\n"));
+        assert_eq!(registry.len(), 1);
+        assert!(registry.lookup(older).is_none());
+        assert_eq!(registry.lookup(replaced).unwrap().destination_addr(), Some("198.51.100.8:443".parse().unwrap()));
+
+        // entries younger than the TTL stop the sweep
+        let _newest = registry.register("192.0.2.4:4124".parse().unwrap(), info(b"PROXY TCP4 192.0.2.4 198.51.100.7 4124 443\r\n"));
+        assert_eq!(registry.len(), 2);
+        assert!(registry.lookup(replaced).is_some());
+    }
+}
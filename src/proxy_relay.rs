@@ -0,0 +1,299 @@
+use std::io::{self, Write};
+use std::net::{Shutdown, TcpStream};
+use std::thread;
+
+use hyper::net::NetworkStream;
+
+use proxy_stream::ProxyStream;
+
+
+/// Copy everything the client sends on `client` to `upstream`, and everything `upstream`
+/// sends back to `client`, until both directions reach EOF; for relays which forward accepted
+/// connections to another server. When one direction reaches EOF, the writing side of the
+/// other end is shut down, so half-closed connections are passed along. Returns the number of
+/// bytes copied from `client` to `upstream` and from `upstream` to `client`, which are also
+/// counted as read from and written to `client` (see `ProxyStream::bytes_read`).
+///
+/// Any bytes already read off `client` while looking for its header (in optional mode) are
+/// sent on first. After that, on Linux, if `client` wraps an `HttpStream`, data is moved
+/// between the sockets with `splice(2)`, without being copied through userspace; otherwise
+/// (including on other platforms) each direction is copied with a read/write loop on a
+/// thread of its own.
+pub fn proxy_copy_bidirectional<T>(client: &mut ProxyStream<T>, upstream: &mut TcpStream) -> io::Result<(u64, u64)>
+    where T: NetworkStream + Clone + Send {
+    let pushback = client.take_pushback();
+    upstream.write_all(&pushback)?;
+    let (to_upstream, to_client) = match splice_bidirectional(client, upstream) {
+        Some(result) => result?,
+        None => copy_bidirectional(client, upstream)?,
+    };
+    client.add_counts(to_upstream, to_client);
+    Ok((pushback.len() as u64 + to_upstream, to_client))
+}
+
+
+/// Copy with plain reads and writes, through clones of both streams
+fn copy_bidirectional<T>(client: &mut ProxyStream<T>, upstream: &mut TcpStream) -> io::Result<(u64, u64)>
+    where T: NetworkStream + Clone + Send {
+    let (mut client_reader, mut client_writer) = (client.clone(), client.clone());
+    let (mut upstream_reader, mut upstream_writer) = (upstream.try_clone()?, upstream.try_clone()?);
+    thread::scope(|scope| {
+        // the clones' counts are forgotten, since the caller counts everything on `client`
+        let to_upstream = scope.spawn(move || -> io::Result<u64> {
+            let n = io::copy(&mut client_reader, &mut upstream_writer);
+            client_reader.forget_counts();
+            upstream_writer.shutdown(Shutdown::Write)?;
+            n
+        });
+        let to_client = io::copy(&mut upstream_reader, &mut client_writer);
+        client_writer.forget_counts();
+        let to_client = to_client.and_then(|n| client_writer.close(Shutdown::Write).map(|_| n));
+        let to_upstream = to_upstream.join().unwrap_or_else(|_| Err(io::Error::other("copying thread panicked")));
+        Ok((to_upstream?, to_client?))
+    })
+}
+
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn splice_bidirectional<T: NetworkStream>(client: &ProxyStream<T>, upstream: &TcpStream) -> Option<io::Result<(u64, u64)>> {
+    let client = client.tcp_socket()?;
+    Some(thread::scope(|scope| {
+        let to_upstream = scope.spawn(|| splice::copy(client, upstream));
+        let to_client = splice::copy(upstream, client);
+        let to_upstream = to_upstream.join().unwrap_or_else(|_| Err(io::Error::other("splicing thread panicked")));
+        Ok((to_upstream?, to_client?))
+    }))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn splice_bidirectional<T: NetworkStream>(_client: &ProxyStream<T>, _upstream: &TcpStream) -> Option<io::Result<(u64, u64)>> {
+    None
+}
+
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod splice {
+    use std::io;
+    use std::net::{Shutdown, TcpStream};
+    use std::os::unix::io::{AsRawFd, RawFd};
+    use std::ptr;
+
+    use libc;
+
+    /// How much to move through the pipe at once; the default pipe capacity on Linux
+    const CHUNK: usize = 64 * 1024;
+
+    /// A pipe, closed on drop
+    struct Pipe {
+        read: RawFd,
+        write: RawFd,
+    }
+
+    impl Pipe {
+        fn new() -> io::Result<Self> {
+            let mut fds = [0 as libc::c_int; 2];
+            if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Pipe { read: fds[0], write: fds[1] })
+        }
+    }
+
+    impl Drop for Pipe {
+        fn drop(&mut self) {
+            unsafe {
+                libc::close(self.read);
+                libc::close(self.write);
+            }
+        }
+    }
+
+    /// Move up to `len` bytes from `from` to `to`, retrying on `EINTR`
+    fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+        loop {
+            let n = unsafe {
+                libc::splice(from, ptr::null_mut(), to, ptr::null_mut(), len, libc::SPLICE_F_MOVE | libc::SPLICE_F_MORE)
+            };
+            if n >= 0 {
+                return Ok(n as usize);
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+
+    /// Move everything from `from` to `to` until `from` reaches EOF, then shut down the
+    /// writing side of `to`
+    pub(super) fn copy(from: &TcpStream, to: &TcpStream) -> io::Result<u64> {
+        let pipe = Pipe::new()?;
+        let mut total = 0u64;
+        loop {
+            let n = splice(from.as_raw_fd(), pipe.write, CHUNK)?;
+            if n == 0 {
+                break;
+            }
+            let mut left = n;
+            while left > 0 {
+                left -= splice(pipe.read, to.as_raw_fd(), left)?;
+            }
+            total += n as u64;
+        }
+        to.shutdown(Shutdown::Write)?;
+        Ok(total)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Read, Write};
+    use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+
+    use hyper::net::{HttpStream, NetworkStream};
+
+    use proxy_config::ProxyConfig;
+    use proxy_protocol::ProxyProtocolVersion;
+    use proxy_stream::ProxyStream;
+    use super::proxy_copy_bidirectional;
+
+    /// Deterministic pseudo-random bytes (xorshift)
+    fn payload(len: usize, mut seed: u64) -> Vec<u8> {
+        (0..len).map(|_| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed as u8
+        }).collect()
+    }
+
+    /// An `HttpStream` in disguise, which the relay cannot find the socket of
+    #[derive(Clone)]
+    struct Opaque(HttpStream);
+
+    impl Read for Opaque {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for Opaque {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.flush()
+        }
+    }
+
+    impl NetworkStream for Opaque {
+        fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+            self.0.peer_addr()
+        }
+
+        fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+            self.0.set_read_timeout(dur)
+        }
+
+        fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+            self.0.set_write_timeout(dur)
+        }
+
+        fn close(&mut self, how: Shutdown) -> io::Result<()> {
+            self.0.close(how)
+        }
+    }
+
+    /// What each end of a relayed connection received, and what the relay copied each way
+    struct Relayed {
+        at_client: Vec<u8>,
+        at_upstream: Vec<u8>,
+        copied: (u64, u64),
+        counted: (u64, u64),
+    }
+
+    /// Relay a client sending `preamble` then `request`, to an upstream which answers with
+    /// `response`
+    fn relay<T, F>(config: ProxyConfig, preamble: &[u8], request: Vec<u8>, response: Vec<u8>, wrap: F) -> Relayed
+        where T: NetworkStream + Clone + Send, F: FnOnce(HttpStream) -> T {
+        let front = TcpListener::bind("127.0.0.1:0").expect("should be able to bind");
+        let back = TcpListener::bind("127.0.0.1:0").expect("should be able to bind");
+        let back_addr = back.local_addr().unwrap();
+
+        let upstream = thread::spawn(move || {
+            let (mut conn, _) = back.accept().expect("should accept");
+            let writer = {
+                let mut conn = conn.try_clone().unwrap();
+                thread::spawn(move || {
+                    conn.write_all(&response).expect("write must succeed");
+                    conn.shutdown(Shutdown::Write).expect("shutdown must succeed");
+                })
+            };
+            let mut received = Vec::new();
+            conn.read_to_end(&mut received).expect("read must succeed");
+            writer.join().unwrap();
+            received
+        });
+
+        let mut client = TcpStream::connect(front.local_addr().unwrap()).expect("should be able to connect");
+        let writer = {
+            let mut client = client.try_clone().unwrap();
+            let mut sent = preamble.to_vec();
+            sent.extend_from_slice(&request);
+            thread::spawn(move || {
+                client.write_all(&sent).expect("write must succeed");
+                client.shutdown(Shutdown::Write).expect("shutdown must succeed");
+            })
+        };
+        let (socket, _) = front.accept().expect("should accept");
+        let mut stream = ProxyStream::from_stream(wrap(HttpStream(socket)), &config, None, &mut Vec::new()).expect("should parse");
+        let relayed = thread::spawn(move || {
+            let mut upstream = TcpStream::connect(back_addr).expect("should be able to connect");
+            let copied = proxy_copy_bidirectional(&mut stream, &mut upstream).expect("relay should succeed");
+            (copied, (stream.bytes_read(), stream.bytes_written()))
+        });
+
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).expect("read must succeed");
+        writer.join().unwrap();
+        let (copied, counted) = relayed.join().unwrap();
+        Relayed { at_client: received, at_upstream: upstream.join().unwrap(), copied, counted }
+    }
+
+    #[test]
+    fn test_relay_large_payloads() {
+        let header = b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n";
+        let (request, response) = (payload(3 << 20, 1), payload(5 << 20, 2));
+        let config = ProxyConfig::new(ProxyProtocolVersion::V1);
+        let expected = (request.len() as u64, response.len() as u64);
+
+        let r = relay(config.clone(), header, request.clone(), response.clone(), |s| s);
+        assert!(r.at_client == response && r.at_upstream == request, "spliced payloads should arrive intact");
+        assert_eq!((r.copied, r.counted), (expected, expected));
+
+        let r = relay(config, header, request.clone(), response.clone(), Opaque);
+        assert!(r.at_client == response && r.at_upstream == request, "copied payloads should arrive intact");
+        assert_eq!((r.copied, r.counted), (expected, expected));
+    }
+
+    #[test]
+    fn test_relay_pushback_first() {
+        // in optional mode, the first byte of a plain connection has been read already
+        let (request, response) = (payload(1 << 20, 3), payload(1 << 10, 4));
+        let config = ProxyConfig::new(ProxyProtocolVersion::V1).optional(true);
+        let expected = (request.len() as u64, response.len() as u64);
+        for &opaque in &[false, true] {
+            let r = if opaque {
+                relay(config.clone(), b"", request.clone(), response.clone(), Opaque)
+            } else {
+                relay(config.clone(), b"", request.clone(), response.clone(), |s| s)
+            };
+            assert!(r.at_client == response && r.at_upstream == request, "payloads should arrive intact (opaque: {})", opaque);
+            assert_eq!((r.copied, r.counted), (expected, expected));
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use std::net::SocketAddr;
+
+use iron::{status, Handler, IronResult, Request, Response};
+
+use proxy_registry::ProxyRegistry;
+use proxy_trust::Cidr;
+
+
+/// An Iron `Handler` which passes each request on to one of several others, according to the
+/// destination address in its connection's PROXY header; for serving several services which
+/// reach one listener through different load balancer ports (or addresses), where the Host
+/// header cannot be relied upon.
+///
+/// The `ProxyInfo` of each request's connection is looked up in a `ProxyRegistry`, which must
+/// be the one attached to the listener (see `ProxyConfig::registry`). Routes are tried in the
+/// order they were added, and the first match handles the request; requests matching no
+/// route, or arriving on connections without a header (or without a destination, such as
+/// `PROXY UNKNOWN`), go to the fallback, which by default responds `404 Not Found`.
+///
+/// Only available with the `iron` feature.
+///
+/// ```no_run
+/// # extern crate hyper;
+/// # extern crate hyper_networklistener_proxy;
+/// # extern crate iron;
+/// use hyper::net::HttpListener;
+/// use hyper_networklistener_proxy::{DestinationRouter, ProxyConfig, ProxyListener, ProxyProtocolVersion, ProxyRegistry};
+/// use iron::prelude::*;
+/// use iron::status;
+///
+/// # fn main() {
+/// let registry = ProxyRegistry::new();
+/// let config = ProxyConfig::new(ProxyProtocolVersion::V2).registry(registry.clone());
+/// let listener = ProxyListener::from_config(HttpListener::new("0.0.0.0:8080").unwrap(), &config);
+/// let router = DestinationRouter::new(registry)
+///     .port(443, |_: &mut Request| Ok(Response::with((status::Ok, "public API"))))
+///     .destination("10.0.0.0/8".parse().unwrap(), Some(8443), |_: &mut Request| Ok(Response::with((status::Ok, "internal API"))));
+/// Iron::new(router).listen(listener, iron::Protocol::http()).unwrap();
+/// # }
+/// ```
+pub struct DestinationRouter {
+    registry: ProxyRegistry,
+    routes: Vec<(Destination, Box<dyn Handler>)>,
+    fallback: Box<dyn Handler>,
+}
+
+
+/// What a route of a `DestinationRouter` matches
+struct Destination {
+    cidr: Option<Cidr>,
+    port: Option<u16>,
+}
+
+
+impl DestinationRouter {
+    /// Construct a router with no routes, which looks up connections in `registry`
+    pub fn new(registry: ProxyRegistry) -> Self {
+        DestinationRouter {
+            registry,
+            routes: Vec::new(),
+            fallback: Box::new(not_found),
+        }
+    }
+
+    /// Send requests whose connections were originally to `port` (on any address) to
+    /// `handler`
+    pub fn port<H: Handler>(self, port: u16, handler: H) -> Self {
+        self.route(Destination { cidr: None, port: Some(port) }, handler)
+    }
+
+    /// Send requests whose connections were originally to an address in `cidr` (and, if
+    /// given, to `port`) to `handler`
+    pub fn destination<H: Handler>(self, cidr: Cidr, port: Option<u16>, handler: H) -> Self {
+        self.route(Destination { cidr: Some(cidr), port }, handler)
+    }
+
+    /// Send requests matching no route to `handler`, rather than responding `404 Not Found`
+    pub fn fallback<H: Handler>(mut self, handler: H) -> Self {
+        self.fallback = Box::new(handler);
+        self
+    }
+
+    fn route<H: Handler>(mut self, destination: Destination, handler: H) -> Self {
+        self.routes.push((destination, Box::new(handler)));
+        self
+    }
+}
+
+
+impl Destination {
+    fn matches(&self, dest: SocketAddr) -> bool {
+        self.port.map(|port| port == dest.port()).unwrap_or(true)
+            && self.cidr.map(|cidr| cidr.contains(dest.ip())).unwrap_or(true)
+    }
+}
+
+
+fn not_found(_: &mut Request) -> IronResult<Response> {
+    Ok(Response::with(status::NotFound))
+}
+
+
+impl Handler for DestinationRouter {
+    fn handle(&self, request: &mut Request) -> IronResult<Response> {
+        let dest = self.registry.lookup(request.remote_addr).and_then(|info| info.destination_addr());
+        let handler = dest.and_then(|dest| {
+            self.routes.iter()
+                .find(|route| route.0.matches(dest))
+                .map(|route| &route.1)
+        });
+        handler.unwrap_or(&self.fallback).handle(request)
+    }
+}
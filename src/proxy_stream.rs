@@ -1,77 +1,1689 @@
-use std::net::{SocketAddr,Shutdown};
+use std::any::{type_name, Any};
+use std::fmt;
+use std::net::{SocketAddr,Shutdown,Ipv4Addr,TcpStream};
 use std::io::{self,Read,Write};
-use std::time::Duration;
+#[cfg(unix)]
+use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use std::time::{Duration, Instant, SystemTime};
+#[cfg(unix)]
+use std::time::UNIX_EPOCH;
+
+use hyper::net::{HttpStream, NetworkStream};
+
+use proxy_config::{Clock, ProxyConfig, PeerAddrFallback, PeerHop, FailureResponse, UnspecifiedSource};
+use proxy_info::ProxyInfo;
+use proxy_limit::LimitSlot;
+use proxy_observer::ConnectionTracker;
+use proxy_protocol::{self, Command, ParseConfig, Proto, ProxyProtocolVersion, ProxyProtocolHeader, ProxyReadError, ClientGuess};
+use proxy_registry::Registration;
+use proxy_protocol::guess_client;
+use proxy_protocol::header_literal;
+use proxy_protocol::{HeaderParser, V1_LITERAL};
+#[cfg(unix)]
+use proxy_protocol::FieldReader;
+
+
+const CANNED_HTTP_RESPONSE: &[u8] = b"HTTP/1.0 400 Bad Request\r\n\
+Content-Type: text/plain\r\n\
+Content-Length: 72\r\n\
+Connection: close\r\n\
+\r\n\
+This port expects the PROXY protocol; connect through the load balancer\n";
+
+// a fatal handshake_failure alert record
+const CANNED_TLS_ALERT: &[u8] = b"\x15\x03\x01\x00\x02\x02\x28";
+
+// how many leading bytes to keep around for guessing what sort of client failed to parse
+const RECORDED_PREFIX_LEN: usize = 32;
+
+
+/// Feed `parser` from `r` until it has a whole header. `HeaderReader`s wait out `WouldBlock`
+/// themselves, so one only reaches the parser once the header's deadline has passed, and
+/// fails the read as it would have failed a blocking parser's.
+fn read_header<R: Read>(r: &mut R, parser: &mut HeaderParser) -> proxy_protocol::Result<ProxyProtocolHeader> {
+    match parser.feed(r)? {
+        Some(header) => Ok(header),
+        None => Err(io::Error::from(io::ErrorKind::WouldBlock).into()),
+    }
+}
+
+
+/// What was found at the start of a stream
+pub(crate) enum Preamble {
+    Header {
+        /// The header chosen by the `ProxyConfig::peer_hop` from those read
+        header: ProxyProtocolHeader,
+        /// Every header read, if the listener reads more than one (see
+        /// `ProxyConfig::max_proxy_hops`); otherwise empty
+        hops: Vec<ProxyProtocolHeader>,
+        /// The bytes read past the last header while looking for another
+        pushback: Vec<u8>,
+    },
+    /// No header (only possible in optional mode); contains the bytes which were read while
+    /// looking for one
+    Plain(Vec<u8>),
+}
+
+
+/// What was found looking for a single header
+enum Sniffed {
+    Header(ProxyProtocolHeader),
+    /// No header; contains the bytes which were read while looking for one
+    Plain(Vec<u8>),
+}
+
+
+/// The TCP socket underneath `stream`, if it is one we know how to find it in (only
+/// `HttpStream`)
+fn tcp_stream<S: NetworkStream>(stream: &S) -> Option<&TcpStream> {
+    (stream as &dyn Any).downcast_ref::<HttpStream>().map(|stream| &stream.0)
+}
+
+
+/// Apply the socket options from `config` to `stream`, if it is a TCP socket
+fn apply_socket_options<S: NetworkStream>(stream: &S, config: &ProxyConfig) -> io::Result<()> {
+    let socket = match tcp_stream(stream) {
+        Some(socket) => socket,
+        None => return Ok(()),
+    };
+    if config.tcp_nodelay {
+        socket.set_nodelay(true)?;
+    }
+    if let Some(idle) = config.tcp_keepalive {
+        set_keepalive(socket, idle)?;
+    }
+    Ok(())
+}
+
+
+#[cfg(unix)]
+fn set_keepalive(socket: &TcpStream, idle: Duration) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    fn setsockopt(fd: libc::c_int, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+        let ret = unsafe {
+            libc::setsockopt(fd, level, name, &value as *const libc::c_int as *const libc::c_void,
+                             ::std::mem::size_of::<libc::c_int>() as libc::socklen_t)
+        };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    let fd = socket.as_raw_fd();
+    setsockopt(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios"))]
+    {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        let name = libc::TCP_KEEPIDLE;
+        #[cfg(any(target_os = "macos", target_os = "ios"))]
+        let name = libc::TCP_KEEPALIVE;
+        let secs = idle.as_secs().clamp(1, i32::MAX as u64) as libc::c_int;
+        setsockopt(fd, libc::IPPROTO_TCP, name, secs)?;
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios")))]
+    let _ = idle;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_keepalive(_socket: &TcpStream, _idle: Duration) -> io::Result<()> {
+    Err(io::Error::other("TCP keepalive is only supported on unix"))
+}
+
+
+/// Make closing `socket` send a RST instead of a FIN, by setting a zero linger timeout
+#[cfg(unix)]
+fn set_linger_zero(socket: &TcpStream) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let linger = libc::linger { l_onoff: 1, l_linger: 0 };
+    let ret = unsafe {
+        libc::setsockopt(socket.as_raw_fd(), libc::SOL_SOCKET, libc::SO_LINGER,
+                         &linger as *const libc::linger as *const libc::c_void,
+                         ::std::mem::size_of::<libc::linger>() as libc::socklen_t)
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(unix))]
+fn set_linger_zero(_socket: &TcpStream) -> io::Result<()> {
+    Err(io::Error::other("resetting connections is only supported on unix"))
+}
+
+
+/// Wait for `stream` to become readable, giving up at `deadline`. Returns `false` if the
+/// deadline passed, or if `stream` is not one whose file descriptor we know how to find (only
+/// `HttpStream` is, on unix).
+fn wait_readable<S: NetworkStream>(stream: &S, deadline: Option<Instant>) -> io::Result<bool> {
+    Ok(try_wait_readable(stream, deadline)?.unwrap_or(false))
+}
+
+
+/// `wait_readable`, but `None` if `stream` cannot be waited on
+#[cfg(unix)]
+fn try_wait_readable<S: NetworkStream>(stream: &S, deadline: Option<Instant>) -> io::Result<Option<bool>> {
+    use std::os::unix::io::AsRawFd;
+
+    match tcp_stream(stream) {
+        Some(socket) => poll_readable(socket.as_raw_fd(), deadline).map(Some),
+        None => Ok(None),
+    }
+}
+
+#[cfg(not(unix))]
+fn try_wait_readable<S: NetworkStream>(_stream: &S, _deadline: Option<Instant>) -> io::Result<Option<bool>> {
+    Ok(None)
+}
+
+
+/// Wait for `fd` to become readable (for a listening socket, to have a connection waiting),
+/// giving up at `deadline`; returns whether it did
+#[cfg(unix)]
+pub(crate) fn poll_readable(fd: ::std::os::unix::io::RawFd, deadline: Option<Instant>) -> io::Result<bool> {
+    poll_any_readable(&[fd], deadline).map(|ready| ready[0])
+}
+
+
+/// Wait for any of `fds` to become readable, giving up at `deadline`; returns which of them
+/// are (none, if the deadline passed). A deadline which has already passed still finds those
+/// which are readable straight away.
+#[cfg(unix)]
+pub(crate) fn poll_any_readable(fds: &[::std::os::unix::io::RawFd], deadline: Option<Instant>) -> io::Result<Vec<bool>> {
+    let mut pollfds: Vec<_> = fds.iter().map(|&fd| libc::pollfd { fd, events: libc::POLLIN, revents: 0 }).collect();
+    loop {
+        let timeout_ms = match deadline {
+            None => -1,
+            Some(deadline) => {
+                // round up, so that we never spin with a zero timeout before the deadline
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                let ms = remaining.as_secs().saturating_mul(1000) + u64::from(remaining.subsec_nanos().div_ceil(1_000_000));
+                ms.min(i32::MAX as u64) as libc::c_int
+            },
+        };
+        match unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, timeout_ms) } {
+            -1 => {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            },
+            // readable, or an error or hangup which the next read will report
+            _ => return Ok(pollfds.iter().map(|p| p.revents != 0).collect()),
+        }
+    }
+}
+
+
+/// What reading a header needs from the stream it is read off, beyond `Read`
+pub(crate) trait HeaderSource: Read {
+    /// Replace the read timeout, as `NetworkStream::set_read_timeout` does
+    fn set_header_read_timeout(&self, dur: Option<Duration>) -> io::Result<()>;
+
+    /// After a read failed with `WouldBlock`, wait until the source is readable or `deadline`
+    /// passes; `false` means that the read should fail after all
+    fn wait_readable(&self, deadline: Option<Instant>) -> io::Result<bool>;
+
+    /// Wait until the source is readable or `deadline` passes, returning whether it is, or
+    /// `None` if the source cannot be waited on without reading it
+    fn try_wait_readable(&self, deadline: Instant) -> io::Result<Option<bool>>;
+}
+
+impl<S: NetworkStream> HeaderSource for S {
+    fn set_header_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.set_read_timeout(dur)
+    }
+
+    fn wait_readable(&self, deadline: Option<Instant>) -> io::Result<bool> {
+        wait_readable(self, deadline)
+    }
+
+    fn try_wait_readable(&self, deadline: Instant) -> io::Result<Option<bool>> {
+        try_wait_readable(self, Some(deadline))
+    }
+}
+
+
+/// `Read` adapter used while looking for a header, which can remember the first few bytes
+/// read through it.
+///
+/// Inner streams in nonblocking mode (e.g., sockets handed over from an async accept loop)
+/// fail reads with `WouldBlock` whenever the next part of the header has not arrived yet;
+/// those reads are retried once the stream is readable, until `deadline`.
+pub(crate) struct HeaderReader<'a, S: 'a> {
+    pub(crate) stream: &'a mut S,
+    pub(crate) prefix: Option<&'a mut Vec<u8>>,
+    // everything read, for `ProxyConfig::retain_raw_header`
+    pub(crate) raw: Option<&'a mut Vec<u8>>,
+    pub(crate) deadline: Option<Instant>,
+    // how many bytes have been read, which once a header has been parsed is its length, since
+    // the parsers never read past the end of one
+    pub(crate) consumed: usize,
+}
+
+impl<'a, S: HeaderSource> Read for HeaderReader<'a, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = loop {
+            match self.stream.read(buf) {
+                Err(e) => {
+                    if e.kind() != io::ErrorKind::WouldBlock || !self.stream.wait_readable(self.deadline)? {
+                        return Err(e);
+                    }
+                },
+                Ok(n) => break n,
+            }
+        };
+        if let Some(ref mut prefix) = self.prefix {
+            let wanted = RECORDED_PREFIX_LEN.saturating_sub(prefix.len()).min(n);
+            prefix.extend_from_slice(&buf[..wanted]);
+        }
+        if let Some(ref mut raw) = self.raw {
+            raw.extend_from_slice(&buf[..n]);
+        }
+        self.consumed += n;
+        Ok(n)
+    }
+}
+
+
+/// Read the first byte of the stream, or `None` if none arrives within `grace`. Sockets are
+/// polled until `grace` passes; for any other stream, the read timeout is temporarily
+/// replaced with `grace` (at least a millisecond, since a zero timeout is refused) and then
+/// restored to `read_timeout`.
+fn read_first_byte<S: HeaderSource>(reader: &mut HeaderReader<S>, grace: Option<Duration>, read_timeout: Option<Duration>) -> io::Result<Option<u8>> {
+    let mut first_byte = [0u8; 1];
+    match read_within_grace(reader, &mut first_byte, grace, read_timeout)? {
+        Some(0) => Err(io::ErrorKind::UnexpectedEof.into()),
+        Some(_) => Ok(Some(first_byte[0])),
+        None => Ok(None),
+    }
+}
+
+
+/// `read()` once into `buf`, or `None` if nothing arrives within `grace`, in the same way as
+/// `read_first_byte`
+fn read_within_grace<S: HeaderSource>(reader: &mut HeaderReader<S>, buf: &mut [u8], grace: Option<Duration>, read_timeout: Option<Duration>) -> io::Result<Option<usize>> {
+    let read = |reader: &mut HeaderReader<S>, buf: &mut [u8]| loop {
+        match reader.read(buf) {
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            result => return result,
+        }
+    };
+    let grace = match grace {
+        Some(grace) => grace,
+        None => return read(reader, buf).map(Some),
+    };
+    match reader.stream.try_wait_readable(Instant::now() + grace)? {
+        Some(true) => return read(reader, buf).map(Some),
+        Some(false) => return Ok(None),
+        None => {},
+    }
+    let grace = grace.max(Duration::from_millis(1));
+    reader.stream.set_header_read_timeout(Some(grace))?;
+    let deadline = reader.deadline.replace(Instant::now() + grace);
+    let result = read(reader, buf);
+    reader.deadline = deadline;
+    reader.stream.set_header_read_timeout(read_timeout)?;
+    match result {
+        Ok(n) => Ok(Some(n)),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+
+/// Look for a header (or a chain of them, with `ProxyConfig::max_proxy_hops`) at the start of
+/// `reader` as described by `config`, including checking the source address of each, and
+/// that its address family is allowed. Headers downgraded for their source addresses are
+/// warned about in `warnings`.
+pub(crate) fn read_preamble<S: HeaderSource>(reader: &mut HeaderReader<S>, config: &ProxyConfig, read_timeout: Option<Duration>, warnings: &mut Vec<ProxyWarning>) -> proxy_protocol::Result<Preamble> {
+    if config.require_header_in_first_read {
+        return read_preamble_in_first_read(reader, config, read_timeout, warnings);
+    }
+    let first = match sniff_preamble(reader, config, read_timeout)? {
+        Sniffed::Header(header) => check_header(header, config, warnings)?,
+        Sniffed::Plain(pushback) => return Ok(Preamble::Plain(pushback)),
+    };
+    if config.max_proxy_hops <= 1 {
+        return Ok(Preamble::Header { header: first, hops: Vec::new(), pushback: Vec::new() });
+    }
+    let mut hops = vec![first];
+    let mut pushback = Vec::new();
+    while hops.len() < config.max_proxy_hops {
+        // a client which closes the connection or goes quiet after a header has sent its
+        // last one; it is up to the server to make of that what it will
+        let first_byte = match read_first_byte(reader, config.grace_period, read_timeout) {
+            Ok(Some(first_byte)) => first_byte,
+            Ok(None) => break,
+            Err(ref e) if matches!(e.kind(), io::ErrorKind::UnexpectedEof | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => break,
+            Err(e) => return Err(e.into()),
+        };
+        match sniff_after_first_byte(reader, first_byte, ProxyProtocolVersion::Any, &config.parse)? {
+            Sniffed::Header(header) => hops.push(check_header(header, config, warnings)?),
+            Sniffed::Plain(consumed) => {
+                pushback = consumed;
+                break;
+            },
+        }
+    }
+    Ok(choose_hop(hops, pushback, config))
+}
+
+
+/// The preamble for the chain of `hops` read from a listener configured with `config`
+fn choose_hop(hops: Vec<ProxyProtocolHeader>, pushback: Vec<u8>, config: &ProxyConfig) -> Preamble {
+    let header = match config.peer_hop {
+        PeerHop::Outermost => hops[0].clone(),
+        PeerHop::Innermost => hops[hops.len() - 1].clone(),
+    };
+    Preamble::Header { header, hops, pushback }
+}
+
+
+/// `read_preamble` for `ProxyConfig::require_header_in_first_read`: every header has to be
+/// in the bytes returned by the first read, and whatever follows them is pushed back
+fn read_preamble_in_first_read<S: HeaderSource>(reader: &mut HeaderReader<S>, config: &ProxyConfig, read_timeout: Option<Duration>, warnings: &mut Vec<ProxyWarning>) -> proxy_protocol::Result<Preamble> {
+    let mut segment = vec![0u8; config.parse.longest_header(config.version).max(1)];
+    let grace = if config.optional { config.grace_period } else { None };
+    let len = match read_within_grace(reader, &mut segment, grace, read_timeout)? {
+        None => return Ok(Preamble::Plain(Vec::new())),
+        Some(0) => return Err(ProxyReadError::UnexpectedEof { needed: 1, got: 0 }),
+        Some(len) => len,
+    };
+    segment.truncate(len);
+    let mut hops = Vec::new();
+    let mut used = 0;
+    while hops.len() < config.max_proxy_hops.max(1) {
+        let rest = &segment[used..];
+        // the first header is required, unless the listener is optional, and those after it
+        // are looked for only if something which starts like one follows
+        let version = if hops.is_empty() { config.version } else { ProxyProtocolVersion::Any };
+        if (!hops.is_empty() || config.optional) && !starts_like_header(rest, version, &config.parse) {
+            break;
+        }
+        match proxy_protocol::parse_with(rest, version, &config.parse) {
+            Poll::Ready(Ok((header, header_len))) => {
+                hops.push(check_header(header, config, warnings)?);
+                used += header_len;
+            },
+            Poll::Ready(Err(e)) => return Err(e),
+            Poll::Pending => return Err(ProxyReadError::SplitHeader(len)),
+        }
+    }
+    let pushback = segment.split_off(used);
+    if hops.is_empty() {
+        return Ok(Preamble::Plain(pushback));
+    }
+    if config.max_proxy_hops <= 1 {
+        return Ok(Preamble::Header { header: hops.remove(0), hops, pushback });
+    }
+    Ok(choose_hop(hops, pushback, config))
+}
+
+
+/// Whether `bytes` are the start of a header of `version`, as far as they go; an empty slice
+/// is not
+fn starts_like_header(bytes: &[u8], version: ProxyProtocolVersion, parse: &ParseConfig) -> bool {
+    let literal = match bytes.first().and_then(|&first_byte| header_literal(first_byte, version, parse)) {
+        Some(literal) => literal,
+        None => return false,
+    };
+    bytes.iter().zip(literal).all(|(&byte, &expected)| byte == expected || (parse.allow_lowercase && literal == V1_LITERAL && byte.eq_ignore_ascii_case(&expected)))
+}
+
+
+/// Check the source address and family of `header`, as read by `read_preamble`
+fn check_header(header: ProxyProtocolHeader, config: &ProxyConfig, warnings: &mut Vec<ProxyWarning>) -> proxy_protocol::Result<ProxyProtocolHeader> {
+    let header = check_source(header, config.unspecified_source, warnings)?;
+    if config.require_proxied_address && header.source_addr().is_none() {
+        return Err(ProxyReadError::NoClientAddress(header.command()));
+    }
+    match config.allowed_families {
+        Some(ref families) if !families.contains(&header.proto()) => Err(ProxyReadError::FamilyNotAllowed(header.proto())),
+        _ => Ok(header),
+    }
+}
+
+
+/// Apply `policy` to `header` if its source address is unspecified or has port 0
+fn check_source(header: ProxyProtocolHeader, policy: UnspecifiedSource, warnings: &mut Vec<ProxyWarning>) -> proxy_protocol::Result<ProxyProtocolHeader> {
+    let addr = match header.source_addr() {
+        Some(addr) if addr.ip().is_unspecified() || addr.port() == 0 => addr,
+        _ => return Ok(header),
+    };
+    match policy {
+        UnspecifiedSource::Accept => Ok(header),
+        UnspecifiedSource::Reject => Err(ProxyReadError::UnspecifiedSource(addr)),
+        UnspecifiedSource::Downgrade => {
+            warnings.push(ProxyWarning::UnspecifiedSource(addr));
+            Ok(header.downgraded())
+        },
+    }
+}
+
+
+fn sniff_preamble<S: HeaderSource>(reader: &mut HeaderReader<S>, config: &ProxyConfig, read_timeout: Option<Duration>) -> proxy_protocol::Result<Sniffed> {
+    if !config.optional {
+        let mut parser = HeaderParser::new(config.version).parse_config(config.parse);
+        return read_header(reader, &mut parser).map(Sniffed::Header);
+    }
+    let first_byte = match read_first_byte(reader, config.grace_period, read_timeout) {
+        Ok(Some(first_byte)) => first_byte,
+        Ok(None) => return Ok(Sniffed::Plain(Vec::new())),
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Err(ProxyReadError::UnexpectedEof { needed: 1, got: 0 }),
+        Err(e) => return Err(e.into()),
+    };
+    sniff_after_first_byte(reader, first_byte, config.version, &config.parse)
+}
+
+
+/// Look for a header of `version` whose first byte, if it is one, has been read already
+fn sniff_after_first_byte<S: HeaderSource>(reader: &mut HeaderReader<S>, first_byte: u8, version: ProxyProtocolVersion, parse: &ParseConfig) -> proxy_protocol::Result<Sniffed> {
+    let literal = match header_literal(first_byte, version, parse) {
+        Some(literal) => literal,
+        None => return Ok(Sniffed::Plain(vec![first_byte])),
+    };
+    // Plenty of plain requests start with the same byte as a header ("POST", "PUT", and the
+    // HTTP/2 preface all start with 'P'), so only commit to parsing once the whole literal
+    // has arrived, and hand back everything read so far otherwise.
+    let mut consumed = vec![first_byte];
+    while consumed.len() < literal.len() {
+        let mut byte = [0u8; 1];
+        if reader.read(&mut byte)? == 0 {
+            return Ok(Sniffed::Plain(consumed));
+        }
+        consumed.push(byte[0]);
+        let expected = literal[consumed.len() - 1];
+        let matches = byte[0] == expected || (parse.allow_lowercase && literal == V1_LITERAL && byte[0].eq_ignore_ascii_case(&expected));
+        if !matches {
+            return Ok(Sniffed::Plain(consumed));
+        }
+    }
+    let mut parser = HeaderParser::new(ProxyProtocolVersion::Any).parse_config(*parse);
+    match parser.advance(&consumed) {
+        Poll::Ready(result) => result.map(|(header, _)| Sniffed::Header(header)),
+        Poll::Pending => read_header(reader, &mut parser).map(Sniffed::Header),
+    }
+}
+
+
+/// Best-effort write of the configured `FailureResponse` to a client which sent `prefix`
+/// instead of a PROXY header, and so failed with `err`
+fn send_failure_response<S: NetworkStream>(stream: &mut S, response: &FailureResponse, prefix: &[u8], err: &ProxyReadError) {
+    // a parser which gives up on TLS at its first byte leaves too little of the record to
+    // tell from the prefix
+    let guess = match *err {
+        ProxyReadError::LooksLikeTls => ClientGuess::Tls,
+        _ => guess_client(prefix),
+    };
+    let bytes: &[u8] = match (response, guess) {
+        (FailureResponse::Nothing, _) | (_, ClientGuess::Unknown) => return,
+        (FailureResponse::Canned, ClientGuess::Http) => CANNED_HTTP_RESPONSE,
+        (FailureResponse::Canned, ClientGuess::Tls) => CANNED_TLS_ALERT,
+        (FailureResponse::Custom(bytes), _) => bytes,
+    };
+    let timeout = Some(Duration::from_millis(100));
+    if stream.set_write_timeout(timeout).is_err() {
+        // without a timeout a client which never reads could block us indefinitely
+        return;
+    }
+    if stream.write_all(bytes).and_then(|_| stream.flush()).is_err() {
+        return;
+    }
+    // Closing a socket with unread data in its receive buffer makes the kernel send an RST,
+    // which can destroy the response before the client reads it, so shut down our side and
+    // briefly discard whatever else the client sends first.
+    let _ = stream.close(Shutdown::Write);
+    if stream.set_read_timeout(timeout).is_ok() {
+        let mut discard = [0u8; 1024];
+        for _ in 0..8 {
+            match stream.read(&mut discard) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {},
+            }
+        }
+    }
+}
+
+
+/// The connection's endpoints as its PROXY header gave them, as returned by
+/// `ProxyStream::proxied_addrs`. Unlike `peer_addr()`, this never mixes in the socket's own
+/// addresses. With the `serde` feature it serializes (for logging) as a map whose `kind` is
+/// the variant's name in snake case, along with `source` and `dest` for `Proxied`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize), serde(tag = "kind", rename_all = "snake_case"))]
+pub enum ProxiedAddrs {
+    /// Relayed for the client at `source`, which connected to `dest`
+    Proxied {
+        source: SocketAddr,
+        dest: SocketAddr,
+    },
+    /// Made by the proxy itself (a version 2 `LOCAL` header), so there is no client; any
+    /// addresses in the header are the proxy's own business
+    Local,
+    /// Relayed for a client without IP addresses the header could carry: a `PROXY UNKNOWN`,
+    /// or a version 2 header of an unspecified or unix address family
+    Unknown,
+    /// There was no header at all (in optional mode, or because the peer is not a trusted
+    /// proxy), so the socket's peer is the client
+    Direct,
+}
+
+
+/// Where the address reported by `ProxyStream::peer_addr()` comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyState {
+    /// The source address from the PROXY header
+    Proxied,
+    /// There was no header (in optional mode, or because the peer is not a trusted proxy) or
+    /// it had no source address, so the inner stream's peer address is reported
+    Socket,
+    /// Neither the header nor the inner stream had an address, so the given placeholder
+    /// (chosen by the listener's `PeerAddrFallback`) is reported. This is not a real address.
+    Placeholder(SocketAddr),
+    /// Neither the header nor the inner stream had an address, and `peer_addr()` returns the
+    /// inner stream's error
+    Unavailable,
+    /// The given address was set with `ProxyStream::set_peer_addr`, in place of whatever the
+    /// stream would have reported
+    Overridden(SocketAddr),
+}
+
+
+/// Something about an accepted connection which was not worth failing it over, but which the
+/// application may want to know about; see `ProxyListener::accept_with_warnings`
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ProxyWarning {
+    /// The socket options in the `ProxyConfig` could not be applied
+    SocketOptions(io::Error),
+    /// A version 2 header's `UNSPEC` address block was too long to keep all of its TLVs (see
+    /// `ParseConfig::max_v2_block_len`), so this many bytes of them were dropped; the rest are
+    /// listed by `ProxyProtocolHeader::tlvs`
+    IgnoredTlvs(usize),
+    /// The header carried no source address (e.g., `PROXY UNKNOWN`), so the inner stream's
+    /// peer address is reported instead
+    SocketPeerFallback,
+    /// The header carried no source address and neither did the inner stream, so the
+    /// placeholder chosen by the listener's `PeerAddrFallback` is reported instead
+    PlaceholderPeer(SocketAddr),
+    /// The header's source address was unspecified or had port 0, so its addresses were
+    /// dropped as `UnspecifiedSource::Downgrade` asks
+    UnspecifiedSource(SocketAddr),
+}
+
+impl fmt::Display for ProxyWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ProxyWarning::SocketOptions(ref e) => write!(f, "could not set socket options: {}", e),
+            ProxyWarning::IgnoredTlvs(len) => write!(f, "dropped {} bytes of TLVs past the address block limit", len),
+            ProxyWarning::SocketPeerFallback => write!(f, "header had no source address; using the socket peer"),
+            ProxyWarning::PlaceholderPeer(addr) => write!(f, "header had no source address; using placeholder {}", addr),
+            ProxyWarning::UnspecifiedSource(addr) => write!(f, "header had source address {}; treating it as unknown", addr),
+        }
+    }
+}
+
+
+/// Bytes which were read from the inner stream while looking for a header but turned out not
+/// to be part of one, and so still have to be delivered to the reader
+#[derive(Debug)]
+pub(crate) struct Pushback {
+    bytes: Vec<u8>,
+    pos: usize,
+}
+
+impl Pushback {
+    pub(crate) fn new(bytes: Vec<u8>) -> Self {
+        Pushback { bytes, pos: 0 }
+    }
+
+    pub(crate) fn read(&mut self, buf: &mut [u8]) -> usize {
+        let remaining = &self.bytes[self.pos..];
+        let n = remaining.len().min(buf.len());
+        buf[..n].copy_from_slice(&remaining[..n]);
+        self.pos += n;
+        n
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.pos == self.bytes.len()
+    }
+
+    /// The bytes which have not been read yet
+    pub(crate) fn remaining(&self) -> &[u8] {
+        &self.bytes[self.pos..]
+    }
+
+    /// Remove and return the bytes not read yet
+    pub(crate) fn take(&mut self) -> Vec<u8> {
+        let bytes = self.bytes.split_off(self.pos);
+        self.bytes.clear();
+        self.pos = 0;
+        bytes
+    }
+
+    /// Add `bytes` after those not read yet
+    pub(crate) fn extend(&mut self, bytes: &[u8]) {
+        self.bytes.drain(..self.pos);
+        self.pos = 0;
+        self.bytes.extend_from_slice(bytes);
+    }
+}
+
+
+/// The most `ProxyStream::fill_peek` will buffer
+const PEEK_CAP: usize = 16 * 1024;
+
+
+/// Wrapper class for holding a `NetworkStream` off of which we have already
+/// read a PROXY protocol header
+///
+/// Clones behave like duplicated sockets: any bytes that were read off the inner stream while
+/// looking for a header (in optional mode) are delivered exactly once, to whichever clone
+/// reads first.
+///
+/// Each clone counts the bytes read and written through it (see `bytes_read` and
+/// `bytes_written`); the listener's `ProxyObserver`, if any, is told the totals over all
+/// clones once the last one is dropped.
+///
+/// A server holds one of these (or more, with clones) for every open connection, so only what
+/// every read and write needs is kept inline; the header and everything else is boxed away in
+/// `ProxyExtras`, and left out entirely for streams which have none of it.
+///
+/// Code which downcasts hyper's `NetworkStream` to get at the socket (e.g., to hijack the
+/// connection for a websocket) finds one of these in place of the listener's own stream, so
+/// should downcast to `ProxyStream<S>` instead (`ProxyHttpStream`, for an `HttpListener`) and
+/// go through `get_ref`:
+///
+/// ```no_run
+/// # extern crate hyper;
+/// # extern crate hyper_networklistener_proxy;
+/// use hyper::server::{Request, Response};
+/// use hyper_networklistener_proxy::ProxyHttpStream;
+///
+/// fn handle(req: Request, res: Response) {
+///     let socket = req.downcast_ref::<ProxyHttpStream>().map(|stream| stream.get_ref().0.try_clone().unwrap());
+///     // ...answer with 101 Switching Protocols, and carry on over `socket`
+/// #   drop((socket, res));
+/// }
+/// # fn main() {}
+/// ```
+pub struct ProxyStream<T: NetworkStream> {
+    inner: T,
+    state: StateTag,
+    // shared between clones, and dropped by each clone once it finds it drained
+    pushback: Option<Arc<Mutex<Pushback>>>,
+    // by the extras' `clock`, which `age` also goes by
+    accepted_at: Instant,
+    books: Bookkeeping,
+}
+
+
+/// The stream accepted by a `ProxyListener<HttpListener>`
+pub type ProxyHttpStream = ProxyStream<HttpStream>;
+
+
+/// `ProxyState`, along with the address the stream reports in place of the inner stream's
+/// (for `Proxied`, the header's source address)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StateTag {
+    Proxied(SocketAddr),
+    Socket,
+    Placeholder(SocketAddr),
+    Unavailable,
+    Overridden(SocketAddr),
+}
+
+impl StateTag {
+    /// The address `peer_addr()` reports, unless it goes to the inner stream
+    fn addr(&self) -> Option<SocketAddr> {
+        match *self {
+            StateTag::Proxied(addr) | StateTag::Placeholder(addr) | StateTag::Overridden(addr) => Some(addr),
+            StateTag::Socket | StateTag::Unavailable => None,
+        }
+    }
+}
+
+
+/// A clone's byte counts, and the extras they are reported to: the counts are added to
+/// the tracker's totals when this is dropped, along with the clone or once `into_parts` has
+/// taken the clone apart
+struct Bookkeeping {
+    bytes_read: u64,
+    bytes_written: u64,
+    extras: Option<Box<ProxyExtras>>,
+}
+
+impl Bookkeeping {
+    /// Start counting for a new clone
+    fn new(extras: Option<Box<ProxyExtras>>) -> Self {
+        Bookkeeping { bytes_read: 0, bytes_written: 0, extras }
+    }
+}
+
+impl Drop for Bookkeeping {
+    fn drop(&mut self) {
+        if let Some(tracker) = self.extras.as_ref().and_then(|extras| extras.tracker.as_ref()) {
+            tracker.add(self.bytes_read, self.bytes_written);
+        }
+    }
+}
+
+
+/// The parts of a `ProxyStream` which few connections have, or which are rarely looked at
+#[derive(Clone, Default)]
+struct ProxyExtras {
+    info: Option<ProxyInfo>,
+    // how many bytes the header took up on the wire
+    header_len: usize,
+    raw_header: Option<Vec<u8>>,
+    // every header read, with `ProxyConfig::max_proxy_hops`
+    hops: Vec<ProxyProtocolHeader>,
+    // shared between clones, so that the registry entry outlives all of them
+    registration: Option<Arc<Registration>>,
+    tracker: Option<Arc<ConnectionTracker>>,
+    // the read timeout the stream was left with once accepted, which `fill_peek` restores
+    read_timeout: Option<Duration>,
+    // shared between clones, so that the connection counts against its client's limit until
+    // all of them are gone
+    limit: Option<Arc<LimitSlot>>,
+    clock: Option<Clock>,
+}
+
+impl ProxyExtras {
+    /// Box the extras, unless there are none
+    fn boxed(self) -> Option<Box<Self>> {
+        let empty = self.info.is_none() && self.registration.is_none() && self.tracker.is_none() &&
+            self.read_timeout.is_none() && self.limit.is_none() && self.clock.is_none();
+        if empty {
+            None
+        } else {
+            Some(Box::new(self))
+        }
+    }
+}
+
+// Clone and Debug are implemented by hand, since deriving them would require the inner stream
+// to be Debug even to clone, and Clone even to print.
+/// Clones share the header, registry entry and any bytes read past the header, as described
+/// above. Cloning is only as fallible as the inner stream's `Clone`: in particular,
+/// `HttpStream`'s panics if the socket cannot be duplicated (e.g. when the process is out of
+/// file descriptors). Use `try_clone` to get an error instead.
+impl<T: NetworkStream + Clone> Clone for ProxyStream<T> {
+    fn clone(&self) -> Self {
+        ProxyStream {
+            inner: self.inner.clone(),
+            state: self.state,
+            pushback: self.pushback.clone(),
+            accepted_at: self.accepted_at,
+            books: Bookkeeping::new(self.books.extras.clone()),
+        }
+    }
+}
+
+impl<T: NetworkStream> fmt::Debug for ProxyStream<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ProxyStream")
+            .field("inner", &type_name::<T>())
+            .field("peer_addr", &self.state.addr())
+            .field("state", &self.proxy_state())
+            .field("info", &self.proxy_info())
+            .field("pushback", &self.pushback.as_ref().map(|pushback| pushback.lock().unwrap().bytes.len()))
+            .field("bytes_read", &self.books.bytes_read)
+            .field("bytes_written", &self.books.bytes_written)
+            .finish()
+    }
+}
+
+impl<T: NetworkStream> ProxyStream<T> {
+    /// Read the PROXY header off of `stream` as described by `config`. `read_timeout` is the
+    /// read timeout the stream should be left with if it has to be changed along the way.
+    /// Anything worth warning about is added to `warnings`.
+    pub(crate) fn from_stream(mut stream: T, config: &ProxyConfig, read_timeout: Option<Duration>, warnings: &mut Vec<ProxyWarning>) -> proxy_protocol::Result<Self> {
+        let accepted_at = (config.now(), SystemTime::now());
+        // socket options are a nicety, and a connection is still worth serving without them
+        if let Err(e) = apply_socket_options(&stream, config) {
+            warnings.push(ProxyWarning::SocketOptions(e));
+        }
+        if let Some(ref trusted) = config.trusted_proxies {
+            let is_trusted = match stream.peer_addr() {
+                Ok(peer) => trusted.get().contains(peer.ip()),
+                Err(_) => false,
+            };
+            if !is_trusted {
+                return ProxyStream::untrusted(stream, config, read_timeout, accepted_at.0).map(|stream| stream.attach(config, accepted_at));
+            }
+        }
+        // XXX: without a header timeout, we rely on the read timeout having been set already.
+        // HttpListener sets the timeout in its `accept`, so it should be fine,
+        // but other listeners might not set the timeout until after accept...
+        if config.header_timeout.is_some() {
+            stream.set_read_timeout(config.header_timeout)?;
+        }
+        let header_timeout = config.header_timeout.or(read_timeout);
+        let mut prefix = if config.failure_response == FailureResponse::Nothing {
+            None
+        } else {
+            Some(Vec::new())
+        };
+        let mut raw = if config.retain_raw_header { Some(Vec::new()) } else { None };
+        let (preamble, header_len) = {
+            let mut reader = HeaderReader {
+                stream: &mut stream,
+                prefix: prefix.as_mut(),
+                raw: raw.as_mut(),
+                deadline: header_timeout.map(|timeout| Instant::now() + timeout),
+                consumed: 0,
+            };
+            let preamble = read_preamble(&mut reader, config, header_timeout, warnings);
+            (preamble, reader.consumed)
+        };
+        let (proxy_header, hops, pushback) = match preamble {
+            Ok(Preamble::Header { header, hops, pushback }) => {
+                let kept: usize = header.tlvs().iter().map(|tlv| 3 + tlv.value.len()).sum();
+                if header.tlv_len() > kept {
+                    warnings.push(ProxyWarning::IgnoredTlvs(header.tlv_len() - kept));
+                }
+                (Some(header), hops, pushback)
+            },
+            Ok(Preamble::Plain(pushback)) => (None, Vec::new(), pushback),
+            Err(e) => {
+                if let Some(prefix) = prefix {
+                    send_failure_response(&mut stream, &config.failure_response, &prefix, &e);
+                }
+                return Err(e);
+            }
+        };
+        match config.stream_read_timeout {
+            Some(timeout) => stream.set_read_timeout(Some(timeout))?,
+            None if config.header_timeout.is_some() => stream.set_read_timeout(read_timeout)?,
+            None => {},
+        }
+        let state = match proxy_header.as_ref().and_then(|h| h.source_addr()) {
+            Some(addr) => StateTag::Proxied(addr),
+            None => match (stream.peer_addr(), config.peer_addr_fallback) {
+                (Ok(_), _) => StateTag::Socket,
+                (Err(_), PeerAddrFallback::Error) => StateTag::Unavailable,
+                (Err(_), PeerAddrFallback::Unspecified) => StateTag::Placeholder(SocketAddr::new(Ipv4Addr::new(0, 0, 0, 0).into(), 0)),
+                (Err(_), PeerAddrFallback::Fixed(addr)) => StateTag::Placeholder(addr),
+            }
+        };
+        match state {
+            StateTag::Socket if proxy_header.is_some() => warnings.push(ProxyWarning::SocketPeerFallback),
+            StateTag::Placeholder(addr) => warnings.push(ProxyWarning::PlaceholderPeer(addr)),
+            _ => {},
+        }
+        // anything read past the last of a chain of headers is not part of them
+        let header_len = header_len - pushback.len();
+        if let Some(ref mut raw) = raw {
+            raw.truncate(header_len);
+        }
+        let extras = ProxyExtras {
+            header_len: if proxy_header.is_some() { header_len } else { 0 },
+            raw_header: if proxy_header.is_some() { raw } else { None },
+            info: proxy_header.map(|header| ProxyInfo::from_header(header).accepted(Some(accepted_at.1))),
+            hops,
+            read_timeout: config.stream_read_timeout.or(read_timeout),
+            clock: config.clock.clone(),
+            ..ProxyExtras::default()
+        };
+        let proxy_stream = ProxyStream {
+            state,
+            inner: stream,
+            pushback: if pushback.is_empty() {
+                None
+            } else {
+                Some(Arc::new(Mutex::new(Pushback::new(pushback))))
+            },
+            accepted_at: accepted_at.0,
+            books: Bookkeeping::new(extras.boxed()),
+        };
+        Ok(proxy_stream.attach(config, accepted_at))
+    }
+
+    /// Register a newly accepted stream with the registry and observer from `config`, if any
+    fn attach(mut self, config: &ProxyConfig, accepted_at: (Instant, SystemTime)) -> Self {
+        #[cfg(feature = "rdns")]
+        let reverse_dns = match (config.reverse_dns.as_ref(), self.proxy_info()) {
+            (Some(reverse_dns), Some(info)) if !info.is_local() => info.source_addr().map(|source| {
+                reverse_dns.resolve(source.ip());
+                reverse_dns.clone()
+            }),
+            _ => None,
+        };
+        if config.registry.is_none() && config.observer.is_none() {
+            return self;
+        }
+        // key on the address hyper will see, falling back to the socket peer just as
+        // `peer_addr()` does
+        let peer_addr = self.peer_addr().ok();
+        let info = self.proxy_info().cloned();
+        if let (Some(registry), Some(info), Some(addr)) = (config.registry.as_ref(), info.clone(), peer_addr) {
+            let registration = registry.register(addr, info);
+            self.extras_mut().registration = Some(Arc::new(registration));
+        }
+        if let Some(ref observer) = config.observer {
+            let tracker = ConnectionTracker::new(observer, info, peer_addr, accepted_at.0, accepted_at.1, config.clock.clone());
+            #[cfg(feature = "rdns")]
+            let tracker = tracker.with_reverse_dns(reverse_dns);
+            self.extras_mut().tracker = Some(Arc::new(tracker));
+        }
+        self
+    }
+
+    /// Wrap a stream from an untrusted peer (which has a peer address, or it could not have
+    /// been found to be untrusted) without reading a header from it
+    fn untrusted(stream: T, config: &ProxyConfig, read_timeout: Option<Duration>, accepted_at: Instant) -> proxy_protocol::Result<Self> {
+        if let Some(timeout) = config.stream_read_timeout {
+            stream.set_read_timeout(Some(timeout))?;
+        }
+        let extras = ProxyExtras {
+            read_timeout: config.stream_read_timeout.or(read_timeout),
+            clock: config.clock.clone(),
+            ..ProxyExtras::default()
+        };
+        Ok(ProxyStream {
+            inner: stream,
+            state: StateTag::Socket,
+            pushback: None,
+            accepted_at,
+            books: Bookkeeping::new(extras.boxed()),
+        })
+    }
+
+    /// Serve a read out of the pushback buffer, if there is anything in it. This is kept out
+    /// of line, since `read` only calls it while there is a buffer, and there almost never is.
+    #[cold]
+    #[inline(never)]
+    fn read_pushback(&mut self, buf: &mut [u8]) -> Option<usize> {
+        let (n, drained) = {
+            let mut pushback = self.pushback.as_ref()?.lock().unwrap();
+            (pushback.read(buf), pushback.is_empty())
+        };
+        if drained {
+            self.pushback = None;
+        }
+        if n > 0 {
+            Some(n)
+        } else {
+            None
+        }
+    }
+
+    /// Where the address reported by `peer_addr()` comes from; in particular, whether it is a
+    /// placeholder rather than a real address
+    pub fn proxy_state(&self) -> ProxyState {
+        match self.state {
+            StateTag::Proxied(_) => ProxyState::Proxied,
+            StateTag::Socket => ProxyState::Socket,
+            StateTag::Placeholder(addr) => ProxyState::Placeholder(addr),
+            StateTag::Unavailable => ProxyState::Unavailable,
+            StateTag::Overridden(addr) => ProxyState::Overridden(addr),
+        }
+    }
+
+    /// What was learned from the PROXY header, or `None` if the connection did not send one
+    /// (only possible in optional mode)
+    pub fn proxy_info(&self) -> Option<&ProxyInfo> {
+        self.books.extras.as_ref()?.info.as_ref()
+    }
+
+    /// The PROXY header the connection was relayed with, or `None` if there was none, or it
+    /// did not describe a client: a `PROXY UNKNOWN`, or a version 2 `LOCAL` or unspecified
+    /// header. For whatever header there was, see `proxy_info`.
+    pub fn proxy_header(&self) -> Option<&ProxyProtocolHeader> {
+        let header = self.proxy_info()?.header();
+        match (header.proto(), header.command()) {
+            (Proto::Unknown, _) | (_, Command::Local) | (_, Command::Unspec) => None,
+            _ => Some(header),
+        }
+    }
+
+    /// Every header the connection was relayed with, in the order they were read (so the
+    /// outermost first) if the listener reads chains of them (see
+    /// `ProxyConfig::max_proxy_hops`); otherwise empty, as `proxy_info` has the only one
+    pub fn proxy_hops(&self) -> &[ProxyProtocolHeader] {
+        self.books.extras.as_ref().map_or(&[], |extras| &extras.hops[..])
+    }
+
+    /// The address the client connected to, as the PROXY header gave it (e.g., for picking a
+    /// tenant when several public addresses lead to one backend), or `None` if the header did
+    /// not describe a client (see `proxy_header`)
+    pub fn destination_addr(&self) -> Option<SocketAddr> {
+        self.proxy_header()?.dest_addr()
+    }
+
+    /// What the PROXY header said the connection is, or `None` if there was no header
+    pub fn command(&self) -> Option<Command> {
+        self.proxy_info().map(|info| info.header().command())
+    }
+
+    /// Whether the connection was made by the proxy itself (a version 2 `LOCAL` header), as
+    /// HAProxy's health checks are, rather than relayed for a client
+    pub fn is_local(&self) -> bool {
+        self.command() == Some(Command::Local)
+    }
+
+    /// Whether the PROXY header said the connection was relayed for a client (`PROXY`, rather
+    /// than `LOCAL` or a version 1 `UNKNOWN`). A version 2 `PROXY` header with an unspecified
+    /// address family counts, though it carries no address; see `proxy_header` for that.
+    pub fn is_proxied(&self) -> bool {
+        self.command() == Some(Command::Proxy)
+    }
+
+    /// Which version of the protocol the connection's header was sent in (never `Any`, even
+    /// for listeners which accept either), or `None` if there was no header
+    pub fn negotiated_version(&self) -> Option<ProxyProtocolVersion> {
+        match self.proxy_info()?.version() {
+            1 => Some(ProxyProtocolVersion::V1),
+            _ => Some(ProxyProtocolVersion::V2),
+        }
+    }
+
+    /// Whether `peer_addr()` reports a client address which came out of the PROXY header,
+    /// rather than the socket's peer or a placeholder. This is false for headers without a
+    /// source address (`PROXY UNKNOWN`, version 2 `AF_UNSPEC` and `AF_UNIX`), and for `LOCAL`
+    /// headers, whose addresses are the proxy's own business.
+    pub fn was_proxied(&self) -> bool {
+        matches!(self.state, StateTag::Proxied(_)) && !self.is_local()
+    }
+
+    /// The inner stream. Anything done with it directly bypasses this wrapper: in particular,
+    /// reading from it skips any bytes read past the header which have not been delivered yet
+    /// (see `peek_buffered`), and is not counted by `bytes_read`.
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// The inner stream, mutably; see `get_ref`
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Unwrap the inner stream (e.g., to hand it to a TLS library), dropping the header and
+    /// any bytes read past it which have not been delivered yet, which `into_parts` returns
+    /// instead. See `into_parts` for what else is let go of.
+    pub fn into_inner(self) -> T {
+        self.into_parts().0
+    }
+
+    /// Unwrap the inner stream, along with what was learned from the header (if there was one)
+    /// and the bytes read past the header but not delivered yet (which are taken away from
+    /// every clone), which belong before whatever is read from the inner stream next.
+    ///
+    /// As far as the listener's registry, connection limit and observer are concerned, this
+    /// stream is closed, since it can no longer be told apart from any other socket; the
+    /// connection itself stays as open as the inner stream is.
+    pub fn into_parts(mut self) -> (T, Option<ProxyInfo>, Vec<u8>) {
+        let pushback = self.take_pushback();
+        let info = self.proxy_info().cloned();
+        // the rest, the bookkeeping included, is dropped just as it would be with the stream
+        let ProxyStream { inner, .. } = self;
+        (inner, info, pushback)
+    }
+
+    /// Replace the address `peer_addr()` reports, which is what hyper (and so Iron) sees as the
+    /// request's remote address, with `addr`; or with `None`, go back to the inner stream's
+    /// peer address (e.g., the load balancer's, having decided not to trust its header after
+    /// all). `proxy_state()` reports the change, but the header itself (`proxy_info()`,
+    /// `proxy_header()` and the like) is left as it was read, as are any registry entry made
+    /// under the old address and other clones of the stream.
+    pub fn set_peer_addr(&mut self, addr: Option<SocketAddr>) {
+        self.state = match addr {
+            Some(addr) => StateTag::Overridden(addr),
+            None if self.inner.peer_addr().is_ok() => StateTag::Socket,
+            None => StateTag::Unavailable,
+        };
+    }
+
+    /// Both endpoints from the PROXY header, with whichever sort of connection it was told
+    /// apart; see `ProxiedAddrs`
+    pub fn proxied_addrs(&self) -> ProxiedAddrs {
+        let header = match self.proxy_info() {
+            Some(info) => info.header(),
+            None => return ProxiedAddrs::Direct,
+        };
+        if header.command() == Command::Local {
+            return ProxiedAddrs::Local;
+        }
+        match (header.source_addr(), header.dest_addr()) {
+            (Some(source), Some(dest)) => ProxiedAddrs::Proxied { source, dest },
+            _ => ProxiedAddrs::Unknown,
+        }
+    }
+
+    /// The inner stream's own peer address (i.e., the load balancer's), which `peer_addr()`
+    /// reports the client's address from the header in place of
+    pub fn real_peer_addr(&mut self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    /// How many bytes the PROXY header took up on the wire: the whole line, including the CRLF,
+    /// for version 1, or the 16 byte preamble and the address length it gives for version 2.
+    /// This is 0 if there was no header, and the total over all of them for chains of headers
+    /// (see `proxy_hops`).
+    pub fn header_len(&self) -> usize {
+        self.books.extras.as_ref().map_or(0, |extras| extras.header_len)
+    }
+
+    /// The PROXY header exactly as it was sent, including any TLVs, if the listener was
+    /// configured to keep it with `ProxyConfig::retain_raw_header`; otherwise (or if there was
+    /// no header) `None`. Print it with `HexDump` for a log line worth reading.
+    pub fn raw_header(&self) -> Option<&[u8]> {
+        self.books.extras.as_ref()?.raw_header.as_ref().map(|raw| &raw[..])
+    }
+
+    fn tracker(&self) -> Option<&Arc<ConnectionTracker>> {
+        self.books.extras.as_ref()?.tracker.as_ref()
+    }
+
+    fn extras_mut(&mut self) -> &mut ProxyExtras {
+        self.books.extras.get_or_insert_with(Box::default)
+    }
+
+    /// When the connection was accepted (just before its header was read), by the listener's
+    /// `ProxyConfig::clock`. For a wall-clock time to log, see `ProxyInfo::accepted_at`.
+    pub fn accepted_at(&self) -> Instant {
+        self.accepted_at
+    }
+
+    /// How long ago the connection was accepted, by the listener's `ProxyConfig::clock`; for
+    /// closing long-lived keep-alive connections, e.g., during a deploy
+    pub fn age(&self) -> Duration {
+        let clock = self.books.extras.as_ref().and_then(|extras| extras.clock.as_ref());
+        Clock::now(clock).saturating_duration_since(self.accepted_at)
+    }
+
+    /// The number of bytes read through this clone of the stream, not counting the header.
+    /// Reads through shared references (`&ProxyStream`) are not counted here, though they are
+    /// included in the totals given to the listener's `ProxyObserver`.
+    pub fn bytes_read(&self) -> u64 {
+        self.books.bytes_read
+    }
+
+    /// The number of bytes written through this clone of the stream
+    pub fn bytes_written(&self) -> u64 {
+        self.books.bytes_written
+    }
+
+    /// Call `f` with the bytes which have been read off the inner stream but not yet returned
+    /// by `read`: those read past the header while looking for one (in optional mode), and
+    /// any read by `fill_peek`. Possibly empty; this never reads from the inner stream. Clones
+    /// share these bytes, so they can be peeked at through any of them, but only the first
+    /// clone to read gets them.
+    ///
+    /// The bytes are lent rather than copied, so `f` runs with them locked, and reads through
+    /// any clone wait for it to return.
+    pub fn peek_buffered<F, R>(&self, f: F) -> R where F: FnOnce(&[u8]) -> R {
+        match self.pushback {
+            Some(ref pushback) => f(pushback.lock().unwrap().remaining()),
+            None => f(&[]),
+        }
+    }
+
+    /// Read from the inner stream until at least `min` bytes are buffered for
+    /// `peek_buffered` (or 16KiB, if `min` is any larger), the stream reaches EOF, or
+    /// `timeout` passes, and return how many are buffered. Running out of time is not an
+    /// error: callers should check the count they get back.
+    ///
+    /// Whatever is buffered is still returned by later reads, in order and exactly once. With
+    /// a `timeout`, the stream is left with the read timeout it had once it was accepted.
+    /// Other clones can go on reading what is already buffered while this waits for more.
+    pub fn fill_peek(&mut self, min: usize, timeout: Option<Duration>) -> io::Result<usize> {
+        let min = min.min(PEEK_CAP);
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let result = self.fill_peek_until(min, deadline);
+        if timeout.is_some() {
+            self.inner.set_read_timeout(self.books.extras.as_ref().and_then(|extras| extras.read_timeout))?;
+        }
+        result
+    }
+
+    pub(crate) fn inner_stream(&self) -> &T {
+        &self.inner
+    }
+
+    /// The TCP socket underneath, if the inner stream is an `HttpStream`
+    pub(crate) fn tcp_socket(&self) -> Option<&TcpStream> {
+        tcp_stream(&self.inner)
+    }
+
+    /// Remove and return the bytes waiting to be read out of the pushback buffer, which are
+    /// counted as read
+    pub(crate) fn take_pushback(&mut self) -> Vec<u8> {
+        let bytes = match self.pushback.take() {
+            Some(pushback) => pushback.lock().unwrap().take(),
+            None => Vec::new(),
+        };
+        self.books.bytes_read += bytes.len() as u64;
+        bytes
+    }
+
+    /// Count bytes which were moved through the inner stream's socket directly
+    pub(crate) fn add_counts(&mut self, read: u64, written: u64) {
+        self.books.bytes_read += read;
+        self.books.bytes_written += written;
+    }
+
+    /// Stop counting what has been read and written through this clone so far, so that it
+    /// can be counted elsewhere
+    pub(crate) fn forget_counts(&mut self) {
+        self.books.bytes_read = 0;
+        self.books.bytes_written = 0;
+    }
+
+    /// Have the connection reset (rather than closed cleanly) when the last clone is dropped,
+    /// if it is a TCP socket
+    pub(crate) fn reset_on_close(&self) -> io::Result<()> {
+        match self.tcp_socket() {
+            Some(socket) => set_linger_zero(socket),
+            None => Ok(()),
+        }
+    }
+
+    /// Count the connection against its client's limit until every clone is dropped
+    pub(crate) fn hold_limit(&mut self, slot: LimitSlot) {
+        self.extras_mut().limit = Some(Arc::new(slot));
+    }
+
+    fn fill_peek_until(&mut self, min: usize, deadline: Option<Instant>) -> io::Result<usize> {
+        let pushback = Arc::clone(self.pushback.get_or_insert_with(|| Arc::new(Mutex::new(Pushback::new(Vec::new())))));
+        let mut chunk = [0u8; 4096];
+        loop {
+            let buffered = pushback.lock().unwrap().remaining().len();
+            if buffered >= min {
+                break;
+            }
+            if let Some(deadline) = deadline {
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                self.inner.set_read_timeout(Some(deadline - now))?;
+            }
+            // read without the lock held, which would hold up every other clone's reads
+            let want = (PEEK_CAP - buffered).min(chunk.len());
+            match self.inner.read(&mut chunk[..want]) {
+                Ok(0) => break,
+                Ok(n) => pushback.lock().unwrap().extend(&chunk[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => break,
+                Err(e) => return Err(e),
+            }
+        }
+        let buffered = pushback.lock().unwrap().remaining().len();
+        Ok(buffered)
+    }
+}
+
+impl<T: NetworkStream> NetworkStream for ProxyStream<T> {
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        match self.state.addr() {
+            Some(addr) => Ok(addr),
+            None => self.inner.peer_addr(),
+        }
+    }
+
+    #[inline]
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(dur)
+    }
+
+    #[inline]
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.inner.set_write_timeout(dur)
+    }
+
+    #[inline]
+    fn close(&mut self, how: Shutdown) -> io::Result<()> {
+        self.inner.close(how)
+    }
+}
+
+impl<T: NetworkStream> Read for ProxyStream<T> {
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pushback.is_some() {
+            if let Some(n) = self.read_pushback(buf) {
+                self.books.bytes_read += n as u64;
+                return Ok(n);
+            }
+        }
+        let n = self.inner.read(buf)?;
+        self.books.bytes_read += n as u64;
+        Ok(n)
+    }
+}
+
+/// Reads through a shared reference, for inner streams which support them (as `TcpStream`
+/// does). Bytes which were read past a header while looking for one (in optional mode) are
+/// guarded by a mutex, so each of them is still delivered exactly once, to whichever reader
+/// asks first; after that, reads go straight to the inner stream.
+impl<'a, T: NetworkStream> Read for &'a ProxyStream<T> where &'a T: Read {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let stream: &'a ProxyStream<T> = self;
+        let n = match stream.pushback {
+            Some(ref pushback) => pushback.lock().unwrap().read(buf),
+            None => 0,
+        };
+        let n = if n > 0 { n } else { (&stream.inner).read(buf)? };
+        if let Some(tracker) = stream.tracker() {
+            tracker.add(n as u64, 0);
+        }
+        Ok(n)
+    }
+}
+
+impl<T: NetworkStream> Write for ProxyStream<T> {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.books.bytes_written += n as u64;
+        Ok(n)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(unix)]
+impl<T: NetworkStream+::std::os::unix::io::AsRawFd> ::std::os::unix::io::AsRawFd for ProxyStream<T> {
+    #[inline]
+    fn as_raw_fd(&self) -> ::std::os::unix::io::RawFd {
+        self.inner.as_raw_fd()
+    }
+}
 
-use hyper;
-use hyper::net::NetworkStream;
 
-use proxy_protocol::{ProxyProtocolVersion, ProxyProtocolHeader};
-use proxy_protocol::read_proxy_protocol_v1;
-use proxy_protocol::read_proxy_protocol_v2;
-use proxy_protocol::read_proxy_protocol_any;
+impl<T: NetworkStream + Clone> ProxyStream<T> {
+    /// Split into a reading half and a writing half which can be used from different threads,
+    /// e.g. by a relay copying in both directions.
+    ///
+    /// The `ReadHalf` keeps everything learned while parsing the header: the `ProxyInfo`, the
+    /// peer address, and any bytes read past the header (in optional mode) which have not
+    /// been delivered yet. Those bytes are only ever returned by the `ReadHalf`. The
+    /// `WriteHalf` is a clone of the inner stream, which counts the bytes written through it
+    /// towards the connection's totals, and nothing more.
+    pub fn split(self) -> (ReadHalf<T>, WriteHalf<T>) {
+        let writer = WriteHalf { inner: self.inner.clone(), bytes_written: 0, tracker: self.tracker().cloned() };
+        (ReadHalf { stream: self }, writer)
+    }
+}
 
 
-/// Wrapper class for holding a `NetworkStream` off of which we have already
-/// read a PROXY protocol header
-#[derive(Clone, Debug)]
-pub struct ProxyStream<T: NetworkStream> {
-    inner: T,
-    peer_addr: Option<SocketAddr>
+/// Inner streams which can be duplicated at the OS level without panicking, for
+/// `ProxyStream::try_clone`
+pub trait TryCloneStream: NetworkStream + Sized {
+    /// A new handle to the same connection, as `TcpStream::try_clone` returns
+    fn try_clone_stream(&self) -> io::Result<Self>;
 }
 
-impl<T: NetworkStream> ProxyStream<T> {
-    pub(crate) fn from_stream(mut stream: T, v: ProxyProtocolVersion) -> hyper::Result<Self> {
-        // XXX: should we be setting a read timeout here?
-        // HttpListener sets the timeout in its `accept`, so it should be fine,
-        // but other listeners might not set the timeout until after accept...
-        let proxy_header: hyper::Result<ProxyProtocolHeader> = match v {
-            ProxyProtocolVersion::V1 => read_proxy_protocol_v1(&mut stream),
-            ProxyProtocolVersion::V2 => read_proxy_protocol_v2(&mut stream),
-            ProxyProtocolVersion::Any => read_proxy_protocol_any(&mut stream),
-        }.map_err(|e| e.into());
+impl TryCloneStream for HttpStream {
+    fn try_clone_stream(&self) -> io::Result<Self> {
+        self.0.try_clone().map(HttpStream)
+    }
+}
+
+
+impl<T: TryCloneStream> ProxyStream<T> {
+    /// A new handle to the same connection which reports the same peer address, state and
+    /// `ProxyInfo`, or the error from duplicating the inner stream.
+    ///
+    /// Unlike `clone`, the new handle does not share any bytes which were read past the header
+    /// (in optional mode) and are still waiting to be delivered: those stay with `self`, and
+    /// the new handle reads straight from the inner stream. This makes it suited to the
+    /// common split of reading from one handle and writing to the other; if the new handle is
+    /// read from before `self` has delivered those bytes, they will be read out of order.
+    pub fn try_clone(&self) -> io::Result<Self> {
         Ok(ProxyStream {
-            peer_addr: proxy_header?.source_addr(),
-            inner: stream,
+            inner: self.inner.try_clone_stream()?,
+            state: self.state,
+            pushback: None,
+            accepted_at: self.accepted_at,
+            books: Bookkeeping::new(self.books.extras.clone()),
         })
     }
 }
 
-impl<T: NetworkStream> NetworkStream for ProxyStream<T> {
-    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
-        if let Some(a) = self.peer_addr {
-            Ok(a)
-        } else {
-            self.inner.peer_addr()
+
+/// What the bytes from `ProxyStream::into_raw_parts` start with, the last byte being the
+/// version of their format
+#[cfg(unix)]
+const HANDOFF_MAGIC: &[u8] = b"PXH\x01";
+
+
+#[cfg(unix)]
+impl ProxyStream<HttpStream> {
+    /// Take the stream apart to hand its connection to another process (e.g., over a unix
+    /// socket with `SCM_RIGHTS`, for a zero-downtime restart), since the header has already
+    /// been read off the connection and the new process could not read it again. Returns a
+    /// new file descriptor for the socket, and the bytes `from_raw_parts` needs to put the
+    /// stream back together: its peer address, `ProxyState` and `ProxyInfo`, and any bytes
+    /// read past the header but not yet delivered (which are taken away from every clone).
+    /// The new stream's `age` carries on from this one's.
+    ///
+    /// The descriptor is a duplicate, which the caller owns and must close once it has been
+    /// passed on. As with any clone, the connection stays open in this process until every
+    /// other clone of the stream is dropped too. The registry entry, connection limit slot
+    /// and observer are not carried over; as far as this process is concerned the stream is
+    /// closed once all its clones are gone.
+    pub fn into_raw_parts(mut self) -> io::Result<(RawFd, Vec<u8>)> {
+        let fd = self.inner.0.try_clone()?.into_raw_fd();
+        let mut parts = HANDOFF_MAGIC.to_vec();
+        match self.proxy_state() {
+            ProxyState::Proxied => parts.push(0),
+            ProxyState::Socket => parts.push(1),
+            ProxyState::Placeholder(addr) => {
+                parts.push(2);
+                proxy_protocol::encode_addr(&mut parts, Some(addr));
+            },
+            ProxyState::Unavailable => parts.push(3),
+            ProxyState::Overridden(addr) => {
+                parts.push(4);
+                proxy_protocol::encode_addr(&mut parts, Some(addr));
+            },
         }
+        proxy_protocol::encode_addr(&mut parts, self.state.addr());
+        match self.proxy_info() {
+            Some(info) => {
+                parts.push(1);
+                info.header().encode(&mut parts);
+                parts.extend_from_slice(&(self.header_len() as u32).to_be_bytes());
+                match self.raw_header() {
+                    Some(raw) => {
+                        parts.push(1);
+                        parts.extend_from_slice(&(raw.len() as u32).to_be_bytes());
+                        parts.extend_from_slice(raw);
+                    },
+                    None => parts.push(0),
+                }
+                match info.accepted_at().and_then(|at| at.duration_since(UNIX_EPOCH).ok()) {
+                    Some(since_epoch) => {
+                        parts.push(1);
+                        parts.extend_from_slice(&since_epoch.as_secs().to_be_bytes());
+                        parts.extend_from_slice(&since_epoch.subsec_nanos().to_be_bytes());
+                    },
+                    None => parts.push(0),
+                }
+            },
+            None => parts.push(0),
+        }
+        // as an age, since the other process's `Instant`s may not be comparable with ours
+        parts.extend_from_slice(&(self.age().as_micros() as u64).to_be_bytes());
+        // counted as read here, since this process will never deliver them
+        let pushback = self.take_pushback();
+        parts.extend_from_slice(&(pushback.len() as u32).to_be_bytes());
+        parts.extend_from_slice(&pushback);
+        Ok((fd, parts))
     }
 
-    #[inline]
-    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
-        self.inner.set_read_timeout(dur)
+    /// Put a stream taken apart by `into_raw_parts` back together, in this process or
+    /// another. The new stream reports the same peer address, state, `ProxyInfo`,
+    /// `header_len` and `raw_header`, and delivers any bytes which had been read past the
+    /// header before the rest of the connection. It has no registry entry, limit or observer, and the socket
+    /// options and timeouts are whatever the socket was left with.
+    ///
+    /// Fails with `InvalidData`, without taking ownership of `fd`, if `parts` did not come
+    /// from `into_raw_parts` (of this version of the crate).
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be an open TCP socket, which the returned stream takes ownership of and
+    /// closes once it (and every clone) is dropped, so nothing else may use or close it
+    /// afterwards. `parts` should have come from the same connection as `fd`; if not, the
+    /// stream will report the addresses of whichever connection they did come from, though
+    /// that is not unsafe in itself.
+    pub unsafe fn from_raw_parts(fd: RawFd, parts: &[u8]) -> io::Result<Self> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "not the parts of a ProxyStream");
+        if !parts.starts_with(HANDOFF_MAGIC) {
+            return Err(invalid());
+        }
+        let mut fields = FieldReader(&parts[HANDOFF_MAGIC.len()..]);
+        let tag = fields.u8();
+        let state_addr = match tag {
+            Some(2) | Some(4) => Some(fields.addr().and_then(|addr| addr).ok_or_else(invalid)?),
+            _ => None,
+        };
+        // the address the stream reports, which must agree with the state
+        let peer_addr = fields.addr().ok_or_else(invalid)?;
+        let state = match (tag, peer_addr) {
+            (Some(0), Some(addr)) => StateTag::Proxied(addr),
+            (Some(1), None) => StateTag::Socket,
+            (Some(2), Some(addr)) if state_addr == Some(addr) => StateTag::Placeholder(addr),
+            (Some(3), None) => StateTag::Unavailable,
+            (Some(4), Some(addr)) if state_addr == Some(addr) => StateTag::Overridden(addr),
+            _ => return Err(invalid()),
+        };
+        let mut header_len = 0;
+        let mut raw_header = None;
+        let info = match fields.u8() {
+            Some(0) => None,
+            Some(1) => {
+                let header = ProxyProtocolHeader::decode(&mut fields).ok_or_else(invalid)?;
+                header_len = fields.u32().ok_or_else(invalid)? as usize;
+                raw_header = match fields.u8() {
+                    Some(0) => None,
+                    Some(1) => Some(fields.u32().and_then(|len| fields.take(len as usize)).ok_or_else(invalid)?.to_vec()),
+                    _ => return Err(invalid()),
+                };
+                let accepted_at = match fields.u8() {
+                    Some(0) => None,
+                    Some(1) => {
+                        let (secs, nanos) = fields.u64().and_then(|secs| fields.u32().map(|nanos| (secs, nanos))).ok_or_else(invalid)?;
+                        UNIX_EPOCH.checked_add(Duration::new(secs, nanos))
+                    },
+                    _ => return Err(invalid()),
+                };
+                Some(ProxyInfo::from_header(header).accepted(accepted_at))
+            },
+            _ => return Err(invalid()),
+        };
+        let age = Duration::from_micros(fields.u64().ok_or_else(invalid)?);
+        let now = Instant::now();
+        let pushback = fields.u32().and_then(|len| fields.take(len as usize)).ok_or_else(invalid)?;
+        if !fields.0.is_empty() {
+            return Err(invalid());
+        }
+
+        let socket = TcpStream::from_raw_fd(fd);
+        let read_timeout = socket.read_timeout().unwrap_or(None);
+        let extras = ProxyExtras { info, header_len, raw_header, read_timeout, ..ProxyExtras::default() };
+        Ok(ProxyStream {
+            inner: HttpStream(socket),
+            state,
+            pushback: if pushback.is_empty() {
+                None
+            } else {
+                Some(Arc::new(Mutex::new(Pushback::new(pushback.to_vec()))))
+            },
+            accepted_at: now.checked_sub(age).unwrap_or(now),
+            books: Bookkeeping::new(extras.boxed()),
+        })
     }
+}
 
-    #[inline]
-    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
-        self.inner.set_write_timeout(dur)
+
+/// The reading half of a `ProxyStream`, as returned by `ProxyStream::split`
+pub struct ReadHalf<T: NetworkStream> {
+    stream: ProxyStream<T>,
+}
+
+impl<T: NetworkStream> fmt::Debug for ReadHalf<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ReadHalf").field("stream", &self.stream).finish()
     }
+}
 
-    #[inline]
-    fn close(&mut self, how: Shutdown) -> io::Result<()> {
-        self.inner.close(how)
+impl<T: NetworkStream> ReadHalf<T> {
+    /// The peer address of the stream this was split from
+    pub fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    /// Where the address reported by `peer_addr()` comes from
+    pub fn proxy_state(&self) -> ProxyState {
+        self.stream.proxy_state()
+    }
+
+    /// What was learned from the PROXY header, or `None` if the connection did not send one
+    pub fn proxy_info(&self) -> Option<&ProxyInfo> {
+        self.stream.proxy_info()
     }
 }
 
-impl<T: NetworkStream> Read for ProxyStream<T> {
+impl<T: NetworkStream> Read for ReadHalf<T> {
     #[inline]
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.inner.read(buf)
+        self.stream.read(buf)
     }
 }
 
-impl<T: NetworkStream> Write for ProxyStream<T> {
+
+/// The writing half of a `ProxyStream`, as returned by `ProxyStream::split`
+pub struct WriteHalf<T: NetworkStream> {
+    inner: T,
+    bytes_written: u64,
+    tracker: Option<Arc<ConnectionTracker>>,
+}
+
+impl<T: NetworkStream> Drop for WriteHalf<T> {
+    fn drop(&mut self) {
+        if let Some(ref tracker) = self.tracker {
+            tracker.add(0, self.bytes_written);
+        }
+    }
+}
+
+impl<T: NetworkStream> fmt::Debug for WriteHalf<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WriteHalf").field("inner", &type_name::<T>()).finish()
+    }
+}
+
+impl<T: NetworkStream> WriteHalf<T> {
+    /// Shut down the writing direction of the connection, so that the peer reads EOF
+    pub fn shutdown(&mut self) -> io::Result<()> {
+        self.inner.close(Shutdown::Write)
+    }
+}
+
+impl<T: NetworkStream> Write for WriteHalf<T> {
     #[inline]
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.inner.write(buf)
+        let n = self.inner.write(buf)?;
+        self.bytes_written += n as u64;
+        Ok(n)
     }
 
     #[inline]
@@ -80,10 +1692,1072 @@ impl<T: NetworkStream> Write for ProxyStream<T> {
     }
 }
 
-#[cfg(unix)]
-impl<T: NetworkStream+::std::os::unix::io::AsRawFd> ::std::os::unix::io::AsRawFd for ProxyStream<T> {
-    #[inline]
-    fn as_raw_fd(&self) -> ::std::os::unix::io::RawFd {
-        self.inner.as_raw_fd()
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Cursor, Read, Write};
+    use std::mem::size_of;
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::{Duration, Instant, SystemTime};
+
+    use hyper::net::{HttpStream, NetworkStream};
+
+    use proxy_config::{ProxyConfig, PeerAddrFallback, PeerHop, UnspecifiedSource};
+    use proxy_limit::TestClock;
+    use proxy_protocol::{Command, ParseConfig, Proto, ProxyProtocolHeader, ProxyProtocolVersion, ProxyReadError};
+    use super::{ProxiedAddrs, ProxyStream, ProxyState, ProxyWarning};
+
+    /// A stream with no socket behind it, like a unix-socket stream
+    struct AddresslessStream(Cursor<Vec<u8>>);
+
+    impl AddresslessStream {
+        fn new(bytes: &[u8]) -> Self {
+            AddresslessStream(Cursor::new(bytes.to_vec()))
+        }
+    }
+
+    impl Read for AddresslessStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for AddresslessStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl NetworkStream for AddresslessStream {
+        fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+            Err(io::Error::new(io::ErrorKind::AddrNotAvailable, "no peer address"))
+        }
+
+        fn set_read_timeout(&self, _dur: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn set_write_timeout(&self, _dur: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn accept(header: &[u8], fallback: PeerAddrFallback) -> ProxyStream<AddresslessStream> {
+        let config = ProxyConfig::new(ProxyProtocolVersion::V1).peer_addr_fallback(fallback);
+        ProxyStream::from_stream(AddresslessStream::new(header), &config, None, &mut Vec::new()).expect("should parse")
+    }
+
+    #[test]
+    fn test_age() {
+        let clock = TestClock::new();
+        let ticks = clock.clone();
+        let config = ProxyConfig::new(ProxyProtocolVersion::V1).clock(move || ticks.now());
+        let before = SystemTime::now();
+        let header = b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n";
+        let stream = ProxyStream::from_stream(AddresslessStream::new(header), &config, None, &mut Vec::new()).expect("should parse");
+        let accepted_at = stream.proxy_info().and_then(|info| info.accepted_at()).expect("should be stamped");
+        assert!(accepted_at >= before && accepted_at <= SystemTime::now());
+        assert_eq!(stream.accepted_at(), clock.now());
+        assert_eq!(stream.age(), Duration::from_secs(0));
+
+        clock.advance(Duration::from_secs(600));
+        assert_eq!(stream.age(), Duration::from_secs(600));
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(stream.age(), Duration::from_millis(600_001));
+    }
+
+    #[test]
+    fn test_size() {
+        // the inner stream and peer address (with the state in their padding), the accept
+        // time, and four words for the pushback buffer, the two counters and the extras, with
+        // one to spare for alignment. Anything more belongs in the extras, unless every read or
+        // write needs it.
+        let bound = size_of::<HttpStream>() + size_of::<Option<SocketAddr>>() + size_of::<Instant>() + 5 * size_of::<usize>();
+        assert!(size_of::<ProxyStream<HttpStream>>() <= bound, "ProxyStream<HttpStream> is {} bytes, over {}", size_of::<ProxyStream<HttpStream>>(), bound);
+    }
+
+    #[test]
+    fn test_proxy_header() {
+        let stream = accept(b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n", PeerAddrFallback::Error);
+        let header = stream.proxy_header().expect("should have the header");
+        assert_eq!(header.dest_addr(), Some("198.51.100.7:443".parse().unwrap()));
+        assert_eq!(header.command(), Command::Proxy);
+
+        assert!(accept(b"PROXY UNKNOWN\r\n", PeerAddrFallback::Unspecified).proxy_header().is_none());
+        let config = ProxyConfig::new(ProxyProtocolVersion::V2);
+        let local = b"\r\n\r\n\0\r\nQUIT\n\x20\x00\x00\x00";
+        let stream = ProxyStream::from_stream(AddresslessStream::new(local), &config, None, &mut Vec::new()).expect("should parse");
+        assert!(stream.proxy_info().unwrap().is_local());
+        assert!(stream.proxy_header().is_none());
+    }
+
+    #[test]
+    fn test_negotiated_version() {
+        let v1 = b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n";
+        let v2 = ProxyProtocolHeader::for_addrs("192.0.2.1:4124".parse().unwrap(), "198.51.100.7:443".parse().unwrap()).to_bytes(ProxyProtocolVersion::V2);
+        let negotiated = |version: ProxyProtocolVersion, bytes: &[u8]| {
+            let config = ProxyConfig::new(version).optional(true);
+            ProxyStream::from_stream(AddresslessStream::new(bytes), &config, None, &mut Vec::new()).expect("should parse").negotiated_version()
+        };
+        for &version in &[ProxyProtocolVersion::V1, ProxyProtocolVersion::Any] {
+            assert_eq!(negotiated(version, v1), Some(ProxyProtocolVersion::V1));
+        }
+        for &version in &[ProxyProtocolVersion::V2, ProxyProtocolVersion::Any] {
+            assert_eq!(negotiated(version, &v2), Some(ProxyProtocolVersion::V2));
+        }
+        for &version in &[ProxyProtocolVersion::V1, ProxyProtocolVersion::V2, ProxyProtocolVersion::Any] {
+            assert_eq!(negotiated(version, b"GET / HTTP/1.1\r\n\r\n"), None);
+        }
+    }
+
+    #[test]
+    fn test_was_proxied() {
+        let config = ProxyConfig::new(ProxyProtocolVersion::Any).optional(true);
+        let stream = |bytes: &[u8]| ProxyStream::from_stream(AddresslessStream::new(bytes), &config, None, &mut Vec::new()).expect("should parse");
+        let mut unix = b"\r\n\r\n\0\r\nQUIT\n\x21\x31\x00\xd8".to_vec();
+        unix.extend_from_slice(&[b'a'; 216]);
+        let cases: Vec<(&[u8], bool)> = vec![
+            (b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n", true),
+            (b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c\x0a\x0b\x0c\x0d\x7f\x00\x00\x01\x22\xb8\x27\x0f", true),
+            (b"PROXY UNKNOWN\r\n", false),
+            (b"\r\n\r\n\0\r\nQUIT\n\x20\x11\x00\x0c\x0a\x0b\x0c\x0d\x7f\x00\x00\x01\x22\xb8\x27\x0f", false),
+            (b"\r\n\r\n\0\r\nQUIT\n\x21\x00\x00\x00", false),
+            (&unix, false),
+            (b"GET / HTTP/1.1\r\n\r\n", false),
+        ];
+        for (bytes, proxied) in cases {
+            assert_eq!(stream(bytes).was_proxied(), proxied, "for {:?}", bytes);
+        }
+    }
+
+    #[test]
+    fn test_proxied_addrs() {
+        let config = ProxyConfig::new(ProxyProtocolVersion::Any).optional(true);
+        let stream = |bytes: &[u8]| ProxyStream::from_stream(AddresslessStream::new(bytes), &config, None, &mut Vec::new()).expect("should parse");
+        let mut unix = b"\r\n\r\n\0\r\nQUIT\n\x21\x31\x00\xd8".to_vec();
+        unix.extend_from_slice(&[b'a'; 216]);
+        let proxied = |source: &str, dest: &str| ProxiedAddrs::Proxied { source: source.parse().unwrap(), dest: dest.parse().unwrap() };
+        let cases: Vec<(&[u8], ProxiedAddrs)> = vec![
+            (b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n", proxied("192.0.2.1:4124", "198.51.100.7:443")),
+            (b"\r\n\r\n\0\r\nQUIT\n\x21\x21\x00\x24\xfd\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x22\xb8\x27\x0f", proxied("[fd00::1]:8888", "[::1]:9999")),
+            (b"\r\n\r\n\0\r\nQUIT\n\x20\x11\x00\x0c\x0a\x0b\x0c\x0d\x7f\x00\x00\x01\x22\xb8\x27\x0f", ProxiedAddrs::Local),
+            (b"\r\n\r\n\0\r\nQUIT\n\x20\x00\x00\x00", ProxiedAddrs::Local),
+            (b"PROXY UNKNOWN\r\n", ProxiedAddrs::Unknown),
+            (b"\r\n\r\n\0\r\nQUIT\n\x21\x00\x00\x00", ProxiedAddrs::Unknown),
+            (&unix, ProxiedAddrs::Unknown),
+            (b"GET / HTTP/1.1\r\n\r\n", ProxiedAddrs::Direct),
+        ];
+        for (bytes, addrs) in cases {
+            assert_eq!(stream(bytes).proxied_addrs(), addrs, "for {:?}", bytes);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_proxied_addrs_serialize() {
+        use toml;
+
+        let addrs = ProxiedAddrs::Proxied { source: "192.0.2.1:4124".parse().unwrap(), dest: "[::1]:443".parse().unwrap() };
+        let expected: toml::Value = toml::from_str("kind = \"proxied\"\nsource = \"192.0.2.1:4124\"\ndest = \"[::1]:443\"").unwrap();
+        assert_eq!(toml::Value::try_from(addrs).expect("should serialize"), expected);
+        let expected: toml::Value = toml::from_str("kind = \"local\"").unwrap();
+        assert_eq!(toml::Value::try_from(ProxiedAddrs::Local).expect("should serialize"), expected);
+    }
+
+    #[test]
+    fn test_command() {
+        let config = ProxyConfig::new(ProxyProtocolVersion::V2).optional(true);
+        let stream = |bytes: &[u8]| ProxyStream::from_stream(AddresslessStream::new(bytes), &config, None, &mut Vec::new()).expect("should parse");
+        let local = stream(b"\r\n\r\n\0\r\nQUIT\n\x20\x00\x00\x00");
+        assert_eq!((local.command(), local.is_local(), local.is_proxied()), (Some(Command::Local), true, false));
+        let proxied = stream(b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c\x0a\x0b\x0c\x0d\x7f\x00\x00\x01\x22\xb8\x27\x0f");
+        assert_eq!((proxied.command(), proxied.is_local(), proxied.is_proxied()), (Some(Command::Proxy), false, true));
+        let unspec = stream(b"\r\n\r\n\0\r\nQUIT\n\x21\x00\x00\x00");
+        assert_eq!((unspec.command(), unspec.is_local(), unspec.is_proxied()), (Some(Command::Proxy), false, true));
+        assert_eq!(unspec.proxy_header(), None);
+        let none = stream(b"GET / HTTP/1.1\r\n\r\n");
+        assert_eq!((none.command(), none.is_local(), none.is_proxied()), (None, false, false));
+    }
+
+    #[test]
+    fn test_destination_addr() {
+        let v1 = |line: &[u8]| accept(line, PeerAddrFallback::Error).destination_addr();
+        assert_eq!(v1(b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n"), Some("198.51.100.7:443".parse().unwrap()));
+        assert_eq!(v1(b"PROXY TCP6 2001:db8::1 2001:db8::2 4124 8443\r\n"), Some("[2001:db8::2]:8443".parse().unwrap()));
+        assert_eq!(v1(b"PROXY UNKNOWN\r\n"), None);
+
+        let config = ProxyConfig::new(ProxyProtocolVersion::V2);
+        for &(source, dest) in &[("192.0.2.1:4124", "198.51.100.7:443"), ("[2001:db8::1]:4124", "[2001:db8::2]:8443")] {
+            let dest: SocketAddr = dest.parse().unwrap();
+            let header = ProxyProtocolHeader::for_addrs(source.parse().unwrap(), dest).to_bytes(ProxyProtocolVersion::V2);
+            let stream = ProxyStream::from_stream(AddresslessStream::new(&header), &config, None, &mut Vec::new()).expect("should parse");
+            assert_eq!(stream.destination_addr(), Some(dest));
+        }
+    }
+
+    #[test]
+    fn test_fallback_error() {
+        let mut stream = accept(b"PROXY UNKNOWN\r\n", PeerAddrFallback::Error);
+        stream.peer_addr().expect_err("should return the inner error");
+        assert_eq!(stream.proxy_state(), ProxyState::Unavailable);
+    }
+
+    #[test]
+    fn test_fallback_unspecified() {
+        let mut stream = accept(b"PROXY UNKNOWN\r\n", PeerAddrFallback::Unspecified);
+        let placeholder: SocketAddr = "0.0.0.0:0".parse().unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), placeholder);
+        assert_eq!(stream.proxy_state(), ProxyState::Placeholder(placeholder));
+    }
+
+    #[test]
+    fn test_fallback_fixed() {
+        let fixed: SocketAddr = "192.0.2.200:1".parse().unwrap();
+        let mut stream = accept(b"PROXY UNKNOWN\r\n", PeerAddrFallback::Fixed(fixed));
+        assert_eq!(stream.peer_addr().unwrap(), fixed);
+        assert_eq!(stream.proxy_state(), ProxyState::Placeholder(fixed));
+    }
+
+    #[test]
+    fn test_fallback_unused_when_proxied() {
+        let mut stream = accept(b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n", PeerAddrFallback::Unspecified);
+        assert_eq!(stream.peer_addr().unwrap(), "192.0.2.1:4124".parse().unwrap());
+        assert_eq!(stream.proxy_state(), ProxyState::Proxied);
+    }
+
+    fn accept_families(header: &[u8], families: &[Proto]) -> io::Result<SocketAddr> {
+        let config = ProxyConfig::new(ProxyProtocolVersion::Any)
+            .peer_addr_fallback(PeerAddrFallback::Unspecified)
+            .allowed_families(families);
+        match ProxyStream::from_stream(AddresslessStream::new(header), &config, None, &mut Vec::new()) {
+            Ok(mut stream) => stream.peer_addr(),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    #[test]
+    fn test_allowed_families() {
+        let tcp6 = b"PROXY TCP6 2001:db8::1 2001:db8::2 4124 443\r\n";
+        let err = accept_families(tcp6, &[Proto::Tcp4]).expect_err("TCP6 should be rejected");
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(err.to_string().contains("Tcp6"), "unexpected error {}", err);
+
+        let tcp4 = b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n";
+        assert_eq!(accept_families(tcp4, &[Proto::Tcp4]).unwrap(), "192.0.2.1:4124".parse().unwrap());
+
+        // address-free headers have to be allowed explicitly, in both versions
+        let local_v2 = b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x20\x00\x00\x00";
+        for unknown in &[&b"PROXY UNKNOWN\r\n"[..], &local_v2[..]] {
+            let err = accept_families(unknown, &[Proto::Tcp4]).expect_err("UNKNOWN should be rejected");
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+            assert_eq!(accept_families(unknown, &[Proto::Tcp4, Proto::Unknown]).unwrap(), "0.0.0.0:0".parse().unwrap());
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_nonblocking_inner_stream() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("should be able to bind");
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut conn = TcpStream::connect(addr).expect("should be able to connect");
+            // send the header in pieces, so that the server's reads find nothing to read
+            thread::sleep(Duration::from_millis(20));
+            conn.write_all(b"PROXY TCP4 192.0.2.1 ").expect("write must succeed");
+            thread::sleep(Duration::from_millis(20));
+            conn.write_all(b"198.51.100.7 4124 443\r\n").expect("write must succeed");
+            conn
+        });
+        let (socket, _) = listener.accept().expect("should accept");
+        socket.set_nonblocking(true).expect("should be able to set O_NONBLOCK");
+        let config = ProxyConfig::new(ProxyProtocolVersion::V1);
+        let mut stream = ProxyStream::from_stream(HttpStream(socket), &config, Some(Duration::from_secs(5)), &mut Vec::new())
+            .expect("should parse despite WouldBlock");
+        assert_eq!(stream.peer_addr().unwrap(), "192.0.2.1:4124".parse().unwrap());
+        drop(client.join().unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_nonblocking_inner_stream_deadline() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("should be able to bind");
+        let _conn = TcpStream::connect(listener.local_addr().unwrap()).expect("should be able to connect");
+        let (socket, _) = listener.accept().expect("should accept");
+        socket.set_nonblocking(true).expect("should be able to set O_NONBLOCK");
+        let config = ProxyConfig::new(ProxyProtocolVersion::V1);
+        match ProxyStream::from_stream(HttpStream(socket), &config, Some(Duration::from_millis(20)), &mut Vec::new()) {
+            Err(ProxyReadError::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock => {},
+            other => panic!("expected WouldBlock once the deadline passed, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_real_peer_addr() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("should be able to bind");
+        let config = ProxyConfig::new(ProxyProtocolVersion::V1);
+        for &(header, client) in &[(&b"PROXY TCP4 203.0.113.9 198.51.100.7 4124 443\r\n"[..], Some("203.0.113.9:4124")), (&b"PROXY UNKNOWN\r\n"[..], None)] {
+            let mut conn = TcpStream::connect(listener.local_addr().unwrap()).expect("should be able to connect");
+            conn.write_all(header).expect("write must succeed");
+            let (socket, _) = listener.accept().expect("should accept");
+            let mut stream = ProxyStream::from_stream(HttpStream(socket), &config, None, &mut Vec::new()).expect("should parse");
+            let proxy = conn.local_addr().unwrap();
+            assert_eq!(stream.real_peer_addr().unwrap(), proxy);
+            let client = client.map(|client| client.parse().unwrap()).unwrap_or(proxy);
+            assert_eq!(stream.peer_addr().unwrap(), client);
+            assert_eq!(stream.real_peer_addr().unwrap(), proxy);
+        }
+    }
+
+    #[test]
+    fn test_into_parts() {
+        let config = ProxyConfig::new(ProxyProtocolVersion::V1).optional(true);
+        let stream = ProxyStream::from_stream(AddresslessStream::new(b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\nhello"), &config, None, &mut Vec::new()).expect("should parse");
+        let info = stream.proxy_info().cloned();
+        let (mut inner, unwrapped_info, pushback) = stream.into_parts();
+        assert_eq!(unwrapped_info, info);
+        assert_eq!(unwrapped_info.and_then(|info| info.source_addr()), Some("192.0.2.1:4124".parse().unwrap()));
+        assert_eq!(pushback, b"");
+        let mut rest = Vec::new();
+        inner.read_to_end(&mut rest).expect("read must succeed");
+        assert_eq!(rest, b"hello");
+
+        // what was read looking for a header comes back, rather than being lost
+        let stream = ProxyStream::from_stream(AddresslessStream::new(b"PUT / HTTP/1.1\r\n\r\n"), &config, None, &mut Vec::new()).expect("should parse");
+        let (mut inner, info, pushback) = stream.into_parts();
+        assert!(info.is_none());
+        assert_eq!(pushback, b"PU");
+        let mut rest = Vec::new();
+        inner.read_to_end(&mut rest).expect("read must succeed");
+        assert_eq!(rest, b"T / HTTP/1.1\r\n\r\n");
+
+        let stream = ProxyStream::from_stream(AddresslessStream::new(b"PROXY UNKNOWN\r\nhello"), &config, None, &mut Vec::new()).expect("should parse");
+        let mut rest = Vec::new();
+        stream.into_inner().read_to_end(&mut rest).expect("read must succeed");
+        assert_eq!(rest, b"hello");
+    }
+
+    #[test]
+    fn test_large_v2_header() {
+        // an address block past 216 bytes, of TLVs the listener does not understand
+        let mut bytes = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x01\x3b\xc0\x00\x02\x01\xc6\x33\x64\x07\x10\x1c\x01\xbb\x04\x01\x2c".to_vec();
+        bytes.resize(bytes.len() + 300, 0);
+        bytes.extend_from_slice(b"GET /");
+        for &version in &[ProxyProtocolVersion::V2, ProxyProtocolVersion::Any] {
+            let config = ProxyConfig::new(version);
+            let mut stream = ProxyStream::from_stream(AddresslessStream::new(&bytes), &config, None, &mut Vec::new()).expect("should parse");
+            assert_eq!(stream.peer_addr().unwrap(), "192.0.2.1:4124".parse::<SocketAddr>().unwrap());
+            assert_eq!(stream.header_len(), bytes.len() - 5);
+            let mut rest = Vec::new();
+            stream.read_to_end(&mut rest).expect("should read");
+            assert_eq!(rest, b"GET /");
+
+            // a ceiling on the whole header applies just as exactly
+            let header_len = bytes.len() - 5;
+            ProxyStream::from_stream(AddresslessStream::new(&bytes), &config.clone().max_header_len(Some(header_len)), None, &mut Vec::new()).expect("should parse");
+            match ProxyStream::from_stream(AddresslessStream::new(&bytes), &config.clone().max_header_len(Some(header_len - 1)), None, &mut Vec::new()) {
+                Err(ProxyReadError::HeaderTooLong { limit, read: 16 }) if limit == header_len - 1 => {},
+                other => panic!("expected HeaderTooLong, got {:?}", other.map(|_| ())),
+            }
+
+            let config = config.parse_config(ParseConfig::default().max_v2_block_len(216));
+            match ProxyStream::from_stream(AddresslessStream::new(&bytes), &config, None, &mut Vec::new()) {
+                Err(ProxyReadError::InvalidProtocol { .. }) => {},
+                other => panic!("expected InvalidProtocol, got {:?}", other.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn test_v2_garbage_fails_before_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("should be able to bind");
+        for &(greeting, version) in &[(&b"\x00\x01\x02"[..], ProxyProtocolVersion::V2), (b"\r\nX", ProxyProtocolVersion::V2), (b"\x00\x01\x02", ProxyProtocolVersion::Any)] {
+            // the client sends its greeting and waits for a reply which never comes
+            let mut conn = TcpStream::connect(listener.local_addr().unwrap()).expect("should be able to connect");
+            conn.write_all(greeting).expect("write must succeed");
+            let (socket, _) = listener.accept().expect("should accept");
+            let config = ProxyConfig::new(version).header_timeout(Some(Duration::from_secs(5)));
+            let started = Instant::now();
+            match ProxyStream::from_stream(HttpStream(socket), &config, None, &mut Vec::new()) {
+                Err(ref e) if !e.is_timeout() => {},
+                other => panic!("expected {:?} to be turned away, got {:?}", greeting, other.map(|s| s.proxy_state())),
+            }
+            assert!(started.elapsed() < Duration::from_secs(1), "took {:?} for {:?}", started.elapsed(), greeting);
+        }
+    }
+
+    #[test]
+    fn test_unix_header_uses_socket_peer() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("should be able to bind");
+        let mut conn = TcpStream::connect(listener.local_addr().unwrap()).expect("should be able to connect");
+        let mut header = b"\r\n\r\n\0\r\nQUIT\n\x21\x31\x00\xd8/run/haproxy.sock".to_vec();
+        header.resize(16 + 108, 0);
+        header.extend_from_slice(b"/run/app.sock");
+        header.resize(16 + 216, 0);
+        conn.write_all(&header).expect("write must succeed");
+        let (socket, _) = listener.accept().expect("should accept");
+        let config = ProxyConfig::new(ProxyProtocolVersion::V2);
+        let mut stream = ProxyStream::from_stream(HttpStream(socket), &config, None, &mut Vec::new()).expect("should parse");
+        assert_eq!(stream.peer_addr().unwrap(), conn.local_addr().unwrap());
+        assert_eq!(stream.proxy_state(), ProxyState::Socket);
+        let paths = stream.proxy_header().and_then(|header| header.unix_addrs());
+        assert_eq!(paths, Some(("/run/haproxy.sock".into(), "/run/app.sock".into())));
+    }
+
+    #[test]
+    fn test_local_uses_socket_peer() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("should be able to bind");
+        let mut conn = TcpStream::connect(listener.local_addr().unwrap()).expect("should be able to connect");
+        // a health check carrying made-up addresses and an SSL TLV, then a request
+        conn.write_all(b"\r\n\r\n\0\r\nQUIT\n\x20\x11\x00\x14\x0a\x0b\x0c\x0d\x7f\x00\x00\x01\x22\xb8\x27\x0f\x20\x00\x05\x01\x00\x00\x00\x00GET /").expect("write must succeed");
+        let (socket, _) = listener.accept().expect("should accept");
+        let config = ProxyConfig::new(ProxyProtocolVersion::V2);
+        let mut stream = ProxyStream::from_stream(HttpStream(socket), &config, None, &mut Vec::new()).expect("should parse");
+        assert_eq!(stream.peer_addr().unwrap(), conn.local_addr().unwrap());
+        assert_eq!(stream.proxy_state(), ProxyState::Socket);
+        assert!(stream.is_local());
+        assert!(!stream.was_proxied());
+        let info = stream.proxy_info().unwrap();
+        assert_eq!((info.source_addr(), info.destination_addr()), (None, None));
+        assert!(info.ssl_info().unwrap().client_ssl());
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).expect("should read past the header");
+        assert_eq!(&buf, b"GET /");
+    }
+
+    #[test]
+    fn test_proxy_hops() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("should be able to bind");
+        let accept = |bytes: &[u8], config: &ProxyConfig| {
+            let mut conn = TcpStream::connect(listener.local_addr().unwrap()).expect("should be able to connect");
+            conn.write_all(bytes).expect("write must succeed");
+            conn.shutdown(::std::net::Shutdown::Write).expect("should shut down");
+            let (socket, _) = listener.accept().expect("should accept");
+            let mut stream = ProxyStream::from_stream(HttpStream(socket), config, None, &mut Vec::new()).expect("should parse");
+            let mut rest = Vec::new();
+            stream.read_to_end(&mut rest).expect("should read past the headers");
+            (stream, rest)
+        };
+        // the nearest proxy's header first, then the one it relayed from the proxy before it
+        let outer = ProxyProtocolHeader::for_addrs("198.51.100.20:35000".parse().unwrap(), "198.51.100.7:443".parse().unwrap());
+        let inner = ProxyProtocolHeader::for_addrs("192.0.2.1:4124".parse().unwrap(), "198.51.100.20:443".parse().unwrap());
+        let v1_v1 = [outer.to_bytes(ProxyProtocolVersion::V1), inner.to_bytes(ProxyProtocolVersion::V1), b"GET /".to_vec()].concat();
+        let v1_v2 = [outer.to_bytes(ProxyProtocolVersion::V1), inner.to_bytes(ProxyProtocolVersion::V2), b"GET /".to_vec()].concat();
+        let config = ProxyConfig::new(ProxyProtocolVersion::V1).max_proxy_hops(2);
+
+        for bytes in &[&v1_v1, &v1_v2] {
+            let (mut stream, rest) = accept(bytes, &config);
+            assert_eq!(rest, b"GET /");
+            assert_eq!(stream.peer_addr().unwrap(), outer.source_addr().unwrap());
+            assert_eq!(stream.proxy_hops().len(), 2);
+            assert_eq!(stream.proxy_hops()[0].source_addr(), outer.source_addr());
+            assert_eq!(stream.proxy_hops()[1].source_addr(), inner.source_addr());
+            assert_eq!(stream.header_len(), bytes.len() - 5);
+
+            let (mut stream, _) = accept(bytes, &config.clone().peer_hop(PeerHop::Innermost));
+            assert_eq!(stream.peer_addr().unwrap(), inner.source_addr().unwrap());
+            assert_eq!(stream.proxy_info().unwrap().destination_addr(), inner.dest_addr());
+        }
+
+        // every header is checked
+        let tcp6_only = config.clone().allowed_families(&[Proto::Tcp6]);
+        let mut conn = TcpStream::connect(listener.local_addr().unwrap()).expect("should be able to connect");
+        conn.write_all(&v1_v2).expect("write must succeed");
+        let (socket, _) = listener.accept().expect("should accept");
+        match ProxyStream::from_stream(HttpStream(socket), &tcp6_only, None, &mut Vec::new()) {
+            Err(ProxyReadError::FamilyNotAllowed(Proto::Tcp4)) => {},
+            other => panic!("expected FamilyNotAllowed, got {:?}", other.map(|s| s.proxy_state())),
+        }
+
+        // fewer headers than allowed, and none of what follows is lost
+        let (stream, rest) = accept(&[outer.to_bytes(ProxyProtocolVersion::V1), b"PUT /".to_vec()].concat(), &config);
+        assert_eq!(rest, b"PUT /");
+        assert_eq!(stream.proxy_hops().len(), 1);
+        let (stream, rest) = accept(&outer.to_bytes(ProxyProtocolVersion::V1), &config);
+        assert_eq!(rest, b"");
+        assert_eq!(stream.proxy_hops().len(), 1);
+
+        // more than allowed, and the rest are left for the server
+        let (stream, rest) = accept(&v1_v1, &ProxyConfig::new(ProxyProtocolVersion::V1));
+        assert_eq!(rest, [inner.to_bytes(ProxyProtocolVersion::V1), b"GET /".to_vec()].concat());
+        assert!(stream.proxy_hops().is_empty());
+        let three = [outer.to_bytes(ProxyProtocolVersion::V1), v1_v2.clone()].concat();
+        let (stream, rest) = accept(&three, &config);
+        assert_eq!(rest, [inner.to_bytes(ProxyProtocolVersion::V2), b"GET /".to_vec()].concat());
+        assert_eq!(stream.proxy_hops().len(), 2);
+    }
+
+    #[test]
+    fn test_unspecified_source() {
+        let dest: SocketAddr = "198.51.100.7:443".parse().unwrap();
+        let cases = [
+            (ProxyProtocolVersion::V1, "0.0.0.0:4124"),
+            (ProxyProtocolVersion::V1, "192.0.2.1:0"),
+            (ProxyProtocolVersion::V2, "[::]:4124"),
+            (ProxyProtocolVersion::V2, "[2001:db8::1]:0"),
+        ];
+        let listener = TcpListener::bind("127.0.0.1:0").expect("should be able to bind");
+        let connect = |version, source: SocketAddr| {
+            let mut conn = TcpStream::connect(listener.local_addr().unwrap()).expect("should be able to connect");
+            let dest = if source.is_ipv4() { dest } else { "[2001:db8::7]:443".parse().unwrap() };
+            conn.write_all(&ProxyProtocolHeader::for_addrs(source, dest).to_bytes(version)).expect("write must succeed");
+            conn.write_all(b"GET /").expect("write must succeed");
+            let (socket, _) = listener.accept().expect("should accept");
+            (conn, HttpStream(socket))
+        };
+        for &(version, source) in &cases {
+            let source: SocketAddr = source.parse().unwrap();
+            let config = ProxyConfig::new(version);
+
+            // accepted as-is by default
+            let (_conn, socket) = connect(version, source);
+            let stream = ProxyStream::from_stream(socket, &config, None, &mut Vec::new()).expect("should parse");
+            assert_eq!(stream.proxy_state(), ProxyState::Proxied, "{}", source);
+            assert_eq!(stream.proxy_info().unwrap().source_addr(), Some(source));
+
+            let (_conn, socket) = connect(version, source);
+            let reject = config.clone().unspecified_source(UnspecifiedSource::Reject);
+            match ProxyStream::from_stream(socket, &reject, None, &mut Vec::new()) {
+                Err(ProxyReadError::UnspecifiedSource(addr)) => assert_eq!(addr, source),
+                other => panic!("{} should have been rejected, got {:?}", source, other.map(|s| s.proxy_state())),
+            }
+
+            let (conn, socket) = connect(version, source);
+            let downgrade = config.clone().unspecified_source(UnspecifiedSource::Downgrade);
+            let mut warnings = Vec::new();
+            let mut stream = ProxyStream::from_stream(socket, &downgrade, None, &mut warnings).expect("should parse");
+            assert!(matches!(warnings[..], [ProxyWarning::UnspecifiedSource(addr), ProxyWarning::SocketPeerFallback] if addr == source), "{:?}", warnings);
+            assert_eq!(stream.proxy_state(), ProxyState::Socket);
+            assert_eq!(stream.peer_addr().unwrap(), conn.local_addr().unwrap());
+            let info = stream.proxy_info().unwrap();
+            assert_eq!((info.proto(), info.source_addr(), info.destination_addr()), (Proto::Unknown, None, None));
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).expect("should read past the header");
+            assert_eq!(&buf, b"GET /");
+
+            // a downgraded header is subject to the allowed families like any unknown one
+            let (_conn, socket) = connect(version, source);
+            let tcp_only = downgrade.allowed_families(&[Proto::Tcp4, Proto::Tcp6]);
+            match ProxyStream::from_stream(socket, &tcp_only, None, &mut Vec::new()) {
+                Err(ProxyReadError::FamilyNotAllowed(Proto::Unknown)) => {},
+                other => panic!("{} should not have been allowed, got {:?}", source, other.map(|s| s.proxy_state())),
+            }
+        }
+    }
+
+    #[test]
+    fn test_tlv_warnings() {
+        // TLVs which were kept, such as the VPC endpoint ID AWS sends on every connection,
+        // are not worth a warning
+        let nlb = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x19\xc0\x00\x02\x01\xc6\x33\x64\x07\x10\x1c\x01\xbb\xea\x00\x0a\x01vpce-1234";
+        let config = ProxyConfig::new(ProxyProtocolVersion::V2);
+        let mut warnings = Vec::new();
+        let stream = ProxyStream::from_stream(AddresslessStream::new(nlb), &config, None, &mut warnings).expect("should parse");
+        assert_eq!(stream.proxy_info().unwrap().header().tlvs().len(), 1);
+        assert!(warnings.is_empty(), "{:?}", warnings);
+
+        // but those dropped from a skipped block are
+        let unspec = b"\r\n\r\n\0\r\nQUIT\n\x20\x00\x00\x0d\xea\x00\x0a\x01vpce-1234";
+        let small = config.parse_config(ParseConfig::default().max_v2_block_len(12));
+        let mut warnings = Vec::new();
+        ProxyStream::from_stream(AddresslessStream::new(unspec), &small, None, &mut warnings).expect("should parse");
+        assert!(matches!(warnings[..], [ProxyWarning::IgnoredTlvs(13)]), "{:?}", warnings);
+        assert_eq!(warnings[0].to_string(), "dropped 13 bytes of TLVs past the address block limit");
+    }
+
+    #[test]
+    fn test_require_proxied_address() {
+        let mut unix = b"\r\n\r\n\0\r\nQUIT\n\x21\x31\x00\xd8/run/haproxy.sock".to_vec();
+        unix.resize(16 + 216, 0);
+        let cases: Vec<(ProxyProtocolVersion, Vec<u8>, Command)> = vec![
+            (ProxyProtocolVersion::V1, b"PROXY UNKNOWN\r\n".to_vec(), Command::Unspec),
+            (ProxyProtocolVersion::V1, b"PROXY UNKNOWN 192.0.2.1 198.51.100.7 4124 443\r\n".to_vec(), Command::Unspec),
+            (ProxyProtocolVersion::V2, b"\r\n\r\n\0\r\nQUIT\n\x21\x00\x00\x00".to_vec(), Command::Proxy),
+            (ProxyProtocolVersion::V2, b"\r\n\r\n\0\r\nQUIT\n\x20\x11\x00\x0c\xc0\x00\x02\x01\xc6\x33\x64\x07\x10\x1c\x01\xbb".to_vec(), Command::Local),
+            (ProxyProtocolVersion::V2, b"\r\n\r\n\0\r\nQUIT\n\x20\x00\x00\x00".to_vec(), Command::Local),
+            (ProxyProtocolVersion::V2, unix, Command::Proxy),
+        ];
+        for (version, header, command) in cases {
+            let mut bytes = header.clone();
+            bytes.extend_from_slice(b"GET /");
+            for &version in &[version, ProxyProtocolVersion::Any] {
+                // served with the inner stream's (lack of an) address by default
+                let config = ProxyConfig::new(version);
+                let stream = ProxyStream::from_stream(AddresslessStream::new(&bytes), &config, None, &mut Vec::new()).expect("should parse");
+                assert_eq!(stream.proxy_state(), ProxyState::Unavailable, "for {:?}", String::from_utf8_lossy(&header));
+
+                let strict = config.require_proxied_address(true);
+                match ProxyStream::from_stream(AddresslessStream::new(&bytes), &strict, None, &mut Vec::new()) {
+                    Err(ProxyReadError::NoClientAddress(c)) if c == command => {},
+                    other => panic!("{:?} should have been rejected, got {:?}", String::from_utf8_lossy(&header), other.map(|s| s.proxy_state())),
+                }
+                let err = io::Error::from(ProxyStream::from_stream(AddresslessStream::new(&bytes), &strict, None, &mut Vec::new()).err().unwrap());
+                assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+            }
+        }
+
+        // headers with addresses are still accepted, unless they are downgraded
+        let strict = ProxyConfig::new(ProxyProtocolVersion::V1).require_proxied_address(true);
+        let stream = ProxyStream::from_stream(AddresslessStream::new(b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n"), &strict, None, &mut Vec::new()).expect("should parse");
+        assert_eq!(stream.proxy_state(), ProxyState::Proxied);
+        let downgrade = strict.unspecified_source(UnspecifiedSource::Downgrade);
+        match ProxyStream::from_stream(AddresslessStream::new(b"PROXY TCP4 0.0.0.0 198.51.100.7 4124 443\r\n"), &downgrade, None, &mut Vec::new()) {
+            Err(ProxyReadError::NoClientAddress(Command::Proxy)) => {},
+            other => panic!("a downgraded header should have been rejected, got {:?}", other.map(|s| s.proxy_state())),
+        }
+    }
+
+    #[test]
+    fn test_require_header_in_first_read() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("should be able to bind");
+        // everything in `writes` has been sent before the server's first read
+        let connect = |writes: &[&[u8]]| {
+            let mut conn = TcpStream::connect(listener.local_addr().unwrap()).expect("should be able to connect");
+            for bytes in writes {
+                conn.write_all(bytes).expect("write must succeed");
+            }
+            let (socket, _) = listener.accept().expect("should accept");
+            (conn, HttpStream(socket))
+        };
+        let v1: &[u8] = b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n";
+        let v2: &[u8] = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c\xc0\x00\x02\x01\xc6\x33\x64\x07\x10\x1c\x01\xbb";
+        for &(version, header) in &[(ProxyProtocolVersion::V1, v1), (ProxyProtocolVersion::V2, v2), (ProxyProtocolVersion::Any, v1), (ProxyProtocolVersion::Any, v2)] {
+            let config = ProxyConfig::new(version).require_header_in_first_read(true);
+
+            // the header and the start of the request in one segment, none of which is lost
+            let (mut conn, socket) = connect(&[&[header, &b"GET / HTTP/1.1\r\n"[..]].concat()]);
+            let mut stream = ProxyStream::from_stream(socket, &config, None, &mut Vec::new()).expect("should parse");
+            assert_eq!(stream.peer_addr().unwrap(), "192.0.2.1:4124".parse::<SocketAddr>().unwrap());
+            assert_eq!(stream.header_len(), header.len());
+            conn.write_all(b"\r\n").expect("write must succeed");
+            let mut buf = [0u8; 18];
+            stream.read_exact(&mut buf).expect("should read past the header");
+            assert_eq!(&buf, b"GET / HTTP/1.1\r\n\r\n");
+
+            // a segment of exactly the header, with the request in the next
+            let (mut conn, socket) = connect(&[header]);
+            let mut stream = ProxyStream::from_stream(socket, &config, None, &mut Vec::new()).expect("should parse");
+            assert_eq!(stream.header_len(), header.len());
+            conn.write_all(b"GET /").expect("write must succeed");
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).expect("should read past the header");
+            assert_eq!(&buf, b"GET /");
+
+            // the header split over two segments is rejected, wherever it is split, while the
+            // same split is fine without the option
+            for &split in &[1, 6, 13, header.len() - 1] {
+                let (_conn, socket) = connect(&[&header[..split]]);
+                match ProxyStream::from_stream(socket, &config, None, &mut Vec::new()) {
+                    Err(ProxyReadError::SplitHeader(read)) => assert_eq!(read, split),
+                    other => panic!("a header split at {} should have been rejected, got {:?}", split, other.map(|s| s.proxy_state())),
+                }
+                let (mut lax_conn, socket) = connect(&[&header[..split]]);
+                let sender = thread::spawn(move || {
+                    thread::sleep(Duration::from_millis(20));
+                    lax_conn.write_all(&header[split..]).expect("write must succeed");
+                    lax_conn
+                });
+                let stream = ProxyStream::from_stream(socket, &ProxyConfig::new(version), None, &mut Vec::new()).expect("should parse");
+                assert_eq!(stream.proxy_state(), ProxyState::Proxied);
+                sender.join().unwrap();
+            }
+        }
+
+        // every hop has to be in the first read, and whatever is not a header is the request
+        let config = ProxyConfig::new(ProxyProtocolVersion::V2).require_header_in_first_read(true).max_proxy_hops(3).peer_hop(PeerHop::Innermost);
+        let (_conn, socket) = connect(&[&[v2, v1, b"GET /"].concat()]);
+        let mut stream = ProxyStream::from_stream(socket, &config, None, &mut Vec::new()).expect("should parse");
+        assert_eq!(stream.proxy_hops().len(), 2);
+        assert_eq!(stream.header_len(), v2.len() + v1.len());
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).expect("should read past the headers");
+        assert_eq!(&buf, b"GET /");
+        let (_conn, socket) = connect(&[&[v2, &v1[..10]].concat()]);
+        assert!(matches!(ProxyStream::from_stream(socket, &config, None, &mut Vec::new()), Err(ProxyReadError::SplitHeader(_))));
+
+        // and an optional listener serves plain connections as usual
+        let optional = ProxyConfig::new(ProxyProtocolVersion::Any).require_header_in_first_read(true).optional(true);
+        let (_conn, socket) = connect(&[b"GET / HTTP/1.1\r\n"]);
+        let mut stream = ProxyStream::from_stream(socket, &optional, None, &mut Vec::new()).expect("should be plain");
+        assert_eq!(stream.proxy_state(), ProxyState::Socket);
+        let mut buf = [0u8; 16];
+        stream.read_exact(&mut buf).expect("should read the request");
+        assert_eq!(&buf, b"GET / HTTP/1.1\r\n");
+    }
+
+    #[test]
+    fn test_set_peer_addr() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("should be able to bind");
+        let mut conn = TcpStream::connect(listener.local_addr().unwrap()).expect("should be able to connect");
+        conn.write_all(b"PROXY TCP4 203.0.113.9 198.51.100.7 4124 443\r\n").expect("write must succeed");
+        let (socket, _) = listener.accept().expect("should accept");
+        let config = ProxyConfig::new(ProxyProtocolVersion::V1);
+        let mut stream = ProxyStream::from_stream(HttpStream(socket), &config, None, &mut Vec::new()).expect("should parse");
+        let header = stream.proxy_header().cloned();
+
+        let elsewhere: SocketAddr = "192.0.2.77:1234".parse().unwrap();
+        stream.set_peer_addr(Some(elsewhere));
+        assert_eq!(stream.peer_addr().unwrap(), elsewhere);
+        assert_eq!(stream.proxy_state(), ProxyState::Overridden(elsewhere));
+        assert!(!stream.was_proxied());
+        assert_eq!(stream.proxy_header().cloned(), header, "the header should be left alone");
+
+        stream.set_peer_addr(None);
+        assert_eq!(stream.peer_addr().unwrap(), conn.local_addr().unwrap());
+        assert_eq!(stream.proxy_state(), ProxyState::Socket);
+        assert_eq!(stream.proxy_header().cloned(), header);
+
+        #[cfg(unix)]
+        {
+            stream.set_peer_addr(Some(elsewhere));
+            let (fd, parts) = stream.into_raw_parts().expect("should take apart");
+            let mut stream = unsafe { ProxyStream::from_raw_parts(fd, &parts) }.expect("should put back together");
+            assert_eq!(stream.peer_addr().unwrap(), elsewhere);
+            assert_eq!(stream.proxy_state(), ProxyState::Overridden(elsewhere));
+        }
+
+        // without a socket peer to fall back to, there is no address at all
+        let mut stream = accept(b"PROXY TCP4 203.0.113.9 198.51.100.7 4124 443\r\n", PeerAddrFallback::Error);
+        stream.set_peer_addr(None);
+        assert!(stream.peer_addr().is_err());
+        assert_eq!(stream.proxy_state(), ProxyState::Unavailable);
+    }
+
+    #[test]
+    fn test_header_len() {
+        let mut unix = b"\r\n\r\n\0\r\nQUIT\n\x21\x31\x00\xd8".to_vec();
+        unix.extend_from_slice(&[b'a'; 216]);
+        let v1: Vec<&[u8]> = vec![
+            b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n",
+            b"PROXY TCP6 2001:db8::1 2001:db8::2 4124 443\r\n",
+            b"PROXY UNKNOWN\r\n",
+            b"PROXY UNKNOWN ffff:f...f:ffff ffff:f...f:ffff 65535 65535\r\n",
+            b"PROXY UNKNOWN ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff 65535 65535\r\n",
+        ];
+        let v2: Vec<&[u8]> = vec![
+            b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x11\x00\x0c\x0a\x0b\x0c\x0d\x7f\x00\x00\x01\x22\xb8\x27\x0f",
+            b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x21\x00\x24\xfd\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x22\xb8\x27\x0f",
+            // with a TLV after the addresses
+            b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x11\x00\x14\xc0\x00\x02\x01\xc6\x33\x64\x07\x10\x1c\x01\xbb\x20\x00\x05\x07\x00\x00\x00\x00",
+            b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x20\x00\x00\x00",
+            &unix,
+        ];
+        let configs = [
+            (ProxyConfig::new(ProxyProtocolVersion::V1), &v1),
+            (ProxyConfig::new(ProxyProtocolVersion::V2), &v2),
+            (ProxyConfig::new(ProxyProtocolVersion::Any), &v1),
+            (ProxyConfig::new(ProxyProtocolVersion::Any).optional(true), &v2),
+        ];
+        for &(ref config, vectors) in &configs {
+            for header in vectors {
+                let mut bytes = header.to_vec();
+                bytes.extend_from_slice(b"GET / HTTP/1.1\r\n\r\n");
+                let mut stream = ProxyStream::from_stream(AddresslessStream::new(&bytes), config, None, &mut Vec::new()).expect("should parse");
+                assert_eq!(stream.header_len(), header.len(), "for {:?}", header);
+                let mut rest = Vec::new();
+                stream.read_to_end(&mut rest).expect("read must succeed");
+                assert_eq!(rest, b"GET / HTTP/1.1\r\n\r\n");
+            }
+        }
+        // the bytes read looking for a header, when there was none, were not a header
+        let config = ProxyConfig::new(ProxyProtocolVersion::V1).optional(true);
+        let plain = ProxyStream::from_stream(AddresslessStream::new(b"POST / HTTP/1.1\r\n\r\n"), &config, None, &mut Vec::new()).expect("should parse");
+        assert_eq!(plain.header_len(), 0);
+    }
+
+    #[test]
+    fn test_raw_header() {
+        let header = b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x11\x00\x14\xc0\x00\x02\x01\xc6\x33\x64\x07\x10\x1c\x01\xbb\x04\x00\x05hello";
+        let mut bytes = header.to_vec();
+        bytes.extend_from_slice(b"GET / HTTP/1.1\r\n\r\n");
+        let config = ProxyConfig::new(ProxyProtocolVersion::Any).optional(true);
+        let stream = ProxyStream::from_stream(AddresslessStream::new(&bytes), &config, None, &mut Vec::new()).expect("should parse");
+        assert_eq!(stream.raw_header(), None, "should not be kept by default");
+
+        let config = config.retain_raw_header(true);
+        let mut stream = ProxyStream::from_stream(AddresslessStream::new(&bytes), &config, None, &mut Vec::new()).expect("should parse");
+        // the TLV is kept, though it is not parsed
+        assert_eq!(stream.raw_header(), Some(&header[..]));
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).expect("read must succeed");
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n\r\n");
+        let plain = ProxyStream::from_stream(AddresslessStream::new(b"PUT / HTTP/1.1\r\n\r\n"), &config, None, &mut Vec::new()).expect("should parse");
+        assert_eq!(plain.raw_header(), None);
+    }
+
+    /// Echo whatever the client sends through the two halves of a split stream, each used
+    /// from its own thread; returns what the client got back and what the read half knew
+    fn echo_through_split(client_bytes: &'static [u8]) -> (Vec<u8>, Option<SocketAddr>) {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("should be able to bind");
+        let mut conn = TcpStream::connect(listener.local_addr().unwrap()).expect("should be able to connect");
+        conn.write_all(client_bytes).expect("write must succeed");
+        conn.shutdown(::std::net::Shutdown::Write).expect("shutdown must succeed");
+        let (socket, _) = listener.accept().expect("should accept");
+        let config = ProxyConfig::new(ProxyProtocolVersion::V1).optional(true);
+        let stream = ProxyStream::from_stream(HttpStream(socket), &config, None, &mut Vec::new()).expect("should parse");
+        let (mut reader, mut writer) = stream.split();
+        let source = reader.proxy_info().and_then(|info| info.source_addr());
+
+        let (tx, rx) = mpsc::channel::<Vec<u8>>();
+        let read_thread = thread::spawn(move || {
+            let mut buf = [0u8; 4];
+            loop {
+                match reader.read(&mut buf).expect("read must succeed") {
+                    0 => break,
+                    n => tx.send(buf[..n].to_vec()).unwrap(),
+                }
+            }
+        });
+        let write_thread = thread::spawn(move || {
+            for chunk in rx {
+                writer.write_all(&chunk).expect("write must succeed");
+            }
+            writer.shutdown().expect("shutdown must succeed");
+        });
+        let mut echoed = Vec::new();
+        conn.read_to_end(&mut echoed).expect("read must succeed");
+        read_thread.join().unwrap();
+        write_thread.join().unwrap();
+        (echoed, source)
+    }
+
+    #[test]
+    fn test_try_clone() {
+        for client_bytes in &[&b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\nrequest"[..], &b"request"[..]] {
+            let listener = TcpListener::bind("127.0.0.1:0").expect("should be able to bind");
+            let mut conn = TcpStream::connect(listener.local_addr().unwrap()).expect("should be able to connect");
+            conn.write_all(client_bytes).expect("write must succeed");
+            conn.shutdown(::std::net::Shutdown::Write).expect("shutdown must succeed");
+            let (socket, _) = listener.accept().expect("should accept");
+            let config = ProxyConfig::new(ProxyProtocolVersion::V1).optional(true);
+            let mut stream = ProxyStream::from_stream(HttpStream(socket), &config, None, &mut Vec::new()).expect("should parse");
+            let mut clone = stream.try_clone().expect("should be able to clone");
+            assert_eq!(clone.proxy_info(), stream.proxy_info());
+            assert_eq!(clone.peer_addr().unwrap(), stream.peer_addr().unwrap());
+
+            // the original keeps any bytes read past the header (in the plain case, the first)
+            let mut body = String::new();
+            stream.read_to_string(&mut body).expect("read must succeed");
+            assert_eq!(body, "request");
+            clone.write_all(b"response").expect("write must succeed");
+            clone.close(::std::net::Shutdown::Write).expect("shutdown must succeed");
+            let mut response = String::new();
+            conn.read_to_string(&mut response).expect("read must succeed");
+            assert_eq!(response, "response");
+        }
+    }
+
+    #[test]
+    fn test_fill_peek() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("should be able to bind");
+        let mut conn = TcpStream::connect(listener.local_addr().unwrap()).expect("should be able to connect");
+        conn.write_all(b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\nGET / HTTP/1.1\r\n").expect("write must succeed");
+        let (socket, _) = listener.accept().expect("should accept");
+        let config = ProxyConfig::new(ProxyProtocolVersion::V1);
+        let mut stream = ProxyStream::from_stream(HttpStream(socket), &config, None, &mut Vec::new()).expect("should parse");
+        stream.peek_buffered(|bytes| assert_eq!(bytes, b""));
+
+        // time out waiting for more than has been sent, keeping what has arrived
+        let timeout = Duration::from_millis(100);
+        let start = ::std::time::Instant::now();
+        let buffered = stream.fill_peek(100, Some(timeout)).expect("fill should succeed");
+        assert!(start.elapsed() >= timeout);
+        assert_eq!(buffered, 16);
+        assert!(stream.peek_buffered(|bytes| bytes.starts_with(b"GET ")));
+        assert_eq!(stream.fill_peek(4, None).expect("fill should succeed"), 16);
+
+        conn.write_all(b"Host: localhost\r\n\r\n").expect("write must succeed");
+        conn.shutdown(::std::net::Shutdown::Write).expect("shutdown must succeed");
+        assert_eq!(stream.fill_peek(1 << 20, None).expect("fill should succeed"), 35);
+        let mut request = String::new();
+        stream.read_to_string(&mut request).expect("read must succeed");
+        assert_eq!(request, "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert_eq!(stream.bytes_read(), 35);
+        stream.peek_buffered(|bytes| assert_eq!(bytes, b""));
+    }
+
+    #[test]
+    fn test_fill_peek_before_more_arrives() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("should be able to bind");
+        let mut conn = TcpStream::connect(listener.local_addr().unwrap()).expect("should be able to connect");
+        conn.write_all(b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\nGET ").expect("write must succeed");
+        let (socket, _) = listener.accept().expect("should accept");
+        let config = ProxyConfig::new(ProxyProtocolVersion::V1);
+        let mut stream = ProxyStream::from_stream(HttpStream(socket), &config, None, &mut Vec::new()).expect("should parse");
+        assert_eq!(stream.fill_peek(4, None).expect("fill should succeed"), 4);
+        let clone = stream.clone();
+
+        // another clone can peek at what is buffered while one waits for more
+        let (done_tx, done_rx) = mpsc::channel();
+        let filler = thread::spawn(move || {
+            let buffered = stream.fill_peek(100, Some(Duration::from_secs(5))).expect("fill should succeed");
+            done_tx.send(()).unwrap();
+            buffered
+        });
+        thread::sleep(Duration::from_millis(100));
+        assert!(clone.peek_buffered(|bytes| bytes == b"GET "));
+        assert!(done_rx.try_recv().is_err(), "fill_peek should still be waiting");
+        conn.write_all(b"/ HTTP/1.1\r\n").expect("write must succeed");
+        conn.shutdown(::std::net::Shutdown::Write).expect("shutdown must succeed");
+        assert_eq!(filler.join().unwrap(), 16);
+        assert!(clone.peek_buffered(|bytes| bytes == b"GET / HTTP/1.1\r\n"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_raw_parts_round_trip() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("should be able to bind");
+        let mut conn = TcpStream::connect(listener.local_addr().unwrap()).expect("should be able to connect");
+        let header = b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x11\x00\x14\xc0\x00\x02\x01\xc6\x33\x64\x07\x10\x1c\x01\xbb\x20\x00\x05\x07\x00\x00\x00\x00";
+        conn.write_all(header).expect("write must succeed");
+        conn.write_all(b"GET / HTTP/1.1\r\n").expect("write must succeed");
+        let (socket, _) = listener.accept().expect("should accept");
+        let config = ProxyConfig::new(ProxyProtocolVersion::V2).retain_raw_header(true);
+        let mut stream = ProxyStream::from_stream(HttpStream(socket), &config, None, &mut Vec::new()).expect("should parse");
+        assert_eq!(stream.fill_peek(16, Some(Duration::from_secs(5))).expect("fill should succeed"), 16);
+        let info = stream.proxy_info().cloned();
+        assert!(info.as_ref().and_then(|info| info.ssl_info()).map(|ssl| ssl.cert_verified()).unwrap_or(false));
+
+        let (fd, parts) = stream.into_raw_parts().expect("should take apart");
+        assert!(unsafe { ProxyStream::from_raw_parts(fd, b"PXH\x01junk") }.is_err(), "junk should not decode");
+        assert!(unsafe { ProxyStream::from_raw_parts(fd, &parts[..parts.len() - 1]) }.is_err(), "short parts should not decode");
+        let mut addressless = parts.clone();
+        addressless.splice(5..12, Some(0));
+        assert!(unsafe { ProxyStream::from_raw_parts(fd, &addressless) }.is_err(), "a proxied state needs an address");
+        let mut stream = unsafe { ProxyStream::from_raw_parts(fd, &parts) }.expect("should put back together");
+        assert_eq!(stream.peer_addr().unwrap(), "192.0.2.1:4124".parse().unwrap());
+        assert_eq!(stream.proxy_state(), ProxyState::Proxied);
+        assert_eq!(stream.proxy_info().cloned(), info);
+        assert_eq!(stream.header_len(), header.len());
+        assert_eq!(stream.raw_header(), Some(&header[..]));
+
+        conn.write_all(b"Host: localhost\r\n\r\n").expect("write must succeed");
+        conn.shutdown(::std::net::Shutdown::Write).expect("shutdown must succeed");
+        let mut request = String::new();
+        stream.read_to_string(&mut request).expect("read must succeed");
+        assert_eq!(request, "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    }
+
+    #[test]
+    fn test_peek_optional_pushback() {
+        let config = ProxyConfig::new(ProxyProtocolVersion::V1).optional(true);
+        let mut stream = ProxyStream::from_stream(AddresslessStream::new(b"\x16\x03\x01 hello"), &config, None, &mut Vec::new()).expect("should parse");
+        // the first byte was read looking for a header, and is enough to tell this is TLS
+        stream.peek_buffered(|bytes| assert_eq!(bytes, b"\x16"));
+        // reads take whatever has arrived, which may be more than asked for
+        assert_eq!(stream.fill_peek(3, None).expect("fill should succeed"), 9);
+        assert!(stream.peek_buffered(|bytes| bytes.starts_with(b"\x16\x03\x01")));
+        let mut all = Vec::new();
+        stream.read_to_end(&mut all).expect("read must succeed");
+        assert_eq!(all, b"\x16\x03\x01 hello");
+    }
+
+    #[test]
+    fn test_split_echo() {
+        let (echoed, source) = echo_through_split(b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\nhello, relay");
+        assert_eq!(echoed, b"hello, relay");
+        assert_eq!(source, Some("192.0.2.1:4124".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_split_echo_pushback() {
+        // the first byte was read while looking for a header, and must come out of the read half
+        let (echoed, source) = echo_through_split(b"hello, relay");
+        assert_eq!(echoed, b"hello, relay");
+        assert_eq!(source, None);
+    }
+
+    /// A stream which, like `TcpStream`, can be read through a shared reference
+    struct SharedStream(TcpStream);
+
+    impl Read for SharedStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Read for &SharedStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            (&self.0).read(buf)
+        }
+    }
+
+    impl Write for SharedStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.flush()
+        }
+    }
+
+    impl NetworkStream for SharedStream {
+        fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+            self.0.peer_addr()
+        }
+
+        fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+            self.0.set_read_timeout(dur)
+        }
+
+        fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+            self.0.set_write_timeout(dur)
+        }
+    }
+
+    #[test]
+    fn test_shared_reads() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("should be able to bind");
+        let mut conn = TcpStream::connect(listener.local_addr().unwrap()).expect("should be able to connect");
+        let (socket, _) = listener.accept().expect("should accept");
+        conn.write_all(b"hello").expect("write must succeed");
+        let config = ProxyConfig::new(ProxyProtocolVersion::V1).optional(true);
+        let stream = Arc::new(ProxyStream::from_stream(SharedStream(socket), &config, None, &mut Vec::new()).expect("should parse"));
+
+        // the pushed-back first byte comes out of a shared read too
+        let mut buf = [0u8; 16];
+        let n = (&*stream).read(&mut buf).expect("read must succeed");
+        assert_eq!(&buf[..n], b"h");
+        let mut rest = [0u8; 4];
+        (&*stream).read_exact(&mut rest).expect("read must succeed");
+        assert_eq!(&rest, b"ello");
+
+        let payload: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+        let readers: Vec<_> = (0..2).map(|_| {
+            let stream = Arc::clone(&stream);
+            thread::spawn(move || {
+                let mut total = 0;
+                let mut buf = [0u8; 512];
+                loop {
+                    match (&*stream).read(&mut buf).expect("read must succeed") {
+                        0 => return total,
+                        n => total += n,
+                    }
+                }
+            })
+        }).collect();
+        conn.write_all(&payload).expect("write must succeed");
+        conn.shutdown(::std::net::Shutdown::Write).expect("shutdown must succeed");
+        let total: usize = readers.into_iter().map(|reader| reader.join().unwrap()).sum();
+        assert_eq!(total, payload.len());
+    }
+
+    #[test]
+    fn test_debug_and_clone_bounds() {
+        // AddresslessStream is neither Clone nor Debug, and that must not stop it being printed
+        let stream = accept(b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n", PeerAddrFallback::Error);
+        let printed = format!("{:?}", stream);
+        assert!(printed.starts_with("ProxyStream { inner: \"hyper_networklistener_proxy::proxy_stream::tests::AddresslessStream\""), "{}", printed);
+        assert!(printed.contains("192.0.2.1:4124"), "{}", printed);
+
+        // while streams over a Clone inner stream are themselves Clone
+        fn assert_clone<C: Clone>() {}
+        assert_clone::<ProxyStream<HttpStream>>();
     }
 }
@@ -0,0 +1,204 @@
+use std::io::{self, Read};
+use std::time::{Duration, Instant};
+
+use proxy_config::ProxyConfig;
+use proxy_info::ProxyInfo;
+use proxy_protocol::ProxyProtocolVersion;
+use proxy_stream::{read_preamble, HeaderReader, HeaderSource, Preamble, Pushback};
+
+
+/// A `Read` adapter which removes a PROXY header from the start of any reader, for use with
+/// servers which are not built on hyper.
+///
+/// The header is read and parsed on first use (the first call to `read`, or to `header`),
+/// after which reads are passed through to the inner reader, starting with any bytes which
+/// had to be read past the header.
+///
+/// All of `ProxyConfig` applies except for the timeouts (including the grace period of
+/// optional mode), since plain readers have no read timeout to enforce them with; and the
+/// peer address fallback, failure response, socket options and trusted proxies, which only make
+/// sense for network streams.
+///
+/// ```
+/// use std::io::Read;
+/// use hyper_networklistener_proxy::{ProxyProtocolVersion, StripProxy};
+///
+/// let bytes: &[u8] = b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\nhello";
+/// let mut reader = StripProxy::new(bytes, ProxyProtocolVersion::V1);
+/// let mut payload = String::new();
+/// reader.read_to_string(&mut payload).unwrap();
+/// assert_eq!(payload, "hello");
+/// assert_eq!(reader.header().unwrap().unwrap().source_addr(), Some("192.0.2.1:4124".parse().unwrap()));
+/// ```
+#[derive(Debug)]
+pub struct StripProxy<R> {
+    inner: R,
+    state: StripState,
+    info: Option<ProxyInfo>,
+    pushback: Option<Pushback>,
+}
+
+
+#[derive(Debug)]
+enum StripState {
+    Unparsed(Box<ProxyConfig>),
+    Parsed,
+    Failed,
+}
+
+
+/// The inner reader of a `StripProxy`, as seen by the header parser
+struct PlainSource<'a, R: 'a>(&'a mut R);
+
+impl<'a, R: Read> Read for PlainSource<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<'a, R: Read> HeaderSource for PlainSource<'a, R> {
+    fn set_header_read_timeout(&self, _dur: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn wait_readable(&self, _deadline: Option<Instant>) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    fn try_wait_readable(&self, _deadline: Instant) -> io::Result<Option<bool>> {
+        Ok(None)
+    }
+}
+
+
+impl<R: Read> StripProxy<R> {
+    /// Wrap `reader`, which should start with a header of the given version
+    pub fn new(reader: R, version: ProxyProtocolVersion) -> Self {
+        StripProxy::from_config(reader, &ProxyConfig::new(version))
+    }
+
+    /// Wrap `reader`, which should start with a header as described by `config`
+    pub fn from_config(reader: R, config: &ProxyConfig) -> Self {
+        StripProxy {
+            inner: reader,
+            state: StripState::Unparsed(Box::new(config.clone())),
+            info: None,
+            pushback: None,
+        }
+    }
+
+    /// The header, reading it first if that has not happened yet. Returns `None` if there
+    /// was no header (only possible in optional mode), and an error if the header could not
+    /// be read.
+    pub fn header(&mut self) -> io::Result<Option<&ProxyInfo>> {
+        self.parse()?;
+        Ok(self.info.as_ref())
+    }
+
+    /// Unwrap this adapter, returning the inner reader. Any bytes which were read past the
+    /// header and have not been delivered yet are lost.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn parse(&mut self) -> io::Result<()> {
+        let config = match self.state {
+            StripState::Parsed => return Ok(()),
+            StripState::Failed => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "could not read the PROXY header"));
+            },
+            StripState::Unparsed(ref config) => (**config).clone(),
+        };
+        let preamble = {
+            let mut source = PlainSource(&mut self.inner);
+            let mut reader = HeaderReader { stream: &mut source, prefix: None, raw: None, deadline: None, consumed: 0 };
+            read_preamble(&mut reader, &config, None, &mut Vec::new())
+        };
+        match preamble {
+            Ok(Preamble::Header { header, pushback, .. }) => {
+                self.info = Some(ProxyInfo::from_header(header));
+                if !pushback.is_empty() {
+                    self.pushback = Some(Pushback::new(pushback));
+                }
+            },
+            Ok(Preamble::Plain(pushback)) => self.pushback = Some(Pushback::new(pushback)),
+            Err(e) => {
+                self.state = StripState::Failed;
+                return Err(e.into());
+            },
+        }
+        self.state = StripState::Parsed;
+        Ok(())
+    }
+}
+
+
+impl<R: Read> Read for StripProxy<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.parse()?;
+        if let Some(ref mut pushback) = self.pushback {
+            let n = pushback.read(buf);
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+        }
+        self.pushback = None;
+        self.inner.read(buf)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Cursor, Read};
+
+    use proxy_config::{PeerHop, ProxyConfig};
+    use proxy_protocol::ProxyProtocolVersion;
+    use super::StripProxy;
+
+    #[test]
+    fn test_strip_v2() {
+        let mut bytes = b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x11\x00\x0c\x0a\x0b\x0c\x0d\x7f\x00\x00\x01\x22\xb8\x27\x0f".to_vec();
+        let payload: Vec<u8> = (0..=255).collect();
+        bytes.extend_from_slice(&payload);
+        let mut reader = StripProxy::new(Cursor::new(bytes), ProxyProtocolVersion::Any);
+        let mut read = Vec::new();
+        reader.read_to_end(&mut read).expect("read must succeed");
+        assert_eq!(read, payload);
+        let info = reader.header().unwrap().expect("should have a header");
+        assert_eq!(info.source_addr(), Some("10.11.12.13:8888".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_strip_optional_pushback() {
+        let config = ProxyConfig::new(ProxyProtocolVersion::V1).optional(true);
+        let mut reader = StripProxy::from_config(Cursor::new(b"PRI * HTTP/2.0\r\n".to_vec()), &config);
+        assert!(reader.header().unwrap().is_none());
+        let mut read = String::new();
+        reader.read_to_string(&mut read).expect("read must succeed");
+        assert_eq!(read, "PRI * HTTP/2.0\r\n");
+    }
+
+    #[test]
+    fn test_strip_chained_headers() {
+        let bytes = b"PROXY TCP4 198.51.100.20 198.51.100.7 35000 443\r\nPROXY TCP4 192.0.2.1 198.51.100.20 4124 443\r\nGET / HTTP/1.1\r\n".to_vec();
+        let config = ProxyConfig::new(ProxyProtocolVersion::V1).max_proxy_hops(2).peer_hop(PeerHop::Innermost);
+        let mut reader = StripProxy::from_config(Cursor::new(bytes), &config);
+        let mut read = String::new();
+        reader.read_to_string(&mut read).expect("read must succeed");
+        assert_eq!(read, "GET / HTTP/1.1\r\n");
+        let info = reader.header().unwrap().expect("should have a header");
+        assert_eq!(info.source_addr(), Some("192.0.2.1:4124".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_strip_no_header() {
+        let mut reader = StripProxy::new(Cursor::new(b"GET / HTTP/1.1\r\n\r\n".to_vec()), ProxyProtocolVersion::V1);
+        let mut buf = [0u8; 16];
+        let err = reader.read(&mut buf).expect_err("should fail without a header");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        // and keep failing, rather than passing through what is left of the request
+        assert!(reader.read(&mut buf).is_err());
+        assert!(reader.header().is_err());
+    }
+}
@@ -0,0 +1,225 @@
+use std::error::Error;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+
+/// A block of IP addresses in CIDR notation, such as `10.0.0.0/8` or `fd00::/8`.
+///
+/// A bare address (`192.0.2.1`) stands for just that address. Any bits of the address past
+/// the prefix length are ignored, so `10.1.2.3/8` is the same block as `10.0.0.0/8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+
+impl Cidr {
+    /// The block of addresses sharing the first `prefix_len` bits of `addr`, or `None` if
+    /// `prefix_len` is longer than the address
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Option<Self> {
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix_len > max {
+            return None;
+        }
+        Some(Cidr { addr, prefix_len })
+    }
+
+    /// Whether `addr` is in this block. IPv4-mapped IPv6 addresses (`::ffff:192.0.2.1`, as
+    /// reported for IPv4 clients of dual-stack sockets) are treated as the IPv4 address they
+    /// map.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let addr = match addr {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(addr),
+            addr => addr,
+        };
+        match (self.addr, addr) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => prefix_matches(u128::from(u32::from(net)), u128::from(u32::from(addr)), 32, self.prefix_len),
+            (IpAddr::V6(net), IpAddr::V6(addr)) => prefix_matches(u128::from(net), u128::from(addr), 128, self.prefix_len),
+            _ => false,
+        }
+    }
+}
+
+
+fn prefix_matches(net: u128, addr: u128, bits: u8, prefix_len: u8) -> bool {
+    let host_bits = u32::from(bits - prefix_len);
+    net.checked_shr(host_bits).unwrap_or(0) == addr.checked_shr(host_bits).unwrap_or(0)
+}
+
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+
+impl FromStr for Cidr {
+    type Err = ParseCidrError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ParseCidrError(s.to_owned());
+        let mut parts = s.splitn(2, '/');
+        let addr: IpAddr = parts.next().unwrap_or("").parse().map_err(|_| err())?;
+        let prefix_len = match parts.next() {
+            Some(len) => len.parse().map_err(|_| err())?,
+            None if addr.is_ipv4() => 32,
+            None => 128,
+        };
+        Cidr::new(addr, prefix_len).ok_or_else(err)
+    }
+}
+
+
+#[cfg(feature = "serde")]
+impl<'de> ::serde::Deserialize<'de> for Cidr {
+    fn deserialize<D: ::serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(::serde::de::Error::custom)
+    }
+}
+
+
+/// A string which is not an address or CIDR block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseCidrError(String);
+
+impl fmt::Display for ParseCidrError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid address or CIDR block {:?}", self.0)
+    }
+}
+
+impl Error for ParseCidrError {}
+
+
+/// The set of addresses which are trusted to send PROXY headers, i.e., those of the load
+/// balancers in front of a listener.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrustedProxies {
+    cidrs: Vec<Cidr>,
+}
+
+
+impl TrustedProxies {
+    /// Trust the addresses in any of `cidrs`
+    pub fn new(cidrs: Vec<Cidr>) -> Self {
+        TrustedProxies { cidrs }
+    }
+
+    /// Whether `addr` is trusted
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        self.cidrs.iter().any(|cidr| cidr.contains(addr))
+    }
+
+    /// The trusted blocks
+    pub fn cidrs(&self) -> &[Cidr] {
+        &self.cidrs
+    }
+}
+
+
+/// A shared, updatable `TrustedProxies`, for fleets whose load balancers come and go.
+///
+/// Attach a handle with `ProxyConfig::trusted_proxies` and keep a clone of it: every listener
+/// built from that configuration (and every clone of those listeners) consults the same
+/// handle on each accept, so `set` takes effect from the next connection onwards without
+/// rebuilding anything. Accepts only take the lock for long enough to clone an `Arc`, and
+/// `set` only for long enough to swap one in, so neither holds up the other.
+///
+/// ```
+/// use hyper_networklistener_proxy::{ProxyConfig, ProxyProtocolVersion, TrustedHandle, TrustedProxies};
+///
+/// let trust = TrustedHandle::new(TrustedProxies::new(vec!["10.0.0.0/8".parse().unwrap()]));
+/// let config = ProxyConfig::new(ProxyProtocolVersion::V2).trusted_proxies(trust.clone());
+/// // ...and later, once the fleet has grown
+/// trust.set(TrustedProxies::new(vec!["10.0.0.0/8".parse().unwrap(), "172.16.0.0/12".parse().unwrap()]));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct TrustedHandle {
+    current: Arc<RwLock<Arc<TrustedProxies>>>,
+}
+
+
+impl TrustedHandle {
+    /// Construct a handle which initially trusts `trusted`
+    pub fn new(trusted: TrustedProxies) -> Self {
+        TrustedHandle {
+            current: Arc::new(RwLock::new(Arc::new(trusted))),
+        }
+    }
+
+    /// The trusted addresses, as of now
+    pub fn get(&self) -> Arc<TrustedProxies> {
+        Arc::clone(&self.current.read().unwrap())
+    }
+
+    /// Replace the trusted addresses, for every connection accepted from now on
+    pub fn set(&self, trusted: TrustedProxies) {
+        let trusted = Arc::new(trusted);
+        *self.current.write().unwrap() = trusted;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::{Cidr, TrustedHandle, TrustedProxies};
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_cidr_parse() {
+        assert_eq!("10.0.0.0/8".parse(), Ok(Cidr::new(ip("10.0.0.0"), 8).unwrap()));
+        assert_eq!("192.0.2.1".parse(), Ok(Cidr::new(ip("192.0.2.1"), 32).unwrap()));
+        assert_eq!("fd00::/8".parse(), Ok(Cidr::new(ip("fd00::"), 8).unwrap()));
+        assert_eq!("::1".parse(), Ok(Cidr::new(ip("::1"), 128).unwrap()));
+        for bad in &["", "10.0.0.0/", "10.0.0.0/33", "::/129", "10.0.0/8", "10.0.0.0/8/8", "localhost"] {
+            assert!(bad.parse::<Cidr>().is_err(), "{:?} should not parse", bad);
+        }
+        assert_eq!("10.0.0.0/8".parse::<Cidr>().unwrap().to_string(), "10.0.0.0/8");
+
+        fn assert_send_sync<E: ::std::error::Error + Send + Sync + 'static>() {}
+        assert_send_sync::<super::ParseCidrError>();
+    }
+
+    #[test]
+    fn test_cidr_contains() {
+        let net: Cidr = "10.1.2.3/8".parse().unwrap();
+        assert!(net.contains(ip("10.0.0.0")));
+        assert!(net.contains(ip("10.255.255.255")));
+        assert!(!net.contains(ip("11.0.0.0")));
+        assert!(net.contains(ip("::ffff:10.9.8.7")));
+        assert!(!net.contains(ip("::a09:807")));
+
+        let all: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains(ip("255.255.255.255")));
+        assert!(!all.contains(ip("fd00::1")));
+
+        let v6: Cidr = "fd00::/8".parse().unwrap();
+        assert!(v6.contains(ip("fdff::1")));
+        assert!(!v6.contains(ip("fe00::1")));
+        let host: Cidr = "::1".parse().unwrap();
+        assert!(host.contains(ip("::1")));
+        assert!(!host.contains(ip("::2")));
+    }
+
+    #[test]
+    fn test_handle_shared_between_clones() {
+        let handle = TrustedHandle::default();
+        let clone = handle.clone();
+        assert!(!clone.get().contains(ip("192.0.2.1")));
+        let before = clone.get();
+        handle.set(TrustedProxies::new(vec!["192.0.2.0/24".parse().unwrap()]));
+        assert!(clone.get().contains(ip("192.0.2.1")));
+        // a snapshot taken before the update is unaffected
+        assert!(!before.contains(ip("192.0.2.1")));
+    }
+}
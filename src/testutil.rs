@@ -0,0 +1,266 @@
+//! Helpers for testing servers which sit behind a `ProxyListener`.
+//!
+//! Only available with the `testutil` feature; enable it for tests alone by depending on this
+//! crate from `[dev-dependencies]` with `features = ["testutil"]`.
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use proxy_protocol::{ProxyProtocolHeader, ProxyProtocolVersion};
+
+
+/// A client which connects to a server and plays a script at it, for testing how the server
+/// copes with load balancers (and other clients) which send good headers, bad headers, or
+/// good headers badly.
+///
+/// The script is built up with the builder-style methods, and played with `run` (or `spawn`,
+/// for servers accepted from the test's own thread), which returns everything the server sent
+/// back before closing the connection. Servers often hang up on pathological clients part way
+/// through, so a connection which is reset counts as closed: the rest of the script is skipped,
+/// and whatever had arrived is returned.
+///
+/// ```
+/// # extern crate hyper;
+/// # extern crate hyper_networklistener_proxy;
+/// use std::time::Duration;
+/// use hyper::net::{HttpListener, NetworkListener};
+/// use hyper_networklistener_proxy::{ProxyListener, ProxyProtocolVersion};
+/// use hyper_networklistener_proxy::testutil::MockProxyClient;
+///
+/// # fn main() {
+/// let mut listener = ProxyListener::new(HttpListener::new("127.0.0.1:0").unwrap(), ProxyProtocolVersion::V1);
+/// let addr = listener.bound_addr().unwrap();
+/// let client = MockProxyClient::new(addr)
+///     .dribble(b"PROXY TCP4 192.0.2.1 ", Duration::from_millis(1))
+///     .pause(Duration::from_millis(10))
+///     .send(b"198.51.100.7 4124 443\r\n")
+///     .half_close()
+///     .spawn();
+/// let mut stream = listener.accept().unwrap();
+/// assert_eq!(stream.proxy_info().unwrap().source_addr(), Some("192.0.2.1:4124".parse().unwrap()));
+/// drop(stream);
+/// assert_eq!(client.join().unwrap().unwrap(), b"");
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct MockProxyClient {
+    addr: SocketAddr,
+    steps: Vec<Step>,
+    read_timeout: Option<Duration>,
+}
+
+
+#[derive(Debug, Clone)]
+enum Step {
+    Send(Vec<u8>),
+    Dribble(Vec<u8>, Duration),
+    Pause(Duration),
+    HalfClose,
+}
+
+
+impl MockProxyClient {
+    /// A client which will connect to `addr`, with an empty script
+    pub fn new(addr: SocketAddr) -> Self {
+        MockProxyClient {
+            addr,
+            steps: Vec::new(),
+            read_timeout: Some(Duration::from_secs(10)),
+        }
+    }
+
+    /// Send a valid version 1 header from `source` (to the server's address), then a `GET /`
+    pub fn valid_v1(addr: SocketAddr, source: SocketAddr) -> Self {
+        MockProxyClient::new(addr).v1_header(source, addr).get("/")
+    }
+
+    /// Send a valid version 2 header from `source` (to the server's address) carrying `tlvs`,
+    /// then a `GET /`
+    pub fn valid_v2_with_tlvs(addr: SocketAddr, source: SocketAddr, tlvs: &[(u8, &[u8])]) -> Self {
+        MockProxyClient::new(addr).v2_header_with_tlvs(source, addr, tlvs).get("/")
+    }
+
+    /// Send the start of a version 1 header, and nothing more
+    pub fn truncated_header(addr: SocketAddr) -> Self {
+        MockProxyClient::new(addr).send(b"PROXY TCP4 192.0.2.1").half_close()
+    }
+
+    /// Send a valid version 1 header from `source` a byte at a time, `interval` apart, then a
+    /// `GET /`
+    pub fn dribbled_header(addr: SocketAddr, source: SocketAddr, interval: Duration) -> Self {
+        let header = ProxyProtocolHeader::for_addrs(source, addr).to_bytes(ProxyProtocolVersion::V1);
+        MockProxyClient::new(addr).dribble(&header, interval).get("/")
+    }
+
+    /// Send a `GET /` with no header at all
+    pub fn no_header(addr: SocketAddr) -> Self {
+        MockProxyClient::new(addr).get("/")
+    }
+
+    /// Send `bytes`, exactly, in one write
+    pub fn send(mut self, bytes: &[u8]) -> Self {
+        self.steps.push(Step::Send(bytes.to_vec()));
+        self
+    }
+
+    /// Send `bytes` one at a time, waiting `interval` after each
+    pub fn dribble(mut self, bytes: &[u8], interval: Duration) -> Self {
+        self.steps.push(Step::Dribble(bytes.to_vec(), interval));
+        self
+    }
+
+    /// Send nothing for `duration`
+    pub fn pause(mut self, duration: Duration) -> Self {
+        self.steps.push(Step::Pause(duration));
+        self
+    }
+
+    /// Send a version 1 `PROXY` header for a connection from `source` to `dest`
+    pub fn v1_header(self, source: SocketAddr, dest: SocketAddr) -> Self {
+        let header = ProxyProtocolHeader::for_addrs(source, dest).to_bytes(ProxyProtocolVersion::V1);
+        self.send(&header)
+    }
+
+    /// Send a version 2 `PROXY` header for a connection from `source` to `dest`
+    pub fn v2_header(self, source: SocketAddr, dest: SocketAddr) -> Self {
+        self.v2_header_with_tlvs(source, dest, &[])
+    }
+
+    /// Send a version 2 `PROXY` header for a connection from `source` to `dest`, with a TLV
+    /// for each `(type, value)` in `tlvs` after the addresses
+    pub fn v2_header_with_tlvs(self, source: SocketAddr, dest: SocketAddr, tlvs: &[(u8, &[u8])]) -> Self {
+        let mut header = ProxyProtocolHeader::for_addrs(source, dest).to_bytes(ProxyProtocolVersion::V2);
+        for &(kind, value) in tlvs {
+            header.push(kind);
+            header.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            header.extend_from_slice(value);
+        }
+        let len = (header.len() - 16) as u16;
+        header[14..16].copy_from_slice(&len.to_be_bytes());
+        self.send(&header)
+    }
+
+    /// Send a `GET` request for `path`, asking for the connection to be closed after the
+    /// response
+    pub fn get(self, path: &str) -> Self {
+        let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path);
+        self.send(request.as_bytes())
+    }
+
+    /// Shut down the writing half of the connection, so the server reads EOF
+    pub fn half_close(mut self) -> Self {
+        self.steps.push(Step::HalfClose);
+        self
+    }
+
+    /// How long to wait for the server to send anything (or close the connection) once the
+    /// script is done, before `run` fails with the read's error. Defaults to 10 seconds, so a
+    /// server which never closes the connection fails the test rather than hanging it.
+    pub fn read_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.read_timeout = timeout;
+        self
+    }
+
+    /// Connect, play the script, and return what the server sent before it closed (or reset)
+    /// the connection. Fails if the connection cannot be made, or on any other error,
+    /// including the read timing out.
+    pub fn run(&self) -> io::Result<Vec<u8>> {
+        let mut conn = TcpStream::connect(self.addr)?;
+        conn.set_read_timeout(self.read_timeout)?;
+        for step in &self.steps {
+            match play(&mut conn, step) {
+                Ok(()) => {},
+                Err(ref e) if is_hangup(e) => break,
+                Err(e) => return Err(e),
+            }
+        }
+        let mut response = Vec::new();
+        match conn.read_to_end(&mut response) {
+            Ok(_) => Ok(response),
+            Err(ref e) if is_hangup(e) => Ok(response),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// `run` on a thread of its own
+    pub fn spawn(self) -> thread::JoinHandle<io::Result<Vec<u8>>> {
+        thread::spawn(move || self.run())
+    }
+}
+
+
+fn play(conn: &mut TcpStream, step: &Step) -> io::Result<()> {
+    match *step {
+        Step::Send(ref bytes) => conn.write_all(bytes),
+        Step::Dribble(ref bytes, interval) => {
+            for byte in bytes.chunks(1) {
+                conn.write_all(byte)?;
+                thread::sleep(interval);
+            }
+            Ok(())
+        },
+        Step::Pause(duration) => {
+            thread::sleep(duration);
+            Ok(())
+        },
+        Step::HalfClose => conn.shutdown(Shutdown::Write),
+    }
+}
+
+
+/// Whether `e` means the server has hung up
+fn is_hangup(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted |
+             io::ErrorKind::BrokenPipe | io::ErrorKind::NotConnected)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use hyper::net::HttpListener;
+
+    use proxy_listener::ProxyListener;
+    use proxy_protocol::ProxyProtocolVersion;
+    use super::MockProxyClient;
+
+    fn listener(version: ProxyProtocolVersion) -> ProxyListener<HttpListener> {
+        ProxyListener::new(HttpListener::new("127.0.0.1:0").unwrap(), version)
+    }
+
+    #[test]
+    fn test_valid_headers() {
+        let source = "192.0.2.1:4124".parse().unwrap();
+        let mut v1 = listener(ProxyProtocolVersion::V1);
+        let client = MockProxyClient::dribbled_header(v1.bound_addr().unwrap(), source, Duration::from_millis(1)).spawn();
+        let stream = v1.accept_detailed().expect("should accept");
+        assert_eq!(stream.proxy_info().unwrap().source_addr(), Some(source));
+        assert_eq!(stream.proxy_info().unwrap().destination_addr(), v1.bound_addr());
+        drop(stream);
+        client.join().unwrap().expect("should run");
+
+        let mut v2 = listener(ProxyProtocolVersion::V2);
+        let tlvs: &[(u8, &[u8])] = &[(0x20, b"\x01\x00\x00\x00\x00"), (0x04, b"noop")];
+        let client = MockProxyClient::valid_v2_with_tlvs(v2.bound_addr().unwrap(), source, tlvs).spawn();
+        let stream = v2.accept_detailed().expect("should accept");
+        let info = stream.proxy_info().unwrap();
+        assert_eq!(info.source_addr(), Some(source));
+        assert!(info.ssl_info().unwrap().client_ssl());
+        drop(stream);
+        client.join().unwrap().expect("should run");
+    }
+
+    #[test]
+    fn test_bad_headers() {
+        let mut v1 = listener(ProxyProtocolVersion::V1);
+        let addr = v1.bound_addr().unwrap();
+        for client in [MockProxyClient::truncated_header(addr), MockProxyClient::no_header(addr)] {
+            let client = client.spawn();
+            v1.accept_detailed().expect_err("should not accept");
+            assert_eq!(client.join().unwrap().expect("a refusal should not be an error"), b"");
+        }
+    }
+}
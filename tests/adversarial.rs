@@ -0,0 +1,176 @@
+//! Inputs no sender should ever send: the parsers must turn every one of them down with an
+//! error (or wait for more), and never panic.
+
+extern crate hyper_networklistener_proxy;
+
+use hyper_networklistener_proxy::{HeaderParser, ParseConfig, ProxyProtocolVersion};
+use hyper_networklistener_proxy::proxy_protocol::{detect_version, explain, parse_v2_datagram, parse_with, PROXY_V2_SIGNATURE};
+
+const VERSIONS: [ProxyProtocolVersion; 3] = [ProxyProtocolVersion::V1, ProxyProtocolVersion::V2, ProxyProtocolVersion::Any];
+
+
+fn configs() -> Vec<ParseConfig> {
+    vec![
+        ParseConfig::default(),
+        ParseConfig::strict(),
+        ParseConfig::lenient().normalize_mapped_v4(true),
+        // limits too small for any header
+        ParseConfig::default().max_v1_line_len(0).max_v2_block_len(0),
+    ]
+}
+
+
+/// Run `bytes` through everything which parses headers, whole and bit by bit (a byte at a
+/// time, for all but the longest)
+fn parse_everything(bytes: &[u8]) {
+    for config in configs() {
+        for &version in &VERSIONS {
+            let _ = parse_with(bytes, version, &config);
+            let mut parser = HeaderParser::new(version).parse_config(config);
+            for chunk in bytes.chunks(1 + bytes.len() / 256) {
+                if parser.advance(chunk).is_ready() {
+                    break;
+                }
+            }
+            let _ = HeaderParser::new(version).parse_config(config).feed(&mut &bytes[..]);
+        }
+    }
+    let _ = parse_v2_datagram(bytes);
+    let _ = detect_version(bytes);
+    for &version in &VERSIONS {
+        let _ = explain(bytes, version);
+    }
+}
+
+
+/// A version 2 fixed header with the given command and family bytes, and `addrlen`
+fn v2(command: u8, family: u8, addrlen: u16) -> Vec<u8> {
+    let mut header = PROXY_V2_SIGNATURE.to_vec();
+    header.extend_from_slice(&[command, family]);
+    header.extend_from_slice(&addrlen.to_be_bytes());
+    header
+}
+
+
+#[test]
+fn test_adversarial_inputs() {
+    let mut inputs: Vec<Vec<u8>> = vec![
+        // CR at position 0, alone and followed by all sorts
+        b"\r".to_vec(),
+        b"\r\n".to_vec(),
+        b"\r\n\r\n\0\r\nQUIT".to_vec(),
+        b"\rPROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n".to_vec(),
+        // interior NULs
+        b"PROXY TCP4 192.0.2.1\0 198.51.100.7 4124 443\r\n".to_vec(),
+        b"PROXY\0TCP4 192.0.2.1 198.51.100.7 4124 443\r\n".to_vec(),
+        b"PROXY UNKNOWN\0\0\0\r\n".to_vec(),
+        vec![0; 300],
+        // lines which are all terminator, or all spaces
+        b"PROXY \r\n".to_vec(),
+        b"PROXY\r\n".to_vec(),
+        b"P\r\n".to_vec(),
+        b"\n".to_vec(),
+        b"PROXY     \n".to_vec(),
+        [&b"PROXY "[..], &[b' '; 200]].concat(),
+        b"PROXY TCP6 [ ] [] 1 2\r\n".to_vec(),
+        b"PROXY TCP6 [\r\n".to_vec(),
+        b"PROXY TCP4 1.2.3.4 5.6.7.8 99999999999999999999 -0\r\n".to_vec(),
+        b"PROXY TCP4 \xff\xfe 5.6.7.8 1 2\r\n".to_vec(),
+        b"\x16".to_vec(),
+        b"\x16\x03".to_vec(),
+        b"GET".to_vec(),
+    ];
+    // and every truncation of those
+    for input in inputs.clone() {
+        for end in 0..input.len() {
+            inputs.push(input[..end].to_vec());
+        }
+    }
+    // every valid command, family and transport nibble and a few invalid ones, with address
+    // blocks of absurd lengths, both missing and present in full
+    let nibbles = [0u8, 1, 2, 3, 4, 0xf];
+    for &addrlen in &[0u16, 1, 11, 12, 35, 36, 215, 216, 217, 16 * 1024 + 1, 65535] {
+        for &version in &[1u8, 2, 0xf] {
+            for &command in &nibbles {
+                for &family in &nibbles {
+                    for &transport in &nibbles {
+                        let header = v2(version << 4 | command, family << 4 | transport, addrlen);
+                        if version == 2 && command <= 1 && family <= 3 && transport <= 2 {
+                            let mut full = header.clone();
+                            full.resize(16 + addrlen as usize, 0xff);
+                            inputs.push(full);
+                        }
+                        inputs.push(header);
+                    }
+                }
+            }
+        }
+    }
+    // TLVs which overrun the block, nest absurdly, or claim to be SSL with no room for it
+    for tlvs in [&b"\x20"[..], b"\x20\x00", b"\x20\x00\x05", b"\x20\xff\xff\x01", b"\x20\x00\x00", b"\x04\xff\xff"] {
+        let mut header = v2(0x21, 0x11, (12 + tlvs.len()) as u16);
+        header.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 7, 0x10, 0x1c, 0x01, 0xbb]);
+        header.extend_from_slice(tlvs);
+        inputs.push(header.clone());
+        let mut unspec = v2(0x21, 0x00, tlvs.len() as u16);
+        unspec.extend_from_slice(tlvs);
+        inputs.push(unspec);
+    }
+    for input in &inputs {
+        parse_everything(input);
+    }
+}
+
+
+/// xorshift64*, so that failures can be reproduced from the seed
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+
+#[test]
+fn test_random_bytes_never_panic() {
+    let seeds: &[&[u8]] = &[
+        b"",
+        b"PROXY ",
+        b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n",
+        b"PROXY TCP6 2001:db8::1 ::ffff:198.51.100.7 4124 443\r\n",
+        b"PROXY UNKNOWN\r\n",
+        &PROXY_V2_SIGNATURE,
+        b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x11\x00\x14\xc0\x00\x02\x01\xc6\x33\x64\x07\x10\x1c\x01\xbb\x20\x00\x05\x01\x00\x00\x00\x00",
+    ];
+    // alphabets biased towards the bytes the parsers look for
+    let alphabets: &[&[u8]] = &[b" \r\n0123456789.:[]PROXYTCP46UNKOW\t", b"\x00\x01\x02\x03\x11\x12\x20\x21\x22\x31\x0d\x0a\xff"];
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    for _ in 0..2000 {
+        let mut bytes = seeds[rng.below(seeds.len())].to_vec();
+        let alphabet = alphabets[rng.below(alphabets.len())];
+        for _ in 0..rng.below(4) {
+            if bytes.is_empty() {
+                break;
+            }
+            // flip, truncate or splice somewhere in the middle
+            let at = rng.below(bytes.len());
+            match rng.below(3) {
+                0 => bytes[at] = rng.next() as u8,
+                1 => bytes.truncate(at),
+                _ => bytes.insert(at, alphabet[rng.below(alphabet.len())]),
+            }
+        }
+        for _ in 0..rng.below(120) {
+            bytes.push(if rng.below(4) == 0 { rng.next() as u8 } else { alphabet[rng.below(alphabet.len())] });
+        }
+        parse_everything(&bytes);
+    }
+}
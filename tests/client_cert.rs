@@ -0,0 +1,90 @@
+#![cfg(feature = "iron")]
+
+extern crate hyper;
+extern crate hyper_networklistener_proxy;
+extern crate iron;
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+
+use hyper::net::HttpListener;
+use hyper_networklistener_proxy::{ProxyConfig, ProxyListener, ProxyProtocolVersion, ProxyRegistry, RequireClientCert};
+use iron::prelude::*;
+use iron::status;
+
+
+fn request(addr: SocketAddr, header: &[u8], path: &str) -> String {
+    let mut conn = TcpStream::connect(addr).expect("should be able to connect");
+    conn.write_all(header).expect("write must succeed");
+    write!(&mut conn, "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path).expect("write must succeed");
+    let mut response = String::new();
+    conn.read_to_string(&mut response).expect("read must succeed");
+    response
+}
+
+
+/// A version 2 header from 10.11.12.13:`port` to 127.0.0.1:9999 carrying `tlvs`
+fn v2_header(port: u16, tlvs: &[u8]) -> Vec<u8> {
+    let mut header = b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x11\x00".to_vec();
+    header.push(12 + tlvs.len() as u8);
+    header.extend_from_slice(b"\x0a\x0b\x0c\x0d\x7f\x00\x00\x01");
+    header.push((port >> 8) as u8);
+    header.push(port as u8);
+    header.extend_from_slice(b"\x27\x0f");
+    header.extend_from_slice(tlvs);
+    header
+}
+
+
+fn serve(guard: RequireClientCert, registry: ProxyRegistry) -> iron::Listening {
+    let config = ProxyConfig::new(ProxyProtocolVersion::V2).registry(registry);
+    let inner = HttpListener::new("127.0.0.1:0").expect("should be able to bind");
+    let listener = ProxyListener::from_config(inner, &config);
+    let mut chain = Chain::new(|_: &mut Request| Ok(Response::with((status::Ok, "guarded"))));
+    chain.link_before(guard);
+    Iron::new(chain).listen(listener, iron::Protocol::http()).expect("should listen")
+}
+
+
+#[test]
+fn test_verified_cert() {
+    let registry = ProxyRegistry::new();
+    let mut listening = serve(RequireClientCert::new(registry.clone()), registry);
+
+    let response = request(listening.socket, &v2_header(8888, b"\x20\x00\x05\x07\x00\x00\x00\x00"), "/");
+    assert!(response.starts_with("HTTP/1.1 200") && response.ends_with("guarded"), "unexpected response {:?}", response);
+
+    listening.close().expect("should close");
+}
+
+
+#[test]
+fn test_unverified_cert() {
+    let registry = ProxyRegistry::new();
+    let mut listening = serve(RequireClientCert::new(registry.clone()), registry);
+
+    let response = request(listening.socket, &v2_header(8888, b"\x20\x00\x05\x07\x00\x00\x00\x0a"), "/");
+    assert!(response.starts_with("HTTP/1.1 403"), "unexpected response {:?}", response);
+    assert!(!response.contains("guarded"), "unexpected response {:?}", response);
+
+    listening.close().expect("should close");
+}
+
+
+#[test]
+fn test_missing_ssl_tlv() {
+    let registry = ProxyRegistry::new();
+    let guard = RequireClientCert::new(registry.clone()).status(status::Unauthorized).exempt("/health");
+    let mut listening = serve(guard, registry);
+    let addr = listening.socket;
+
+    let response = request(addr, &v2_header(8888, b""), "/");
+    assert!(response.starts_with("HTTP/1.1 401"), "unexpected response {:?}", response);
+    assert!(!response.contains("guarded"), "unexpected response {:?}", response);
+    let response = request(addr, &v2_header(8889, b""), "/health?verbose=1");
+    assert!(response.starts_with("HTTP/1.1 200") && response.ends_with("guarded"), "unexpected response {:?}", response);
+    let response = request(addr, &v2_header(8890, b""), "/healthz");
+    assert!(response.starts_with("HTTP/1.1 401"), "unexpected response {:?}", response);
+
+    listening.close().expect("should close");
+}
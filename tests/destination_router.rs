@@ -0,0 +1,76 @@
+#![cfg(feature = "iron")]
+
+extern crate hyper;
+extern crate hyper_networklistener_proxy;
+extern crate iron;
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+
+use hyper::net::HttpListener;
+use hyper_networklistener_proxy::{DestinationRouter, ProxyConfig, ProxyListener, ProxyProtocolVersion, ProxyRegistry};
+use iron::prelude::*;
+use iron::status;
+
+
+fn request(addr: SocketAddr, header: &str) -> String {
+    let mut conn = TcpStream::connect(addr).expect("should be able to connect");
+    write!(&mut conn, "{}GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", header).expect("write must succeed");
+    let mut response = String::new();
+    conn.read_to_string(&mut response).expect("read must succeed");
+    response
+}
+
+
+fn respond(body: &'static str) -> impl Fn(&mut Request) -> IronResult<Response> {
+    move |_: &mut Request| Ok(Response::with((status::Ok, body)))
+}
+
+
+#[test]
+fn test_route_by_destination() {
+    let registry = ProxyRegistry::new();
+    let config = ProxyConfig::new(ProxyProtocolVersion::V1).optional(true).registry(registry.clone());
+    let inner = HttpListener::new("127.0.0.1:0").expect("should be able to bind");
+    let listener = ProxyListener::from_config(inner, &config);
+    let router = DestinationRouter::new(registry)
+        .destination("10.0.0.0/8".parse().unwrap(), Some(443), respond("internal"))
+        .port(443, respond("public"))
+        .port(8080, respond("admin"));
+    let mut listening = Iron::new(router).listen(listener, iron::Protocol::http()).expect("should listen");
+    let addr = listening.socket;
+
+    let cases = [
+        ("PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n", "HTTP/1.1 200", "public"),
+        ("PROXY TCP4 192.0.2.1 10.1.2.3 4125 443\r\n", "HTTP/1.1 200", "internal"),
+        ("PROXY TCP6 2001:db8::1 2001:db8::2 4126 8080\r\n", "HTTP/1.1 200", "admin"),
+        ("PROXY TCP4 192.0.2.1 10.1.2.3 4127 22\r\n", "HTTP/1.1 404", ""),
+        ("PROXY UNKNOWN\r\n", "HTTP/1.1 404", ""),
+        ("", "HTTP/1.1 404", ""),
+    ];
+    for &(header, status, body) in &cases {
+        let response = request(addr, header);
+        assert!(response.starts_with(status), "unexpected response to {:?}: {:?}", header, response);
+        assert!(response.ends_with(&format!("\r\n\r\n{}", body)), "unexpected response to {:?}: {:?}", header, response);
+    }
+
+    listening.close().expect("should close");
+}
+
+
+#[test]
+fn test_fallback() {
+    let registry = ProxyRegistry::new();
+    let config = ProxyConfig::new(ProxyProtocolVersion::V1).optional(true).registry(registry.clone());
+    let inner = HttpListener::new("127.0.0.1:0").expect("should be able to bind");
+    let listener = ProxyListener::from_config(inner, &config);
+    let router = DestinationRouter::new(registry).port(443, respond("public")).fallback(respond("fallback"));
+    let mut listening = Iron::new(router).listen(listener, iron::Protocol::http()).expect("should listen");
+    let addr = listening.socket;
+
+    assert!(request(addr, "PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n").ends_with("public"));
+    assert!(request(addr, "PROXY TCP4 192.0.2.1 198.51.100.7 4124 80\r\n").ends_with("fallback"));
+    assert!(request(addr, "").ends_with("fallback"));
+
+    listening.close().expect("should close");
+}
@@ -0,0 +1,73 @@
+//! Taking over a connection from a hyper handler, as websocket libraries do, behind a
+//! `ProxyListener`. Iron's `Request` does not expose the connection at all, so this has to
+//! happen in a hyper `Handler`.
+
+extern crate hyper;
+extern crate hyper_networklistener_proxy;
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+
+use hyper::header::{Connection, ConnectionOption};
+use hyper::net::HttpListener;
+use hyper::server::{Request, Response, Server};
+use hyper::status::StatusCode;
+use hyper_networklistener_proxy::{ProxyHttpStream, ProxyListener, ProxyProtocolVersion};
+
+
+/// Switch the connection over to echoing everything back, prefixed with the client's address
+fn hijack(req: Request, mut res: Response) {
+    let (mut socket, client) = match req.downcast_ref::<ProxyHttpStream>() {
+        Some(stream) => (stream.get_ref().0.try_clone().expect("should clone the socket"), stream.proxy_info().and_then(|info| info.source_addr())),
+        None => {
+            *res.status_mut() = StatusCode::InternalServerError;
+            res.send(b"not a ProxyHttpStream").unwrap();
+            return;
+        },
+    };
+    *res.status_mut() = StatusCode::SwitchingProtocols;
+    res.headers_mut().set(Connection(vec![ConnectionOption::ConnectionHeader("upgrade".parse().unwrap())]));
+    res.send(b"").unwrap();
+
+    writeln!(&mut socket, "hello {:?}", client).unwrap();
+    let mut buf = [0u8; 64];
+    loop {
+        match socket.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => socket.write_all(&buf[..n]).unwrap(),
+        }
+    }
+}
+
+
+#[test]
+fn test_hijack_through_proxy_stream() {
+    let listener = ProxyListener::new(HttpListener::new("127.0.0.1:0").expect("should be able to bind"), ProxyProtocolVersion::V1);
+    let mut listening = Server::new(listener).handle(hijack).expect("should listen");
+
+    let mut conn = TcpStream::connect(listening.socket).expect("should be able to connect");
+    conn.write_all(b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n").unwrap();
+    conn.write_all(b"GET /echo HTTP/1.1\r\nHost: localhost\r\nConnection: upgrade\r\nUpgrade: echo\r\n\r\n").unwrap();
+    let mut reader = BufReader::new(conn.try_clone().unwrap());
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "HTTP/1.1 101 Switching Protocols\r\n");
+    while line != "\r\n" {
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+    }
+
+    line.clear();
+    reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "hello Some(192.0.2.1:4124)\n");
+    conn.write_all(b"ping\n").unwrap();
+    line.clear();
+    reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "ping\n");
+
+    conn.shutdown(::std::net::Shutdown::Write).unwrap();
+    let mut rest = Vec::new();
+    reader.read_to_end(&mut rest).unwrap();
+    assert_eq!(rest, b"");
+    listening.close().expect("should close");
+}
@@ -0,0 +1,49 @@
+extern crate hyper;
+extern crate hyper_networklistener_proxy;
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+
+use hyper::net::HttpListener;
+use hyper::server::{Request, Response, Server};
+use hyper_networklistener_proxy::{ProxyAwareHandler, ProxyConfig, ProxyInfo, ProxyListener, ProxyProtocolVersion};
+
+
+fn request(addr: SocketAddr, header: &[u8]) -> String {
+    let mut conn = TcpStream::connect(addr).expect("should be able to connect");
+    conn.write_all(header).expect("write must succeed");
+    write!(&mut conn, "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").expect("write must succeed");
+    let mut response = String::new();
+    conn.read_to_string(&mut response).expect("read must succeed");
+    response
+}
+
+
+fn handler(_req: Request, res: Response, proxy: Option<&ProxyInfo>) {
+    let body = match proxy {
+        Some(info) => format!("dest={:?}", info.destination_addr()),
+        None => "no proxy info".to_string(),
+    };
+    res.send(body.as_bytes()).unwrap();
+}
+
+
+#[test]
+fn test_proxy_info_reaches_handler() {
+    let config = ProxyConfig::new(ProxyProtocolVersion::Any).optional(true);
+    let inner = HttpListener::new("127.0.0.1:0").expect("should be able to bind");
+    let listener = ProxyListener::from_config(inner, &config);
+    let mut listening = Server::new(listener).handle(ProxyAwareHandler::new(handler)).expect("should listen");
+    let addr = listening.socket;
+
+    let response = request(addr, b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n");
+    assert!(response.ends_with("dest=Some(198.51.100.7:443)"), "unexpected response {:?}", response);
+
+    let response = request(addr, b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x11\x00\x0c\x0a\x0b\x0c\x0d\x7f\x00\x00\x01\x22\xb8\x27\x0f");
+    assert!(response.ends_with("dest=Some(127.0.0.1:9999)"), "unexpected response {:?}", response);
+
+    let response = request(addr, b"");
+    assert!(response.ends_with("no proxy info"), "unexpected response {:?}", response);
+
+    listening.close().expect("should close");
+}
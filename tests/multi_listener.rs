@@ -0,0 +1,47 @@
+#![cfg(unix)]
+
+extern crate hyper;
+extern crate hyper_networklistener_proxy;
+
+use std::net::SocketAddr;
+
+use hyper::net::HttpListener;
+use hyper::server::{Request, Response, Server};
+use hyper_networklistener_proxy::{MultiProxyListener, ProxyListener, ProxyProtocolVersion};
+use hyper_networklistener_proxy::testutil::MockProxyClient;
+
+
+/// Send a request after `header`, and return whatever arrives before the connection closes
+/// (or is reset, as rejected connections can be)
+fn request(addr: SocketAddr, header: &[u8]) -> String {
+    let response = MockProxyClient::new(addr).send(header).get("/").run().expect("should get a response");
+    String::from_utf8(response).expect("response should be text")
+}
+
+
+fn handler(req: Request, res: Response) {
+    res.send(format!("client={}", req.remote_addr).as_bytes()).unwrap();
+}
+
+
+#[test]
+fn test_one_server_two_versions() {
+    let v1 = ProxyListener::new(HttpListener::new("127.0.0.1:0").expect("should be able to bind"), ProxyProtocolVersion::V1);
+    let v2 = ProxyListener::new(HttpListener::new("127.0.0.1:0").expect("should be able to bind"), ProxyProtocolVersion::V2);
+    let listener = MultiProxyListener::new(vec![v1, v2]).expect("should set up listeners");
+    let addrs = listener.addrs();
+    let mut listening = Server::new(listener).handle_threads(handler, 2).expect("should listen");
+    assert_eq!(listening.socket, addrs[0]);
+
+    let response = request(addrs[0], b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n");
+    assert!(response.ends_with("client=192.0.2.1:4124"), "unexpected response {:?}", response);
+
+    let response = request(addrs[1], b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x11\x00\x0c\x0a\x0b\x0c\x0d\x7f\x00\x00\x01\x22\xb8\x27\x0f");
+    assert!(response.ends_with("client=10.11.12.13:8888"), "unexpected response {:?}", response);
+
+    // each port only takes its own version
+    let response = request(addrs[1], b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n");
+    assert!(!response.contains("client="), "unexpected response {:?}", response);
+
+    listening.close().expect("should close");
+}
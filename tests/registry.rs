@@ -0,0 +1,60 @@
+extern crate hyper;
+extern crate hyper_networklistener_proxy;
+extern crate iron;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+use hyper::net::HttpListener;
+use hyper_networklistener_proxy::{ProxyConfig, ProxyListener, ProxyProtocolVersion, ProxyRegistry};
+use iron::prelude::*;
+use iron::status;
+
+
+fn request(addr: &str, header: &str) -> String {
+    let mut conn = TcpStream::connect(addr).expect("should be able to connect");
+    write!(&mut conn, "{}", header).expect("write must succeed");
+    write!(&mut conn, "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").expect("write must succeed");
+    let mut response = String::new();
+    conn.read_to_string(&mut response).expect("read must succeed");
+    response
+}
+
+
+#[test]
+fn test_destination_from_handler() {
+    let registry = ProxyRegistry::new();
+    let config = ProxyConfig::new(ProxyProtocolVersion::V1).registry(registry.clone());
+    let inner = HttpListener::new("127.0.0.1:0").expect("should be able to bind");
+    let listener = ProxyListener::from_config(inner, &config);
+
+    let handler_registry = registry.clone();
+    let handler = move |req: &mut Request| {
+        let dest = handler_registry.lookup(req.remote_addr)
+            .and_then(|info| info.destination_addr())
+            .map(|addr| addr.to_string())
+            .unwrap_or_else(|| "none".to_string());
+        Ok(Response::with((status::Ok, format!("dest={}", dest))))
+    };
+    let mut listening = Iron::new(handler).listen(listener, iron::Protocol::http()).expect("should listen");
+    let addr = listening.socket.to_string();
+
+    let response = request(&addr, "PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n");
+    assert!(response.ends_with("dest=198.51.100.7:443"), "unexpected response {:?}", response);
+
+    let response = request(&addr, "PROXY UNKNOWN\r\n");
+    assert!(response.ends_with("dest=none"), "unexpected response {:?}", response);
+
+    // the server drops its streams asynchronously after the connection closes
+    for _ in 0..100 {
+        if registry.is_empty() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(registry.is_empty(), "entries should be evicted once the connections are closed");
+
+    listening.close().expect("should close");
+}
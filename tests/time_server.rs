@@ -0,0 +1,92 @@
+//! Drives the `time_server` example in-process, as a client behind a load balancer would.
+
+extern crate hyper;
+extern crate hyper_networklistener_proxy;
+extern crate iron;
+#[macro_use] extern crate log;
+extern crate router;
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+
+use hyper_networklistener_proxy::ProxyProtocolVersion;
+
+#[path = "../examples/time_server/server.rs"]
+mod server;
+
+
+fn request(addr: SocketAddr, header: &[u8], path: &str) -> String {
+    let mut conn = TcpStream::connect(addr).expect("should be able to connect");
+    conn.write_all(header).expect("write must succeed");
+    write!(&mut conn, "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path).expect("write must succeed");
+    let mut response = String::new();
+    conn.read_to_string(&mut response).expect("read must succeed");
+    response
+}
+
+
+fn body(response: &str) -> &str {
+    response.split_once("\r\n\r\n").map(|(_, body)| body).unwrap_or("")
+}
+
+
+const V1: &[u8] = b"PROXY TCP4 192.0.2.1 198.51.100.7 4124 443\r\n";
+const V2: &[u8] = b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x21\x00\x24\
+                    \x20\x01\x0d\xb8\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\
+                    \x20\x01\x0d\xb8\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x02\
+                    \x10\x1c\x01\xbb";
+const LOCAL: &[u8] = b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x20\x00\x00\x00";
+
+
+#[test]
+fn test_mode_flag() {
+    assert_eq!(server::parse_mode("off"), Ok(None));
+    assert_eq!(server::parse_mode("v1"), Ok(Some(ProxyProtocolVersion::V1)));
+    assert_eq!(server::parse_mode("any"), Ok(Some(ProxyProtocolVersion::Any)));
+    assert!(server::parse_mode("v3").is_err());
+}
+
+
+#[test]
+fn test_json_string() {
+    assert_eq!(server::json_string("tcp4"), "\"tcp4\"");
+    assert_eq!(server::json_string("a \"b\" \\c\n\u{1}"), r#""a \"b\" \\c\n\u0001""#);
+}
+
+
+#[test]
+fn test_info_route() {
+    let mut listening = server::serve("127.0.0.1:0", Some(ProxyProtocolVersion::Any)).expect("should listen");
+    let addr = listening.socket;
+
+    let response = request(addr, V1, "/info");
+    assert_eq!(body(&response), "src=192.0.2.1:4124 dst=198.51.100.7:443 ver=1 cmd=proxy proto=tcp4\n");
+    let response = request(addr, V1, "/");
+    assert!(body(&response).ends_with("you: 192.0.2.1:4124"), "unexpected response {:?}", response);
+
+    let response = request(addr, V2, "/info?format=json");
+    assert!(response.contains("Content-Type: application/json"), "unexpected response {:?}", response);
+    assert_eq!(body(&response), "{\"src\":\"[2001:db8::1]:4124\",\"dst\":\"[2001:db8::2]:443\",\"version\":2,\
+                                  \"proto\":\"tcp6\",\"local\":false,\"ssl_verified\":null}\n");
+
+    // health checks are answered whatever they ask for
+    let response = request(addr, LOCAL, "/no/such/route");
+    assert!(response.starts_with("HTTP/1.1 200"), "unexpected response {:?}", response);
+    assert_eq!(body(&response), "ok\n");
+
+    listening.close().expect("should close");
+}
+
+
+#[test]
+fn test_plain_clients() {
+    let mut listening = server::serve("127.0.0.1:0", None).expect("should listen");
+    let addr = listening.socket;
+
+    let response = request(addr, b"", "/info");
+    assert_eq!(body(&response), "no PROXY header\n");
+    let response = request(addr, b"", "/info?format=json");
+    assert_eq!(body(&response), "null\n");
+
+    listening.close().expect("should close");
+}
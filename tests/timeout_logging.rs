@@ -0,0 +1,92 @@
+//! What hyper logs about connections accepted by an Iron server behind a `ProxyListener`.
+//! This is its own test binary, since the logger is global to the process.
+
+extern crate hyper;
+extern crate hyper_networklistener_proxy;
+extern crate iron;
+extern crate log;
+
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use hyper::net::HttpListener;
+use hyper_networklistener_proxy::{ProxyConfig, ProxyListener, ProxyProtocolVersion};
+use hyper_networklistener_proxy::testutil::MockProxyClient;
+use iron::prelude::*;
+use iron::status;
+use log::{Level, Log, Metadata, Record};
+
+
+struct Capture(Mutex<Vec<(Level, String)>>);
+
+impl Log for Capture {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        self.0.lock().unwrap().push((record.level(), record.args().to_string()));
+    }
+
+    fn flush(&self) {}
+}
+
+static CAPTURE: Capture = Capture(Mutex::new(Vec::new()));
+
+
+/// Everything logged about failed connections so far, at any level
+fn failures() -> Vec<(Level, String)> {
+    CAPTURE.0.lock().unwrap().iter().filter(|&(_, message)| message.starts_with("Connection failed")).cloned().collect()
+}
+
+
+/// Wait for `n` failed connections to have been logged, which happens just after the server
+/// drops the connection
+fn wait_for_failures(n: usize) -> Vec<(Level, String)> {
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while failures().len() < n && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    failures()
+}
+
+
+fn listen(config: &ProxyConfig) -> (iron::Listening, SocketAddr) {
+    let listener = ProxyListener::from_config(HttpListener::new("127.0.0.1:0").expect("should be able to bind"), config);
+    let listening = Iron::new(|_: &mut Request| Ok(Response::with((status::Ok, "ok"))))
+        .listen(listener, iron::Protocol::http())
+        .expect("should listen");
+    let addr = listening.socket;
+    (listening, addr)
+}
+
+
+#[test]
+fn test_header_timeouts_are_not_errors() {
+    log::set_logger(&CAPTURE).expect("should set the logger");
+    log::set_max_level(log::LevelFilter::Trace);
+    let config = ProxyConfig::new(ProxyProtocolVersion::V1).header_timeout(Some(Duration::from_millis(100)));
+
+    let (mut listening, addr) = listen(&config);
+    let silent = MockProxyClient::new(addr).read_timeout(Some(Duration::from_secs(2))).run();
+    assert_eq!(silent.expect("the server should close the connection"), b"");
+    let logged = wait_for_failures(1);
+    assert_eq!(logged.len(), 1, "expected the timeout to be logged, got {:?}", logged);
+    assert!(logged[0].1.contains("timed out"), "expected a timeout, got {:?}", logged[0]);
+    MockProxyClient::no_header(addr).run().expect("the server should close the connection");
+    let logged = wait_for_failures(2);
+    assert_eq!(logged.len(), 2, "expected the malformed header to be logged, got {:?}", logged);
+    assert!(logged.iter().all(|&(level, _)| level > Level::Error), "nothing should be logged as an error, got {:?}", logged);
+    listening.close().expect("should close");
+
+    // skipped timeouts are not logged at all, but malformed headers still are
+    let (mut listening, addr) = listen(&config.skip_header_timeouts(true));
+    MockProxyClient::new(addr).read_timeout(Some(Duration::from_secs(2))).run().expect("the server should close the connection");
+    MockProxyClient::no_header(addr).run().expect("the server should close the connection");
+    let logged = wait_for_failures(3);
+    assert_eq!(logged.len(), 3, "expected only the malformed header to be logged, got {:?}", logged);
+    assert!(!logged[2].1.contains("timed out"), "expected the malformed header, got {:?}", logged[2]);
+    listening.close().expect("should close");
+}
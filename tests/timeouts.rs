@@ -0,0 +1,69 @@
+extern crate hyper;
+extern crate hyper_networklistener_proxy;
+extern crate iron;
+
+use std::io::Read;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use hyper::net::HttpListener;
+use hyper_networklistener_proxy::{ProxyConfig, ProxyListener, ProxyProtocolVersion};
+use hyper_networklistener_proxy::testutil::MockProxyClient;
+use iron::prelude::*;
+use iron::status;
+
+
+fn listen(config: &ProxyConfig) -> (iron::Listening, SocketAddr) {
+    let inner = HttpListener::new("127.0.0.1:0").expect("should be able to bind");
+    let listener = ProxyListener::from_config(inner, config);
+    let handler = |req: &mut Request| {
+        let mut body = String::new();
+        req.body.read_to_string(&mut body).expect("body read should succeed");
+        Ok(Response::with((status::Ok, format!("got {:?}", body))))
+    };
+    let listening = Iron::new(handler).listen(listener, iron::Protocol::http()).expect("should listen");
+    let addr = listening.socket;
+    (listening, addr)
+}
+
+
+fn config() -> ProxyConfig {
+    ProxyConfig::new(ProxyProtocolVersion::V1)
+        .header_timeout(Some(Duration::from_millis(100)))
+        .stream_read_timeout(Some(Duration::from_secs(5)))
+}
+
+
+#[test]
+fn test_slow_client_after_fast_header() {
+    let (mut listening, addr) = listen(&config());
+    let source = "192.0.2.1:4124".parse().unwrap();
+    // both stalls are well past the header timeout, which must not outlive the header
+    let response = MockProxyClient::new(addr)
+        .v1_header(source, "198.51.100.7:443".parse().unwrap())
+        .pause(Duration::from_millis(500))
+        .send(b"POST / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: 10\r\n\r\nhello")
+        .pause(Duration::from_millis(500))
+        .send(b"world")
+        .run()
+        .expect("should get a response");
+    let response = String::from_utf8(response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "unexpected response {:?}", response);
+    assert!(response.ends_with("got \"helloworld\""), "unexpected response {:?}", response);
+    listening.close().expect("should close");
+}
+
+
+#[test]
+fn test_slow_header() {
+    let (mut listening, addr) = listen(&config());
+    // the server should give up on the header and close the connection long before the
+    // client's read times out
+    let response = MockProxyClient::new(addr)
+        .send(b"PROXY TCP4 ")
+        .read_timeout(Some(Duration::from_secs(2)))
+        .run()
+        .expect("the connection should be closed");
+    assert_eq!(response, b"");
+    listening.close().expect("should close");
+}